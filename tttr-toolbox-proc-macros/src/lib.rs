@@ -23,6 +23,29 @@ impl Parse for PTUTagRead {
     }
 }
 
+struct PTUStreamArgs {
+    stream_type: Ident,
+    width: Ident,
+}
+
+impl Parse for PTUStreamArgs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let stream_type: Ident = input.parse()?;
+        // Records are 32 bits wide unless stated otherwise
+        let width = if input.parse::<Option<Token![,]>>()?.is_some() {
+            let width: Ident = input.parse()?;
+            if width != "u32" && width != "u64" {
+                return Err(syn::Error::new(width.span(), "Records must be u32 or u64"));
+            }
+            width
+        } else {
+            format_ident!("u32")
+        };
+
+        Ok(PTUStreamArgs { stream_type, width })
+    }
+}
+
 // example use
 // read_ptu_tag!(header[SOME_VALUE] as Int8);
 #[proc_macro]
@@ -48,61 +71,135 @@ pub fn make_ptu_stream(args: TokenStream, item: TokenStream) -> TokenStream {
     //let name: Ident = Parse::parse(args).unwrap();
 
     let input = syn::parse_macro_input!(item as syn::ItemFn);
-    let stream_type = parse_macro_input!(args as syn::Ident);
+    // example use
+    // #[make_ptu_stream(PHT2)] or #[make_ptu_stream(SOME_FORMAT, u64)]
+    let PTUStreamArgs { stream_type, width } = parse_macro_input!(args as PTUStreamArgs);
     let stream_name = format_ident!("{}Stream", stream_type);
+    let read_into = format_ident!("read_{}_into", width);
 
     let output = quote! {
         #[allow(non_camel_case_types)]
         pub struct #stream_name {
             // todo: make it just with a trait that implements readbuf
             source: BufReader<std::fs::File>,
-            click_buffer: [u32; BUFFER_SIZE],
+            click_buffer: Vec<#width>,
             effective_buffer_size: u32,
-            num_records: usize,
+            num_records: u64,
+            /// Byte offset of the first record of the stream
+            data_start: u64,
+            /// Whether the stream may pick up records appended to the file
+            growable: bool,
             time_resolution: f64,
             photons_in_buffer: i32,
-            click_count: usize,
+            click_count: u64,
             overflow_correction: u64,
+            marker_channel_offset: Option<i32>,
+            overflow_channel: Option<i32>,
+            /// Number of overflow records parsed so far
+            pub overflow_count: u64,
+            /// Number of marker records parsed so far
+            pub marker_count: u64,
         }
 
         impl #stream_name {
+            /// Bytes taken on disk by each record of the stream
+            pub const RECORD_SIZE: usize = std::mem::size_of::<#width>();
+
             pub fn new(ptu_file: &ptu::PTUFile, start_record: Option<usize>, stop_record: Option<usize>) -> Result<Self, Error> {
                 let header = &ptu_file.header;
                 let number_of_records: i64 = read_ptu_tag!(header[TAG_NUM_RECORDS] as Int8);
-                let data_offset: i64 = read_ptu_tag!(header["DataOffset"] as Int8);
+                let data_offset = ptu_file.data_offset()?;
 
-                let mut buffered = BufReader::with_capacity(8*1024, std::fs::File::open(ptu_file.path.clone())?);
+                let mut buffered = BufReader::with_capacity(reader_capacity(), std::fs::File::open(ptu_file.path.clone())?);
 
-                let record_offset = if let Some(offset) = start_record {
-                    offset as i64
-                } else {
-                    0 as i64
-                };
-
-                let last_record = if let Some(last) = stop_record {
-                    last as i64
-                } else {
-                    number_of_records as i64
-                };
+                // Record counts and offsets are kept in 64 bits so that huge files
+                // don't overflow on 32 bit targets
+                let record_offset = start_record.map_or(0, |offset| offset as u64);
+                let last_record = stop_record.map_or(number_of_records.max(0) as u64, |last| last as u64);
 
-                // 4 bytes per record
-                buffered.seek(SeekFrom::Start(((data_offset as u64) + (4*record_offset) as u64)))?;
+                let record_size = Self::RECORD_SIZE as u64;
+                let data_start = data_offset + record_size * record_offset;
+                buffered.seek(SeekFrom::Start(data_start))?;
+                // Files still being written may hold fewer records than announced
+                let available = buffered.get_ref().metadata()?.len().saturating_sub(data_start) / record_size;
 
                 Ok(Self {
                     source: buffered,
-                    click_buffer: [0; BUFFER_SIZE],
+                    click_buffer: vec![0; buffer_size()],
                     effective_buffer_size: 0,
-                    num_records: (last_record - record_offset) as usize,
+                    num_records: last_record.saturating_sub(record_offset).min(available),
+                    data_start,
+                    growable: stop_record.is_none(),
                     time_resolution: ptu_file.time_resolution()?,
                     photons_in_buffer: 0,
                     click_count: 0,
                     overflow_correction: 0,
+                    marker_channel_offset: None,
+                    overflow_channel: None,
+                    overflow_count: 0,
+                    marker_count: 0,
                 })
             }
+
+            /// Report marker records on the virtual channel `offset + marker_bits`
+            /// instead of on a negative channel number. Passing `None` keeps the
+            /// default behaviour.
+            pub fn with_marker_channels(mut self, offset: Option<i32>) -> Self {
+                self.marker_channel_offset = offset;
+                self
+            }
+
+            /// Report overflow records as clicks on `channel` at the time the time
+            /// tag wrapped around. Passing `None` keeps the default behaviour.
+            pub fn with_overflow_channel(mut self, channel: Option<i32>) -> Self {
+                self.overflow_channel = channel;
+                self
+            }
+
+            /// Decode `records` records from each read instead of `buffer_size()`.
+            pub fn with_buffer_size(mut self, records: usize) -> Self {
+                // Records already read into the buffer must stay where they are
+                let in_use = self.effective_buffer_size as usize;
+                self.click_buffer.resize(records.max(in_use).max(1), 0);
+                self
+            }
+
+            /// Pick up the records appended to the file since the stream was created,
+            /// e.g. while an acquisition is still running, so that iteration can
+            /// resume where it stopped. Returns whether new records are available.
+            /// Streams created with a `stop_record` never grow.
+            pub fn poll_new(&mut self) -> Result<bool, Error> {
+                if !self.growable {
+                    return Ok(false);
+                }
+                let file_len = self.source.get_ref().metadata()?.len();
+                // Only complete records are picked up
+                let available = file_len.saturating_sub(self.data_start) / (Self::RECORD_SIZE as u64);
+                if available > self.num_records {
+                    self.num_records = available;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+
+            /// Start the overflow correction at `state` instead of zero. Pass the
+            /// state returned by `into_overflow_state` from the stream that read the
+            /// preceding records to keep the time of flight continuous.
+            pub fn with_overflow_state(mut self, state: u64) -> Self {
+                self.overflow_correction = state;
+                self
+            }
+
+            /// Consume the stream and return its overflow correction, i.e. the time
+            /// tag offset in native units accumulated by the overflows read so far.
+            pub fn into_overflow_state(self) -> u64 {
+                self.overflow_correction
+            }
         }
 
         impl TTTRStream for #stream_name {
-            type RecordSize = u32;
+            type RecordSize = #width;
             #[inline(always)]
             #input
 
@@ -119,14 +216,12 @@ pub fn make_ptu_stream(args: TokenStream, item: TokenStream) -> TokenStream {
             }
             if self.photons_in_buffer == 0 {
                 let records_remaining = self.num_records - self.click_count;
-                let clicks_requested = if records_remaining < BUFFER_SIZE {
-                    records_remaining
-                } else {
-                    BUFFER_SIZE
-                };
+                // Only the clamped request is narrowed down to index the buffer
+                let clicks_requested =
+                    records_remaining.min(self.click_buffer.len() as u64) as usize;
                 let read_res = self
                     .source
-                    .read_u32_into::<NativeEndian>(&mut self.click_buffer[..clicks_requested]);
+                    .#read_into::<NativeEndian>(&mut self.click_buffer[..clicks_requested]);
                 if let Err(_x) = read_res {
                     return None;
                 };
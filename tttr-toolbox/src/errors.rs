@@ -15,4 +15,8 @@ pub enum Error {
     InvalidHeader(String),
     #[error("{0}")]
     NotImplemented(String),
+    #[error("{0}")]
+    InvalidParams(String),
+    #[error("{0}")]
+    InvalidHistogram(String),
 }
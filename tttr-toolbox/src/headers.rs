@@ -1,4 +1,15 @@
-#[derive(Debug)]
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::errors::Error;
+use crate::parsers::custom::CustomStream;
+use crate::parsers::ptu::streamers::{HHT2_HH1Stream, HHT2_HH2Stream, HHT3_HH2Stream, PHT2Stream};
+use crate::parsers::ptu::PTUFile;
+use crate::{to_native_units, Click, RecordKind, TTTRFile, TTTRRecord, TTTRStream};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RecordType {
     PHT2,
     #[allow(non_camel_case_types)]
@@ -10,6 +21,808 @@ pub enum RecordType {
     NotImplemented,
 }
 
+impl RecordType {
+    /// Number of bytes each record of this type takes on disk.
+    ///
+    /// It is the record width its stream was declared with, e.g. 64 bit records come
+    /// from `#[make_ptu_stream(TYPE, u64)]`, so the two can't disagree.
+    pub fn record_size(&self) -> usize {
+        match self {
+            RecordType::PHT2 => PHT2Stream::RECORD_SIZE,
+            RecordType::HHT2_HH1 => HHT2_HH1Stream::RECORD_SIZE,
+            RecordType::HHT2_HH2 => HHT2_HH2Stream::RECORD_SIZE,
+            RecordType::HHT3_HH2 => HHT3_HH2Stream::RECORD_SIZE,
+            RecordType::NotImplemented => 4,
+        }
+    }
+
+    /// Time tag overflow period of this record type.
+    ///
+    /// It is expressed in native units, multiples of `MeasDesc_GlobalResolution`
+    /// in T2 and of the sync period in T3, and only depends on the width of the time
+    /// tag field of the record. Files acquired with a different base resolution
+    /// therefore share the same value and reconstruct their durations correctly once
+    /// scaled by the time resolution declared on their header. The HydraHarp V1
+    /// format is the exception to the power of two rule as documented by PicoQuant.
+    pub const fn wraparound(&self) -> u64 {
+        match self {
+            RecordType::PHT2 => 210698240,
+            RecordType::HHT2_HH1 => 33552000,
+            RecordType::HHT2_HH2 => 33554432,
+            RecordType::HHT3_HH2 => 1024,
+            RecordType::NotImplemented => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RecordType::PHT2 => "PHT2",
+            RecordType::HHT2_HH1 => "HHT2_HH1",
+            RecordType::HHT2_HH2 => "HHT2_HH2",
+            RecordType::HHT3_HH2 => "HHT3_HH2",
+            RecordType::NotImplemented => "NotImplemented",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parse the name of a supported record type as printed by `Display`, e.g.
+/// `"HHT2_HH2"`. `NotImplemented` and unknown names are rejected.
+impl std::str::FromStr for RecordType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PHT2" => Ok(RecordType::PHT2),
+            "HHT2_HH1" => Ok(RecordType::HHT2_HH1),
+            "HHT2_HH2" => Ok(RecordType::HHT2_HH2),
+            "HHT3_HH2" => Ok(RecordType::HHT3_HH2),
+            _ => Err(Error::InvalidParams(format!("Unknown record type {}", s))),
+        }
+    }
+}
+
+/// File format and record types the crate can decode, see `supported_formats`.
+///
+/// `modes` are the TCSPC measurement modes of the record types, `T2` or `T3`, and
+/// `can_lifetime` tells whether the lifetime algorithms accept them.
+#[derive(Debug, Copy, Clone)]
+pub struct FormatInfo {
+    pub name: &'static str,
+    pub record_types: &'static [RecordType],
+    pub modes: &'static [&'static str],
+    pub can_lifetime: bool,
+}
+
+const SUPPORTED_FORMATS: [FormatInfo; 2] = [
+    FormatInfo {
+        name: "PTU",
+        record_types: &[RecordType::PHT2, RecordType::HHT2_HH1, RecordType::HHT2_HH2],
+        modes: &["T2"],
+        can_lifetime: false,
+    },
+    FormatInfo {
+        name: "PTU",
+        record_types: &[RecordType::HHT3_HH2],
+        modes: &["T3"],
+        can_lifetime: true,
+    },
+];
+
+/// Formats supported by the crate, so that user interfaces can only offer the
+/// options that apply to a file. Formats plugged in through `File::Custom` are not
+/// listed. None of the cargo features change the supported formats.
+pub fn supported_formats() -> &'static [FormatInfo] {
+    &SUPPORTED_FORMATS
+}
+
+// Magic strings at the start of the PicoQuant file formats
+const PTU_MAGIC: &[u8] = b"PQTTTR";
+const PHU_MAGIC: &[u8] = b"PQHISTO";
+
+/// A TTTR file of any of the supported formats.
+///
+/// It is neither `Send` nor `Sync` because custom files are boxed without those
+/// bounds. Threads working on the same PTU file should each open their own
+/// `PTUFile`, which is both.
 pub enum File {
     PTU(crate::parsers::ptu::PTUFile),
+    /// User provided file format. See `TTTRFile::raw_records` and `RecordDecoder`.
+    Custom(Box<dyn TTTRFile>),
+}
+
+impl File {
+    /// Open the file at `path` with the parser matching its format.
+    ///
+    /// The format is detected from the magic string at the start of the file rather
+    /// than from its extension.
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        if !path.exists() {
+            return Err(Error::FileNotAvailable(path.display().to_string()));
+        }
+        // Files shorter than the magic string are reported as unsupported
+        let mut magic = Vec::with_capacity(8);
+        std::fs::File::open(&path)?
+            .take(8)
+            .read_to_end(&mut magic)?;
+
+        if magic.starts_with(PTU_MAGIC) {
+            Ok(File::PTU(PTUFile::new(path)?))
+        } else if magic.starts_with(PHU_MAGIC) {
+            Err(Error::NotImplemented(String::from(
+                "PHU histogram files are not supported",
+            )))
+        } else {
+            Err(Error::InvalidHeader(format!(
+                "{} is not a supported TTTR file",
+                path.display()
+            )))
+        }
+    }
+
+    /// Curated, human friendly overview of the file metadata.
+    pub fn summary(&self) -> String {
+        match self {
+            File::PTU(x) => x.summary(),
+            File::Custom(x) => {
+                let resolution = match x.time_resolution() {
+                    Ok(x) => format!("{:e} s", x),
+                    Err(_) => String::from("unknown"),
+                };
+                format!("{:<20}: {}\n", "Resolution", resolution)
+            }
+        }
+    }
+
+    /// Duration of the measurement in seconds if declared on the file metadata.
+    pub fn acquisition_time(&self) -> Option<f64> {
+        match self {
+            File::PTU(x) => x.acquisition_time(),
+            File::Custom(_) => None,
+        }
+    }
+
+    /// Electronic delay in seconds of each input keyed by channel number.
+    pub fn input_offsets(&self) -> Result<HashMap<i32, f64>, Error> {
+        match self {
+            File::PTU(x) => x.input_offsets(),
+            File::Custom(_) => Ok(HashMap::new()),
+        }
+    }
+
+    /// Sorted list of the channels with photons within the first `sample` records,
+    /// or within the whole file if `None`. Markers and overflows are not included but
+    /// the sync of HydraHarp T2 files is, as channel 0.
+    ///
+    /// Useful to discover which channels to correlate before running an algorithm.
+    pub fn active_channels(&self, sample: Option<usize>) -> Result<Vec<i32>, Error> {
+        let channels = self
+            .records()?
+            .take(sample.unwrap_or(usize::MAX))
+            .filter_map(|rec| match rec.kind() {
+                RecordKind::Photon { channel } => Some(channel),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        Ok(channels.into_iter().collect())
+    }
+
+    /// Stream all the records in the file regardless of their format.
+    pub fn records(&self) -> Result<RecordStream, Error> {
+        self.records_range(None, None)
+    }
+
+    /// Record range `(start, stop)` holding the clicks between `t_start` and `t_stop`,
+    /// in seconds since the start of the measurement. `None` means the start or end
+    /// of the file respectively.
+    ///
+    /// The time of a record depends on all the overflows before it so the file is
+    /// read from its start up to `t_stop`.
+    pub fn time_to_record_range(
+        &self,
+        t_start: Option<f64>,
+        t_stop: Option<f64>,
+    ) -> Result<(usize, usize), Error> {
+        let stream = self.records()?;
+        let time_resolution = stream.time_resolution();
+        let start_tof = t_start
+            .map(|t| to_native_units(t, time_resolution))
+            .transpose()?;
+        let stop_tof = t_stop
+            .map(|t| to_native_units(t, time_resolution))
+            .transpose()?;
+
+        let mut start = if start_tof.is_none() { Some(0) } else { None };
+        let mut num_records = 0;
+        for (idx, rec) in stream.enumerate() {
+            num_records = idx + 1;
+            if rec.kind() == RecordKind::Overflow {
+                continue;
+            }
+            let tof = *rec.tof();
+            if start.is_none() && start_tof.is_some_and(|t| tof >= t) {
+                start = Some(idx);
+            }
+            if stop_tof.is_some_and(|t| tof >= t) {
+                return Ok((start.unwrap_or(idx), idx));
+            }
+        }
+        Ok((start.unwrap_or(num_records), num_records))
+    }
+
+    /// Stream the last `n` records of the file without reading the ones before them.
+    ///
+    /// The data section is entered directly at the first requested record so the
+    /// overflows that happened before it are unknown. Times of flight are therefore
+    /// relative to an arbitrary origin and only differences between them are
+    /// meaningful. Not available for custom files.
+    pub fn records_tail(&self, n: usize) -> Result<RecordStream, Error> {
+        match self {
+            File::PTU(x) => {
+                let num_records = x.num_records()?;
+                let start =
+                    usize::try_from(num_records.saturating_sub(n as u64)).map_err(|_| {
+                        Error::InvalidParams(String::from(
+                            "The first trailing record can't be addressed on this platform",
+                        ))
+                    })?;
+                self.records_range(Some(start), None)
+            }
+            File::Custom(_) => Err(Error::NotImplemented(String::from(
+                "Streaming the trailing records is not supported on custom files",
+            ))),
+        }
+    }
+
+    /// Stream the records between `start_record` and `stop_record` regardless of
+    /// the file format. `None` means the start or end of the file respectively.
+    ///
+    /// The overflow correction starts at zero on `start_record`, so the time of
+    /// flight of the records is relative to the last overflow before it. Delays
+    /// within the range are correct but times can't be compared across ranges. Use
+    /// `records_range_absolute` for times since the start of the measurement.
+    pub fn records_range(
+        &self,
+        start_record: Option<usize>,
+        stop_record: Option<usize>,
+    ) -> Result<RecordStream, Error> {
+        let (start, stop) = (start_record, stop_record);
+        match self {
+            File::PTU(x) => Ok(match x.record_type()? {
+                RecordType::PHT2 => RecordStream::PHT2(PHT2Stream::new(x, start, stop)?),
+                RecordType::HHT2_HH1 => {
+                    RecordStream::HHT2_HH1(HHT2_HH1Stream::new(x, start, stop)?)
+                }
+                RecordType::HHT2_HH2 => {
+                    RecordStream::HHT2_HH2(HHT2_HH2Stream::new(x, start, stop)?)
+                }
+                RecordType::HHT3_HH2 => {
+                    RecordStream::HHT3_HH2(HHT3_HH2Stream::new(x, start, stop)?)
+                }
+                RecordType::NotImplemented => {
+                    return Err(Error::NotImplemented(String::from(
+                        "Record type not implemented",
+                    )))
+                }
+            }),
+            File::Custom(x) => Ok(RecordStream::Custom(CustomStream::new(
+                x.as_ref(),
+                start,
+                stop,
+            )?)),
+        }
+    }
+
+    /// Overflow state of the stream right before `record`, to be passed to
+    /// `RecordStream::with_overflow_state`.
+    ///
+    /// The overflows are only found by scanning the file from its start up to
+    /// `record`. Records are decoded but nothing else is done with them so the scan
+    /// is cheap compared with running an algorithm on them.
+    pub fn overflow_state_at(&self, record: usize) -> Result<u64, Error> {
+        let mut stream = self.records_range(None, Some(record))?;
+        stream.by_ref().for_each(drop);
+        stream.into_overflow_state()
+    }
+
+    /// Same as `records_range` but the time of flight of the records is counted from
+    /// the start of the measurement, as if the whole file was streamed. See
+    /// `overflow_state_at` for the cost of finding the overflows before the range.
+    pub fn records_range_absolute(
+        &self,
+        start_record: Option<usize>,
+        stop_record: Option<usize>,
+    ) -> Result<RecordStream, Error> {
+        let stream = self.records_range(start_record, stop_record)?;
+        match start_record {
+            Some(start) if start > 0 => stream.with_overflow_state(self.overflow_state_at(start)?),
+            _ => Ok(stream),
+        }
+    }
+}
+
+/// Stream of records for any of the supported record formats.
+///
+/// Like `File` it is not `Send` because of the custom streams. The PTU streams it
+/// wraps are.
+#[allow(non_camel_case_types)]
+pub enum RecordStream {
+    PHT2(PHT2Stream),
+    HHT2_HH1(HHT2_HH1Stream),
+    HHT2_HH2(HHT2_HH2Stream),
+    HHT3_HH2(HHT3_HH2Stream),
+    Custom(CustomStream),
+}
+
+impl RecordStream {
+    /// Time resolution in seconds of the `tof` of the streamed records.
+    pub fn time_resolution(&self) -> f64 {
+        match self {
+            RecordStream::PHT2(x) => x.time_resolution(),
+            RecordStream::HHT2_HH1(x) => x.time_resolution(),
+            RecordStream::HHT2_HH2(x) => x.time_resolution(),
+            RecordStream::HHT3_HH2(x) => x.time_resolution(),
+            RecordStream::Custom(x) => x.time_resolution(),
+        }
+    }
+
+    /// Sync period of the laser in native units. `None` in T2 mode.
+    pub fn sync_period(&self) -> Option<u64> {
+        match self {
+            RecordStream::HHT3_HH2(x) => Some(x.sync_period),
+            _ => None,
+        }
+    }
+
+    /// Map marker records onto the virtual channel `offset + marker_bits`.
+    pub fn with_marker_channels(self, offset: Option<i32>) -> Self {
+        match self {
+            RecordStream::PHT2(x) => RecordStream::PHT2(x.with_marker_channels(offset)),
+            RecordStream::HHT2_HH1(x) => RecordStream::HHT2_HH1(x.with_marker_channels(offset)),
+            RecordStream::HHT2_HH2(x) => RecordStream::HHT2_HH2(x.with_marker_channels(offset)),
+            RecordStream::HHT3_HH2(x) => RecordStream::HHT3_HH2(x.with_marker_channels(offset)),
+            RecordStream::Custom(x) => RecordStream::Custom(x.with_marker_channels(offset)),
+        }
+    }
+
+    /// Pick up the records appended to a file that is still being written. Returns
+    /// whether new records are available, in which case iteration resumes where it
+    /// stopped. Not available for custom files.
+    pub fn poll_new(&mut self) -> Result<bool, Error> {
+        match self {
+            RecordStream::PHT2(x) => x.poll_new(),
+            RecordStream::HHT2_HH1(x) => x.poll_new(),
+            RecordStream::HHT2_HH2(x) => x.poll_new(),
+            RecordStream::HHT3_HH2(x) => x.poll_new(),
+            RecordStream::Custom(_) => Err(Error::NotImplemented(String::from(
+                "Polling for new records is not supported on custom files",
+            ))),
+        }
+    }
+
+    /// Resume the overflow correction from the `state` returned by
+    /// `into_overflow_state`, e.g. to stream records appended to a file or a
+    /// `records_range` following the one of a previous stream. Not available for
+    /// custom files, whose decoders keep their own state.
+    pub fn with_overflow_state(self, state: u64) -> Result<Self, Error> {
+        match self {
+            RecordStream::PHT2(x) => Ok(RecordStream::PHT2(x.with_overflow_state(state))),
+            RecordStream::HHT2_HH1(x) => Ok(RecordStream::HHT2_HH1(x.with_overflow_state(state))),
+            RecordStream::HHT2_HH2(x) => Ok(RecordStream::HHT2_HH2(x.with_overflow_state(state))),
+            RecordStream::HHT3_HH2(x) => Ok(RecordStream::HHT3_HH2(x.with_overflow_state(state))),
+            RecordStream::Custom(_) => Err(Error::NotImplemented(String::from(
+                "Overflow states are not supported on custom files",
+            ))),
+        }
+    }
+
+    /// Consume the stream and return its final overflow state. It is the time tag
+    /// offset in native units in T2 mode and the sync counter offset in T3 mode.
+    /// Not available for custom files.
+    pub fn into_overflow_state(self) -> Result<u64, Error> {
+        match self {
+            RecordStream::PHT2(x) => Ok(x.into_overflow_state()),
+            RecordStream::HHT2_HH1(x) => Ok(x.into_overflow_state()),
+            RecordStream::HHT2_HH2(x) => Ok(x.into_overflow_state()),
+            RecordStream::HHT3_HH2(x) => Ok(x.into_overflow_state()),
+            RecordStream::Custom(_) => Err(Error::NotImplemented(String::from(
+                "Overflow states are not supported on custom files",
+            ))),
+        }
+    }
+
+    /// Report overflow records as clicks on `channel` at their reconstructed time of
+    /// flight. A timetrace of that channel reveals dead zones in the count rate.
+    pub fn with_overflow_channel(self, channel: Option<i32>) -> Self {
+        match self {
+            RecordStream::PHT2(x) => RecordStream::PHT2(x.with_overflow_channel(channel)),
+            RecordStream::HHT2_HH1(x) => RecordStream::HHT2_HH1(x.with_overflow_channel(channel)),
+            RecordStream::HHT2_HH2(x) => RecordStream::HHT2_HH2(x.with_overflow_channel(channel)),
+            RecordStream::HHT3_HH2(x) => RecordStream::HHT3_HH2(x.with_overflow_channel(channel)),
+            RecordStream::Custom(x) => RecordStream::Custom(x.with_overflow_channel(channel)),
+        }
+    }
+}
+
+impl Iterator for RecordStream {
+    type Item = TTTRRecord;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            RecordStream::PHT2(x) => x.next(),
+            RecordStream::HHT2_HH1(x) => x.next(),
+            RecordStream::HHT2_HH2(x) => x.next(),
+            RecordStream::HHT3_HH2(x) => x.next(),
+            RecordStream::Custom(x) => x.next(),
+        }
+    }
+}
+
+/// Adapt a record stream to yield `(channel, seconds)` pairs.
+///
+/// The time of flight of each record is converted to seconds with the time
+/// resolution of the stream, which is the one of the file header in T2 mode and a
+/// picosecond in T3 mode. Markers and overflows are kept with the negative channel
+/// reported by the stream.
+pub fn in_seconds(stream: RecordStream) -> impl Iterator<Item = (i32, f64)> {
+    let time_resolution = stream.time_resolution();
+    stream.map(move |rec| (*rec.channel(), (*rec.tof() as f64) * time_resolution))
+}
+
+/// Records of a file iterated with `for rec in &file { ... }`.
+///
+/// If the records can't be streamed, e.g. because the record type is not supported,
+/// the error is yielded once and iteration stops.
+///
+/// ```
+/// use tttr_toolbox::headers::File;
+/// use tttr_toolbox::{errors::Error, RecordDecoder, RecordKind, TTTRFile, TTTRRecord};
+///
+/// /// In memory file with the channel on the top four bits of each record
+/// struct Clicks(Vec<u32>);
+///
+/// struct Decoder;
+///
+/// impl RecordDecoder for Decoder {
+///     fn decode(&mut self, raw: u32) -> TTTRRecord {
+///         TTTRRecord::new((raw >> 28) as i32, (raw & 0x0FFF_FFFF) as u64)
+///     }
+/// }
+///
+/// impl TTTRFile for Clicks {
+///     fn time_resolution(&self) -> Result<f64, Error> {
+///         Ok(1e-12)
+///     }
+///     fn record_type(&self) -> Result<tttr_toolbox::headers::RecordType, Error> {
+///         Ok(tttr_toolbox::headers::RecordType::NotImplemented)
+///     }
+///     fn record_size_bytes(&self) -> usize {
+///         4
+///     }
+///     fn raw_records(
+///         &self,
+///         _start: Option<usize>,
+///         _stop: Option<usize>,
+///     ) -> Result<Box<dyn Iterator<Item = u32>>, Error> {
+///         Ok(Box::new(self.0.clone().into_iter()))
+///     }
+///     fn decoder(&self) -> Result<Box<dyn RecordDecoder>, Error> {
+///         Ok(Box::new(Decoder))
+///     }
+/// }
+///
+/// let file = File::Custom(Box::new(Clicks(vec![10, (1 << 28) | 25, 40])));
+/// let mut channels = vec![];
+/// for rec in &file {
+///     if let RecordKind::Photon { channel } = rec?.kind() {
+///         channels.push(channel);
+///     }
+/// }
+/// assert_eq!(channels, vec![0, 1, 0]);
+/// # Ok::<(), Error>(())
+/// ```
+pub enum FileRecords {
+    Records(RecordStream),
+    Failed(Option<Error>),
+}
+
+impl Iterator for FileRecords {
+    type Item = Result<TTTRRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            FileRecords::Records(stream) => stream.next().map(Ok),
+            FileRecords::Failed(error) => error.take().map(Err),
+        }
+    }
+}
+
+impl IntoIterator for &File {
+    type Item = Result<TTTRRecord, Error>;
+    type IntoIter = FileRecords;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self.records() {
+            Ok(stream) => FileRecords::Records(stream),
+            Err(error) => FileRecords::Failed(Some(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        encode_hht3, encode_pht2, random_clicks, write_ptu, Event, T3Event, Tag, HHT3_HH2, PHT2,
+    };
+
+    /// Custom file that doesn't provide its records
+    struct NoDecoder;
+
+    impl TTTRFile for NoDecoder {
+        fn time_resolution(&self) -> Result<f64, Error> {
+            Ok(1e-12)
+        }
+        fn record_type(&self) -> Result<RecordType, Error> {
+            Ok(RecordType::NotImplemented)
+        }
+        fn record_size_bytes(&self) -> usize {
+            4
+        }
+    }
+
+    #[test]
+    fn iterating_unreadable_file_yields_the_error() {
+        let file = File::Custom(Box::new(NoDecoder));
+        let mut records = (&file).into_iter();
+        assert!(matches!(
+            records.next(),
+            Some(Err(Error::NotImplemented(_)))
+        ));
+        assert!(records.next().is_none());
+    }
+
+    /// Channels and delays since the first photon of the photons among `records`.
+    fn relative_photons(records: impl Iterator<Item = TTTRRecord>) -> Vec<(i32, u64)> {
+        let photons: Vec<(i32, u64)> = records
+            .filter_map(|rec| match rec.kind() {
+                RecordKind::Photon { channel } => Some((channel, *rec.tof())),
+                _ => None,
+            })
+            .collect();
+        let origin = photons.first().map_or(0, |&(_, tof)| tof);
+        photons
+            .into_iter()
+            .map(|(channel, tof)| (channel, tof - origin))
+            .collect()
+    }
+
+    #[test]
+    fn tail_keeps_the_delays_of_the_last_records() {
+        // Long gaps so the time tag overflows many times
+        let records = encode_pht2(&random_clicks(2_000, 1_000_000, 7));
+        let tmp = write_ptu(PHT2, 4e-12, &[], &records);
+        let f = File::open(tmp.path()).unwrap();
+
+        let tail: Vec<TTTRRecord> = f.records_tail(500).unwrap().collect();
+        assert_eq!(tail.len(), 500);
+        assert_eq!(
+            relative_photons(tail.into_iter()),
+            relative_photons(f.records().unwrap().skip(records.len() - 500))
+        );
+        assert_eq!(
+            f.records_tail(records.len() + 10).unwrap().count(),
+            records.len()
+        );
+        assert_eq!(f.records_tail(0).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn record_type_names_round_trip() {
+        let record_types = [
+            RecordType::PHT2,
+            RecordType::HHT2_HH1,
+            RecordType::HHT2_HH2,
+            RecordType::HHT3_HH2,
+        ];
+        for record_type in record_types.iter() {
+            let name = record_type.to_string();
+            assert_eq!(name.parse::<RecordType>().unwrap(), *record_type);
+        }
+        assert_eq!(
+            "HHT2_HH2".parse::<RecordType>().unwrap(),
+            RecordType::HHT2_HH2
+        );
+
+        for name in ["NotImplemented", "hht2_hh2", "PHT3", " PHT2", ""].iter() {
+            assert!(matches!(
+                name.parse::<RecordType>(),
+                Err(Error::InvalidParams(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn open_detects_the_format_from_the_magic() {
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&random_clicks(10, 1_000, 1)));
+        assert!(matches!(File::open(tmp.path()), Ok(File::PTU(_))));
+
+        // The same path rewritten with other contents
+        let mut phu = b"PQHISTO\0".to_vec();
+        phu.extend_from_slice(&[0; 64]);
+        std::fs::write(tmp.path(), phu).unwrap();
+        assert!(matches!(
+            File::open(tmp.path()),
+            Err(Error::NotImplemented(_))
+        ));
+        for contents in [&b"not a TTTR file"[..], &b"PQ"[..], &[][..]].iter() {
+            std::fs::write(tmp.path(), contents).unwrap();
+            assert!(matches!(
+                File::open(tmp.path()),
+                Err(Error::InvalidHeader(_))
+            ));
+        }
+
+        let missing = tmp.path().with_extension("missing");
+        assert!(matches!(
+            File::open(missing),
+            Err(Error::FileNotAvailable(_))
+        ));
+    }
+
+    #[test]
+    fn in_seconds_of_t2_and_t3_files() {
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * b.abs().max(1e-12);
+
+        // T2 time tags are multiples of the global resolution
+        let events = random_clicks(1_000, 6_250, 9);
+        let t2 = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let clicks: Vec<(i32, f64)> = in_seconds(File::open(t2.path()).unwrap().records().unwrap())
+            .filter(|&(channel, _)| channel >= 0)
+            .collect();
+        assert_eq!(clicks.len(), events.len());
+        for (&(channel, seconds), event) in clicks.iter().zip(events.iter()) {
+            match *event {
+                Event::Photon(ch, tof) => {
+                    assert_eq!(channel, ch);
+                    assert!(close(seconds, tof as f64 * 4e-12));
+                }
+                Event::Marker(..) => unreachable!(),
+            }
+        }
+
+        // T3 clicks are the sync period times the sync number plus the dtime
+        let events: Vec<T3Event> = (0..1_000u64)
+            .map(|k| T3Event::Photon {
+                channel: 1 + (k % 2) as i32,
+                nsync: 3 * k,
+                dtime: 7 * k,
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let t3 = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&events));
+        let clicks: Vec<(i32, f64)> = in_seconds(File::open(t3.path()).unwrap().records().unwrap())
+            .filter(|&(channel, _)| channel > 0)
+            .collect();
+        assert_eq!(clicks.len(), events.len());
+        for (k, &(channel, seconds)) in clicks.iter().enumerate() {
+            let k = k as u64;
+            assert_eq!(channel, 1 + (k % 2) as i32);
+            assert!(close(
+                seconds,
+                (3 * k) as f64 * 100e-9 + (7 * k) as f64 * 4e-12
+            ));
+        }
+    }
+
+    #[test]
+    fn resuming_with_the_overflow_state_keeps_tofs_continuous() {
+        // Clicks spread over many wraparounds of the time tag
+        let wraparound = RecordType::PHT2.wraparound();
+        let events: Vec<Event> = (1..=2_000u64)
+            .map(|k| Event::Photon((k % 2) as i32, k * wraparound / 3 + k % 11))
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let tofs = |stream: RecordStream| -> Vec<u64> { stream.map(|rec| rec.tof).collect() };
+        let all = tofs(f.records().unwrap());
+        let n = all.len();
+
+        for &split in &[1, n / 3, n / 2 + 1, n - 1] {
+            let mut head = f.records_range(None, Some(split)).unwrap();
+            let mut resumed: Vec<u64> = head.by_ref().map(|rec| rec.tof).collect();
+            let state = head.into_overflow_state().unwrap();
+            let tail = f
+                .records_range(Some(split), None)
+                .unwrap()
+                .with_overflow_state(state)
+                .unwrap();
+            resumed.extend(tofs(tail));
+            assert_eq!(resumed, all);
+        }
+
+        // Without the state the tail starts over from zero
+        let tail = tofs(f.records_range(Some(n / 2), None).unwrap());
+        assert!(tail[0] < all[n / 2]);
+    }
+
+    #[test]
+    fn absolute_mid_range_tofs_match_the_full_file() {
+        let wraparound = RecordType::PHT2.wraparound();
+        let t2_events: Vec<Event> = (1..=1_000u64)
+            .map(|k| Event::Photon((k % 2) as i32, k * wraparound / 4 + k % 13))
+            .collect();
+        let t2 = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&t2_events));
+        // Sync numbers well past the wraparound of the T3 sync counter
+        let t3_events: Vec<T3Event> = (1..=1_000u64)
+            .map(|k| T3Event::Photon {
+                channel: 1,
+                nsync: k * 700,
+                dtime: k % 100,
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let t3 = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&t3_events));
+
+        for tmp in [t2, t3].iter() {
+            let f = File::open(tmp.path()).unwrap();
+            let all: Vec<u64> = f.records().unwrap().map(|rec| rec.tof).collect();
+            let n = all.len();
+            for &(start, stop) in &[(n / 3, 2 * n / 3), (n / 2, n), (0, n / 4)] {
+                let range: Vec<u64> = f
+                    .records_range_absolute(Some(start), Some(stop))
+                    .unwrap()
+                    .map(|rec| rec.tof)
+                    .collect();
+                assert_eq!(range, all[start..stop]);
+            }
+            // A plain range restarts the overflow correction
+            let relative = f.records_range(Some(n / 2), None).unwrap().next().unwrap();
+            assert!(relative.tof < all[n / 2]);
+        }
+    }
+
+    #[test]
+    fn active_channels_of_a_two_channel_file() {
+        let wraparound = RecordType::PHT2.wraparound();
+        // Markers and overflows in between the clicks are not channels
+        let events: Vec<Event> = (1..=200u64)
+            .map(|k| match k % 5 {
+                0 => Event::Marker(2, k * wraparound / 3),
+                1 | 2 => Event::Photon(1, k * wraparound / 3),
+                _ => Event::Photon(3, k * wraparound / 3),
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        assert_eq!(f.active_channels(None).unwrap(), vec![1, 3]);
+        assert_eq!(f.active_channels(Some(2)).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn supported_formats_list_every_record_type_once() {
+        let formats = supported_formats();
+        let hht2 = formats
+            .iter()
+            .find(|info| info.record_types.contains(&RecordType::HHT2_HH2))
+            .unwrap();
+        assert_eq!(hht2.name, "PTU");
+        assert_eq!(hht2.modes, ["T2"]);
+        assert!(!hht2.can_lifetime);
+
+        let mut listed: Vec<String> = formats
+            .iter()
+            .flat_map(|info| info.record_types.iter().map(|rt| rt.to_string()))
+            .collect();
+        listed.sort();
+        assert_eq!(listed, ["HHT2_HH1", "HHT2_HH2", "HHT3_HH2", "PHT2"]);
+        // Only T3 records carry the sync delay the lifetime algorithms need
+        for info in formats {
+            assert_eq!(info.can_lifetime, info.modes == ["T3"]);
+        }
+    }
 }
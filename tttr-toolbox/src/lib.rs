@@ -12,12 +12,17 @@
 //!
 //! ## Algorithms available
 //! - [second order autocorrelation](tttr_tools/g2/fn.g2.html)
+//! - [g2 peak drift](tttr_tools/g2/g2_drift/fn.g2_peak_drift.html)
+//! - [g2 with live snapshots](tttr_tools/g2/g2_snapshots/fn.g2_with_snapshots.html)
 //! - [third order autocorrelation](tttr_tools/g3/fn.g3.html)
 //! - [third order autocorrelation](tttr_tools/synced_g3/fn.g3_sync.html) 
 //! - [intensity time trace](tttr_tools/timetrace/fn.timetrace.html)
 //! - [record number time trace](tttr_tools/timetrace/fn.timetrace.html)
 //! - [zero delay finder](tttr_tools/zero_finder/fn.zerofinder.html)
 //! - [lifetimes](tttr_tools/lifetime/fn.lifetime.html)
+//! - [coincidence to accidental ratio](tttr_tools/car/fn.car.html)
+//! - [self delay scan](tttr_tools/self_delay/fn.self_delay_scan.html)
+//! - [marker timeline](tttr_tools/timeline/fn.timeline.html)
 //!
 //! ## Supported file and record formats
 //! - PicoQuant PTU
@@ -26,18 +31,33 @@
 //!   - HHT2_HH2
 //!   - HHT3_HH2
 //!
+//! Other formats can be plugged in without forking the crate by implementing
+//! `TTTRFile` and `RecordDecoder` and wrapping the file in `File::Custom`.
+//!
 //! If you want support for more record formats and file formats please ask for it.
 //! At the very least we will need the file format specification and a file with some
 //! discernible features to test the implementation.
 //!
+//! ## Cargo features
+//! - `python` (default): Python conversions for the header types via pyo3. Disable
+//!   the default features for pure Rust use.
+//! - `matlab` (default): Writer for MATLAB level 5 `.mat` files and the
+//!   `--format mat` option of the command line tool.
+//! - `ndarray` (default): The g3 and g3sync algorithms, which return two dimensional
+//!   `ndarray` histograms, and the command line tool. The one dimensional tools are
+//!   available without it.
+//! - `mmap`: `g3_mapped`, a g3 accumulated on a memory mapped file for histograms
+//!   that don't fit in RAM.
+//!
 //! ## Examples
 //! ```ignore
 //! pub fn main() {
 //!     let filename = PathBuf::from("/Users/garfield/Downloads/20191205_Xminus_0p1Ve-6_CW_HBT.ptu");
-//!     let ptu_file = File::PTU(PTUFile::new(filename).unwrap());
+//!     let ptu_file = File::open(filename).unwrap();
 //!     // Unwrap the file so we can print the header
-//!     let File::PTU(f) = &ptu_file;
-//!     println!("{}", f);
+//!     if let File::PTU(f) = &ptu_file {
+//!         println!("{}", f);
+//!     }
 //!
 //!     let params = G2Params {
 //!         channel_1: 0,
@@ -58,8 +78,11 @@ extern crate byteorder;
 
 pub mod errors;
 pub mod headers;
+pub mod output;
 pub mod parsers;
 pub mod tttr_tools;
+#[cfg(test)]
+mod test_utils;
 
 pub(crate) trait TTTRStream {
     type RecordSize;
@@ -71,11 +94,96 @@ pub(crate) trait TTTRStream {
 pub struct TTTRRecord {
     channel: i32,
     tof: u64,
+    markers: u8,
+    overflow: bool,
+}
+
+/// What kind of event a `TTTRRecord` represents.
+///
+/// Streams signal non-photon events with negative channel numbers. Prefer matching on
+/// `TTTRRecord::kind` over checking for those magic values.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A click on `channel`. In T3 mode channels are shifted by one, see the streamers.
+    Photon { channel: i32 },
+    /// An external marker with the marker `bits` that were set.
+    Marker { bits: u8 },
+    /// A time tag overflow or any other special record that carries no click.
+    Overflow,
+}
+
+impl TTTRRecord {
+    /// Build a marker record carrying the marker `bits`. Like on the built-in
+    /// streams markers are reported on a negative channel.
+    pub fn marker(bits: u8, tof: u64) -> Self {
+        Self {
+            channel: -1,
+            tof,
+            markers: bits,
+            overflow: false,
+        }
+    }
+
+    /// Build an overflow record. It carries no click.
+    pub fn overflow() -> Self {
+        Self {
+            channel: -1,
+            tof: 0,
+            markers: 0,
+            overflow: true,
+        }
+    }
+
+    /// Build a photon record from its channel and time of flight. The time of flight
+    /// is expressed in units of the time resolution of the stream it belongs to.
+    pub fn new(channel: i32, tof: u64) -> Self {
+        Self {
+            channel,
+            tof,
+            markers: 0,
+            overflow: false,
+        }
+    }
+
+    /// Classify the record as a photon, marker or overflow.
+    pub fn kind(&self) -> RecordKind {
+        if self.markers != 0 {
+            RecordKind::Marker { bits: self.markers }
+        } else if self.overflow || self.channel < 0 {
+            RecordKind::Overflow
+        } else {
+            RecordKind::Photon {
+                channel: self.channel,
+            }
+        }
+    }
+
+    /// Human readable description of the record with its time of flight in seconds,
+    /// e.g. `ch 0 @ 1.234567 µs`.
+    ///
+    /// `time_resolution` is the resolution in seconds of the stream the record comes
+    /// from. The unit is picked so that the value is at least one.
+    pub fn describe(&self, time_resolution: f64) -> String {
+        let seconds = (self.tof as f64) * time_resolution;
+        let (value, unit) = [(1.0, "s"), (1e-3, "ms"), (1e-6, "µs"), (1e-9, "ns")]
+            .iter()
+            .find(|(scale, _)| seconds >= *scale)
+            .map_or((seconds * 1e12, "ps"), |(scale, unit)| {
+                (seconds / scale, *unit)
+            });
+        let time = format!("{:.6} {}", value, unit);
+        match self.kind() {
+            RecordKind::Photon { channel } => format!("ch {} @ {}", channel, time),
+            RecordKind::Marker { bits } => format!("marker {:#06b} @ {}", bits, time),
+            RecordKind::Overflow => format!("overflow @ {}", time),
+        }
+    }
 }
 
 pub(crate) trait Click {
     fn channel(&self) -> &i32;
     fn tof(&self) -> &u64;
+    fn markers(&self) -> &u8;
 }
 
 impl Click for TTTRRecord {
@@ -87,6 +195,41 @@ impl Click for TTTRRecord {
     fn tof(&self) -> &u64 {
         &self.tof
     }
+    /// Marker bits carried by the record. Zero for photons and overflows.
+    #[inline]
+    fn markers(&self) -> &u8 {
+        &self.markers
+    }
+}
+
+/// Convert a duration in `seconds` into native units of a file with the given
+/// `time_resolution`, truncating towards zero.
+///
+/// Unlike a plain `as u64` cast, negative, NaN or infinite inputs and durations too
+/// long to be represented are reported as `Error::InvalidParams` instead of being
+/// silently saturated.
+pub fn to_native_units(seconds: f64, time_resolution: f64) -> Result<u64, errors::Error> {
+    if !time_resolution.is_finite() || time_resolution <= 0.0 {
+        return Err(errors::Error::InvalidParams(format!(
+            "The time resolution must be positive and finite but got {}",
+            time_resolution
+        )));
+    }
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(errors::Error::InvalidParams(format!(
+            "Durations must be non-negative and finite but got {} s",
+            seconds
+        )));
+    }
+    let ticks = (seconds / time_resolution).trunc();
+    // u64::MAX as f64 rounds up to 2^64 which is already out of range
+    if ticks >= u64::MAX as f64 {
+        return Err(errors::Error::InvalidParams(format!(
+            "{} s is too long to be represented with a time resolution of {} s",
+            seconds, time_resolution
+        )));
+    }
+    Ok(ticks as u64)
 }
 
 /// The TTTRFile trait ensures that all files we support are aware of the time_resolution
@@ -95,7 +238,123 @@ impl Click for TTTRRecord {
 /// TTTR files don't usually represent time in seconds but rather as a multiple of them
 /// that matches the equipment time resolution. This makes it possible to shave a few
 /// bits per record.
+///
+/// Formats not supported by the crate can be plugged in through `File::Custom` by
+/// implementing `raw_records` and `decoder`. The record type of custom files is not
+/// used and they are always treated as T2 data.
 pub trait TTTRFile {
     fn time_resolution(&self) -> Result<f64, errors::Error>;
     fn record_type(&self) -> Result<headers::RecordType, errors::Error>;
+    /// Number of bytes each record takes on disk.
+    fn record_size_bytes(&self) -> usize;
+
+    /// Raw records between `start_record` and `stop_record`. `None` means the start
+    /// or end of the file respectively.
+    fn raw_records(
+        &self,
+        _start_record: Option<usize>,
+        _stop_record: Option<usize>,
+    ) -> Result<Box<dyn Iterator<Item = u32>>, errors::Error> {
+        Err(errors::Error::NotImplemented(String::from(
+            "Raw record access is not implemented for this file",
+        )))
+    }
+
+    /// Decoder turning the output of `raw_records` into `TTTRRecord`s.
+    fn decoder(&self) -> Result<Box<dyn RecordDecoder>, errors::Error> {
+        Err(errors::Error::NotImplemented(String::from(
+            "No record decoder available for this file",
+        )))
+    }
+}
+
+/// Decodes the raw records of a custom file format.
+///
+/// The decoder is fed the records in file order so it can keep state such as the
+/// overflow correction. The `tof` of the returned records must be in units of the
+/// `time_resolution` of the file.
+pub trait RecordDecoder {
+    fn decode(&mut self, raw: u32) -> TTTRRecord;
+}
+
+/// Compile time check that the parameters and results of the algorithms, the PTU
+/// files and their streams can be moved across threads.
+#[allow(dead_code)]
+fn assert_send() {
+    fn is_send<T: Send>() {}
+
+    is_send::<errors::Error>();
+    is_send::<TTTRRecord>();
+    is_send::<parsers::ptu::PTUFile>();
+    is_send::<parsers::ptu::streamers::PHT2Stream>();
+    is_send::<parsers::ptu::streamers::HHT2_HH1Stream>();
+    is_send::<parsers::ptu::streamers::HHT2_HH2Stream>();
+    is_send::<parsers::ptu::streamers::HHT3_HH2Stream>();
+    is_send::<tttr_tools::car::CarParams>();
+    is_send::<tttr_tools::car::CarResult>();
+    is_send::<tttr_tools::g2::G2Params>();
+    is_send::<tttr_tools::g2::G2Result>();
+    is_send::<tttr_tools::g2::G2ResultFull>();
+    is_send::<tttr_tools::g2::g2_core::G2>();
+    is_send::<tttr_tools::g2::g2_core::G2State>();
+    is_send::<tttr_tools::g2::g2_engine::G2Engine>();
+    is_send::<tttr_tools::lifetime::LifetimeParams>();
+    is_send::<tttr_tools::lifetime::LifetimeResult>();
+    is_send::<tttr_tools::quick_stats::QuickStats>();
+    is_send::<tttr_tools::self_delay::SelfDelayParams>();
+    is_send::<tttr_tools::self_delay::SelfDelayResult>();
+    is_send::<tttr_tools::timetrace::TimeTraceParams>();
+    is_send::<tttr_tools::timetrace::TimeTraceResult>();
+    is_send::<tttr_tools::zero_finder::ZeroFinderParams>();
+    is_send::<tttr_tools::zero_finder::ZeroFinderResult>();
+    #[cfg(feature = "ndarray")]
+    {
+        is_send::<tttr_tools::g3::G3Params>();
+        is_send::<tttr_tools::g3::G3Result>();
+        is_send::<tttr_tools::synced_g3::G3SyncParams>();
+        is_send::<tttr_tools::synced_g3::G3SyncResult>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        assert_eq!(to_native_units(1e-6, 1e-12).unwrap(), 1_000_000);
+        assert_eq!(to_native_units(0.0, 1e-12).unwrap(), 0);
+        for &seconds in [f64::NAN, -1e-9, f64::INFINITY, 1e9].iter() {
+            assert!(matches!(
+                to_native_units(seconds, 1e-12),
+                Err(errors::Error::InvalidParams(_))
+            ));
+        }
+        for &time_resolution in [f64::NAN, 0.0, -1e-12, f64::INFINITY].iter() {
+            assert!(matches!(
+                to_native_units(1.0, time_resolution),
+                Err(errors::Error::InvalidParams(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn describe_in_seconds() {
+        assert_eq!(
+            TTTRRecord::new(0, 1_234_567).describe(1e-12),
+            "ch 0 @ 1.234567 µs"
+        );
+        assert_eq!(
+            TTTRRecord::new(3, 375_000_000_000).describe(4e-12),
+            "ch 3 @ 1.500000 s"
+        );
+        assert_eq!(
+            TTTRRecord::marker(0b101, 2_000).describe(1e-12),
+            "marker 0b0101 @ 2.000000 ns"
+        );
+        assert_eq!(
+            TTTRRecord::overflow().describe(1e-12),
+            "overflow @ 0.000000 ps"
+        );
+    }
 }
@@ -1,21 +1,27 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use ndarray::arr1;
-use ndarray_npy::NpzWriter;
+use ndarray::{arr1, ArrayBase, Data, Dimension};
+use ndarray_npy::{NpzWriter, WritableElement};
 
 use std;
+use std::io::BufWriter;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 extern crate clap;
 extern crate tttr_toolbox_proc_macros;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
+#[cfg(feature = "matlab")]
+use tttr_toolbox::output::matlab::{MatElement, MatWriter};
 use tttr_toolbox::{
-    headers::File,
+    headers::{File, RecordType},
+    output::npz::npz_writer,
     parsers::ptu::PTUFile,
     tttr_tools::{
-        g2::{g2, G2Mode, G2Params},
+        dump::dump_records,
+        g2::{g2, ChannelDelays, G2Mode, G2Params},
         g3::{g3, G3Params},
         lifetime::{lifetime, LifetimeParams},
         synced_g3::{g3_sync, G3SyncParams},
@@ -23,6 +29,162 @@ use tttr_toolbox::{
     },
 };
 
+/// Array elements that can be written on every enabled output format.
+#[cfg(feature = "matlab")]
+trait OutputElement: WritableElement + MatElement {}
+#[cfg(feature = "matlab")]
+impl<T: WritableElement + MatElement> OutputElement for T {}
+#[cfg(not(feature = "matlab"))]
+trait OutputElement: WritableElement {}
+#[cfg(not(feature = "matlab"))]
+impl<T: WritableElement> OutputElement for T {}
+
+/// Output file of a subcommand in any of the supported formats.
+enum Output {
+    Npz(NpzWriter<std::fs::File>),
+    #[cfg(feature = "matlab")]
+    Mat(MatWriter<std::fs::File>),
+}
+
+impl Output {
+    fn add_array<T, S, D>(&mut self, name: &str, array: &ArrayBase<S, D>) -> Result<()>
+    where
+        T: OutputElement,
+        S: Data<Elem = T>,
+        D: Dimension,
+    {
+        match self {
+            Output::Npz(x) => x.add_array(name, array)?,
+            #[cfg(feature = "matlab")]
+            Output::Mat(x) => x.add_array(name, array)?,
+        };
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::Npz(x) => {
+                x.finish()?;
+            }
+            #[cfg(feature = "matlab")]
+            Output::Mat(x) => {
+                x.finish()?;
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Create the writer for the output file of a subcommand. Failures report the path
+/// and the subcommand that tried to write to it. Arrays are compressed when
+/// `--compress` is set.
+fn create_output(matches: &ArgMatches, subcommand: &str) -> Result<Output> {
+    let path = matches.value_of("output").unwrap();
+    let file = std::fs::File::create(path).with_context(|| {
+        format!(
+            "Could not create output file {} for the {} subcommand",
+            path, subcommand
+        )
+    })?;
+    let compress = matches.is_present("compress");
+    match matches.value_of("format").unwrap_or("npz") {
+        #[cfg(feature = "matlab")]
+        "mat" => Ok(Output::Mat(
+            MatWriter::new(file)?.with_compression(compress),
+        )),
+        "npz" => Ok(Output::Npz(npz_writer(file, compress))),
+        format => Err(anyhow::anyhow!("Unsupported output format {}", format)),
+    }
+}
+
+/// Parse the value of the argument `name`, if given. Failures name the argument and
+/// the offending value.
+fn parse_arg<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    matches
+        .value_of(name)
+        .map(|x| {
+            x.parse::<T>()
+                .with_context(|| format!("Invalid value '{}' for argument <{}>", x, name))
+        })
+        .transpose()
+}
+
+/// Same as `parse_arg` for arguments that clap already enforces as required.
+fn parse_required<T>(matches: &ArgMatches, name: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    parse_arg(matches, name)?.ok_or_else(|| anyhow::anyhow!("Missing argument <{}>", name))
+}
+
+/// Parse a channel number, in decimal or in hexadecimal with a `0x` prefix.
+fn parse_channel(value: &str, name: &str) -> Result<i32> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => value.parse::<i32>(),
+    };
+    parsed.with_context(|| format!("Invalid channel '{}' for argument <{}>", value, name))
+}
+
+/// Parse the required channel argument `name`. See `parse_channel`.
+fn channel_arg(matches: &ArgMatches, name: &str) -> Result<i32> {
+    let value = matches
+        .value_of(name)
+        .ok_or_else(|| anyhow::anyhow!("Missing argument <{}>", name))?;
+    parse_channel(value, name)
+}
+
+/// Arguments to select the records of a subcommand by time since the start of the
+/// measurement.
+fn time_range_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("t_start")
+            .long("t-start")
+            .help("Only process records after this time in seconds")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("t_stop")
+            .long("t-stop")
+            .help("Only process records before this time in seconds")
+            .takes_value(true)
+            .required(false),
+    ]
+}
+
+/// Record range selected by the `--t-start` and `--t-stop` arguments, if any.
+fn time_range(matches: &ArgMatches, f: &File) -> Result<Option<(usize, usize)>> {
+    let t_start = parse_arg::<f64>(matches, "t_start")?;
+    let t_stop = parse_arg::<f64>(matches, "t_stop")?;
+    if t_start.is_none() && t_stop.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(f.time_to_record_range(t_start, t_stop)?))
+}
+
+/// Open the input file of a subcommand, forcing the record type given with
+/// `--record-type` if any.
+fn open_input(matches: &ArgMatches) -> Result<File> {
+    let filename = PathBuf::from(matches.value_of("input").unwrap());
+    let file = match (File::open(filename)?, matches.value_of("record_type")) {
+        (File::PTU(x), Some(record_type)) => {
+            File::PTU(x.with_record_type(record_type.parse::<RecordType>()?))
+        }
+        (file, _) => file,
+    };
+    match (file, parse_arg::<u64>(matches, "data_offset")?) {
+        (File::PTU(x), Some(data_offset)) => Ok(File::PTU(x.with_data_offset(data_offset))),
+        (file, _) => Ok(file),
+    }
+}
+
 // ToDo
 // 1. Check magic number for PTU
 // 2. Documentation for g3 and g2 symmetrizing algorithm
@@ -38,9 +200,41 @@ pub fn main() -> Result<()> {
             .takes_value(false)
             .global(true)
         )
+        .arg(
+            Arg::with_name("compress")
+            .long("compress")
+            .help("Compress the arrays of the output file")
+            .takes_value(false)
+            .global(true)
+        )
+        .arg(
+            Arg::with_name("format")
+            .long("format")
+            .help("Format of the output file")
+            .possible_values(&["npz", "mat"])
+            .default_value("npz")
+            .takes_value(true)
+            .global(true)
+        )
+        .arg(
+            Arg::with_name("record_type")
+            .long("record-type")
+            .help("Record type used to decode the input, overriding the header")
+            .possible_values(&["PHT2", "HHT2_HH1", "HHT2_HH2", "HHT3_HH2"])
+            .takes_value(true)
+            .global(true)
+        )
+        .arg(
+            Arg::with_name("data_offset")
+            .long("data-offset")
+            .help("Byte offset of the first record of the input, overriding the header")
+            .takes_value(true)
+            .global(true)
+        )
         .subcommand(
             SubCommand::with_name("intensity")
             .about("Obtain intensity trace for one or all channels")
+            .args(&time_range_args())
             .arg(
                 Arg::with_name("input")
                 .short("i")
@@ -51,7 +245,7 @@ pub fn main() -> Result<()> {
             .arg(
                 Arg::with_name("output")
                 .short("o")
-                .help("Output Numpy npz file path")
+                .help("Output Numpy npz or MATLAB mat file path")
                 .takes_value(true)
                 .required(true)
             )
@@ -69,10 +263,17 @@ pub fn main() -> Result<()> {
                 .takes_value(true)
                 .required(false)
             )
+            .arg(
+                Arg::with_name("fixed_length")
+                .long("fixed-length")
+                .help("Pad the trace with empty bins up to the acquisition duration")
+                .takes_value(false)
+            )
         )
         .subcommand(
             SubCommand::with_name("lifetime")
             .about("Compute the lifetime histogram from a pulsed excitation experiment. Only supports T3 mode.")
+            .args(&time_range_args())
             .arg(
                 Arg::with_name("input")
                 .short("i")
@@ -83,13 +284,13 @@ pub fn main() -> Result<()> {
             .arg(
                 Arg::with_name("output")
                 .short("o")
-                .help("Output Numpy npz file path")
+                .help("Output Numpy npz or MATLAB mat file path")
                 .takes_value(true)
                 .required(true)
             )
             .arg(
                 Arg::with_name("ch_sync")
-                .help("Sync channel")
+                .help("Sync channel, or comma separated list of sync channels")
                 .takes_value(true)
                 .required(true)
             )
@@ -110,6 +311,7 @@ pub fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("g2")
             .about("Compute second order coincidences between two channels")
+            .args(&time_range_args())
             .arg(
                 Arg::with_name("input")
                 .short("i")
@@ -120,7 +322,7 @@ pub fn main() -> Result<()> {
             .arg(
                 Arg::with_name("output")
                 .short("o")
-                .help("Output Numpy npz file path")
+                .help("Output Numpy npz or MATLAB mat file path")
                 .takes_value(true)
                 .required(true)
             )
@@ -152,10 +354,17 @@ pub fn main() -> Result<()> {
                 .takes_value(true)
                 .required(true)
             )
+            .arg(
+                Arg::with_name("input_offsets")
+                .long("input-offsets")
+                .help("Correct the delays with the input offsets stored on the file header")
+                .takes_value(false)
+            )
         )
         .subcommand(
             SubCommand::with_name("g3")
             .about("Compute third order coincidences between two channels")
+            .args(&time_range_args())
             .arg(
                 Arg::with_name("input")
                 .short("i")
@@ -166,7 +375,7 @@ pub fn main() -> Result<()> {
             .arg(
                 Arg::with_name("output")
                 .short("o")
-                .help("Output Numpy npz file path")
+                .help("Output Numpy npz or MATLAB mat file path")
                 .takes_value(true)
                 .required(true)
             )
@@ -209,6 +418,7 @@ pub fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("g3sync")
             .about("Compute third order coincidences between channels with a regular sync")
+            .args(&time_range_args())
             .arg(
                 Arg::with_name("input")
                 .short("i")
@@ -219,7 +429,7 @@ pub fn main() -> Result<()> {
             .arg(
                 Arg::with_name("output")
                 .short("o")
-                .help("Output Numpy npz file path")
+                .help("Output Numpy npz or MATLAB mat file path")
                 .takes_value(true)
                 .required(true)
             )
@@ -252,123 +462,210 @@ pub fn main() -> Result<()> {
                 .required(true)
             )
         )
+        .subcommand(
+            SubCommand::with_name("dump")
+            .about("Write the decoded records as CSV")
+            .args(&time_range_args())
+            .arg(
+                Arg::with_name("input")
+                .short("i")
+                .help("Input file path")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("output")
+                .short("o")
+                .help("Output CSV file path. Defaults to stdout")
+                .takes_value(true)
+                .required(false)
+            )
+            .arg(
+                Arg::with_name("start_record")
+                .short("s")
+                .help("First record to dump")
+                .takes_value(true)
+                .required(false)
+            )
+            .arg(
+                Arg::with_name("stop_record")
+                .short("e")
+                .help("Record at which to stop dumping")
+                .takes_value(true)
+                .required(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("header")
+            .about("Print the file header or the value of a single tag")
+            .arg(
+                Arg::with_name("input")
+                .short("i")
+                .help("Input file path")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("tag")
+                .long("tag")
+                .help("Name of the tag to print, e.g. MeasDesc_GlobalResolution")
+                .takes_value(true)
+                .required(false)
+            )
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("intensity", Some(intensity_matches)) => {
-            let filename = PathBuf::from(intensity_matches.value_of("input").unwrap());
-            let ptu_file = File::PTU(PTUFile::new(filename)?);
+            let ptu_file = open_input(intensity_matches)?;
+            let records = time_range(intensity_matches, &ptu_file)?;
             let params = TimeTraceParams {
-                resolution: intensity_matches
-                    .value_of("resolution")
-                    .unwrap()
-                    .parse::<f64>()?,
+                resolution: parse_required(intensity_matches, "resolution")?,
                 channel: intensity_matches
                     .value_of("channel")
-                    .map(|x| x.parse::<i32>().unwrap()),
+                    .map(|x| parse_channel(x, "channel"))
+                    .transpose()?,
+                marker_channels: None,
+                overflow_channel: None,
+                fixed_length: intensity_matches.is_present("fixed_length"),
+                mandel_q: false,
+                smoothing: None,
+                start_record: records.map(|x| x.0),
+                stop_record: records.map(|x| x.1),
             };
             let tt = timetrace(&ptu_file, &params)?;
 
-            let mut npz = NpzWriter::new(std::fs::File::create(
-                intensity_matches.value_of("output").unwrap(),
-            )?);
-            npz.add_array("intensity", &arr1(&tt.intensity))?;
-            npz.add_array("recnum_trace", &arr1(&tt.recnum_trace))?;
-            npz.finish()?;
+            let mut out = create_output(intensity_matches, "intensity")?;
+            out.add_array("intensity", &arr1(&tt.intensity))?;
+            out.add_array("recnum_trace", &arr1(&tt.recnum_trace))?;
+            out.finish()?;
         }
         ("g2", Some(g2_matches)) => {
-            let filename = PathBuf::from(g2_matches.value_of("input").unwrap());
-            let ptu_file = File::PTU(PTUFile::new(filename)?);
+            let ptu_file = open_input(g2_matches)?;
             let params = G2Params {
-                channel_1: g2_matches.value_of("channel1").unwrap().parse::<i32>()?,
-                channel_2: g2_matches.value_of("channel2").unwrap().parse::<i32>()?,
-                correlation_window: g2_matches
-                    .value_of("correlation_window")
-                    .unwrap()
-                    .parse::<f64>()?,
-                resolution: g2_matches.value_of("resolution").unwrap().parse::<f64>()?,
-                record_ranges: None,
+                channel_1: channel_arg(g2_matches, "channel1")?,
+                channel_2: channel_arg(g2_matches, "channel2")?,
+                correlation_window: parse_required(g2_matches, "correlation_window")?,
+                resolution: parse_required(g2_matches, "resolution")?,
+                record_ranges: time_range(g2_matches, &ptu_file)?.map(|x| vec![x]),
+                marker_channels: None,
+                raw_deltas_window: None,
+                channel_delays: if g2_matches.is_present("input_offsets") {
+                    Some(ChannelDelays::FromHeader)
+                } else {
+                    None
+                },
+                buffer_sizes: None,
+                centered: false,
+                burst_veto: None,
+                last_start_only: false,
             };
             let g2_histogram = g2(&ptu_file, &params, G2Mode::Symmetric)?;
 
-            let mut npz = NpzWriter::new(std::fs::File::create(
-                g2_matches.value_of("output").unwrap(),
-            )?);
-            npz.add_array("histogram", &arr1(&g2_histogram.hist))?;
-            npz.add_array("t", &arr1(&g2_histogram.t))?;
-            npz.finish()?;
+            let mut out = create_output(g2_matches, "g2")?;
+            out.add_array("histogram", &arr1(&g2_histogram.hist))?;
+            out.add_array("t", &arr1(&g2_histogram.t))?;
+            out.finish()?;
         }
         ("g3", Some(g3_matches)) => {
-            let filename = PathBuf::from(g3_matches.value_of("input").unwrap());
-            let ptu_file = File::PTU(PTUFile::new(filename)?);
+            let ptu_file = open_input(g3_matches)?;
+            let records = time_range(g3_matches, &ptu_file)?;
             let params = G3Params {
-                channel_1: g3_matches.value_of("channel1").unwrap().parse::<i32>()?,
-                channel_2: g3_matches.value_of("channel2").unwrap().parse::<i32>()?,
-                channel_3: g3_matches.value_of("channel3").unwrap().parse::<i32>()?,
-                correlation_window: g3_matches
-                    .value_of("correlation_window")
-                    .unwrap()
-                    .parse::<f64>()?,
-                resolution: g3_matches.value_of("resolution").unwrap().parse::<f64>()?,
-                start_record: None,
-                stop_record: None,
+                channel_1: channel_arg(g3_matches, "channel1")?,
+                channel_2: channel_arg(g3_matches, "channel2")?,
+                channel_3: channel_arg(g3_matches, "channel3")?,
+                correlation_window: parse_required(g3_matches, "correlation_window")?,
+                resolution: parse_required(g3_matches, "resolution")?,
+                start_record: records.map(|x| x.0),
+                stop_record: records.map(|x| x.1),
+                memory_limit: None,
             };
-            let g3_histogram = g3(&ptu_file, &params).unwrap();
+            let g3_histogram = g3(&ptu_file, &params)?;
 
-            let mut npz = NpzWriter::new(std::fs::File::create(
-                g3_matches.value_of("output").unwrap(),
-            )?);
-            npz.add_array("histogram", &g3_histogram.hist)?;
-            npz.add_array("t", &arr1(&g3_histogram.t))?;
-            npz.finish()?;
+            let mut out = create_output(g3_matches, "g3")?;
+            out.add_array("histogram", &g3_histogram.hist)?;
+            out.add_array("t", &arr1(&g3_histogram.t))?;
+            out.finish()?;
         }
         ("g3sync", Some(g3_matches)) => {
-            let filename = PathBuf::from(g3_matches.value_of("input").unwrap());
-            let ptu_file = File::PTU(PTUFile::new(filename)?);
+            let ptu_file = open_input(g3_matches)?;
+            let records = time_range(g3_matches, &ptu_file)?;
             let params = G3SyncParams {
-                channel_sync: g3_matches.value_of("channelS").unwrap().parse::<i32>()?,
-                channel_1: g3_matches.value_of("channel1").unwrap().parse::<i32>()?,
-                channel_2: g3_matches.value_of("channel2").unwrap().parse::<i32>()?,
-                resolution: g3_matches.value_of("resolution").unwrap().parse::<f64>()?,
-                start_record: None,
-                stop_record: None,
+                channel_sync: channel_arg(g3_matches, "channelS")?,
+                channel_1: channel_arg(g3_matches, "channel1")?,
+                channel_2: channel_arg(g3_matches, "channel2")?,
+                resolution: parse_required(g3_matches, "resolution")?,
+                start_record: records.map(|x| x.0),
+                stop_record: records.map(|x| x.1),
             };
-            let g3_histogram = g3_sync(&ptu_file, &params).unwrap();
+            let g3_histogram = g3_sync(&ptu_file, &params)?;
 
-            let mut npz = NpzWriter::new(std::fs::File::create(
-                g3_matches.value_of("output").unwrap(),
-            )?);
-            npz.add_array("histogram", &g3_histogram.hist)?;
-            npz.add_array("t", &arr1(&g3_histogram.t))?;
-            npz.finish()?;
+            let mut out = create_output(g3_matches, "g3sync")?;
+            out.add_array("histogram", &g3_histogram.hist)?;
+            out.add_array("t", &arr1(&g3_histogram.t))?;
+            out.finish()?;
         }
         ("lifetime", Some(lifetime_matches)) => {
-            let filename = PathBuf::from(lifetime_matches.value_of("input").unwrap());
-            let ptu_file = File::PTU(PTUFile::new(filename)?);
+            let ptu_file = open_input(lifetime_matches)?;
+            let records = time_range(lifetime_matches, &ptu_file)?;
             let params = LifetimeParams {
                 channel_sync: lifetime_matches
                     .value_of("ch_sync")
-                    .unwrap()
-                    .parse::<i32>()?,
-                channel_source: lifetime_matches
-                    .value_of("ch_source")
-                    .unwrap()
-                    .parse::<i32>()?,
-                resolution: lifetime_matches
-                    .value_of("resolution")
-                    .unwrap()
-                    .parse::<f64>()?,
-                start_record: None,
-                stop_record: None,
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|x| parse_channel(x, "ch_sync"))
+                    .collect::<Result<Vec<_>>>()?,
+                channel_source: channel_arg(lifetime_matches, "ch_source")?,
+                resolution: parse_required(lifetime_matches, "resolution")?,
+                start_record: records.map(|x| x.0),
+                stop_record: records.map(|x| x.1),
+                sync_offset: None,
+                log_bins: None,
+                sync_jitter: None,
             };
             let lifetime_histogram = lifetime(&ptu_file, &params)?;
 
-            let mut npz = NpzWriter::new(std::fs::File::create(
-                lifetime_matches.value_of("output").unwrap(),
-            )?);
-            npz.add_array("histogram", &arr1(&lifetime_histogram.hist))?;
-            npz.add_array("t", &arr1(&lifetime_histogram.t))?;
-            npz.finish()?;
+            let mut out = create_output(lifetime_matches, "lifetime")?;
+            out.add_array("histogram", &arr1(&lifetime_histogram.hist))?;
+            out.add_array("t", &arr1(&lifetime_histogram.t))?;
+            out.finish()?;
+        }
+        ("dump", Some(dump_matches)) => {
+            let ptu_file = open_input(dump_matches)?;
+            let mut start_record = parse_arg::<usize>(dump_matches, "start_record")?;
+            let mut stop_record = parse_arg::<usize>(dump_matches, "stop_record")?;
+            if let Some((start, stop)) = time_range(dump_matches, &ptu_file)? {
+                if start_record.is_some() || stop_record.is_some() {
+                    anyhow::bail!("Records can't be selected both by index and by time");
+                }
+                start_record = Some(start);
+                stop_record = Some(stop);
+            }
+
+            if let Some(path) = dump_matches.value_of("output") {
+                let file = std::fs::File::create(path).with_context(|| {
+                    format!(
+                        "Could not create output file {} for the dump subcommand",
+                        path
+                    )
+                })?;
+                dump_records(&ptu_file, start_record, stop_record, BufWriter::new(file))?;
+            } else {
+                let stdout = std::io::stdout();
+                dump_records(&ptu_file, start_record, stop_record, stdout.lock())?;
+            }
+        }
+        ("header", Some(header_matches)) => {
+            let filename = PathBuf::from(header_matches.value_of("input").unwrap());
+            let ptu_file = PTUFile::new(filename)?;
+            if let Some(tag) = header_matches.value_of("tag") {
+                match ptu_file.header.get(tag) {
+                    Some(value) => println!("{}", value),
+                    None => anyhow::bail!("Tag {} is not on the header", tag),
+                }
+            } else {
+                print!("{}", ptu_file);
+            }
         }
         (_, None) => println!("No subcommand was used"),
         _ => unreachable!(), // Assuming you've listed all direct children above, this is unreachable
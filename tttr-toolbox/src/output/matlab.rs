@@ -0,0 +1,264 @@
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::ZlibEncoder, Compression};
+use ndarray::{ArrayBase, Data, Dimension};
+
+use crate::errors::Error;
+
+// MAT-file level 5 data types
+const MI_INT8: u32 = 1;
+const MI_INT32: u32 = 5;
+const MI_UINT32: u32 = 6;
+const MI_DOUBLE: u32 = 9;
+const MI_UINT64: u32 = 13;
+const MI_MATRIX: u32 = 14;
+const MI_COMPRESSED: u32 = 15;
+
+// MATLAB array classes
+const MX_DOUBLE_CLASS: u32 = 6;
+const MX_UINT64_CLASS: u32 = 15;
+
+/// Elements that can be stored on a MATLAB array.
+pub trait MatElement: Copy {
+    const DATA_TYPE: u32;
+    const CLASS: u32;
+    fn write_le<W: Write>(self, writer: &mut W) -> std::io::Result<()>;
+}
+
+impl MatElement for f64 {
+    const DATA_TYPE: u32 = MI_DOUBLE;
+    const CLASS: u32 = MX_DOUBLE_CLASS;
+    fn write_le<W: Write>(self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_f64::<LittleEndian>(self)
+    }
+}
+
+impl MatElement for u64 {
+    const DATA_TYPE: u32 = MI_UINT64;
+    const CLASS: u32 = MX_UINT64_CLASS;
+    fn write_le<W: Write>(self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_u64::<LittleEndian>(self)
+    }
+}
+
+/// Bytes needed to pad `len` to the 8 byte alignment of MAT-file data elements.
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+/// Writer of MATLAB level 5 `.mat` files.
+///
+/// Arrays are written as named variables in the order they are added. One
+/// dimensional arrays are stored as column vectors.
+pub struct MatWriter<W: Write> {
+    writer: W,
+    compress: bool,
+}
+
+impl<W: Write> MatWriter<W> {
+    /// Start a new MAT-file on `writer` by writing its header.
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        let mut text = format!(
+            "MATLAB 5.0 MAT-file, Platform: {}, Created by: tttr-toolbox",
+            std::env::consts::OS
+        )
+        .into_bytes();
+        text.resize(116, b' ');
+        writer.write_all(&text)?;
+        // No subsystem data
+        writer.write_all(&[0; 8])?;
+        writer.write_u16::<LittleEndian>(0x0100)?;
+        writer.write_all(b"IM")?;
+        Ok(Self {
+            writer,
+            compress: false,
+        })
+    }
+
+    /// Compress the arrays added from now on with zlib, as MATLAB does on `save`.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Add `array` as the variable `name`.
+    pub fn add_array<T, S, D>(&mut self, name: &str, array: &ArrayBase<S, D>) -> Result<(), Error>
+    where
+        T: MatElement,
+        S: Data<Elem = T>,
+        D: Dimension,
+    {
+        let mut dims: Vec<usize> = array.shape().to_vec();
+        if dims.len() < 2 {
+            dims.resize(2, 1);
+        }
+        let name = name.as_bytes();
+
+        let flags_len = 8 + 8;
+        let dims_len = 8 + 4 * dims.len() + padding(4 * dims.len());
+        let name_len = 8 + name.len() + padding(name.len());
+        let data_bytes = 8 * array.len();
+        let data_len = 8 + data_bytes;
+        let matrix_len = flags_len + dims_len + name_len + data_len;
+
+        let mut element = Vec::with_capacity(8 + matrix_len);
+        let w = &mut element;
+        w.write_u32::<LittleEndian>(MI_MATRIX)?;
+        w.write_u32::<LittleEndian>(matrix_len as u32)?;
+
+        w.write_u32::<LittleEndian>(MI_UINT32)?;
+        w.write_u32::<LittleEndian>(8)?;
+        w.write_u32::<LittleEndian>(T::CLASS)?;
+        w.write_u32::<LittleEndian>(0)?;
+
+        w.write_u32::<LittleEndian>(MI_INT32)?;
+        w.write_u32::<LittleEndian>((4 * dims.len()) as u32)?;
+        for &dim in &dims {
+            w.write_i32::<LittleEndian>(dim as i32)?;
+        }
+        w.write_all(&vec![0; padding(4 * dims.len())])?;
+
+        w.write_u32::<LittleEndian>(MI_INT8)?;
+        w.write_u32::<LittleEndian>(name.len() as u32)?;
+        w.write_all(name)?;
+        w.write_all(&vec![0; padding(name.len())])?;
+
+        // MATLAB stores arrays in column major order
+        w.write_u32::<LittleEndian>(T::DATA_TYPE)?;
+        w.write_u32::<LittleEndian>(data_bytes as u32)?;
+        for &x in array.t().iter() {
+            x.write_le(w)?;
+        }
+
+        if self.compress {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(&element)?;
+            let compressed = encoder.finish()?;
+            self.writer.write_u32::<LittleEndian>(MI_COMPRESSED)?;
+            self.writer
+                .write_u32::<LittleEndian>(compressed.len() as u32)?;
+            self.writer.write_all(&compressed)?;
+        } else {
+            self.writer.write_all(&element)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the file and return the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use ndarray::{arr1, arr2};
+    use std::io::Read;
+
+    use crate::headers::File;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode, G2Params};
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    }
+
+    /// Name, class, dimensions and raw data of every uncompressed variable.
+    fn variables(mat: &[u8]) -> Vec<(String, u32, Vec<u32>, Vec<u8>)> {
+        let mut variables = vec![];
+        let mut offset = 128;
+        while offset < mat.len() {
+            assert_eq!(u32_at(mat, offset), MI_MATRIX);
+            let matrix_len = u32_at(mat, offset + 4) as usize;
+            let element = &mat[offset + 8..offset + 8 + matrix_len];
+            let class = u32_at(element, 8);
+            let dims_bytes = u32_at(element, 20) as usize;
+            let dims = (0..dims_bytes / 4)
+                .map(|i| u32_at(element, 24 + 4 * i))
+                .collect();
+            let name_offset = 24 + dims_bytes + padding(dims_bytes);
+            let name_len = u32_at(element, name_offset + 4) as usize;
+            let name = &element[name_offset + 8..name_offset + 8 + name_len];
+            let data_offset = name_offset + 8 + name_len + padding(name_len);
+            let data_len = u32_at(element, data_offset + 4) as usize;
+            let data = element[data_offset + 8..data_offset + 8 + data_len].to_vec();
+            variables.push((String::from_utf8(name.to_vec()).unwrap(), class, dims, data));
+            offset += 8 + matrix_len;
+        }
+        variables
+    }
+
+    #[test]
+    fn compressed_arrays_inflate_to_the_plain_ones() {
+        let hist = arr1(&[0u64, 3, 7, 7, 7, 7, 7, 7, 1]);
+        let write = |compress| {
+            let mut mat = MatWriter::new(vec![]).unwrap().with_compression(compress);
+            mat.add_array("hist", &hist).unwrap();
+            mat.finish().unwrap()
+        };
+        let plain = write(false);
+        let compressed = write(true);
+
+        assert_eq!(compressed[..128], plain[..128]);
+        let element = &compressed[128..];
+        assert_eq!(element[..4], MI_COMPRESSED.to_le_bytes());
+        let len = u32::from_le_bytes([element[4], element[5], element[6], element[7]]);
+        assert_eq!(element.len(), 8 + len as usize);
+
+        let mut inflated = vec![];
+        ZlibDecoder::new(&element[8..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, plain[128..]);
+    }
+
+    #[test]
+    fn g2_variables_keep_their_names_and_shapes() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(2_000, 6_250, 3)),
+        );
+        let params = G2Params::new(0, 1, 100e-9, 10e-9);
+        let result = g2(&File::open(tmp.path()).unwrap(), &params, G2Mode::Symmetric).unwrap();
+        let matrix = arr2(&[[1u64, 2, 3], [4, 5, 6]]);
+
+        let mut mat = MatWriter::new(vec![]).unwrap();
+        mat.add_array("t", &arr1(&result.t)).unwrap();
+        mat.add_array("hist", &arr1(&result.hist)).unwrap();
+        mat.add_array("g3", &matrix).unwrap();
+        let mat = mat.finish().unwrap();
+
+        let variables = variables(&mat);
+        let summary: Vec<(&str, u32, &[u32])> = variables
+            .iter()
+            .map(|(name, class, dims, _)| (name.as_str(), *class, dims.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("t", MX_DOUBLE_CLASS, &[20, 1][..]),
+                ("hist", MX_UINT64_CLASS, &[20, 1][..]),
+                ("g3", MX_UINT64_CLASS, &[2, 3][..]),
+            ]
+        );
+        let hist: Vec<u8> = result.hist.iter().flat_map(|x| x.to_le_bytes()).collect();
+        assert_eq!(variables[1].3, hist);
+        // Column major
+        let g3: Vec<u8> = [1u64, 4, 2, 5, 3, 6]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        assert_eq!(variables[2].3, g3);
+    }
+}
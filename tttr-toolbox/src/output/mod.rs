@@ -0,0 +1,4 @@
+#[cfg(feature = "matlab")]
+pub mod matlab;
+#[cfg(feature = "ndarray")]
+pub mod npz;
@@ -0,0 +1,13 @@
+use std::io::{Seek, Write};
+
+use ndarray_npy::NpzWriter;
+
+/// Start a new Numpy `.npz` file on `writer`. Arrays are deflated when `compress` is
+/// set and stored as they are otherwise.
+pub fn npz_writer<W: Write + Seek>(writer: W, compress: bool) -> NpzWriter<W> {
+    if compress {
+        NpzWriter::new_compressed(writer)
+    } else {
+        NpzWriter::new(writer)
+    }
+}
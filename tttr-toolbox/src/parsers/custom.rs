@@ -0,0 +1,74 @@
+use crate::errors::Error;
+use crate::{RecordDecoder, TTTRFile, TTTRRecord, TTTRStream};
+
+/// Record stream of a user provided file decoded by its own `RecordDecoder`.
+pub struct CustomStream {
+    source: Box<dyn Iterator<Item = u32>>,
+    decoder: Box<dyn RecordDecoder>,
+    time_resolution: f64,
+    marker_channel_offset: Option<i32>,
+    overflow_channel: Option<i32>,
+}
+
+impl CustomStream {
+    pub fn new(
+        file: &dyn TTTRFile,
+        start_record: Option<usize>,
+        stop_record: Option<usize>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            source: file.raw_records(start_record, stop_record)?,
+            decoder: file.decoder()?,
+            time_resolution: file.time_resolution()?,
+            marker_channel_offset: None,
+            overflow_channel: None,
+        })
+    }
+
+    /// Report marker records on the virtual channel `offset + marker_bits`
+    /// instead of on the channel chosen by the decoder. Passing `None` keeps the
+    /// decoder behaviour.
+    pub fn with_marker_channels(mut self, offset: Option<i32>) -> Self {
+        self.marker_channel_offset = offset;
+        self
+    }
+
+    /// Report overflow records on `channel` instead of on the channel chosen by the
+    /// decoder. Their time of flight is the one given by the decoder.
+    pub fn with_overflow_channel(mut self, channel: Option<i32>) -> Self {
+        self.overflow_channel = channel;
+        self
+    }
+}
+
+impl TTTRStream for CustomStream {
+    type RecordSize = u32;
+
+    #[inline]
+    fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
+        let mut rec = self.decoder.decode(record);
+        if let Some(offset) = self.marker_channel_offset {
+            if rec.markers != 0 {
+                rec.channel = offset + rec.markers as i32;
+            }
+        }
+        if let (true, Some(channel)) = (rec.overflow, self.overflow_channel) {
+            rec.channel = channel;
+        }
+        rec
+    }
+
+    fn time_resolution(&self) -> f64 {
+        self.time_resolution
+    }
+}
+
+impl Iterator for CustomStream {
+    type Item = TTTRRecord;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.source.next()?;
+        Some(self.parse_record(record))
+    }
+}
@@ -1 +1,2 @@
+pub mod custom;
 pub mod ptu;
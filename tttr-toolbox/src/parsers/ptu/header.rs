@@ -36,7 +36,8 @@ fn read_string(slice: &[u8], size: usize) -> Option<String> {
         .ok()
 }
 
-pub(super) fn read_ptu_header(filename: &PathBuf) -> Result<Header, Error> {
+/// Parse the header of the PTU file at `filename`.
+pub fn read_ptu_header(filename: &PathBuf) -> Result<Header, Error> {
     let offset = 16;
     let mut buffered = BufReader::new(std::fs::File::open(filename)?);
     let mut header = HashMap::new();
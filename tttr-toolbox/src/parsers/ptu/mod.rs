@@ -1,16 +1,17 @@
 pub mod header;
 pub mod streamers;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::path::PathBuf;
 
 use num_traits::FromPrimitive;
 
+#[cfg(feature = "python")]
 use pyo3;
 
 use crate::errors::Error;
 use crate::headers;
-use crate::TTTRFile;
+use crate::{RecordKind, TTTRFile, TTTRRecord};
 
 pub type Header = HashMap<String, PTUTag>;
 
@@ -29,6 +30,7 @@ pub enum PTUTag {
     BinaryBlob(Vec<u8>),
 }
 
+#[cfg(feature = "python")]
 impl pyo3::ToPyObject for PTUTag {
     fn to_object(&self, py: pyo3::Python) -> pyo3::PyObject {
         match self {
@@ -80,13 +82,85 @@ const TAG_TTTR_REC_TYPE: &str = "TTResultFormat_TTTRRecType";
 const TAG_NUM_RECORDS: &str = "TTResult_NumberOfRecords"; // Number of TTTR Records in the File;
 const TAG_GLOB_RES: &str = "MeasDesc_GlobalResolution"; // Global Resolution of TimeTag(T2) /NSync (T3)
 const FILE_TAG_END: &str = "Header_End"; // Always appended as last tag (BLOCKEND)
-const _TAG_ACQUISITION_TIMETTTR: &str = "MeasDesc_AcquisitionTime";
-const _TAG_RES: &str = "MeasDesc_Resolution"; // Resolution for the Dtime (T3 Only)
+const TAG_ACQUISITION_TIME: &str = "MeasDesc_AcquisitionTime"; // in ms
+const TAG_HW_TYPE: &str = "HW_Type";
+const TAG_INPUT_CHANNELS: &str = "HW_InpChannels";
+const TAG_INPUT_OFFSETS: [&str; 2] = ["HWInpChan_Offset", "HW_InpChannelOffset"]; // in ps
+/// Inputs looked up by `PTUFile::input_offsets`, as many as the 6 bit channel field
+/// of the HydraHarp T2 records can address
+const MAX_INPUTS: i32 = 64;
+const TAG_RES: &str = "MeasDesc_Resolution"; // Resolution for the Dtime (T3 Only)
+const TAG_BINNING_FACTOR: &str = "MeasDesc_BinningFactor"; // Dtime binning (T3 Only)
+/// Number of records checked for garbled records by `PTUFile::validate`
+const PLAUSIBILITY_SAMPLE: usize = 1024;
+/// Number of records scanned for active channels by `PTUFile::summary`
+const SUMMARY_SAMPLE: usize = 1 << 20;
+const REQUIRED_TAGS: [&str; 4] = [
+    TAG_TTTR_REC_TYPE,
+    TAG_NUM_RECORDS,
+    TAG_GLOB_RES,
+    "DataOffset",
+];
+
+/// Problem found on a PTU header by `PTUFile::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderWarning {
+    /// A tag needed to parse the records is not on the header
+    MissingTag(String),
+    /// A tag is on the header but its value has an unexpected type
+    WrongTagType(String),
+    /// A resolution tag is zero, negative or not finite
+    ImplausibleResolution { tag: String, value: f64 },
+    /// The header declares a negative number of records
+    NegativeRecordCount(i64),
+    /// The record type is unknown or not supported by the parsers
+    UnsupportedRecordType,
+    /// A photon among the first records is on a channel the device doesn't have,
+    /// usually because `DataOffset` doesn't point at the first record
+    ImplausibleChannel(i32),
+    /// The photon at this record index arrived before the previous one, also a sign
+    /// of a wrong `DataOffset`
+    UnorderedRecord(usize),
+    /// The time tag of the record at this index is beyond the wraparound of the
+    /// record type, so the header declares the wrong record format
+    WraparoundMismatch(usize),
+}
+
+impl std::fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderWarning::MissingTag(tag) => write!(f, "Missing header tag {}", tag),
+            HeaderWarning::WrongTagType(tag) => write!(f, "Unexpected type for header tag {}", tag),
+            HeaderWarning::ImplausibleResolution { tag, value } => {
+                write!(f, "Implausible resolution {} on header tag {}", value, tag)
+            }
+            HeaderWarning::NegativeRecordCount(n) => write!(f, "Negative number of records {}", n),
+            HeaderWarning::UnsupportedRecordType => write!(f, "Record type not supported"),
+            HeaderWarning::ImplausibleChannel(ch) => {
+                write!(f, "Photon on channel {} which the device doesn't have", ch)
+            }
+            HeaderWarning::UnorderedRecord(idx) => {
+                write!(
+                    f,
+                    "Photon on record {} arrived before the previous one",
+                    idx
+                )
+            }
+            HeaderWarning::WraparoundMismatch(idx) => write!(
+                f,
+                "Time tag of record {} overflows the wraparound of the record type",
+                idx
+            ),
+        }
+    }
+}
 
 /// Metadata for a PTU file from PicoQuant
 pub struct PTUFile {
     pub path: PathBuf,
     pub header: Header,
+    record_type: Option<headers::RecordType>,
+    data_offset: Option<u64>,
 }
 
 impl PTUFile {
@@ -100,12 +174,391 @@ impl PTUFile {
             Ok(Self {
                 path: filename,
                 header,
+                record_type: None,
+                data_offset: None,
             })
         } else {
             let filename_string = filename.display().to_string();
             Err(Error::FileNotAvailable(filename_string))
         }
     }
+
+    /// Create a PTUFile from its filepath and an already parsed header.
+    ///
+    /// The header is not read again from disk so it must come from the same file,
+    /// e.g. from a previous call to `header::read_ptu_header`.
+    pub fn from_parts(path: PathBuf, header: Header) -> Self {
+        debug_assert!(
+            REQUIRED_TAGS.iter().all(|x| header.contains_key(*x)),
+            "Header is missing required tags"
+        );
+        Self {
+            path,
+            header,
+            record_type: None,
+            data_offset: None,
+        }
+    }
+
+    /// Force the record type used to decode the file instead of the one declared on
+    /// the header. Useful when the header is unreliable.
+    pub fn with_record_type(mut self, record_type: headers::RecordType) -> Self {
+        self.record_type = Some(record_type);
+        self
+    }
+
+    /// Force the byte offset of the first record instead of the `DataOffset` declared
+    /// on the header. Useful for files from non-standard writers whose records
+    /// appear garbled.
+    pub fn with_data_offset(mut self, data_offset: u64) -> Self {
+        self.data_offset = Some(data_offset);
+        self
+    }
+
+    /// Byte offset of the first record on the file.
+    pub fn data_offset(&self) -> Result<u64, Error> {
+        if let Some(data_offset) = self.data_offset {
+            return Ok(data_offset);
+        }
+        let header = &self.header;
+        Ok(read_ptu_tag!(header["DataOffset"] as Int8).max(0) as u64)
+    }
+
+    /// Electronic delay in seconds of each input, keyed by the channel number the
+    /// records of that input are reported on.
+    ///
+    /// Inputs without an offset tag on the header are not included.
+    pub fn input_offsets(&self) -> Result<HashMap<i32, f64>, Error> {
+        // HydraHarp style records reserve channel 0 for the sync
+        let first_channel = match self.record_type()? {
+            headers::RecordType::PHT2 => 0,
+            _ => 1,
+        };
+
+        let mut offsets = HashMap::new();
+        for input in 0..MAX_INPUTS {
+            // Indexed tags are keyed by their name followed by the index
+            let offset = TAG_INPUT_OFFSETS
+                .iter()
+                .find_map(|tag| self.header.get(&format!("{}{}", tag, input)));
+            let offset = match offset {
+                Some(PTUTag::Int8(x)) => *x as f64,
+                Some(PTUTag::Float8(x)) => *x,
+                _ => continue,
+            };
+            offsets.insert(input + first_channel, offset * 1e-12);
+        }
+        Ok(offsets)
+    }
+
+    /// Check the header for common problems without failing.
+    ///
+    /// Every problem found is returned so that it can be reported before starting a
+    /// long computation that would otherwise error out or panic midway. An empty
+    /// list means no problems were found.
+    pub fn validate(&self) -> Vec<HeaderWarning> {
+        let mut warnings = vec![];
+        for tag in REQUIRED_TAGS.iter() {
+            if !self.header.contains_key(*tag) {
+                warnings.push(HeaderWarning::MissingTag(tag.to_string()));
+            }
+        }
+
+        if let Some(PTUTag::Int8(n)) = self.header.get(TAG_NUM_RECORDS) {
+            if *n < 0 {
+                warnings.push(HeaderWarning::NegativeRecordCount(*n));
+            }
+        }
+
+        let record_type = self.record_type();
+        match record_type {
+            Ok(headers::RecordType::NotImplemented) => {
+                warnings.push(HeaderWarning::UnsupportedRecordType)
+            }
+            Err(_) if self.header.contains_key(TAG_TTTR_REC_TYPE) => {
+                warnings.push(HeaderWarning::UnsupportedRecordType)
+            }
+            _ => {}
+        }
+
+        let mut resolution_tags = vec![TAG_GLOB_RES];
+        if let Ok(headers::RecordType::HHT3_HH2) = record_type {
+            if self.header.contains_key(TAG_RES) {
+                resolution_tags.push(TAG_RES);
+            } else {
+                warnings.push(HeaderWarning::MissingTag(TAG_RES.to_string()));
+            }
+        }
+        for tag in resolution_tags {
+            match self.header.get(tag) {
+                Some(PTUTag::Float8(x)) if !(x.is_finite() && *x > 0.0) => {
+                    warnings.push(HeaderWarning::ImplausibleResolution {
+                        tag: tag.to_string(),
+                        value: *x,
+                    })
+                }
+                Some(PTUTag::Float8(_)) | None => {}
+                Some(_) => warnings.push(HeaderWarning::WrongTagType(tag.to_string())),
+            }
+        }
+
+        if let Some(warning) = self.implausible_records(PLAUSIBILITY_SAMPLE) {
+            warnings.push(warning);
+        }
+        warnings
+    }
+
+    /// Look for garbled records within the first `sample_records` records. Photons
+    /// must be on one of the inputs declared on the header, if any, or on the sync
+    /// channel of T2 HydraHarp files, and their time of flight can't go backwards. On T2 files no time tag can be further than the
+    /// wraparound of the record type from the last overflow. `None` if all of them
+    /// look fine or if the records can't be read.
+    fn implausible_records(&self, sample_records: usize) -> Option<HeaderWarning> {
+        // Overflows are reported on a channel of their own to learn their time
+        const OVERFLOW_CHANNEL: i32 = i32::MIN;
+
+        fn scan(
+            stream: impl Iterator<Item = TTTRRecord>,
+            channels: Option<(i32, i32)>,
+            sync_channel: Option<i32>,
+            wraparound: Option<u64>,
+            n: usize,
+        ) -> Option<HeaderWarning> {
+            let mut last_tof = 0;
+            let mut last_overflow = 0;
+            for (idx, rec) in stream.take(n).enumerate() {
+                if rec.overflow {
+                    last_overflow = rec.tof;
+                    continue;
+                }
+                if let Some(wraparound) = wraparound {
+                    if rec.tof.saturating_sub(last_overflow) >= wraparound {
+                        return Some(HeaderWarning::WraparoundMismatch(idx));
+                    }
+                }
+                if let RecordKind::Photon { channel } = rec.kind() {
+                    if let Some((first, n_inputs)) = channels {
+                        let is_input = channel >= first && channel < first + n_inputs;
+                        if !is_input && Some(channel) != sync_channel {
+                            return Some(HeaderWarning::ImplausibleChannel(channel));
+                        }
+                    }
+                    if rec.tof < last_tof {
+                        return Some(HeaderWarning::UnorderedRecord(idx));
+                    }
+                    last_tof = rec.tof;
+                }
+            }
+            None
+        }
+
+        let record_type = self.record_type().ok()?;
+        // HydraHarp style records reserve channel 0 for the sync
+        let first_channel = match record_type {
+            headers::RecordType::PHT2 => 0,
+            _ => 1,
+        };
+        let channels = match self.header.get(TAG_INPUT_CHANNELS) {
+            Some(PTUTag::Int8(x)) => Some((first_channel, *x as i32)),
+            _ => None,
+        };
+        // T2 HydraHarp files report the sync as photons on channel 0
+        let sync_channel = match record_type {
+            headers::RecordType::HHT2_HH1 | headers::RecordType::HHT2_HH2 => Some(0),
+            _ => None,
+        };
+        let wraparound = Some(record_type.wraparound());
+        let overflow_channel = Some(OVERFLOW_CHANNEL);
+
+        match record_type {
+            headers::RecordType::PHT2 => scan(
+                streamers::PHT2Stream::new(self, None, None)
+                    .ok()?
+                    .with_overflow_channel(overflow_channel),
+                channels,
+                sync_channel,
+                wraparound,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH1 => scan(
+                streamers::HHT2_HH1Stream::new(self, None, None)
+                    .ok()?
+                    .with_overflow_channel(overflow_channel),
+                channels,
+                sync_channel,
+                wraparound,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH2 => scan(
+                streamers::HHT2_HH2Stream::new(self, None, None)
+                    .ok()?
+                    .with_overflow_channel(overflow_channel),
+                channels,
+                sync_channel,
+                wraparound,
+                sample_records,
+            ),
+            // The sync counter field can't exceed the wraparound
+            headers::RecordType::HHT3_HH2 => scan(
+                streamers::HHT3_HH2Stream::new(self, None, None).ok()?,
+                channels,
+                sync_channel,
+                None,
+                sample_records,
+            ),
+            headers::RecordType::NotImplemented => None,
+        }
+    }
+
+    /// Number of records on the data section of the file.
+    ///
+    /// It is a `u64` since on 32 bit targets the record count of large files does not
+    /// fit in a `usize`.
+    pub fn num_records(&self) -> Result<u64, Error> {
+        let header = &self.header;
+        Ok(read_ptu_tag!(header[TAG_NUM_RECORDS] as Int8).max(0) as u64)
+    }
+
+    /// Duration of the measurement in seconds if declared on the header.
+    pub fn acquisition_time(&self) -> Option<f64> {
+        match self.header.get(TAG_ACQUISITION_TIME) {
+            Some(PTUTag::Int8(x)) => Some((*x as f64) * 1e-3),
+            _ => None,
+        }
+    }
+
+    /// Check whether photons on channel `ch` show up within the first `sample_records`
+    /// records of the file.
+    ///
+    /// This is a cheap heuristic meant to be run before an algorithm. A sparse channel
+    /// whose first click comes after the sampled records is reported as absent.
+    pub fn contains_channel(&self, ch: i32, sample_records: usize) -> Result<bool, Error> {
+        fn scan(stream: impl Iterator<Item = TTTRRecord>, ch: i32, n: usize) -> bool {
+            stream
+                .take(n)
+                .any(|rec| rec.kind() == RecordKind::Photon { channel: ch })
+        }
+
+        Ok(match self.record_type()? {
+            headers::RecordType::PHT2 => scan(
+                streamers::PHT2Stream::new(self, None, None)?,
+                ch,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH1 => scan(
+                streamers::HHT2_HH1Stream::new(self, None, None)?,
+                ch,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH2 => scan(
+                streamers::HHT2_HH2Stream::new(self, None, None)?,
+                ch,
+                sample_records,
+            ),
+            headers::RecordType::HHT3_HH2 => scan(
+                streamers::HHT3_HH2Stream::new(self, None, None)?,
+                ch,
+                sample_records,
+            ),
+            headers::RecordType::NotImplemented => {
+                return Err(Error::NotImplemented(String::from(
+                    "Record type not implemented",
+                )))
+            }
+        })
+    }
+
+    /// Sorted list of the channels with photons within the first `sample_records`
+    /// records. Markers and overflows are not included.
+    fn sampled_channels(&self, sample_records: usize) -> Result<Vec<i32>, Error> {
+        fn scan(stream: impl Iterator<Item = TTTRRecord>, n: usize) -> Vec<i32> {
+            let channels = stream
+                .take(n)
+                .filter_map(|rec| match rec.kind() {
+                    RecordKind::Photon { channel } => Some(channel),
+                    _ => None,
+                })
+                .collect::<BTreeSet<_>>();
+            channels.into_iter().collect()
+        }
+
+        Ok(match self.record_type()? {
+            headers::RecordType::PHT2 => scan(
+                streamers::PHT2Stream::new(self, None, None)?,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH1 => scan(
+                streamers::HHT2_HH1Stream::new(self, None, None)?,
+                sample_records,
+            ),
+            headers::RecordType::HHT2_HH2 => scan(
+                streamers::HHT2_HH2Stream::new(self, None, None)?,
+                sample_records,
+            ),
+            headers::RecordType::HHT3_HH2 => scan(
+                streamers::HHT3_HH2Stream::new(self, None, None)?,
+                sample_records,
+            ),
+            headers::RecordType::NotImplemented => {
+                return Err(Error::NotImplemented(String::from(
+                    "Record type not implemented",
+                )))
+            }
+        })
+    }
+
+    /// Curated, human friendly overview of the most relevant header values.
+    ///
+    /// Tags missing from the header are reported as `unknown`. The active channels
+    /// are those with photons within the first million records, with the sync of
+    /// HydraHarp T2 files labelled as such. Use the `Display` implementation to get
+    /// the full header dump.
+    pub fn summary(&self) -> String {
+        let tag = |name: &str| {
+            self.header
+                .get(name)
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| String::from("unknown"))
+        };
+        let mode = self
+            .record_type()
+            .map(|x| format!("{:?}", x))
+            .unwrap_or_else(|_| String::from("unknown"));
+        let duration = match self.acquisition_time() {
+            Some(x) => format!("{} s", x),
+            None => String::from("unknown"),
+        };
+        let resolution = match self.time_resolution() {
+            Ok(x) => format!("{:e} s", x),
+            Err(_) => String::from("unknown"),
+        };
+        // T2 HydraHarp records report the sync as photons on channel 0
+        let has_sync = matches!(
+            self.record_type(),
+            Ok(headers::RecordType::HHT2_HH1) | Ok(headers::RecordType::HHT2_HH2)
+        );
+        let channels = match self.sampled_channels(SUMMARY_SAMPLE) {
+            Ok(channels) if channels.is_empty() => String::from("none"),
+            Ok(channels) => channels
+                .iter()
+                .map(|&ch| match ch {
+                    0 if has_sync => String::from("0 (sync)"),
+                    _ => ch.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(_) => String::from("unknown"),
+        };
+
+        let mut summary = String::from("");
+        summary.push_str(&format!("{:<20}: {}\n", "Device", tag(TAG_HW_TYPE)));
+        summary.push_str(&format!("{:<20}: {}\n", "Record type", mode));
+        summary.push_str(&format!("{:<20}: {}\n", "Active channels", channels));
+        summary.push_str(&format!("{:<20}: {}\n", "Records", tag(TAG_NUM_RECORDS)));
+        summary.push_str(&format!("{:<20}: {}\n", "Duration", duration));
+        summary.push_str(&format!("{:<20}: {}\n", "Resolution", resolution));
+        summary
+    }
 }
 
 use tttr_toolbox_proc_macros::read_ptu_tag;
@@ -117,8 +570,12 @@ impl TTTRFile for PTUFile {
     }
 
     /// Returns the `record_type` used in the file. This is matched on each algorithm
-    /// with a specific file parser.
+    /// with a specific file parser. A type forced with `with_record_type` takes
+    /// precedence over the header.
     fn record_type(&self) -> Result<headers::RecordType, Error> {
+        if let Some(record_type) = self.record_type {
+            return Ok(record_type);
+        }
         let header = &self.header;
         let record_type = FromPrimitive::from_i64(read_ptu_tag!(header[TAG_TTTR_REC_TYPE] as Int8));
 
@@ -139,14 +596,293 @@ impl TTTRFile for PTUFile {
             },
         )
     }
+
+    fn record_size_bytes(&self) -> usize {
+        // All PTU record formats are 32 bits wide so fall back to it if the record
+        // type can't be read.
+        self.record_type().map(|x| x.record_size()).unwrap_or(4)
+    }
 }
 
 impl std::fmt::Display for PTUFile {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let mut string = String::from("");
-        for (key, value) in &self.header {
+        // Sort the tags so that dumps of the same header are reproducible
+        let mut tags = self.header.iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, value) in tags {
             string.push_str(&format!("{:<35}: {}\n", key, value));
         }
         write!(f, "{}", string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_hht2_hh2, write_ptu, Event, Tag, HHT2_HH2, HHT3_HH2, PHT2};
+    use crate::TTTRStream;
+
+    #[test]
+    fn summary_reports_records_mode_and_active_channels() {
+        let events: Vec<Event> = (1..=100u64)
+            .flat_map(|k| vec![Event::Photon(0, k * 1000), Event::Photon(3, k * 1000 + 7)])
+            .collect();
+        let records = encode_hht2_hh2(&events);
+        let tags = [("HW_InpChannels", Tag::Int8(8))];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &records);
+        let summary = PTUFile::new(tmp.path()).unwrap().summary();
+
+        assert!(summary.contains(&format!("{:<20}: {}\n", "Records", records.len())));
+        assert!(summary.contains(&format!("{:<20}: {}\n", "Record type", "HHT2_HH2")));
+        assert!(summary.contains(&format!("{:<20}: {}\n", "Active channels", "0 (sync), 3")));
+    }
+
+    #[test]
+    fn time_tag_beyond_the_wraparound_is_reported() {
+        // Valid on the V2 format but past the wraparound of the V1 format
+        let records = encode_hht2_hh2(&[Event::Photon(1, 33_553_000)]);
+        let v2 = write_ptu(HHT2_HH2, 1e-12, &[], &records);
+        let v1 = write_ptu(0x01010204, 1e-12, &[], &records);
+
+        let warnings = PTUFile::new(v2.path()).unwrap().validate();
+        assert!(!warnings.contains(&HeaderWarning::WraparoundMismatch(0)));
+        let warnings = PTUFile::new(v1.path()).unwrap().validate();
+        assert!(warnings.contains(&HeaderWarning::WraparoundMismatch(0)));
+    }
+
+    #[test]
+    fn durations_reconstruct_at_any_resolution() {
+        let events: Vec<Event> = (1..=100u64)
+            .map(|k| Event::Photon(1, k * 10_000_000 + k))
+            .collect();
+        let records = encode_hht2_hh2(&events);
+        for &resolution in &[1e-12, 5e-12] {
+            let tmp = write_ptu(HHT2_HH2, resolution, &[], &records);
+            let f = PTUFile::new(tmp.path()).unwrap();
+            assert!(f.implausible_records(records.len()).is_none());
+
+            let stream = streamers::HHT2_HH2Stream::new(&f, None, None).unwrap();
+            let time_resolution = stream.time_resolution();
+            let last = stream.last().unwrap();
+            let duration = last.tof as f64 * time_resolution;
+            let expected = (100 * 10_000_000 + 100) as f64 * resolution;
+            assert!((duration - expected).abs() < resolution / 2.0);
+        }
+    }
+
+    #[test]
+    fn hydraharp_t2_sync_is_not_an_implausible_channel() {
+        let events = [
+            Event::Photon(0, 1_000),
+            Event::Photon(2, 1_500),
+            Event::Photon(0, 2_000),
+        ];
+        let tags = [("HW_InpChannels", Tag::Int8(2))];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &encode_hht2_hh2(&events));
+        assert_eq!(PTUFile::new(tmp.path()).unwrap().validate(), vec![]);
+    }
+
+    #[test]
+    fn shifted_records_are_fixed_by_the_data_offset() {
+        let events: Vec<Event> = (1..=100u64)
+            .map(|k| Event::Photon(1 + (k % 2) as i32, k * 1000))
+            .collect();
+        // A stray word before the records, decoded as a photon on input 40
+        let mut records = vec![40 << 25];
+        records.extend(encode_hht2_hh2(&events));
+        let tags = [("HW_InpChannels", Tag::Int8(2))];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &records);
+
+        let f = PTUFile::new(tmp.path()).unwrap();
+        let data_offset = f.data_offset().unwrap();
+        assert_eq!(f.validate(), vec![HeaderWarning::ImplausibleChannel(41)]);
+        let f = f.with_data_offset(data_offset + 4);
+        assert_eq!(f.validate(), vec![]);
+    }
+
+    #[test]
+    fn indexed_input_offsets_are_kept_apart() {
+        let tags = [
+            ("HW_InpChannelOffset[0]", Tag::Int8(100)),
+            ("HW_InpChannelOffset[1]", Tag::Int8(350)),
+            ("HW_InpChannelOffset[10]", Tag::Int8(-20)),
+        ];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &[]);
+        let f = PTUFile::new(tmp.path()).unwrap();
+
+        assert!(matches!(
+            f.header.get("HW_InpChannelOffset1"),
+            Some(PTUTag::Int8(350))
+        ));
+        let offsets = f.input_offsets().unwrap();
+        assert_eq!(offsets.len(), 3);
+        // Input n of a HydraHarp is reported on channel n + 1
+        assert_eq!(offsets[&1], 100e-12);
+        assert_eq!(offsets[&2], 350e-12);
+        assert_eq!(offsets[&11], -20e-12);
+    }
+
+    #[test]
+    fn record_size_spans_the_data_section() {
+        let records = vec![0u32; 37];
+        for &record_type in &[PHT2, HHT2_HH2, HHT3_HH2] {
+            let tmp = write_ptu(record_type, 1e-12, &[], &records);
+            let f = PTUFile::new(tmp.path()).unwrap();
+            assert_eq!(f.record_size_bytes(), 4);
+            assert_eq!(
+                f.record_size_bytes(),
+                f.record_type().unwrap().record_size()
+            );
+            let file_size = std::fs::metadata(tmp.path()).unwrap().len();
+            let data_size = file_size - f.data_offset().unwrap();
+            assert_eq!(data_size, (records.len() * f.record_size_bytes()) as u64);
+        }
+    }
+
+    #[test]
+    fn from_parts_matches_opening_the_file() {
+        let events: Vec<Event> = (1..=200u64)
+            .map(|k| Event::Photon(1 + (k % 2) as i32, k * 1000))
+            .collect();
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &[], &encode_hht2_hh2(&events));
+        let path = tmp.path().to_path_buf();
+        let opened = PTUFile::new(path.clone()).unwrap();
+        let header = header::read_ptu_header(&path).unwrap();
+        let from_parts = PTUFile::from_parts(path, header);
+
+        assert_eq!(from_parts.to_string(), opened.to_string());
+        assert_eq!(
+            from_parts.data_offset().unwrap(),
+            opened.data_offset().unwrap()
+        );
+        let fields = |f: &PTUFile| -> Vec<(i32, u64)> {
+            streamers::HHT2_HH2Stream::new(f, None, None)
+                .unwrap()
+                .map(|rec| (rec.channel, rec.tof))
+                .collect()
+        };
+        assert_eq!(fields(&from_parts), fields(&opened));
+        assert_eq!(fields(&from_parts).len(), 200);
+    }
+
+    #[test]
+    fn contains_channel_within_the_sample() {
+        // Inputs 0 and 1 alternate, input 3 only shows up at the very end
+        let mut events: Vec<Event> = (1..=100u64)
+            .map(|k| Event::Photon(1 + (k % 2) as i32, k * 1000))
+            .collect();
+        events.push(Event::Photon(4, 200_000));
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &[], &encode_hht2_hh2(&events));
+        let f = PTUFile::new(tmp.path()).unwrap();
+
+        assert!(f.contains_channel(1, 10).unwrap());
+        assert!(f.contains_channel(2, 10).unwrap());
+        assert!(!f.contains_channel(3, 1_000).unwrap());
+        assert!(!f.contains_channel(4, 100).unwrap());
+        assert!(f.contains_channel(4, 101).unwrap());
+    }
+
+    #[test]
+    fn broken_header_lists_every_problem() {
+        let tmp = write_ptu(PHT2, 1e-12, &[], &[]);
+        let broken = |tags: Vec<(&str, Option<PTUTag>)>| -> PTUFile {
+            let mut f = PTUFile::new(tmp.path()).unwrap();
+            for (key, value) in tags {
+                match value {
+                    Some(value) => f.header.insert(key.to_string(), value),
+                    None => f.header.remove(key),
+                };
+            }
+            f
+        };
+
+        // PicoHarp T3 records, no data offset and a zero resolution
+        let f = broken(vec![
+            (TAG_TTTR_REC_TYPE, Some(PTUTag::Int8(0x00010303))),
+            (TAG_NUM_RECORDS, Some(PTUTag::Int8(-5))),
+            (TAG_GLOB_RES, Some(PTUTag::Float8(0.0))),
+            ("DataOffset", None),
+        ]);
+        assert_eq!(
+            f.validate(),
+            vec![
+                HeaderWarning::MissingTag(String::from("DataOffset")),
+                HeaderWarning::NegativeRecordCount(-5),
+                HeaderWarning::UnsupportedRecordType,
+                HeaderWarning::ImplausibleResolution {
+                    tag: TAG_GLOB_RES.to_string(),
+                    value: 0.0,
+                },
+            ]
+        );
+
+        // Unknown record type and a resolution stored as an integer
+        let f = broken(vec![
+            (TAG_TTTR_REC_TYPE, Some(PTUTag::Int8(0x7F))),
+            (TAG_NUM_RECORDS, None),
+            (TAG_GLOB_RES, Some(PTUTag::Int8(1))),
+        ]);
+        assert_eq!(
+            f.validate(),
+            vec![
+                HeaderWarning::MissingTag(TAG_NUM_RECORDS.to_string()),
+                HeaderWarning::UnsupportedRecordType,
+                HeaderWarning::WrongTagType(TAG_GLOB_RES.to_string()),
+            ]
+        );
+
+        // T3 records also need the resolution of the dtime
+        let f = broken(vec![(TAG_TTTR_REC_TYPE, Some(PTUTag::Int8(HHT3_HH2)))]);
+        assert_eq!(
+            f.validate(),
+            vec![HeaderWarning::MissingTag(TAG_RES.to_string())]
+        );
+        assert_eq!(PTUFile::new(tmp.path()).unwrap().validate(), vec![]);
+    }
+
+    #[test]
+    fn record_counts_above_u32_are_not_truncated() {
+        let events: Vec<Event> = (1..=100u64)
+            .map(|k| Event::Photon(1 + (k % 2) as i32, k * 1000))
+            .collect();
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &[], &encode_hht2_hh2(&events));
+        let mut f = PTUFile::new(tmp.path()).unwrap();
+        let declared = 5_000_000_000u64;
+        assert!(declared > u32::MAX as u64);
+        f.header
+            .insert(TAG_NUM_RECORDS.to_string(), PTUTag::Int8(declared as i64));
+
+        assert_eq!(f.num_records().unwrap(), declared);
+        // Only the records present on disk are streamed
+        let stream = streamers::HHT2_HH2Stream::new(&f, None, None).unwrap();
+        assert_eq!(stream.count(), 100);
+        let stream = streamers::HHT2_HH2Stream::new(&f, Some(40), None).unwrap();
+        assert_eq!(stream.map(|rec| rec.tof).next(), Some(41_000));
+    }
+
+    #[test]
+    fn dumps_of_the_same_file_are_byte_identical() {
+        // Enough tags for the hash map orders of two opens to differ
+        let names: Vec<String> = (0..32)
+            .map(|k| format!("HW_InpChannelOffset[{}]", k))
+            .collect();
+        let tags: Vec<(&str, Tag)> = names
+            .iter()
+            .enumerate()
+            .map(|(k, name)| (name.as_str(), Tag::Int8(k as i64)))
+            .collect();
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &[]);
+        let first = PTUFile::new(tmp.path()).unwrap().to_string();
+        let second = PTUFile::new(tmp.path()).unwrap().to_string();
+        assert_eq!(first.as_bytes(), second.as_bytes());
+
+        let keys: Vec<&str> = first
+            .lines()
+            .map(|line| line.split(':').next().unwrap().trim_end())
+            .collect();
+        // The offsets, the three tags every test file has and the data offset
+        assert_eq!(keys.len(), 32 + 3 + 1);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
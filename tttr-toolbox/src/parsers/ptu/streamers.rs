@@ -1,10 +1,13 @@
 const BUFFER_SIZE: usize = 1024 * 16;
+const READER_CAPACITY: usize = 8 * 1024;
 
 use std::io::{BufReader, Seek, SeekFrom};
+use std::sync::OnceLock;
 
 use crate::errors::Error;
+use crate::headers::RecordType;
 use crate::parsers::ptu;
-use crate::parsers::ptu::{PTUTag, TAG_NUM_RECORDS};
+use crate::parsers::ptu::{PTUTag, TAG_BINNING_FACTOR, TAG_NUM_RECORDS};
 use crate::{TTTRFile, TTTRRecord, TTTRStream};
 
 use byteorder::{NativeEndian, ReadBytesExt};
@@ -12,45 +15,99 @@ use byteorder::{NativeEndian, ReadBytesExt};
 use tttr_toolbox_proc_macros::make_ptu_stream;
 use tttr_toolbox_proc_macros::read_ptu_tag;
 
+/// Read a positive size from the environment variable `var` falling back to `default`
+/// when it is unset or invalid.
+fn size_from_env(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|&x| x > 0)
+        .unwrap_or(default)
+}
+
+/// Number of records decoded from each read into the streamers. Can be tuned with the
+/// `TTTR_BUFFER_SIZE` environment variable, which is only read once.
+pub fn buffer_size() -> usize {
+    static SIZE: OnceLock<usize> = OnceLock::new();
+    *SIZE.get_or_init(|| size_from_env("TTTR_BUFFER_SIZE", BUFFER_SIZE))
+}
+
+/// Capacity in bytes of the buffered reader backing the streamers. Can be tuned with
+/// the `TTTR_READER_CAPACITY` environment variable, which is only read once.
+pub fn reader_capacity() -> usize {
+    static CAPACITY: OnceLock<usize> = OnceLock::new();
+    *CAPACITY.get_or_init(|| size_from_env("TTTR_READER_CAPACITY", READER_CAPACITY))
+}
+
+/// Channel reported for a marker record. If a marker channel offset was requested
+/// the markers are mapped onto the virtual channel `offset + markers`, otherwise
+/// the stream specific negative `default` is used.
+#[inline(always)]
+fn marker_channel(offset: Option<i32>, markers: u8, default: i32) -> i32 {
+    match offset {
+        Some(offset) => offset + markers as i32,
+        None => default,
+    }
+}
+
+/// Report an overflow record as a click on `channel` at `overflow_tof`, the time at
+/// which the time tag wrapped around, if a dedicated overflow channel was requested.
+#[inline(always)]
+fn overflow_click(mut rec: TTTRRecord, channel: Option<i32>, overflow_tof: u64) -> TTTRRecord {
+    if let (true, Some(channel)) = (rec.overflow, channel) {
+        rec.channel = channel;
+        rec.tof = overflow_tof;
+    }
+    rec
+}
+
 // - - - - - - - - - - //
 // PHT2 Record Stream //
 // - - - - - - - - - - //
 #[make_ptu_stream(PHT2)]
 fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
-    const T2WRAPAROUND: u64 = 210698240;
+    const T2WRAPAROUND: u64 = RecordType::PHT2.wraparound();
 
     let ch = ((record & 0b11110000000000000000000000000000) >> 28) as i32;
     let tm = (record & 0b00001111111111111111111111111111) as u64;
 
     let tof;
     let channel;
+    let mut markers = 0;
+    let mut overflow = false;
 
     if ch == 0xF {
         // we have a special record
-        let markers = tm & 0xF;
+        markers = (tm & 0xF) as u8;
         if markers == 0 {
             // overflow record
             tof = 0;
             channel = -1;
+            overflow = true;
             self.overflow_correction += T2WRAPAROUND; // unwrap the time tag overflow
+            self.overflow_count += 1;
         } else {
             // marker
-            // Strictly, in case of a marker, the lower 4 bits of time are invalid
-            // because they carry the marker bits. So one could zero them out.
-            // However, the marker resolution is only a few tens of nanoseconds anyway,
-            // so we can just ignore the few picoseconds of error.
-            tof = self.overflow_correction + tm;
-            channel = -2;
+            // The lower 4 bits of the time tag carry the marker bits instead of time,
+            // so they are kept on `markers` and zeroed out of the time of flight.
+            // Markers are only resolved to a few tens of nanoseconds anyway, so the
+            // truncation costs nothing while making the time independent of the bits.
+            tof = self.overflow_correction + (tm & !0xF);
+            channel = marker_channel(self.marker_channel_offset, markers, -2);
+            self.marker_count += 1;
         }
     } else {
         tof = self.overflow_correction + tm;
         channel = ch;
     }
 
-    TTTRRecord {
+    let rec = TTTRRecord {
         channel: channel as i32,
         tof,
-    }
+        markers,
+        overflow,
+    };
+    overflow_click(rec, self.overflow_channel, self.overflow_correction)
 }
 
 // - - - - - - - - - - - -//
@@ -58,7 +115,7 @@ fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
 // - - - - - - - - - - - -//
 #[make_ptu_stream(HHT2_HH1)]
 fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
-    const T2WRAPAROUND: u64 = 33552000;
+    const T2WRAPAROUND: u64 = RecordType::HHT2_HH1.wraparound();
 
     let sp = (((record & 0b10000000000000000000000000000000) >> 31) == 1) as i32;
     let ch = ((record & 0b01111110000000000000000000000000) >> 25) as i32;
@@ -67,13 +124,31 @@ fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
     let tof;
     let channel;
 
+    let markers = if (sp == 1) && (1..=15).contains(&ch) {
+        ch as u8
+    } else {
+        0
+    };
+
     self.overflow_correction += T2WRAPAROUND * (sp as u64) * ((ch == 0x3F) as u64);
-    channel = (1 - sp) * (ch + 1) - sp * ch;
+    self.overflow_count += (sp as u64) * ((ch == 0x3F) as u64);
+    self.marker_count += (markers != 0) as u64;
+    channel = if markers == 0 {
+        (1 - sp) * (ch + 1) - sp * ch
+    } else {
+        marker_channel(self.marker_channel_offset, markers, -ch)
+    };
     tof = self.overflow_correction + tm;
 
     //println!("channel: {:?}, ch: {:?}, sp: {:?}", channel, ch, sp);
 
-    TTTRRecord { channel, tof }
+    let rec = TTTRRecord {
+        channel,
+        tof,
+        markers,
+        overflow: (sp == 1) && (ch == 0x3F),
+    };
+    overflow_click(rec, self.overflow_channel, self.overflow_correction)
 }
 
 // - - - - - - - - - - - -//
@@ -81,7 +156,7 @@ fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
 // - - - - - - - - - - - -//
 #[make_ptu_stream(HHT2_HH2)]
 fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
-    const T2WRAPAROUND: u64 = 33554432;
+    const T2WRAPAROUND: u64 = RecordType::HHT2_HH2.wraparound();
 
     let sp = (((record & 0b10000000000000000000000000000000) >> 31) == 1) as i32;
     let ch = ((record & 0b01111110000000000000000000000000) >> 25) as i32;
@@ -90,11 +165,34 @@ fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
     let tof;
     let channel;
 
-    self.overflow_correction += T2WRAPAROUND * tm * ((ch == 0x3F) as u64);
-    channel = (1 - sp) * (ch + 1) - sp * ch; // ch +1 - sp ch -sp - sp ch
+    let markers = if (sp == 1) && (1..=15).contains(&ch) {
+        ch as u8
+    } else {
+        0
+    };
+
+    // Only special records can be overflows. Photons on the 64th input share the
+    // 0x3F channel code.
+    let is_overflow = (sp == 1) && (ch == 0x3F);
+    self.overflow_correction += T2WRAPAROUND * tm * (is_overflow as u64);
+    self.overflow_count += is_overflow as u64;
+    self.marker_count += (markers != 0) as u64;
+    // Photons on inputs 0 to 63 are reported on channels 1 to 64, the sync on channel
+    // 0 and the rest of special records on -ch
+    channel = if markers == 0 {
+        (1 - sp) * (ch + 1) - sp * ch
+    } else {
+        marker_channel(self.marker_channel_offset, markers, -ch)
+    };
     tof = self.overflow_correction + tm;
 
-    TTTRRecord { channel, tof }
+    let rec = TTTRRecord {
+        channel,
+        tof,
+        markers,
+        overflow: is_overflow,
+    };
+    overflow_click(rec, self.overflow_channel, self.overflow_correction)
 }
 
 // - - - - - - - - - - - -//
@@ -108,18 +206,31 @@ fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
 pub struct HHT3_HH2Stream {
     // todo: make it just with a trait that implements readbuf
     source: BufReader<std::fs::File>,
-    click_buffer: [u32; BUFFER_SIZE],
+    click_buffer: Vec<u32>,
     effective_buffer_size: u32,
-    num_records: usize,
+    num_records: u64,
+    /// Byte offset of the first record of the stream
+    data_start: u64,
+    /// Whether the stream may pick up records appended to the file
+    growable: bool,
     time_resolution: f64,
     photons_in_buffer: i32,
-    click_count: usize,
+    click_count: u64,
     nsync: u64,
     pub sync_period: u64,
     dtime_res: u64,
+    marker_channel_offset: Option<i32>,
+    overflow_channel: Option<i32>,
+    /// Number of overflow records parsed so far
+    pub overflow_count: u64,
+    /// Number of marker records parsed so far
+    pub marker_count: u64,
 }
 
 impl HHT3_HH2Stream {
+    /// Bytes taken on disk by each record of the stream
+    pub const RECORD_SIZE: usize = std::mem::size_of::<u32>();
+
     pub fn new(
         ptu_file: &ptu::PTUFile,
         start_record: Option<usize>,
@@ -127,27 +238,28 @@ impl HHT3_HH2Stream {
     ) -> Result<Self, Error> {
         let header = &ptu_file.header;
         let number_of_records: i64 = read_ptu_tag!(header[TAG_NUM_RECORDS] as Int8);
-        let data_offset: i64 = read_ptu_tag!(header["DataOffset"] as Int8);
-
-        let mut buffered =
-            BufReader::with_capacity(8 * 1024, std::fs::File::open(ptu_file.path.clone())?);
-
-        let record_offset = if let Some(offset) = start_record {
-            offset as i64
-        } else {
-            0 as i64
-        };
-
-        let last_record = if let Some(last) = stop_record {
-            last as i64
-        } else {
-            number_of_records as i64
-        };
-
-        // 4 bytes per record
-        buffered.seek(SeekFrom::Start(
-            (data_offset as u64) + (4 * record_offset) as u64,
-        ))?;
+        let data_offset = ptu_file.data_offset()?;
+
+        let mut buffered = BufReader::with_capacity(
+            reader_capacity(),
+            std::fs::File::open(ptu_file.path.clone())?,
+        );
+
+        // Record counts and offsets are kept in 64 bits so that huge files don't
+        // overflow on 32 bit targets
+        let record_offset = start_record.map_or(0, |offset| offset as u64);
+        let last_record = stop_record.map_or(number_of_records.max(0) as u64, |last| last as u64);
+
+        let record_size = Self::RECORD_SIZE as u64;
+        let data_start = data_offset + record_size * record_offset;
+        buffered.seek(SeekFrom::Start(data_start))?;
+        // Files still being written may hold fewer records than announced
+        let available = buffered
+            .get_ref()
+            .metadata()?
+            .len()
+            .saturating_sub(data_start)
+            / record_size;
 
         let header = &ptu_file.header;
 
@@ -155,27 +267,102 @@ impl HHT3_HH2Stream {
             Ok(read_ptu_tag!(header["MeasDesc_GlobalResolution"] as Float8));
         let dtime_res: Result<f64, Error> =
             Ok(read_ptu_tag!(header["MeasDesc_Resolution"] as Float8));
+        // The TAC may be coarsened by a binning factor. Older files don't store it.
+        let binning_factor = match header.get(TAG_BINNING_FACTOR) {
+            Some(PTUTag::Int8(x)) if *x > 0 => *x as u64,
+            _ => 1,
+        };
+
+        // The sync period divides the tof of every click on T3 algorithms
+        let sync_period = (sync_period? * 1e12) as u64;
+        if sync_period == 0 {
+            return Err(Error::InvalidHeader(String::from(
+                "MeasDesc_GlobalResolution must be positive to derive the sync period",
+            )));
+        }
 
         Ok(Self {
             source: buffered,
-            click_buffer: [0; BUFFER_SIZE],
+            click_buffer: vec![0; buffer_size()],
             effective_buffer_size: 0,
-            num_records: (last_record - record_offset) as usize,
+            num_records: last_record.saturating_sub(record_offset).min(available),
+            data_start,
+            growable: stop_record.is_none(),
             time_resolution: 1e-12,
             photons_in_buffer: 0,
             click_count: 0,
             nsync: 0,
-            sync_period: (sync_period? * 1e12) as u64,
-            dtime_res: (dtime_res? * 1e12) as u64,
+            sync_period,
+            dtime_res: (dtime_res? * 1e12) as u64 * binning_factor,
+            marker_channel_offset: None,
+            overflow_channel: None,
+            overflow_count: 0,
+            marker_count: 0,
         })
     }
+
+    /// Report marker records on the virtual channel `offset + marker_bits`
+    /// instead of on a negative channel number. Passing `None` keeps the
+    /// default behaviour.
+    pub fn with_marker_channels(mut self, offset: Option<i32>) -> Self {
+        self.marker_channel_offset = offset;
+        self
+    }
+
+    /// Report overflow records as clicks on `channel` at the time the sync counter
+    /// wrapped around. Passing `None` keeps the default behaviour.
+    pub fn with_overflow_channel(mut self, channel: Option<i32>) -> Self {
+        self.overflow_channel = channel;
+        self
+    }
+
+    /// Decode `records` records from each read instead of `buffer_size()`.
+    pub fn with_buffer_size(mut self, records: usize) -> Self {
+        // Records already read into the buffer must stay where they are
+        let in_use = self.effective_buffer_size as usize;
+        self.click_buffer.resize(records.max(in_use).max(1), 0);
+        self
+    }
+
+    /// Pick up the records appended to the file since the stream was created, e.g.
+    /// while an acquisition is still running, so that iteration can resume where it
+    /// stopped. Returns whether new records are available. Streams created with a
+    /// `stop_record` never grow.
+    pub fn poll_new(&mut self) -> Result<bool, Error> {
+        if !self.growable {
+            return Ok(false);
+        }
+        let file_len = self.source.get_ref().metadata()?.len();
+        // Only complete records are picked up
+        let available = file_len.saturating_sub(self.data_start) / (Self::RECORD_SIZE as u64);
+        if available > self.num_records {
+            self.num_records = available;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Start the sync counter overflow correction at `state` instead of zero. Pass
+    /// the state returned by `into_overflow_state` from the stream that read the
+    /// preceding records to keep the time of flight continuous.
+    pub fn with_overflow_state(mut self, state: u64) -> Self {
+        self.nsync = state;
+        self
+    }
+
+    /// Consume the stream and return its overflow correction, i.e. the number of
+    /// syncs accumulated by the overflows read so far.
+    pub fn into_overflow_state(self) -> u64 {
+        self.nsync
+    }
 }
 
 impl TTTRStream for HHT3_HH2Stream {
     type RecordSize = u32;
     #[inline(always)]
     fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
-        const T3WRAPAROUND: u64 = 1024;
+        const T3WRAPAROUND: u64 = RecordType::HHT3_HH2.wraparound();
 
         //  TimeTag: Raw TimeTag from Record * Globalresolution = Real Time arrival of Photon
         //  DTime: Arrival time of Photon after last Sync event (T3 only) DTime * Resolution = Real time arrival of Photon after last Sync event
@@ -187,6 +374,8 @@ impl TTTRStream for HHT3_HH2Stream {
 
         let tof;
         let channel;
+        let mut markers = 0;
+        let mut overflow = false;
 
         if sp == 1 {
             if ch == 0x3F {
@@ -198,16 +387,24 @@ impl TTTRStream for HHT3_HH2Stream {
                 }
                 tof = self.nsync * self.sync_period;
                 channel = 0;
+                overflow = true;
+                self.overflow_count += 1;
             } else if (ch >= 1) && (ch <= 15) {
                 // markers
-                tof = self.nsync * self.sync_period; // wrong look at picoquant for correct value
-                channel = -1;
+                markers = ch as u8;
+                // Like photons, markers are placed at their sync plus their delay
+                // after it. The PicoQuant reference only uses the sync but the
+                // hardware leaves dtime at zero when it has no meaning for markers.
+                let truensync = self.nsync + nsync;
+                tof = truensync * self.sync_period + dtime * self.dtime_res;
+                channel = marker_channel(self.marker_channel_offset, markers, -1);
+                self.marker_count += 1;
             } else {
                 tof = 0;
                 channel = -1;
             }
-            // At the current time we ignore markers. This is signalled by returnig a
-            //negative channel number.
+            // Unless a marker channel offset was requested markers are ignored. This
+            // is signalled by returnig a negative channel number.
         } else {
             let truensync = self.nsync + nsync;
             //the nsync time unit depends on sync period which can be obtained from the file header
@@ -217,7 +414,13 @@ impl TTTRStream for HHT3_HH2Stream {
             channel = ch + 1;
         }
         //println!("channel: {:?}, ch: {:?}, sp: {:?}", channel, ch, sp);
-        TTTRRecord { channel, tof }
+        let rec = TTTRRecord {
+            channel,
+            tof,
+            markers,
+            overflow,
+        };
+        overflow_click(rec, self.overflow_channel, self.nsync * self.sync_period)
     }
 
     fn time_resolution(&self) -> f64 {
@@ -235,11 +438,8 @@ impl Iterator for HHT3_HH2Stream {
         }
         if self.photons_in_buffer == 0 {
             let records_remaining = self.num_records - self.click_count;
-            let clicks_requested = if records_remaining < BUFFER_SIZE {
-                records_remaining
-            } else {
-                BUFFER_SIZE
-            };
+            // Only the clamped request is narrowed down to index the buffer
+            let clicks_requested = records_remaining.min(self.click_buffer.len() as u64) as usize;
             let read_res = self
                 .source
                 .read_u32_into::<NativeEndian>(&mut self.click_buffer[..clicks_requested]);
@@ -257,3 +457,475 @@ impl Iterator for HHT3_HH2Stream {
         Some(self.parse_record(self.click_buffer[current_photon]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::File;
+    use crate::test_utils::{
+        encode_hht3, encode_pht2, random_clicks, write_ptu, Event, T3Event, Tag,
+    };
+    use crate::test_utils::{HHT2_HH2, HHT3_HH2, PHT2};
+    use crate::tttr_tools::lifetime::{lifetime, LifetimeParams};
+    use crate::{Click, RecordKind};
+
+    fn fields(records: impl Iterator<Item = TTTRRecord>) -> Vec<(i32, u64, u8)> {
+        records
+            .map(|rec| (*rec.channel(), *rec.tof(), *rec.markers()))
+            .collect()
+    }
+
+    #[test]
+    fn buffer_sizes_give_identical_streams() {
+        // Long gaps so the time tag overflows many times
+        let events = random_clicks(5_000, 1_000_000, 11);
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let reference = fields(PHT2Stream::new(&f, None, None).unwrap());
+        assert!(reference.len() > events.len());
+        for &size in [1, 7, 4096, 1 << 16].iter() {
+            let stream = PHT2Stream::new(&f, None, None)
+                .unwrap()
+                .with_buffer_size(size);
+            assert_eq!(fields(stream), reference);
+        }
+
+        let events: Vec<T3Event> = (0..5_000u64)
+            .map(|k| T3Event::Photon {
+                channel: 1 + (k % 2) as i32,
+                nsync: k * 317,
+                dtime: k % 1000,
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let reference = fields(HHT3_HH2Stream::new(&f, None, None).unwrap());
+        for &size in [1, 7, 4096, 1 << 16].iter() {
+            let stream = HHT3_HH2Stream::new(&f, None, None)
+                .unwrap()
+                .with_buffer_size(size);
+            assert_eq!(fields(stream), reference);
+        }
+    }
+
+    #[test]
+    fn hht3_markers_match_the_picoquant_reference() {
+        // Markers interleaved with photons and overflows. Hardware markers have no
+        // dtime but every other one is given one to check it is added like for photons.
+        let events: Vec<T3Event> = (0..2_000u64)
+            .map(|k| match k % 5 {
+                0 => T3Event::Marker {
+                    bits: 1 + (k % 4) as u8,
+                    nsync: k * 613,
+                    dtime: if k % 10 == 0 { 0 } else { 250 + k % 100 },
+                },
+                _ => T3Event::Photon {
+                    channel: 1,
+                    nsync: k * 613,
+                    dtime: k % 700,
+                },
+            })
+            .collect();
+        let words = encode_hht3(&events);
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+
+        // Decoding of the PicoQuant demo code, where markers only use the sync, plus
+        // the dtime in 1 ps bins
+        let sync_period = 12_500;
+        let mut oflcorrection = 0;
+        let mut reference = vec![];
+        for &word in words.iter() {
+            let special = word >> 31;
+            let channel = (word >> 25) & 0x3F;
+            let dtime = ((word >> 10) & 0x7FFF) as u64;
+            let nsync = (word & 0x3FF) as u64;
+            if special == 1 && channel == 0x3F {
+                oflcorrection += 1024 * if nsync == 0 { 1 } else { nsync };
+            } else if special == 1 && (1..=15).contains(&channel) {
+                let tof = (oflcorrection + nsync) * sync_period + dtime;
+                reference.push((100 + channel as i32, tof));
+            }
+        }
+        assert_eq!(reference.len(), 400);
+        assert!(oflcorrection > 0);
+
+        let markers: Vec<(i32, u64)> = HHT3_HH2Stream::new(&f, None, None)
+            .unwrap()
+            .with_marker_channels(Some(100))
+            .filter(|rec| *rec.markers() > 0)
+            .map(|rec| (*rec.channel(), *rec.tof()))
+            .collect();
+        assert_eq!(markers, reference);
+        // The second marker, k = 5, on bits 2 with a dtime of 255 ps
+        assert_eq!(markers[1], (102, 5 * 613 * 12_500 + 255));
+    }
+
+    #[test]
+    fn hht2_hh2_inputs_get_distinct_channels() {
+        // One photon on each of the 64 inputs followed by a sync
+        let mut words: Vec<u32> = (0..=63u32).map(|ch| (ch << 25) | (1000 + ch)).collect();
+        words.push((1 << 31) | 2000);
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &[], &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let mut stream = HHT2_HH2Stream::new(&f, None, None).unwrap();
+        let records = fields(&mut stream);
+
+        let mut expected: Vec<(i32, u64, u8)> =
+            (0..=63).map(|ch| (ch + 1, 1000 + ch as u64, 0)).collect();
+        expected.push((0, 2000, 0));
+        assert_eq!(records, expected);
+        assert!(records.iter().all(|&(channel, _, _)| channel >= 0));
+        let mut channels: Vec<i32> = records.iter().map(|&(channel, _, _)| channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        assert_eq!(channels.len(), 65);
+        assert_eq!(stream.overflow_count, 0);
+    }
+
+    /// Synthetic 64 bit records with the channel on the top byte and the time tag,
+    /// which never overflows, on the rest.
+    #[make_ptu_stream(Synthetic64, u64)]
+    fn parse_record(&mut self, record: Self::RecordSize) -> TTTRRecord {
+        TTTRRecord {
+            channel: (record >> 56) as i32,
+            tof: record & ((1 << 56) - 1),
+            markers: 0,
+            overflow: false,
+        }
+    }
+
+    #[test]
+    fn wide_records_are_decoded_whole() {
+        assert_eq!(Synthetic64Stream::RECORD_SIZE, 8);
+        assert_eq!(RecordType::PHT2.record_size(), 4);
+        assert_eq!(RecordType::HHT3_HH2.record_size(), 4);
+
+        // Time tags beyond 32 bits so that reading half records would garble them
+        let records: Vec<u64> = (0..100u64)
+            .map(|k| ((k % 3) << 56) | (k << 40) | k)
+            .collect();
+        let words: Vec<u32> = records
+            .iter()
+            .flat_map(|r| {
+                let bytes = r.to_ne_bytes();
+                vec![
+                    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                    u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+                ]
+            })
+            .collect();
+        // The header counts words, the stream is clamped to the records on disk
+        let tmp = write_ptu(PHT2, 1e-12, &[], &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+
+        let expected = |range: std::ops::Range<usize>| -> Vec<(i32, u64, u8)> {
+            records[range]
+                .iter()
+                .map(|r| ((r >> 56) as i32, r & ((1 << 56) - 1), 0))
+                .collect()
+        };
+        let stream = Synthetic64Stream::new(&f, None, None).unwrap();
+        assert_eq!(fields(stream), expected(0..100));
+        let stream = Synthetic64Stream::new(&f, Some(40), Some(60)).unwrap();
+        assert_eq!(fields(stream), expected(40..60));
+    }
+
+    #[test]
+    fn four_byte_records_seek_to_the_start_record() {
+        // Short gaps so no overflow record shifts the time tags of the later ones
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(1_000, 100, 3)),
+        );
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let reference = fields(PHT2Stream::new(&f, None, None).unwrap());
+        assert_eq!(reference.len(), 1_000);
+        let stream = PHT2Stream::new(&f, Some(123), Some(456)).unwrap();
+        assert_eq!(fields(stream), reference[123..456]);
+
+        let events: Vec<T3Event> = (0..500u64)
+            .map(|k| T3Event::Photon {
+                channel: 1 + (k % 2) as i32,
+                nsync: 2 * k,
+                dtime: k,
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let reference = fields(HHT3_HH2Stream::new(&f, None, None).unwrap());
+        assert_eq!(reference.len(), 500);
+        let stream = HHT3_HH2Stream::new(&f, Some(123), Some(456)).unwrap();
+        assert_eq!(fields(stream), reference[123..456]);
+    }
+
+    #[test]
+    fn binning_factor_scales_the_dtime() {
+        let events: Vec<T3Event> = (0..200u64)
+            .map(|k| T3Event::Photon {
+                channel: 1,
+                nsync: 3 * k,
+                dtime: k,
+            })
+            .collect();
+        let words = encode_hht3(&events);
+        let microtimes = |tags: &[(&str, Tag)]| -> Vec<u64> {
+            let tmp = write_ptu(HHT3_HH2, 12.5e-9, tags, &words);
+            let f = ptu::PTUFile::new(tmp.path()).unwrap();
+            HHT3_HH2Stream::new(&f, None, None)
+                .unwrap()
+                .map(|rec| *rec.tof() % 12_500)
+                .collect()
+        };
+
+        let resolution = || ("MeasDesc_Resolution", Tag::Float8(1e-12));
+        let unbinned = microtimes(&[resolution()]);
+        assert_eq!(unbinned, (0..200).collect::<Vec<u64>>());
+        let binned = microtimes(&[resolution(), ("MeasDesc_BinningFactor", Tag::Int8(4))]);
+        assert_eq!(binned, (0..200).map(|k| 4 * k).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn overflows_markers_and_photons_add_up_to_the_records() {
+        // Long gaps so the time tag overflows many times
+        let events: Vec<Event> = random_clicks(3_000, 1_000_000, 5)
+            .into_iter()
+            .enumerate()
+            .map(|(k, event)| match k % 10 {
+                0 => Event::Marker(2, event.tof()),
+                _ => event,
+            })
+            .collect();
+        let words = encode_pht2(&events);
+        let tmp = write_ptu(PHT2, 4e-12, &[], &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let mut stream = PHT2Stream::new(&f, None, None).unwrap();
+        let photons = (&mut stream)
+            .filter(|rec| matches!(rec.kind(), RecordKind::Photon { .. }))
+            .count() as u64;
+        assert_eq!(photons, 2_700);
+        assert_eq!(stream.marker_count, 300);
+        assert!(stream.overflow_count > 0);
+        assert_eq!(
+            stream.overflow_count + stream.marker_count + photons,
+            words.len() as u64
+        );
+
+        let events: Vec<T3Event> = (0..3_000u64)
+            .map(|k| match k % 10 {
+                0 => T3Event::Marker {
+                    bits: 2,
+                    nsync: k * 613,
+                    dtime: 0,
+                },
+                _ => T3Event::Photon {
+                    channel: 1,
+                    nsync: k * 613,
+                    dtime: k % 700,
+                },
+            })
+            .collect();
+        let words = encode_hht3(&events);
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        let mut stream = HHT3_HH2Stream::new(&f, None, None).unwrap();
+        let photons = (&mut stream)
+            .filter(|rec| matches!(rec.kind(), RecordKind::Photon { .. }))
+            .count() as u64;
+        assert_eq!(photons, 2_700);
+        assert_eq!(stream.marker_count, 300);
+        assert!(stream.overflow_count > 0);
+        assert_eq!(
+            stream.overflow_count + stream.marker_count + photons,
+            words.len() as u64
+        );
+    }
+
+    #[test]
+    fn record_kinds_of_every_format() {
+        let kinds = |records: &mut dyn Iterator<Item = TTTRRecord>| -> Vec<RecordKind> {
+            records.map(|rec| rec.kind()).collect()
+        };
+        let expected = vec![
+            RecordKind::Photon { channel: 2 },
+            RecordKind::Marker { bits: 3 },
+            RecordKind::Overflow,
+            RecordKind::Photon { channel: 1 },
+        ];
+
+        let wraparound = RecordType::PHT2.wraparound();
+        let events = [
+            Event::Photon(2, 100),
+            Event::Marker(3, 200),
+            Event::Photon(1, wraparound + 50),
+        ];
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        assert_eq!(
+            kinds(&mut PHT2Stream::new(&f, None, None).unwrap()),
+            expected
+        );
+
+        // Photon on input 1, marker, overflow and photon on input 0
+        let words = [
+            (1 << 25) | 100,
+            (1 << 31) | (3 << 25) | 200,
+            (1 << 31) | (0x3F << 25) | 1,
+            50,
+        ];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &[], &words);
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        assert_eq!(
+            kinds(&mut HHT2_HH2Stream::new(&f, None, None).unwrap()),
+            expected
+        );
+
+        let wraparound = RecordType::HHT3_HH2.wraparound();
+        let events = [
+            T3Event::Photon {
+                channel: 2,
+                nsync: 1,
+                dtime: 10,
+            },
+            T3Event::Marker {
+                bits: 3,
+                nsync: 2,
+                dtime: 0,
+            },
+            T3Event::Photon {
+                channel: 1,
+                nsync: wraparound + 5,
+                dtime: 10,
+            },
+        ];
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        assert_eq!(
+            kinds(&mut HHT3_HH2Stream::new(&f, None, None).unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn zero_sync_period_is_an_invalid_header() {
+        let events = [T3Event::Photon {
+            channel: 1,
+            nsync: 1,
+            dtime: 10,
+        }];
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 0.0, &tags, &encode_hht3(&events));
+        let f = ptu::PTUFile::new(tmp.path()).unwrap();
+        assert!(matches!(
+            HHT3_HH2Stream::new(&f, None, None),
+            Err(Error::InvalidHeader(_))
+        ));
+
+        let params = LifetimeParams {
+            channel_sync: vec![0],
+            channel_source: 1,
+            resolution: 100e-12,
+            start_record: None,
+            stop_record: None,
+            sync_offset: None,
+            log_bins: None,
+            sync_jitter: None,
+        };
+        let f = File::PTU(f);
+        assert!(matches!(
+            lifetime(&f, &params),
+            Err(Error::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn overflows_on_a_low_rate_file() {
+        // A photon every three and a half wraparounds of the time tag
+        let wraparound = RecordType::PHT2.wraparound();
+        let events: Vec<Event> = (1..=10u64)
+            .map(|k| Event::Photon((k % 2) as i32, k * 7 * wraparound / 2 + 1_000))
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let n_overflows = events.last().unwrap().tof() / wraparound;
+        assert_eq!(n_overflows, 35);
+
+        const OVERFLOW_CHANNEL: i32 = 99;
+        let records: Vec<TTTRRecord> = f
+            .records()
+            .unwrap()
+            .with_overflow_channel(Some(OVERFLOW_CHANNEL))
+            .collect();
+        let overflows: Vec<u64> = records
+            .iter()
+            .filter(|rec| rec.channel == OVERFLOW_CHANNEL)
+            .map(|rec| rec.tof)
+            .collect();
+        let expected: Vec<u64> = (1..=n_overflows).map(|k| k * wraparound).collect();
+        assert_eq!(overflows, expected);
+
+        // Photons are left untouched
+        let photons: Vec<(i32, u64)> = records
+            .iter()
+            .filter_map(|rec| match rec.kind() {
+                RecordKind::Photon { channel } if channel != OVERFLOW_CHANNEL => {
+                    Some((channel, rec.tof))
+                }
+                _ => None,
+            })
+            .collect();
+        let expected: Vec<(i32, u64)> = events
+            .iter()
+            .map(|event| match *event {
+                Event::Photon(channel, tof) => (channel, tof),
+                Event::Marker(..) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(photons, expected);
+    }
+
+    #[test]
+    fn pht2_marker_bits_are_recovered() {
+        // Every combination of the 4 marker bits, with time tags whose lower bits are
+        // set and a photon in between so each marker stands apart
+        let wraparound = RecordType::PHT2.wraparound();
+        let mut events = vec![];
+        for bits in 1..=15u8 {
+            let tof = bits as u64 * wraparound / 2 + 0x0123_4567;
+            events.push(Event::Marker(bits, tof));
+            events.push(Event::Photon(0, tof + 100));
+        }
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let markers: Vec<(RecordKind, u64)> = f
+            .records()
+            .unwrap()
+            .filter(|rec| *rec.markers() != 0)
+            .map(|rec| (rec.kind(), *rec.tof()))
+            .collect();
+        let expected: Vec<(RecordKind, u64)> = (1..=15u8)
+            .map(|bits| {
+                let tof = bits as u64 * wraparound / 2 + 0x0123_4567;
+                (RecordKind::Marker { bits }, tof & !0xF)
+            })
+            .collect();
+        assert_eq!(markers, expected);
+
+        let channels: Vec<i32> = f
+            .records()
+            .unwrap()
+            .with_marker_channels(Some(100))
+            .filter(|rec| *rec.markers() != 0)
+            .map(|rec| *rec.channel())
+            .collect();
+        assert_eq!(channels, (101..=115).collect::<Vec<i32>>());
+    }
+}
@@ -0,0 +1,234 @@
+//! Synthetic PTU files for the unit tests.
+//!
+//! Events are given in native units of the file, i.e. ticks of
+//! `MeasDesc_GlobalResolution` in T2 and `(sync, dtime)` pairs in T3, and the
+//! encoders insert the overflow records needed to reach them.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::headers::RecordType;
+
+/// Record type codes as stored on `TTResultFormat_TTTRRecType`
+pub const PHT2: i64 = 0x00010203;
+pub const HHT2_HH2: i64 = 0x00010204;
+pub const HHT3_HH2: i64 = 0x01010304;
+
+static FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Value of an extra header tag
+pub enum Tag {
+    Int8(i64),
+    Float8(f64),
+}
+
+/// Click or marker on a synthetic T2 file
+#[derive(Debug, Copy, Clone)]
+pub enum Event {
+    Photon(i32, u64),
+    Marker(u8, u64),
+}
+
+impl Event {
+    pub fn tof(&self) -> u64 {
+        match *self {
+            Event::Photon(_, tof) | Event::Marker(_, tof) => tof,
+        }
+    }
+}
+
+/// Click or marker on a synthetic T3 file, at `dtime` ticks after sync `nsync`
+#[derive(Debug, Copy, Clone)]
+pub enum T3Event {
+    Photon {
+        channel: i32,
+        nsync: u64,
+        dtime: u64,
+    },
+    Marker {
+        bits: u8,
+        nsync: u64,
+        dtime: u64,
+    },
+}
+
+/// PTU file on the temporary directory, deleted when dropped.
+pub struct TestFile(PathBuf);
+
+impl TestFile {
+    pub fn path(&self) -> PathBuf {
+        self.0.clone()
+    }
+}
+
+impl Drop for TestFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Bytes of a tag entry. Tags without an index have `index` -1.
+fn tag_bytes(name: &str, index: i32, tag_type: u32, value: [u8; 8]) -> Vec<u8> {
+    let mut bytes = vec![0; 32];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    bytes.extend_from_slice(&index.to_le_bytes());
+    bytes.extend_from_slice(&tag_type.to_le_bytes());
+    bytes.extend_from_slice(&value);
+    bytes
+}
+
+/// Write a PTU file holding `records` with the mandatory tags plus `tags`.
+pub fn write_ptu(
+    record_type: i64,
+    resolution: f64,
+    tags: &[(&str, Tag)],
+    records: &[u32],
+) -> TestFile {
+    let mut bytes = b"PQTTTR\0\0".to_vec();
+    bytes.extend_from_slice(b"1.0.00\0\0");
+    bytes.extend(tag_bytes(
+        "TTResultFormat_TTTRRecType",
+        -1,
+        0x10000008,
+        record_type.to_le_bytes(),
+    ));
+    bytes.extend(tag_bytes(
+        "TTResult_NumberOfRecords",
+        -1,
+        0x10000008,
+        (records.len() as i64).to_le_bytes(),
+    ));
+    bytes.extend(tag_bytes(
+        "MeasDesc_GlobalResolution",
+        -1,
+        0x20000008,
+        resolution.to_le_bytes(),
+    ));
+    for (name, tag) in tags {
+        let (name, index) = match name.strip_suffix(']').and_then(|x| x.split_once('[')) {
+            Some((name, index)) => (name, index.parse().expect("Invalid tag index")),
+            None => (*name, -1),
+        };
+        bytes.extend(match tag {
+            Tag::Int8(x) => tag_bytes(name, index, 0x10000008, x.to_le_bytes()),
+            Tag::Float8(x) => tag_bytes(name, index, 0x20000008, x.to_le_bytes()),
+        });
+    }
+    bytes.extend(tag_bytes("Header_End", -1, 0xFFFF0008, [0; 8]));
+    for record in records {
+        bytes.extend_from_slice(&record.to_ne_bytes());
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "tttr-toolbox-test-{}-{}.ptu",
+        std::process::id(),
+        FILE_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::write(&path, bytes).expect("Can't write the test file");
+    TestFile(path)
+}
+
+/// PHT2 records of time ordered `events` on channels 0 to 3.
+pub fn encode_pht2(events: &[Event]) -> Vec<u32> {
+    let wraparound = RecordType::PHT2.wraparound();
+    let mut records = vec![];
+    let mut base = 0;
+    for event in events {
+        while event.tof() >= base + wraparound {
+            records.push(0xF << 28);
+            base += wraparound;
+        }
+        let tm = (event.tof() - base) as u32;
+        records.push(match *event {
+            Event::Photon(channel, _) => ((channel as u32) << 28) | tm,
+            Event::Marker(bits, _) => (0xF << 28) | (tm & !0xF) | bits as u32,
+        });
+    }
+    records
+}
+
+/// HydraHarp V2 T2 records of time ordered `events`. Channel 0 is the sync and
+/// photons on input `n` are reported on channel `n + 1`.
+pub fn encode_hht2_hh2(events: &[Event]) -> Vec<u32> {
+    let wraparound = RecordType::HHT2_HH2.wraparound();
+    let mut records = vec![];
+    let mut base = 0;
+    for event in events {
+        let wraps = (event.tof() - base) / wraparound;
+        if wraps > 0 {
+            records.push((1 << 31) | (0x3F << 25) | wraps as u32);
+            base += wraps * wraparound;
+        }
+        let tm = (event.tof() - base) as u32;
+        records.push(match *event {
+            Event::Photon(0, _) => (1 << 31) | tm,
+            Event::Photon(channel, _) => (((channel - 1) as u32) << 25) | tm,
+            Event::Marker(bits, _) => (1 << 31) | ((bits as u32) << 25) | tm,
+        });
+    }
+    records
+}
+
+/// HydraHarp V2 T3 records of `events` ordered by sync. Photons on input `n` are
+/// reported on channel `n + 1`.
+pub fn encode_hht3(events: &[T3Event]) -> Vec<u32> {
+    let wraparound = RecordType::HHT3_HH2.wraparound();
+    let mut records = vec![];
+    let mut base = 0;
+    for event in events {
+        let (nsync, dtime) = match *event {
+            T3Event::Photon { nsync, dtime, .. } | T3Event::Marker { nsync, dtime, .. } => {
+                (nsync, dtime)
+            }
+        };
+        let wraps = (nsync - base) / wraparound;
+        if wraps > 0 {
+            records.push((1 << 31) | (0x3F << 25) | wraps as u32);
+            base += wraps * wraparound;
+        }
+        let fields = ((dtime as u32) << 10) | (nsync - base) as u32;
+        records.push(match *event {
+            T3Event::Photon { channel, .. } => (((channel - 1) as u32) << 25) | fields,
+            T3Event::Marker { bits, .. } => (1 << 31) | ((bits as u32) << 25) | fields,
+        });
+    }
+    records
+}
+
+/// Photons on channels 0 and 1 with exponentially distributed gaps of about
+/// `mean_gap` ticks, from a fixed seed so tests are reproducible.
+pub fn random_clicks(n: usize, mean_gap: u64, seed: u64) -> Vec<Event> {
+    let mut state = seed.max(1);
+    let mut next = move || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut tof = 0;
+    (0..n)
+        .map(|_| {
+            let uniform = ((next() >> 11) as f64 + 0.5) / ((1u64 << 53) as f64);
+            tof += 1 + (-uniform.ln() * mean_gap as f64) as u64;
+            Event::Photon((next() % 2) as i32, tof)
+        })
+        .collect()
+}
+
+/// The clicks of `random_clicks` on channels 1 and 2 of a T3 file, i.e. shifted by
+/// one, with their time of flight split into sync periods of `sync_period` ticks.
+pub fn random_t3_clicks(n: usize, mean_gap: u64, seed: u64, sync_period: u64) -> Vec<T3Event> {
+    random_clicks(n, mean_gap, seed)
+        .into_iter()
+        .map(|event| match event {
+            Event::Photon(channel, tof) => T3Event::Photon {
+                channel: channel + 1,
+                nsync: tof / sync_period,
+                dtime: tof % sync_period,
+            },
+            Event::Marker(..) => unreachable!(),
+        })
+        .collect()
+}
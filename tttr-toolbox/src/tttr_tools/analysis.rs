@@ -0,0 +1,225 @@
+use crate::{
+    errors::Error,
+    headers::File,
+    tttr_tools::{
+        g2::{apply_channel_delays, g2, g2_core::G2, G2Mode, G2Params, G2Result},
+        lifetime::{lifetime, LifetimeHistogram, LifetimeParams, LifetimeResult},
+        timetrace::{timetrace, TimeTraceHistogram, TimeTraceParams, TimeTraceResult},
+    },
+    Click,
+};
+
+/// Results of an [Analysis](struct.Analysis.html). Only the algorithms that were
+/// requested on the builder are `Some`.
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisResult {
+    pub g2: Option<G2Result>,
+    pub timetrace: Option<TimeTraceResult>,
+    pub lifetime: Option<LifetimeResult>,
+}
+
+/// Builder to run several algorithms over the same file.
+///
+/// ```ignore
+/// let results = Analysis::new(&f).g2(g2_params).timetrace(tt_params).run()?;
+/// ```
+///
+/// All the algorithms that can share the records are computed on a single pass over
+/// the file. An algorithm falls back to its own pass when:
+///   - The g2 has `record_ranges` or the timetrace or lifetime have a start/stop
+///     record.
+///   - Its marker channel mapping differs from the one of the shared pass.
+///   - The lifetime is requested on a T2 file, so that its usual error is returned.
+///
+/// The g2 is always computed in symmetric mode.
+pub struct Analysis<'a> {
+    file: &'a File,
+    g2_params: Option<G2Params>,
+    timetrace_params: Option<TimeTraceParams>,
+    lifetime_params: Option<LifetimeParams>,
+}
+
+impl<'a> Analysis<'a> {
+    pub fn new(file: &'a File) -> Self {
+        Self {
+            file,
+            g2_params: None,
+            timetrace_params: None,
+            lifetime_params: None,
+        }
+    }
+
+    pub fn g2(mut self, params: G2Params) -> Self {
+        self.g2_params = Some(params);
+        self
+    }
+
+    pub fn timetrace(mut self, params: TimeTraceParams) -> Self {
+        self.timetrace_params = Some(params);
+        self
+    }
+
+    pub fn lifetime(mut self, params: LifetimeParams) -> Self {
+        self.lifetime_params = Some(params);
+        self
+    }
+
+    pub fn run(self) -> Result<AnalysisResult, Error> {
+        if let Some(params) = &self.g2_params {
+            params.validate()?;
+        }
+        if let Some(params) = &self.lifetime_params {
+            params.validate()?;
+        }
+
+        let records = self.file.records()?;
+        let is_t3 = records.sync_period().is_some();
+
+        // The first algorithm that can be multiplexed decides the marker mapping
+        let mut marker_channels: Option<Option<i32>> = None;
+        let mut joins_pass = |shareable: bool, markers: Option<i32>| -> bool {
+            if !shareable {
+                return false;
+            }
+            match marker_channels {
+                Some(shared) => shared == markers,
+                None => {
+                    marker_channels = Some(markers);
+                    true
+                }
+            }
+        };
+        let shared_g2 = self
+            .g2_params
+            .as_ref()
+            .filter(|p| joins_pass(p.record_ranges.is_none(), p.marker_channels));
+        let shared_timetrace = self.timetrace_params.as_ref().filter(|p| {
+            joins_pass(
+                p.overflow_channel.is_none() && p.start_record.is_none() && p.stop_record.is_none(),
+                p.marker_channels,
+            )
+        });
+        let shared_lifetime = self.lifetime_params.as_ref().filter(|p| {
+            joins_pass(
+                is_t3 && p.start_record.is_none() && p.stop_record.is_none(),
+                None,
+            )
+        });
+
+        let mut result = AnalysisResult::default();
+        if shared_g2.is_some() || shared_timetrace.is_some() || shared_lifetime.is_some() {
+            let stream = records.with_marker_channels(marker_channels.unwrap_or(None));
+            let time_resolution = stream.time_resolution();
+
+            let g2_core = shared_g2
+                .map(|p| G2::init(p, time_resolution))
+                .transpose()?;
+            let mut g2_state = g2_core.as_ref().map(|g2| g2.new_state());
+            let mut g2_histogram = vec![0; g2_core.as_ref().map_or(0, |g2| g2.n_bins())];
+            let acquisition_time = self.file.acquisition_time();
+            let mut timetrace_histogram = shared_timetrace
+                .map(|p| TimeTraceHistogram::new(p, time_resolution, acquisition_time))
+                .transpose()?;
+            let mut lifetime_histogram = shared_lifetime
+                .map(|p| LifetimeHistogram::new(p, stream.sync_period().unwrap_or(0)))
+                .transpose()?;
+
+            for (idx, rec) in stream.enumerate() {
+                let (tof, channel) = (*rec.tof(), *rec.channel());
+                if let (Some(g2), Some(state)) = (&g2_core, &mut g2_state) {
+                    g2.push(state, tof, channel, &mut g2_histogram);
+                }
+                if let Some(histogram) = &mut timetrace_histogram {
+                    histogram.push(idx, tof, channel, *rec.markers());
+                }
+                if let Some(histogram) = &mut lifetime_histogram {
+                    histogram.push(tof, channel);
+                }
+            }
+
+            if let (Some(g2), Some(mut state), Some(params)) = (g2_core, g2_state, shared_g2) {
+                let mut t_histogram = vec![0.0; g2.n_bins()];
+                g2.fill_t(&mut t_histogram);
+                let mut g2_result = G2Result {
+                    hist: g2_histogram,
+                    t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| state.take_raw_deltas()),
+                    centered: params.centered,
+                };
+                apply_channel_delays(self.file, params, &mut g2_result)?;
+                result.g2 = Some(g2_result);
+            }
+            result.timetrace = timetrace_histogram.map(|h| h.finish());
+            result.lifetime = lifetime_histogram.map(|h| h.finish());
+        }
+
+        if let (Some(params), None) = (&self.g2_params, shared_g2) {
+            result.g2 = Some(g2(self.file, params, G2Mode::Symmetric)?);
+        }
+        if let (Some(params), None) = (&self.timetrace_params, shared_timetrace) {
+            result.timetrace = Some(timetrace(self.file, params)?);
+        }
+        if let (Some(params), None) = (&self.lifetime_params, shared_lifetime) {
+            result.lifetime = Some(lifetime(self.file, params)?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_hht3, random_t3_clicks, write_ptu, T3Event, Tag, HHT3_HH2};
+    use crate::tttr_tools::{
+        lifetime::tests as lifetime_tests, timetrace::tests as timetrace_tests,
+    };
+
+    #[test]
+    fn builder_matches_individual_calls() {
+        let events: Vec<T3Event> = random_t3_clicks(20_000, 20_000, 9, 12_500);
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let g2_params = G2Params::new(1, 2, 100e-9, 1e-9);
+        let timetrace_params = TimeTraceParams {
+            resolution: 10e-6,
+            channel: Some(2),
+            mandel_q: true,
+            ..timetrace_tests::params(false)
+        };
+        let lifetime_params = lifetime_tests::params(vec![0], 1);
+        let separate_g2 = g2(&f, &g2_params, G2Mode::Symmetric).unwrap();
+        let separate_lifetime = lifetime(&f, &lifetime_params).unwrap();
+        assert!(separate_g2.hist.iter().sum::<u64>() > 0);
+        assert!(separate_lifetime.total_counts > 0);
+
+        // Everything on the shared pass, then the timetrace on its own pass
+        let mut own_pass = timetrace_params;
+        own_pass.start_record = Some(0);
+        for &timetrace_params in &[timetrace_params, own_pass] {
+            let separate_timetrace = timetrace(&f, &timetrace_params).unwrap();
+            let result = Analysis::new(&f)
+                .g2(g2_params.clone())
+                .timetrace(timetrace_params)
+                .lifetime(lifetime_params.clone())
+                .run()
+                .unwrap();
+
+            let combined_g2 = result.g2.unwrap();
+            assert_eq!(combined_g2.hist, separate_g2.hist);
+            assert_eq!(combined_g2.t, separate_g2.t);
+            let combined_timetrace = result.timetrace.unwrap();
+            assert_eq!(combined_timetrace.intensity, separate_timetrace.intensity);
+            assert_eq!(
+                combined_timetrace.recnum_trace,
+                separate_timetrace.recnum_trace
+            );
+            assert_eq!(combined_timetrace.mandel_q, separate_timetrace.mandel_q);
+            let combined_lifetime = result.lifetime.unwrap();
+            assert_eq!(combined_lifetime.hist, separate_lifetime.hist);
+            assert_eq!(combined_lifetime.t, separate_lifetime.t);
+        }
+        assert!(Analysis::new(&f).run().unwrap().g2.is_none());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    errors::Error, headers::File, to_native_units, tttr_tools::circular_buffer::CircularBuffer,
+    Click,
+};
+
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// Result from the CAR algorithm
+///
+/// `car` is `central / delayed`. It is infinite if no accidentals were found on the
+/// delayed window, which usually means the measurement is too short.
+#[derive(Debug, Clone)]
+pub struct CarResult {
+    /// Coincidences within the window centered at zero delay
+    pub central: u64,
+    /// Accidental coincidences within the same window centered at `accidentals_delay`
+    pub delayed: u64,
+    pub car: f64,
+}
+
+/// Parameters for the CAR algorithm
+///
+/// # Parameters
+///    - channel_1: The number of the first input channel into the TCSPC
+///    - channel_2: The number of the second input channel into the TCSPC
+///    - coincidence_window: Full width in seconds of the window around zero delay
+///      in which clicks on both channels count as a coincidence
+///    - accidentals_delay: Delay in seconds of `channel_2` with respect to
+///      `channel_1` at which the accidentals are counted. It must be many
+///      coincidence windows away from zero so that no true coincidences fall in it.
+#[derive(Debug, Clone)]
+pub struct CarParams {
+    pub channel_1: i32,
+    pub channel_2: i32,
+    pub coincidence_window: f64,
+    pub accidentals_delay: f64,
+    pub start_record: Option<usize>,
+    pub stop_record: Option<usize>,
+}
+
+impl CarParams {
+    /// Check that the channels differ and that the delayed window does not overlap
+    /// with the central one.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.channel_1 == self.channel_2 {
+            return Err(Error::InvalidParams(format!(
+                "The CAR needs two different channels but both are {}",
+                self.channel_1
+            )));
+        }
+        if self.coincidence_window.is_nan()
+            || self.accidentals_delay.is_nan()
+            || self.coincidence_window <= 0.0
+            || self.accidentals_delay < self.coincidence_window
+        {
+            return Err(Error::InvalidParams(format!(
+                "The coincidence window must be positive and no larger than the accidentals delay but got {} and {}",
+                self.coincidence_window, self.accidentals_delay
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Computes the coincidence to accidental ratio (CAR) between two channels.
+///
+/// The CAR is the key figure of merit of photon pair sources. Coincidences are
+/// counted on a window of `coincidence_window` around zero delay and the accidentals
+/// on a window of the same width after delaying `channel_2` by `accidentals_delay`,
+/// where the clicks of both channels are uncorrelated.
+///
+/// ## Algorithm description
+/// Like the g2 the past clicks on each channel are kept in circular buffers of
+/// 4096 clicks and each new click is compared against the buffer of the other
+/// channel. The accidentals are only looked for at positive delays so the buffer of
+/// `channel_1` must span at least `accidentals_delay`. See the g2 documentation for
+/// the finite buffer artifacts.
+pub fn car(f: &File, params: &CarParams) -> Result<CarResult, Error> {
+    params.validate()?;
+    let stream = f.records_range(params.start_record, params.stop_record)?;
+    let time_resolution = stream.time_resolution();
+    let half_window = to_native_units(params.coincidence_window / 2.0, time_resolution)?;
+    let delay = to_native_units(params.accidentals_delay, time_resolution)?;
+
+    let mut buff_1 = CircularBuffer::new(MAX_BUFFER_SIZE);
+    let mut buff_2 = CircularBuffer::new(MAX_BUFFER_SIZE);
+    let mut central = 0;
+    let mut delayed = 0;
+
+    // Substractions between u64 below are safe from over/underflows due to
+    // algorithm invariants.
+    //   1. `tof` is always the most recent click on the detector.
+    //   2. The `if` guard on `delta`.
+    for rec in stream {
+        let (tof, channel) = (*rec.tof(), *rec.channel());
+
+        if channel == params.channel_1 {
+            buff_1.push(tof);
+            // Negative delays only fall on the central window
+            for click in buff_2.iter() {
+                let delta = tof - click;
+                if delta < half_window {
+                    central += 1;
+                } else {
+                    break;
+                }
+            }
+        } else if channel == params.channel_2 {
+            buff_2.push(tof);
+            for click in buff_1.iter() {
+                let delta = tof - click;
+                if delta < half_window {
+                    central += 1;
+                } else if delta + half_window > delay {
+                    if delta < delay + half_window {
+                        delayed += 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(CarResult {
+        central,
+        delayed,
+        car: (central as f64) / (delayed as f64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, PHT2};
+
+    #[test]
+    fn correlated_source() {
+        // Pairs 0.4 ns apart over uncorrelated background on both channels
+        let mut events: Vec<Event> = random_clicks(2_000, 500_000, 4)
+            .iter()
+            .flat_map(|e| vec![Event::Photon(0, e.tof()), Event::Photon(1, e.tof() + 100)])
+            .chain(random_clicks(4_000, 250_000, 8))
+            .collect();
+        events.sort_by_key(|e| e.tof());
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let params = CarParams {
+            channel_1: 0,
+            channel_2: 1,
+            coincidence_window: 2e-9,
+            accidentals_delay: 100e-9,
+            start_record: None,
+            stop_record: None,
+        };
+        let result = car(&File::open(tmp.path()).unwrap(), &params).unwrap();
+
+        // Every pair of clicks on both channels, 250 ticks being half a window
+        let clicks = |channel| -> Vec<i64> {
+            events
+                .iter()
+                .filter_map(|e| match *e {
+                    Event::Photon(ch, tof) if ch == channel => Some(tof as i64),
+                    _ => None,
+                })
+                .collect()
+        };
+        let (clicks_1, clicks_2) = (clicks(0), clicks(1));
+        let (mut central, mut delayed) = (0, 0);
+        for t_1 in clicks_1.iter() {
+            for t_2 in clicks_2.iter() {
+                let delta = t_2 - t_1;
+                if delta.abs() < 250 {
+                    central += 1;
+                } else if delta > 25_000 - 250 && delta < 25_000 + 250 {
+                    delayed += 1;
+                }
+            }
+        }
+        assert_eq!(result.central, central);
+        assert_eq!(result.delayed, delayed);
+        assert!(result.central >= 2_000);
+        assert!(result.car > 10.0);
+
+        let invalid = CarParams {
+            coincidence_window: f64::NAN,
+            ..params
+        };
+        assert!(matches!(invalid.validate(), Err(Error::InvalidParams(_))));
+    }
+}
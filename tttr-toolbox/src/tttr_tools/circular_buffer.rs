@@ -1,19 +1,32 @@
+/// Fixed capacity buffer of the most recent clicks.
+///
+/// The `unsafe` blocks only skip bounds checks on indices that are always within the
+/// initialized part of the `Vec`. No pointers are held so the buffer and its
+/// iterator are `Send` and `Sync` like the `Vec` they wrap.
 pub(super) struct CircularBuffer {
     buffer: Vec<u64>,
+    capacity: usize,
     pub head: i64,
 }
 
 impl CircularBuffer {
+    /// Empty buffer holding up to `buffer_size` clicks. Panics if `buffer_size` is
+    /// zero since there would be nowhere to push the clicks.
     pub fn new(buffer_size: usize) -> Self {
+        assert!(
+            buffer_size > 0,
+            "A circular buffer needs a positive capacity"
+        );
         Self {
             buffer: Vec::with_capacity(buffer_size),
+            capacity: buffer_size,
             head: 0,
         }
     }
 
     #[inline(always)]
     pub fn push(&mut self, val: u64) {
-        if self.len() < self.buffer.capacity() {
+        if self.len() < self.capacity {
             self.buffer.push(val);
         } else {
             let head = self.head;
@@ -22,7 +35,13 @@ impl CircularBuffer {
                 *elem = val;
             }
         }
-        self.head = (self.head + 1) % (self.buffer.capacity() as i64);
+        self.head = (self.head + 1) % (self.capacity as i64);
+    }
+
+    /// Forget all the clicks while keeping the allocation.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.head = 0;
     }
 
     #[inline(always)]
@@ -1,19 +1,29 @@
+/// `CircularBuffer` keeping the channel of each click. The same reasoning about its
+/// `unsafe` blocks and thread safety applies.
 pub(super) struct CCircularBuffer {
     buffer: Vec<(u64, i32)>,
+    capacity: usize,
     pub head: i64,
 }
 
 impl CCircularBuffer {
+    /// Empty buffer holding up to `buffer_size` clicks. Panics if `buffer_size` is
+    /// zero.
     pub fn new(buffer_size: usize) -> Self {
+        assert!(
+            buffer_size > 0,
+            "A circular buffer needs a positive capacity"
+        );
         Self {
             buffer: Vec::with_capacity(buffer_size),
+            capacity: buffer_size,
             head: 0,
         }
     }
 
     #[inline(always)]
     pub fn push(&mut self, val: u64, ch: i32) {
-        if self.len() < self.buffer.capacity() {
+        if self.len() < self.capacity {
             self.buffer.push((val, ch));
         } else {
             let head = self.head;
@@ -22,7 +32,7 @@ impl CCircularBuffer {
                 *elem = (val, ch);
             }
         }
-        self.head = (self.head + 1) % (self.buffer.capacity() as i64);
+        self.head = (self.head + 1) % (self.capacity as i64);
     }
 
     #[inline(always)]
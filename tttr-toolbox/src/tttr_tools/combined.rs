@@ -0,0 +1,271 @@
+use crate::{
+    errors::Error,
+    headers::{File, RecordType},
+    parsers::ptu,
+    tttr_tools::{
+        g2::{g2_core::G2, G2Params, G2Result},
+        lifetime::{LifetimeHistogram, LifetimeParams, LifetimeResult},
+        timetrace::{TimeTraceHistogram, TimeTraceParams, TimeTraceResult},
+    },
+    Click, RecordKind, TTTRFile,
+};
+
+/// Computes the symmetric g2 and the lifetime histogram in a single pass over the file.
+///
+/// The results are the same as running [g2](../g2/fn.g2.html) and
+/// [lifetime](../lifetime/fn.lifetime.html) separately but the records are only read
+/// once. Like the lifetime algorithm it is only supported in T3 mode.
+///
+/// ## Parameters
+/// The record range is taken from `lifetime_params`. Since the records are shared
+/// between both algorithms `g2_params.record_ranges` must be `None`.
+pub fn g2_and_lifetime(
+    f: &File,
+    g2_params: &G2Params,
+    lifetime_params: &LifetimeParams,
+) -> Result<(G2Result, LifetimeResult), Error> {
+    g2_params.validate()?;
+    lifetime_params.validate()?;
+    if g2_params.record_ranges.is_some() {
+        return Err(Error::InvalidParams(String::from(
+            "Record ranges are not supported by the combined g2 and lifetime algorithm",
+        )));
+    }
+
+    let start_record = lifetime_params.start_record;
+    let stop_record = lifetime_params.stop_record;
+    match f {
+        File::PTU(x) => match x.record_type()? {
+            RecordType::PHT2 => Err(Error::NotImplemented(String::from(
+                "The combined g2 and lifetime algorithm is only supported in T3 mode",
+            ))),
+            RecordType::HHT2_HH1 => Err(Error::NotImplemented(String::from(
+                "The combined g2 and lifetime algorithm is only supported in T3 mode",
+            ))),
+            RecordType::HHT2_HH2 => Err(Error::NotImplemented(String::from(
+                "The combined g2 and lifetime algorithm is only supported in T3 mode",
+            ))),
+            RecordType::HHT3_HH2 => {
+                let stream = ptu::streamers::HHT3_HH2Stream::new(x, start_record, stop_record)?
+                    .with_marker_channels(g2_params.marker_channels);
+
+                let g2 = G2::init(g2_params, 1e-12)?;
+                let mut g2_state = g2.new_state();
+                let mut g2_histogram = vec![0; g2.n_bins()];
+                let mut t_histogram = vec![0.0; g2.n_bins()];
+                let mut lifetime_histogram =
+                    LifetimeHistogram::new(lifetime_params, stream.sync_period)?;
+
+                for rec in stream {
+                    let (tof, channel) = (*rec.tof(), *rec.channel());
+                    g2.push(&mut g2_state, tof, channel, &mut g2_histogram);
+                    lifetime_histogram.push(tof, channel);
+                }
+                g2.fill_t(&mut t_histogram);
+
+                Ok((
+                    G2Result {
+                        hist: g2_histogram,
+                        t: t_histogram,
+                        raw_deltas: g2_params
+                            .raw_deltas_window
+                            .map(|_| g2_state.take_raw_deltas()),
+                        centered: g2_params.centered,
+                    },
+                    lifetime_histogram.finish(),
+                ))
+            }
+            RecordType::NotImplemented => panic! {"Record type not implemented"},
+        },
+        File::Custom(_) => Err(Error::NotImplemented(String::from(
+            "The combined g2 and lifetime algorithm is only supported in T3 mode",
+        ))),
+    }
+}
+
+/// Computes the intensity timetrace and the micro-time histogram of the same clicks in
+/// a single pass over a T3 file.
+///
+/// The micro-time of a click is its arrival time after the last sync pulse. Summed over
+/// the whole measurement it gives a quick lifetime overview next to the intensity
+/// trace without reading the file twice. The histogram is binned like the
+/// [lifetime](../lifetime/fn.lifetime.html) algorithm with a resolution of
+/// `microtime_resolution` seconds. It includes the photons on `params.channel`, or on
+/// every channel if it is `None`.
+pub fn timetrace_and_microtime(
+    f: &File,
+    params: &TimeTraceParams,
+    microtime_resolution: f64,
+) -> Result<(TimeTraceResult, LifetimeResult), Error> {
+    if microtime_resolution.is_nan() || microtime_resolution <= 0.0 {
+        return Err(Error::InvalidParams(String::from(
+            "The micro-time resolution must be positive",
+        )));
+    }
+    // Only the binning of the lifetime parameters is used. Micro-times are taken
+    // straight from the T3 records instead of from a sync channel.
+    let microtime_params = LifetimeParams {
+        channel_sync: vec![],
+        channel_source: -1,
+        resolution: microtime_resolution,
+        start_record: None,
+        stop_record: None,
+        sync_offset: None,
+        log_bins: None,
+        sync_jitter: None,
+    };
+
+    match f {
+        File::PTU(x) => match x.record_type()? {
+            RecordType::PHT2 => Err(Error::NotImplemented(String::from(
+                "The micro-time histogram is only supported in T3 mode",
+            ))),
+            RecordType::HHT2_HH1 => Err(Error::NotImplemented(String::from(
+                "The micro-time histogram is only supported in T3 mode",
+            ))),
+            RecordType::HHT2_HH2 => Err(Error::NotImplemented(String::from(
+                "The micro-time histogram is only supported in T3 mode",
+            ))),
+            RecordType::HHT3_HH2 => {
+                let stream = ptu::streamers::HHT3_HH2Stream::new(x, None, None)?
+                    .with_marker_channels(params.marker_channels)
+                    .with_overflow_channel(params.overflow_channel);
+
+                let mut timetrace_histogram =
+                    TimeTraceHistogram::new(params, 1e-12, f.acquisition_time())?;
+                let mut microtime_histogram =
+                    LifetimeHistogram::new(&microtime_params, stream.sync_period)?;
+
+                for (idx, rec) in stream.enumerate() {
+                    let (tof, channel) = (*rec.tof(), *rec.channel());
+                    timetrace_histogram.push(idx, tof, channel, *rec.markers());
+                    if let RecordKind::Photon { channel } = rec.kind() {
+                        let selected = match params.channel {
+                            Some(ch) => ch == channel,
+                            None => true,
+                        };
+                        if selected {
+                            microtime_histogram.push_microtime(tof);
+                        }
+                    }
+                }
+
+                Ok((timetrace_histogram.finish(), microtime_histogram.finish()))
+            }
+            RecordType::NotImplemented => panic! {"Record type not implemented"},
+        },
+        File::Custom(_) => Err(Error::NotImplemented(String::from(
+            "The micro-time histogram is only supported in T3 mode",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_hht3, random_t3_clicks, write_ptu, T3Event, Tag, HHT3_HH2};
+    use crate::tttr_tools::{
+        g2::{g2, G2Mode},
+        lifetime::{lifetime, tests as lifetime_tests},
+        timetrace::{tests as timetrace_tests, timetrace},
+    };
+
+    /// Photons on channels 1 and 2 of a T3 file with a 12.5 ns sync period and 1 ps
+    /// micro-time resolution.
+    fn t3_file() -> crate::test_utils::TestFile {
+        let events: Vec<T3Event> = random_t3_clicks(20_000, 20_000, 5, 12_500);
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events))
+    }
+
+    #[test]
+    fn g2_and_lifetime_match_separate_runs() {
+        let tmp = t3_file();
+        let f = File::open(tmp.path()).unwrap();
+
+        let g2_params = G2Params::new(1, 2, 100e-9, 1e-9);
+        let lifetime_params = lifetime_tests::params(vec![0], 1);
+
+        let (combined_g2, combined_lifetime) =
+            g2_and_lifetime(&f, &g2_params, &lifetime_params).unwrap();
+        let separate_g2 = g2(&f, &g2_params, G2Mode::Symmetric).unwrap();
+        let separate_lifetime = lifetime(&f, &lifetime_params).unwrap();
+
+        assert!(combined_g2.hist.iter().sum::<u64>() > 0);
+        assert_eq!(combined_g2.hist, separate_g2.hist);
+        assert_eq!(combined_g2.t, separate_g2.t);
+        assert_eq!(combined_lifetime.hist, separate_lifetime.hist);
+        assert_eq!(combined_lifetime.t, separate_lifetime.t);
+        assert_eq!(
+            combined_lifetime.total_counts,
+            separate_lifetime.total_counts
+        );
+        assert!(combined_lifetime.total_counts > 0);
+    }
+
+    #[test]
+    fn microtime_matches_the_lifetime_of_the_same_channel() {
+        let tmp = t3_file();
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = TimeTraceParams {
+            resolution: 1e-6,
+            channel: Some(1),
+            ..timetrace_tests::params(false)
+        };
+        let lifetime_params = lifetime_tests::params(vec![0], 1);
+
+        let (trace, microtime) = timetrace_and_microtime(&f, &params, 100e-12).unwrap();
+        let separate_lifetime = lifetime(&f, &lifetime_params).unwrap();
+        assert!(microtime.total_counts > 0);
+        assert_eq!(microtime.hist, separate_lifetime.hist);
+        assert_eq!(microtime.t, separate_lifetime.t);
+        assert_eq!(trace.intensity, timetrace(&f, &params).unwrap().intensity);
+
+        // Without a channel the photons of both channels go into the histogram
+        params.channel = None;
+        let (_, all_channels) = timetrace_and_microtime(&f, &params, 100e-12).unwrap();
+        let other_lifetime = lifetime(
+            &f,
+            &LifetimeParams {
+                channel_source: 2,
+                ..lifetime_params
+            },
+        )
+        .unwrap();
+        let sum: Vec<u64> = separate_lifetime
+            .hist
+            .iter()
+            .zip(other_lifetime.hist.iter())
+            .map(|(x, y)| x + y)
+            .collect();
+        assert_eq!(all_channels.hist, sum);
+
+        assert!(matches!(
+            timetrace_and_microtime(&f, &params, f64::NAN),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn lifetime_resolutions_outside_the_sync_period_are_rejected() {
+        let events = [T3Event::Photon {
+            channel: 2,
+            nsync: 1,
+            dtime: 100,
+        }];
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        for resolution in [0.0, -1e-12, f64::NAN, 0.5e-12, 20e-9] {
+            let params = LifetimeParams {
+                resolution,
+                ..lifetime_tests::params(vec![0], 1)
+            };
+            assert!(matches!(
+                lifetime(&f, &params),
+                Err(Error::InvalidParams(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::Write;
+
+use crate::errors::Error;
+use crate::headers::File;
+use crate::Click;
+
+/// Write the decoded records of a file as CSV with the columns
+/// `record_index,channel,tof`.
+///
+/// Only the records between `start_record` and `stop_record` are written. `None`
+/// means the start or end of the file respectively. `tof` is given in units of the
+/// file time resolution. Records are streamed into `writer` one at a time so
+/// arbitrarily large ranges can be dumped. Wrap `writer` in a `BufWriter` if it is
+/// not buffered already.
+pub fn dump_records<W: Write>(
+    f: &File,
+    start_record: Option<usize>,
+    stop_record: Option<usize>,
+    mut writer: W,
+) -> Result<(), Error> {
+    let first_record = start_record.unwrap_or(0);
+
+    writeln!(writer, "record_index,channel,tof")?;
+    for (idx, rec) in f.records_range(start_record, stop_record)?.enumerate() {
+        writeln!(
+            writer,
+            "{},{},{}",
+            first_record + idx,
+            rec.channel(),
+            rec.tof()
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, write_ptu, Event, PHT2};
+
+    #[test]
+    fn first_and_last_rows_match_the_records() {
+        let events: Vec<Event> = (1..=100u64)
+            .map(|k| Event::Photon((k % 2) as i32, k * 1000 + k % 7))
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let mut csv = vec![];
+        dump_records(&f, Some(10), Some(50), &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 41);
+        assert_eq!(lines[0], "record_index,channel,tof");
+        assert_eq!(lines[1], "10,1,11004");
+        assert_eq!(lines[40], "49,0,50001");
+
+        let mut csv = vec![];
+        dump_records(&f, None, None, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(1), Some("0,1,1001"));
+        assert_eq!(csv.lines().last(), Some("99,0,100002"));
+    }
+}
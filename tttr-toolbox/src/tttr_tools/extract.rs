@@ -0,0 +1,78 @@
+use crate::errors::Error;
+use crate::headers::File;
+use crate::RecordKind;
+
+/// Arrival times of the photons of a single channel.
+#[derive(Debug, Clone)]
+pub struct ChannelTofs {
+    /// Overflow corrected arrival times in units of `time_resolution`
+    pub tofs: Vec<u64>,
+    /// Time resolution of the file in seconds
+    pub time_resolution: f64,
+}
+
+/// Collect the arrival times of every photon detected on `channel`.
+///
+/// Only the records within `range` are read, or the whole file when it is `None`.
+/// Markers and overflow records are skipped. The arrival times are returned in the
+/// native units of the file, multiply them by `time_resolution` to get seconds.
+pub fn channel_tofs(
+    f: &File,
+    channel: i32,
+    range: Option<(usize, usize)>,
+) -> Result<ChannelTofs, Error> {
+    let (start, stop) = match range {
+        Some((start, stop)) => (Some(start), Some(stop)),
+        None => (None, None),
+    };
+    let stream = f.records_range(start, stop)?;
+    let time_resolution = stream.time_resolution();
+
+    let tofs = stream
+        .filter(|rec| rec.kind() == RecordKind::Photon { channel })
+        .map(|rec| rec.tof)
+        .collect();
+
+    Ok(ChannelTofs {
+        tofs,
+        time_resolution,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, PHT2};
+    use crate::tttr_tools::quick_stats::quick_stats;
+
+    #[test]
+    fn lengths_match_the_channel_counts() {
+        let mut events = random_clicks(5_000, 6_250, 7);
+        events.push(Event::Marker(1, events.last().unwrap().tof() + 10));
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let stats = quick_stats(&f).unwrap();
+        assert!(!stats.approximate);
+
+        for channel in 0..2 {
+            let extracted = channel_tofs(&f, channel, None).unwrap();
+            assert_eq!(extracted.tofs.len() as u64, stats.counts[&channel]);
+            assert_eq!(extracted.time_resolution, 4e-12);
+            let expected: Vec<u64> = events
+                .iter()
+                .filter(|event| matches!(event, Event::Photon(ch, _) if *ch == channel))
+                .map(Event::tof)
+                .collect();
+            assert_eq!(extracted.tofs, expected);
+        }
+
+        let in_range = channel_tofs(&f, 1, Some((1_000, 2_000))).unwrap().tofs;
+        let expected = f
+            .records_range(Some(1_000), Some(2_000))
+            .unwrap()
+            .filter(|rec| rec.kind() == RecordKind::Photon { channel: 1 })
+            .count();
+        assert!(expected > 0);
+        assert_eq!(in_range.len(), expected);
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
     errors::Error,
     headers::{File, RecordType},
-    parsers::ptu,
+    parsers::{custom::CustomStream, ptu},
+    to_native_units,
     tttr_tools::{
         circular_buffer::CircularBuffer,
-        g2::{G2Params, G2Result},
+        g2::{g2_core::BurstVeto, G2Params, G2Result},
     },
     Click, TTTRFile, TTTRStream,
 };
@@ -19,25 +20,54 @@ struct G2Asymetric {
     real_resolution: f64,
     channel_1: i32,
     channel_2: i32,
+    raw_deltas_window: Option<u64>,
+    buffer_size: usize,
+    burst_veto: Option<(usize, u64)>,
+    /// Number of past `channel_1` clicks a `channel_2` click is correlated against.
+    max_starts: usize,
 }
 
 impl G2Asymetric {
-    fn init(params: &G2Params, time_resolution: f64) -> Self {
+    fn init(params: &G2Params, time_resolution: f64) -> Result<Self, Error> {
         let real_resolution = params.resolution.clone();
         let n_bins = (params.correlation_window / params.resolution) as u64;
-        let correlation_window = params.correlation_window / time_resolution;
+        let correlation_window =
+            to_native_units(params.correlation_window, time_resolution)? as f64;
 
         let resolution = (correlation_window / (n_bins as f64)) as u64;
+        if resolution == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The g2 resolution {} is finer than the time resolution of the file {}",
+                params.resolution, time_resolution
+            )));
+        }
         let correlation_window = n_bins * resolution;
 
-        Self {
+        Ok(Self {
             n_bins,
             resolution,
             correlation_window,
             real_resolution,
             channel_1: params.channel_1,
             channel_2: params.channel_2,
-        }
+            raw_deltas_window: params
+                .raw_deltas_window
+                .map(|x| to_native_units(x, time_resolution))
+                .transpose()?,
+            // Only past clicks on channel_1 are kept
+            buffer_size: params.buffer_sizes.map_or(MAX_BUFFER_SIZE, |x| x.0),
+            burst_veto: params
+                .burst_veto
+                .map(|(max_clicks, window)| {
+                    to_native_units(window, time_resolution).map(|x| (max_clicks, x))
+                })
+                .transpose()?,
+            max_starts: if params.last_start_only {
+                1
+            } else {
+                usize::MAX
+            },
+        })
     }
 
     fn compute<P: TTTRStream + Iterator>(
@@ -45,10 +75,13 @@ impl G2Asymetric {
         streamer: P,
         out_hist: &mut [u64],
         out_t: &mut [f64],
+        out_raw_deltas: &mut Vec<i64>,
     ) where
         <P as Iterator>::Item: Debug + Click,
     {
-        let mut buff_1 = CircularBuffer::new(MAX_BUFFER_SIZE);
+        let mut buff_1 = CircularBuffer::new(self.buffer_size);
+        let mut veto_1 = self.burst_veto.map(|(k, window)| BurstVeto::new(k, window));
+        let mut veto_2 = self.burst_veto.map(|(k, window)| BurstVeto::new(k, window));
 
         // Substractions between u64 below are safe from over/underflows due to
         // algorithm invariants.
@@ -58,13 +91,24 @@ impl G2Asymetric {
             let (tof, channel) = (*rec.tof(), *rec.channel());
 
             if channel == self.channel_1 {
+                if veto_1.as_mut().is_some_and(|x| x.veto(tof)) {
+                    continue;
+                }
                 buff_1.push(tof);
             } else if channel == self.channel_2 {
-                for click in buff_1.iter() {
+                if veto_2.as_mut().is_some_and(|x| x.veto(tof)) {
+                    continue;
+                }
+                for click in buff_1.iter().take(self.max_starts) {
                     let delta = tof - click;
                     if delta < self.correlation_window {
                         let hist_idx = delta / self.resolution;
                         out_hist[hist_idx as usize] += 1;
+                        if let Some(raw_window) = self.raw_deltas_window {
+                            if delta < raw_window {
+                                out_raw_deltas.push(delta as i64);
+                            }
+                        }
                     } else {
                         break;
                     }
@@ -125,9 +169,10 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
     match f {
         File::PTU(x) => match x.record_type().unwrap() {
             RecordType::PHT2 => {
-                let tt = G2Asymetric::init(params, x.time_resolution()?);
+                let tt = G2Asymetric::init(params, x.time_resolution()?)?;
                 let mut g2_histogram = vec![0; tt.n_bins as usize];
                 let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -135,22 +180,27 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::PHT2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::PHT2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: false,
                 })
             }
             RecordType::HHT2_HH1 => {
-                let tt = G2Asymetric::init(params, x.time_resolution()?);
+                let tt = G2Asymetric::init(params, x.time_resolution()?)?;
                 let mut g2_histogram = vec![0; tt.n_bins as usize];
                 let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -158,22 +208,27 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT2_HH1Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT2_HH1Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: false,
                 })
             }
             RecordType::HHT2_HH2 => {
-                let tt = G2Asymetric::init(params, x.time_resolution()?);
+                let tt = G2Asymetric::init(params, x.time_resolution()?)?;
                 let mut g2_histogram = vec![0; tt.n_bins as usize];
                 let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -181,22 +236,27 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT2_HH2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT2_HH2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: false,
                 })
             }
             RecordType::HHT3_HH2 => {
-                let tt = G2Asymetric::init(params, 1e-12);
+                let tt = G2Asymetric::init(params, 1e-12)?;
                 let mut g2_histogram = vec![0; tt.n_bins as usize];
                 let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -204,19 +264,48 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT3_HH2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT3_HH2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: false,
                 })
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(x) => {
+            let tt = G2Asymetric::init(params, x.time_resolution()?)?;
+            let mut g2_histogram = vec![0; tt.n_bins as usize];
+            let mut t_histogram = vec![0.0; tt.n_bins as usize];
+            let mut raw_deltas = vec![];
+
+            if let Some(record_ranges) = &params.record_ranges {
+                for &(start_record, stop_record) in record_ranges {
+                    let stream =
+                        CustomStream::new(x.as_ref(), Some(start_record), Some(stop_record))?
+                            .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
+                }
+            } else {
+                let stream = CustomStream::new(x.as_ref(), None, None)?
+                    .with_marker_channels(params.marker_channels);
+                tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
+            };
+            Ok(G2Result {
+                hist: g2_histogram,
+                t: t_histogram,
+                raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                centered: false,
+            })
+        }
     }
 }
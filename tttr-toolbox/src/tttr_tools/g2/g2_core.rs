@@ -0,0 +1,569 @@
+use crate::{
+    errors::Error,
+    to_native_units,
+    tttr_tools::{circular_buffer::CircularBuffer, g2::G2Params},
+    Click, TTTRRecord,
+};
+
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// Symmetric g2 histogramming decoupled from any file format.
+///
+/// The correlation only needs the time of flight and channel of each click, so it
+/// can run over any in-memory stream of records, not just those read from a file.
+pub struct G2 {
+    central_bin: u64,
+    n_bins: u64,
+    resolution: u64,
+    correlation_window: u64,
+    real_resolution: f64,
+    channel_1: i32,
+    channel_2: i32,
+    raw_deltas_window: Option<u64>,
+    buffer_sizes: (usize, usize),
+    centered: bool,
+    burst_veto: Option<(usize, u64)>,
+    /// Number of past `channel_1` clicks a `channel_2` click is correlated against.
+    max_starts: usize,
+    /// Half a bin width when zero delay is centered on a bin, otherwise zero.
+    half_bin: u64,
+    /// Bin layout of adaptive resolution histograms.
+    coarse: Option<CoarseBins>,
+}
+
+/// Geometry of each half of an adaptive resolution histogram. Delays below
+/// `fine_window` use the regular bins and the rest bins `factor` times wider.
+#[derive(Debug, Copy, Clone)]
+struct CoarseBins {
+    n_fine: u64,
+    fine_window: u64,
+    resolution: u64,
+    factor: u64,
+}
+
+impl CoarseBins {
+    /// Index of a delay counted from zero delay outwards.
+    #[inline(always)]
+    fn side_idx(&self, delta: u64, fine_resolution: u64) -> u64 {
+        if delta < self.fine_window {
+            delta / fine_resolution
+        } else {
+            self.n_fine + (delta - self.fine_window) / self.resolution
+        }
+    }
+}
+
+impl G2 {
+    /// Prepare the histogram geometry for a stream with the given `time_resolution`
+    /// in seconds. Fails if `params` are invalid, the windows can't be expressed in
+    /// native units or the resolution is finer than a tick of the stream.
+    pub fn init(params: &G2Params, time_resolution: f64) -> Result<Self, Error> {
+        params.validate()?;
+        let real_resolution = params.resolution;
+        let n_bins = (params.correlation_window / params.resolution) as u64;
+        let correlation_window =
+            to_native_units(params.correlation_window, time_resolution)? as f64;
+
+        let resolution = (correlation_window / (n_bins as f64)) as u64;
+        if resolution == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The g2 resolution {} is finer than the time resolution of the file {}",
+                params.resolution, time_resolution
+            )));
+        }
+        // A centered histogram gets an extra bin straddling zero delay and its edges
+        // move out by half a bin
+        let half_bin = if params.centered { resolution / 2 } else { 0 };
+        let correlation_window = n_bins * resolution + half_bin;
+        let central_bin = n_bins;
+        let n_bins = if params.centered {
+            n_bins * 2 + 1
+        } else {
+            n_bins * 2
+        };
+
+        Ok(Self {
+            central_bin,
+            n_bins,
+            resolution,
+            correlation_window,
+            real_resolution,
+            channel_1: params.channel_1,
+            channel_2: params.channel_2,
+            raw_deltas_window: params
+                .raw_deltas_window
+                .map(|x| to_native_units(x, time_resolution))
+                .transpose()?,
+            buffer_sizes: params
+                .buffer_sizes
+                .unwrap_or((MAX_BUFFER_SIZE, MAX_BUFFER_SIZE)),
+            centered: params.centered,
+            burst_veto: params
+                .burst_veto
+                .map(|(max_clicks, window)| {
+                    to_native_units(window, time_resolution).map(|x| (max_clicks, x))
+                })
+                .transpose()?,
+            max_starts: if params.last_start_only {
+                1
+            } else {
+                usize::MAX
+            },
+            half_bin,
+            coarse: None,
+        })
+    }
+
+    /// Keep bins of the requested resolution only for delays shorter than
+    /// `fine_window` seconds, rounded to whole bins, and make the rest `factor` times
+    /// wider, saving memory on wide windows. The outermost coarse bins may be cut
+    /// short by the correlation window. Centered bins, a fine window shorter than a
+    /// bin and a zero `factor` are invalid params.
+    pub fn with_coarse_bins(mut self, fine_window: f64, factor: u64) -> Result<Self, Error> {
+        if self.centered {
+            return Err(Error::InvalidParams(String::from(
+                "Centered bins are only supported by the symmetric g2",
+            )));
+        }
+        if fine_window.is_nan() || fine_window < self.real_resolution || factor == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The fine window of an adaptive g2 must span at least one bin and the coarse factor must be positive but got {} and {}",
+                fine_window, factor
+            )));
+        }
+        // Rounded since e.g. 100e-9 / 1e-9 falls just short of 100 bins
+        let n_fine = ((fine_window / self.real_resolution).round() as u64).min(self.central_bin);
+        let fine_window = n_fine * self.resolution;
+        let coarse_resolution = self.resolution * factor;
+        let n_coarse = (self.correlation_window - fine_window).div_ceil(coarse_resolution);
+        self.central_bin = n_fine + n_coarse;
+        self.n_bins = 2 * self.central_bin;
+        self.coarse = Some(CoarseBins {
+            n_fine,
+            fine_window,
+            resolution: coarse_resolution,
+            factor,
+        });
+        Ok(self)
+    }
+
+    /// Histogram index of a delay where `channel_1` clicked last.
+    #[inline(always)]
+    fn negative_idx(&self, delta: u64) -> usize {
+        if let Some(coarse) = &self.coarse {
+            (self.central_bin - coarse.side_idx(delta, self.resolution) - 1) as usize
+        } else if self.centered {
+            (self.central_bin - (delta + self.half_bin) / self.resolution) as usize
+        } else {
+            (self.central_bin - delta / self.resolution - 1) as usize
+        }
+    }
+
+    /// Histogram index of a delay where `channel_2` clicked last.
+    #[inline(always)]
+    fn positive_idx(&self, delta: u64) -> usize {
+        if let Some(coarse) = &self.coarse {
+            return (self.central_bin + coarse.side_idx(delta, self.resolution)) as usize;
+        }
+        (self.central_bin + (delta + self.half_bin) / self.resolution) as usize
+    }
+
+    /// Add a pair of clicks `delta` ticks apart to `out_hist`. `positive` tells if
+    /// `channel_2` clicked last. Returns false, leaving the histogram untouched, if
+    /// the pair falls outside of the correlation window.
+    #[inline(always)]
+    pub(super) fn add_pair(&self, delta: u64, positive: bool, out_hist: &mut [u64]) -> bool {
+        if delta >= self.correlation_window {
+            return false;
+        }
+        if positive {
+            out_hist[self.positive_idx(delta)] += 1;
+        } else {
+            out_hist[self.negative_idx(delta)] += 1;
+        }
+        true
+    }
+
+    /// Number of bins `out_hist` and `out_t` must have.
+    pub fn n_bins(&self) -> usize {
+        self.n_bins as usize
+    }
+
+    /// Whether zero delay is at the center of a bin, see `G2Params::centered`.
+    pub fn centered(&self) -> bool {
+        self.centered
+    }
+
+    /// Fresh click buffers to feed into `push`.
+    pub fn new_state(&self) -> G2State {
+        // Autocorrelations only use the first buffer
+        let buff_2_size = if self.channel_1 == self.channel_2 {
+            1
+        } else {
+            self.buffer_sizes.1
+        };
+        G2State {
+            buff_1: CircularBuffer::new(self.buffer_sizes.0),
+            buff_2: CircularBuffer::new(buff_2_size),
+            raw_deltas: Vec::new(),
+            veto_1: self.burst_veto.map(|(k, window)| BurstVeto::new(k, window)),
+            veto_2: self.burst_veto.map(|(k, window)| BurstVeto::new(k, window)),
+        }
+    }
+
+    /// Process a single click, adding the coincidences it closes to `out_hist`.
+    ///
+    /// Clicks must be pushed in time order. When `channel_1` and `channel_2` are the
+    /// same the autocorrelation of that channel is computed instead, see `push_auto`.
+    #[inline(always)]
+    pub fn push(&self, state: &mut G2State, tof: u64, channel: i32, out_hist: &mut [u64]) {
+        // Substractions between u64 below are safe from over/underflows due to
+        // algorithm invariants.
+        //   1. `tof` is always the most recent click on the detector.
+        //   2. The `if` guard on `delta`.
+        let veto = if channel == self.channel_1 {
+            &mut state.veto_1
+        } else if channel == self.channel_2 {
+            &mut state.veto_2
+        } else {
+            return;
+        };
+        if veto.as_mut().is_some_and(|x| x.veto(tof)) {
+            return;
+        }
+
+        if self.channel_1 == self.channel_2 {
+            if channel == self.channel_1 {
+                self.push_auto(state, tof, out_hist);
+            }
+        } else if channel == self.channel_1 {
+            state.buff_1.push(tof);
+
+            for click in state.buff_2.iter() {
+                let delta = tof - click;
+                if delta < self.correlation_window {
+                    out_hist[self.negative_idx(delta)] += 1;
+                    if let Some(raw_window) = self.raw_deltas_window {
+                        if delta < raw_window {
+                            state.raw_deltas.push(-(delta as i64));
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+        } else if channel == self.channel_2 {
+            state.buff_2.push(tof);
+
+            for click in state.buff_1.iter().take(self.max_starts) {
+                let delta = tof - click;
+                if delta < self.correlation_window {
+                    out_hist[self.positive_idx(delta)] += 1;
+                    if let Some(raw_window) = self.raw_deltas_window {
+                        if delta < raw_window {
+                            state.raw_deltas.push(delta as i64);
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Single buffer autocorrelation of `channel_1`.
+    ///
+    /// Each click is correlated against the previous clicks on the same channel,
+    /// never against itself. Every pair is added at both its positive and negative
+    /// delay so the histogram is symmetric by construction.
+    #[inline(always)]
+    fn push_auto(&self, state: &mut G2State, tof: u64, out_hist: &mut [u64]) {
+        for click in state.buff_1.iter().take(self.max_starts) {
+            let delta = tof - click;
+            if delta < self.correlation_window {
+                out_hist[self.positive_idx(delta)] += 1;
+                out_hist[self.negative_idx(delta)] += 1;
+                if let Some(raw_window) = self.raw_deltas_window {
+                    if delta < raw_window {
+                        state.raw_deltas.push(delta as i64);
+                        state.raw_deltas.push(-(delta as i64));
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+        state.buff_1.push(tof);
+    }
+
+    /// Write the delay of each histogram bin into `out_t`.
+    pub fn fill_t(&self, out_t: &mut [f64]) {
+        if let Some(coarse) = &self.coarse {
+            // Delay of the inner edge of the k-th bin counting from zero delay. The
+            // last coarse bin ends at the correlation window.
+            let window_bins = (self.correlation_window / self.resolution) as f64;
+            let edge = |k: u64| {
+                let fine = k.min(coarse.n_fine) as f64;
+                let wide = k.saturating_sub(coarse.n_fine) as f64;
+                (fine + wide * coarse.factor as f64).min(window_bins) * self.real_resolution
+            };
+            for k in 0..self.central_bin {
+                out_t[(self.central_bin + k) as usize] = edge(k);
+                out_t[(self.central_bin - k - 1) as usize] = -edge(k + 1);
+            }
+            return;
+        }
+        for i in 0..self.n_bins {
+            out_t[i as usize] = ((i as f64) - (self.central_bin as f64)) * self.real_resolution
+        }
+    }
+
+    /// Accumulate the coincidences found on `records` into `out_hist` and write the
+    /// bin delays into `out_t`. Raw deltas, if requested, are appended to
+    /// `out_raw_deltas`.
+    pub fn compute<I>(
+        &self,
+        records: I,
+        out_hist: &mut [u64],
+        out_t: &mut [f64],
+        out_raw_deltas: &mut Vec<i64>,
+    ) where
+        I: IntoIterator<Item = TTTRRecord>,
+    {
+        let mut state = self.new_state();
+        for rec in records {
+            self.push(&mut state, *rec.tof(), *rec.channel(), out_hist);
+        }
+        self.fill_t(out_t);
+        out_raw_deltas.append(&mut state.raw_deltas);
+    }
+}
+
+/// Past clicks on each channel of an ongoing g2 computation.
+pub struct G2State {
+    buff_1: CircularBuffer,
+    buff_2: CircularBuffer,
+    raw_deltas: Vec<i64>,
+    veto_1: Option<BurstVeto>,
+    veto_2: Option<BurstVeto>,
+}
+
+impl G2State {
+    /// Forget all the clicks and raw deltas seen so far, keeping the allocations, so
+    /// that the state can be reused on an unrelated stream.
+    pub fn clear(&mut self) {
+        self.buff_1.clear();
+        self.buff_2.clear();
+        self.raw_deltas.clear();
+        if let Some(veto) = &mut self.veto_1 {
+            veto.recent.clear();
+        }
+        if let Some(veto) = &mut self.veto_2 {
+            veto.recent.clear();
+        }
+    }
+
+    /// Take the raw deltas collected so far.
+    pub fn take_raw_deltas(&mut self) -> Vec<i64> {
+        core::mem::take(&mut self.raw_deltas)
+    }
+}
+
+/// Causal filter dropping the clicks of a single channel that arrive in bursts.
+///
+/// A click is vetoed when the `max_clicks` clicks before it on the same channel all
+/// happened within `window` of it, i.e. when more than `max_clicks` clicks fall within
+/// `window`. Vetoed clicks still count towards later bursts.
+pub(super) struct BurstVeto {
+    recent: CircularBuffer,
+    max_clicks: usize,
+    window: u64,
+}
+
+impl BurstVeto {
+    pub(super) fn new(max_clicks: usize, window: u64) -> Self {
+        Self {
+            recent: CircularBuffer::new(max_clicks),
+            max_clicks,
+            window,
+        }
+    }
+
+    /// Register a click at `tof` and tell whether it must be dropped.
+    #[inline(always)]
+    pub(super) fn veto(&mut self, tof: u64) -> bool {
+        let vetoed = self.recent.len() == self.max_clicks
+            && self
+                .recent
+                .iter()
+                .last()
+                .is_some_and(|&oldest| tof - oldest < self.window);
+        self.recent.push(tof);
+        vetoed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::File;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode};
+
+    #[test]
+    fn push_over_memory_matches_file_g2() {
+        let params = G2Params::new(0, 1, 100e-9, 1e-9);
+        // About 25 ns between clicks with 4 ps ticks
+        let events = random_clicks(10_000, 6_250, 7);
+
+        let g2_core = G2::init(&params, 4e-12).unwrap();
+        let mut state = g2_core.new_state();
+        let mut hist = vec![0; g2_core.n_bins()];
+        for event in events.iter() {
+            if let Event::Photon(channel, tof) = *event {
+                g2_core.push(&mut state, tof, channel, &mut hist);
+            }
+        }
+
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let from_file = g2(&f, &params, G2Mode::Symmetric).unwrap();
+        assert_eq!(hist, from_file.hist);
+
+        // Every pair closer than the correlation window, once rounded to whole bins of
+        // native units, lands on the histogram
+        let window = g2_core.correlation_window as f64;
+        let times = |ch: i32| -> Vec<u64> {
+            events
+                .iter()
+                .filter_map(|e| match *e {
+                    Event::Photon(c, tof) if c == ch => Some(tof),
+                    _ => None,
+                })
+                .collect()
+        };
+        let (ch1, ch2) = (times(0), times(1));
+        let pairs = ch1
+            .iter()
+            .map(|&t1| {
+                ch2.iter()
+                    .filter(|&&t2| ((t2 as f64) - (t1 as f64)).abs() < window)
+                    .count() as u64
+            })
+            .sum::<u64>();
+        assert_eq!(hist.iter().sum::<u64>(), pairs);
+    }
+
+    #[test]
+    fn mismatched_buffer_sizes_only_limit_their_own_side() {
+        let mut params = G2Params::new(0, 1, 100e-9, 1e-9);
+        let events = random_clicks(10_000, 6_250, 11);
+        let run = |params: &G2Params| {
+            let g2_core = G2::init(params, 4e-12).unwrap();
+            let mut hist = vec![0; g2_core.n_bins()];
+            g2_core.compute(
+                events.iter().filter_map(|e| match *e {
+                    Event::Photon(channel, tof) => Some(TTTRRecord::new(channel, tof)),
+                    _ => None,
+                }),
+                &mut hist,
+                &mut vec![0.0; g2_core.n_bins()],
+                &mut vec![],
+            );
+            (hist, g2_core.correlation_window)
+        };
+        let (full, window) = run(&params);
+        params.buffer_sizes = Some((4096, 1));
+        let (skewed, _) = run(&params);
+        let central = full.len() / 2;
+
+        // Clicks of channel_1 keep their whole history so the positive delays agree
+        assert_eq!(skewed[central..], full[central..]);
+        // Each channel_1 click only sees the last channel_2 click before it
+        let mut last_2 = None;
+        let mut expected = 0;
+        for event in events.iter() {
+            match *event {
+                Event::Photon(1, tof) => last_2 = Some(tof),
+                Event::Photon(0, tof) => {
+                    expected += last_2.map_or(0, |t2| (tof - t2 < window) as u64);
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(skewed[..central].iter().sum::<u64>(), expected);
+        assert!(expected < full[..central].iter().sum::<u64>());
+
+        params.buffer_sizes = Some((4096, 0));
+        assert!(matches!(
+            G2::init(&params, 4e-12),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn burst_noise_is_vetoed() {
+        // About 1 µs between clicks with 4 ps ticks
+        let clean = random_clicks(20_000, 250_000, 3);
+        // Bursts of 30 clicks 0.4 ns apart alternating between both channels
+        let mut noisy = clean.clone();
+        let n_bursts = 50;
+        for burst in 0..n_bursts {
+            let start = clean[burst * 300].tof() + 1;
+            for k in 0..30 {
+                noisy.push(Event::Photon((k % 2) as i32, start + 100 * k));
+            }
+        }
+        noisy.sort_by_key(|e| e.tof());
+
+        let tmp_clean = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&clean));
+        let tmp_noisy = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&noisy));
+        let f_clean = File::open(tmp_clean.path()).unwrap();
+        let f_noisy = File::open(tmp_noisy.path()).unwrap();
+        let mut params = G2Params::new(0, 1, 1e-6, 10e-9);
+        let distance = |a: &[u64], b: &[u64]| -> u64 {
+            a.iter()
+                .zip(b.iter())
+                .map(|(x, y)| x.max(y) - x.min(y))
+                .sum()
+        };
+
+        for &mode in [G2Mode::Symmetric, G2Mode::Asymmetric].iter() {
+            let reference = g2(&f_clean, &params, mode).unwrap();
+            let unfiltered = g2(&f_noisy, &params, mode).unwrap();
+            params.burst_veto = Some((2, 20e-9));
+            let filtered = g2(&f_noisy, &params, mode).unwrap();
+            params.burst_veto = None;
+
+            // Only the first two clicks of each burst on each channel get through
+            assert!(distance(&unfiltered.hist, &reference.hist) > 50 * n_bursts as u64);
+            assert!(distance(&filtered.hist, &reference.hist) < 10 * n_bursts as u64);
+        }
+    }
+
+    #[test]
+    fn invalid_coarse_bins_are_rejected() {
+        let params = G2Params::new(0, 1, 100e-9, 1e-9);
+        let coarse = |params: &G2Params, fine_window: f64, factor: u64| {
+            G2::init(params, 4e-12)
+                .unwrap()
+                .with_coarse_bins(fine_window, factor)
+        };
+
+        assert!(coarse(&params, 10e-9, 4).is_ok());
+        for &(fine_window, factor) in &[(10e-9, 0), (0.0, 4), (-10e-9, 4), (f64::NAN, 4)] {
+            assert!(matches!(
+                coarse(&params, fine_window, factor),
+                Err(Error::InvalidParams(_))
+            ));
+        }
+        let centered = G2Params {
+            centered: true,
+            ..params
+        };
+        assert!(matches!(
+            coarse(&centered, 10e-9, 4),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use crate::{
+    errors::Error,
+    headers::File,
+    to_native_units,
+    tttr_tools::g2::{apply_channel_delays, g2_core::G2, G2Params, G2Result},
+    Click,
+};
+
+/// Tracks the position of the g2 peak during an acquisition.
+///
+/// The symmetric g2 is computed on windows of `window` seconds of macro-time that
+/// slide by `step` seconds, and the delay of the highest bin of each window is
+/// returned next to the time of the window center, both in seconds. Useful to
+/// monitor the drift of the electronic delays over long measurements.
+///
+/// ## Parameters
+/// The window is rounded to a whole number of steps. Only windows that are fully
+/// contained in the measurement and have at least one coincidence are reported. Since
+/// the whole file is streamed `params.record_ranges` must be `None`.
+///
+/// ## Algorithm description
+/// Coincidences are assigned to the step in which the click closing them arrived.
+/// A g2 histogram is kept for each of the steps making up the current window so
+/// every record is only correlated once regardless of the overlap between windows.
+pub fn g2_peak_drift(
+    f: &File,
+    params: &G2Params,
+    window: f64,
+    step: f64,
+) -> Result<Vec<(f64, f64)>, Error> {
+    params.validate()?;
+    if params.record_ranges.is_some() {
+        return Err(Error::InvalidParams(String::from(
+            "Record ranges are not supported by the g2 peak drift",
+        )));
+    }
+    if step.is_nan() || window.is_nan() || step <= 0.0 || window < step {
+        return Err(Error::InvalidParams(format!(
+            "The g2 drift step must be positive and no larger than the window but got {} and {}",
+            step, window
+        )));
+    }
+
+    let stream = f.records()?.with_marker_channels(params.marker_channels);
+    let time_resolution = stream.time_resolution();
+    let step_ticks = to_native_units(step, time_resolution)?;
+    if step_ticks == 0 {
+        return Err(Error::InvalidParams(format!(
+            "The g2 drift step {} is finer than the time resolution of the file {}",
+            step, time_resolution
+        )));
+    }
+    let steps_per_window = (window / step).round() as usize;
+
+    let g2 = G2::init(params, time_resolution)?;
+    let mut state = g2.new_state();
+    let mut t = G2Result {
+        t: vec![0.0; g2.n_bins()],
+        hist: vec![],
+        raw_deltas: None,
+        centered: params.centered,
+    };
+    g2.fill_t(&mut t.t);
+    apply_channel_delays(f, params, &mut t)?;
+
+    let mut drift = Drift {
+        t: t.t,
+        step,
+        steps_per_window,
+        steps: VecDeque::with_capacity(steps_per_window),
+        window_hist: vec![0; g2.n_bins()],
+        points: vec![],
+    };
+
+    let mut current_step = 0;
+    let mut step_hist = vec![0; g2.n_bins()];
+    for rec in stream {
+        let (tof, channel) = (*rec.tof(), *rec.channel());
+        let rec_step = tof / step_ticks;
+        while current_step < rec_step {
+            drift.close_step(current_step, &mut step_hist);
+            current_step += 1;
+        }
+        g2.push(&mut state, tof, channel, &mut step_hist);
+    }
+    // The last step is cut short by the end of the measurement and never closed
+    Ok(drift.points)
+}
+
+/// Running sum of the g2 histograms of the steps in the current window.
+struct Drift {
+    t: Vec<f64>,
+    step: f64,
+    steps_per_window: usize,
+    steps: VecDeque<Vec<u64>>,
+    window_hist: Vec<u64>,
+    points: Vec<(f64, f64)>,
+}
+
+impl Drift {
+    /// Move the histogram of step `idx` into the window, leaving `step_hist` zeroed,
+    /// and record the peak of the window if it is complete.
+    fn close_step(&mut self, idx: u64, step_hist: &mut Vec<u64>) {
+        for (acc, &x) in self.window_hist.iter_mut().zip(step_hist.iter()) {
+            *acc += x;
+        }
+        if self.steps.len() == self.steps_per_window {
+            let mut oldest = self.steps.pop_front().unwrap();
+            for (acc, &x) in self.window_hist.iter_mut().zip(oldest.iter()) {
+                *acc -= x;
+            }
+            oldest.iter_mut().for_each(|x| *x = 0);
+            self.steps.push_back(std::mem::replace(step_hist, oldest));
+        } else {
+            let empty = vec![0; step_hist.len()];
+            self.steps.push_back(std::mem::replace(step_hist, empty));
+        }
+
+        if self.steps.len() == self.steps_per_window {
+            if let Some(peak) = peak_position(&self.window_hist, &self.t) {
+                let window_start = (idx + 1) as f64 - self.steps_per_window as f64;
+                let center = (window_start + self.steps_per_window as f64 / 2.0) * self.step;
+                self.points.push((center, peak));
+            }
+        }
+    }
+}
+
+/// Delay of the highest bin of `hist`. The first one wins on ties and empty
+/// histograms have no peak.
+fn peak_position(hist: &[u64], t: &[f64]) -> Option<f64> {
+    let mut peak: Option<(u64, f64)> = None;
+    for (&counts, &delay) in hist.iter().zip(t.iter()) {
+        if counts > peak.map_or(0, |(max, _)| max) {
+            peak = Some((counts, delay));
+        }
+    }
+    peak.map(|(_, delay)| delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, write_ptu, Event, PHT2};
+
+    #[test]
+    fn linear_drift_is_tracked() {
+        // Pairs every µs over 10 ms whose delay drifts from 10 ns to 20 ns, with 4 ps
+        // ticks
+        let delay = |tof: u64| 2_500 + tof / 1_000_000;
+        let events: Vec<Event> = (1..10_000u64)
+            .flat_map(|k| {
+                let tof = k * 250_000;
+                vec![Event::Photon(0, tof), Event::Photon(1, tof + delay(tof))]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let params = G2Params::new(0, 1, 100e-9, 1e-9);
+
+        let points = g2_peak_drift(&f, &params, 0.5e-3, 0.25e-3).unwrap();
+        assert!(points.len() > 30);
+        for &(center, peak) in points.iter() {
+            let expected = (delay((center / 4e-12) as u64) as f64) * 4e-12;
+            // The peak is the left edge of the bin holding most of the delays
+            assert!((peak + 0.5e-9 - expected).abs() <= 1e-9);
+        }
+        let (first, last) = (points[0], points[points.len() - 1]);
+        let slope = (last.1 - first.1) / (last.0 - first.0);
+        assert!((slope - 1e-6).abs() < 0.2e-6);
+
+        assert!(matches!(
+            g2_peak_drift(&f, &params, f64::NAN, 0.25e-3),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    errors::Error,
+    tttr_tools::g2::{
+        g2_core::{G2State, G2},
+        G2Params, G2Result,
+    },
+    Click, TTTRRecord,
+};
+
+/// Symmetric g2 that keeps its click buffers between computations.
+///
+/// Running the g2 on thousands of small record ranges, e.g. after post-selecting
+/// them with a timetrace, otherwise allocates new buffers for every range. The
+/// buffers are cleared before each range so results are the same as those of
+/// independent g2 computations.
+///
+/// ```ignore
+/// let mut engine = G2Engine::new(&params, stream.time_resolution())?;
+/// for &(start, stop) in ranges.iter() {
+///     let result = engine.compute_range(file.records_range(Some(start), Some(stop))?);
+/// }
+/// ```
+///
+/// Channel delays are not applied since the engine doesn't know about the file.
+pub struct G2Engine {
+    g2: G2,
+    state: G2State,
+    t: Vec<f64>,
+    raw_deltas: bool,
+}
+
+impl G2Engine {
+    /// Prepare an engine for streams with the given `time_resolution` in seconds.
+    pub fn new(params: &G2Params, time_resolution: f64) -> Result<Self, Error> {
+        let g2 = G2::init(params, time_resolution)?;
+        let state = g2.new_state();
+        let mut t = vec![0.0; g2.n_bins()];
+        g2.fill_t(&mut t);
+        Ok(Self {
+            g2,
+            state,
+            t,
+            raw_deltas: params.raw_deltas_window.is_some(),
+        })
+    }
+
+    /// Compute the g2 of `records` from scratch, reusing the click buffers.
+    ///
+    /// A new histogram and delay axis are allocated for each result. Use
+    /// `compute_range_into` to avoid those allocations too.
+    pub fn compute_range<I>(&mut self, records: I) -> G2Result
+    where
+        I: IntoIterator<Item = TTTRRecord>,
+    {
+        let mut hist = vec![0; self.g2.n_bins()];
+        let mut raw_deltas = Vec::new();
+        self.compute_range_into(records, &mut hist, &mut raw_deltas);
+        G2Result {
+            t: self.t.clone(),
+            hist,
+            raw_deltas: if self.raw_deltas {
+                Some(raw_deltas)
+            } else {
+                None
+            },
+            centered: self.g2.centered(),
+        }
+    }
+
+    /// Same as `compute_range` but the coincidences are accumulated into `out_hist`
+    /// and the raw deltas, if requested, appended to `out_raw_deltas`. Neither is
+    /// cleared, so zero them between ranges unless a sum over ranges is wanted.
+    ///
+    /// Panics if `out_hist` doesn't have `n_bins` bins.
+    pub fn compute_range_into<I>(
+        &mut self,
+        records: I,
+        out_hist: &mut [u64],
+        out_raw_deltas: &mut Vec<i64>,
+    ) where
+        I: IntoIterator<Item = TTTRRecord>,
+    {
+        assert_eq!(
+            out_hist.len(),
+            self.g2.n_bins(),
+            "The histogram doesn't match the g2 bins"
+        );
+        self.state.clear();
+        for rec in records {
+            self.g2
+                .push(&mut self.state, *rec.tof(), *rec.channel(), out_hist);
+        }
+        out_raw_deltas.append(&mut self.state.take_raw_deltas());
+    }
+
+    /// Number of bins of the histograms computed by the engine.
+    pub fn n_bins(&self) -> usize {
+        self.g2.n_bins()
+    }
+
+    /// Delays in seconds of the histogram bins, shared by all the results.
+    pub fn t(&self) -> &[f64] {
+        &self.t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::File;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode};
+
+    fn params() -> G2Params {
+        G2Params {
+            raw_deltas_window: Some(20e-9),
+            ..G2Params::new(0, 1, 200e-9, 5e-9)
+        }
+    }
+
+    #[test]
+    fn caller_owned_buffers_match_fresh_runs() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 2_500, 11)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let params = params();
+        let mut engine = G2Engine::new(&params, 4e-12).unwrap();
+        let mut hist = vec![0; engine.n_bins()];
+        let mut raw_deltas = vec![];
+        let mut total = vec![0; engine.n_bins()];
+        let mut expected_total = vec![0; engine.n_bins()];
+
+        let ranges = [(0, 5_000), (5_000, 6_000), (6_000, 20_000)];
+        for &(start, stop) in ranges.iter() {
+            hist.iter_mut().for_each(|x| *x = 0);
+            raw_deltas.clear();
+            engine.compute_range_into(
+                f.records_range(Some(start), Some(stop)).unwrap(),
+                &mut hist,
+                &mut raw_deltas,
+            );
+            let mut fresh_params = params.clone();
+            fresh_params.record_ranges = Some(vec![(start, stop)]);
+            let fresh = g2(&f, &fresh_params, G2Mode::Symmetric).unwrap();
+            assert_eq!(engine.t(), &fresh.t[..]);
+            assert_eq!(hist, fresh.hist);
+            assert_eq!(Some(&raw_deltas), fresh.raw_deltas.as_ref());
+            assert!(hist.iter().sum::<u64>() > 0);
+            for (x, y) in expected_total.iter_mut().zip(fresh.hist.iter()) {
+                *x += y;
+            }
+
+            // Without clearing the buffers the ranges add up
+            engine.compute_range_into(
+                f.records_range(Some(start), Some(stop)).unwrap(),
+                &mut total,
+                &mut vec![],
+            );
+        }
+        assert_eq!(total, expected_total);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::{
+    errors::Error,
+    headers::File,
+    tttr_tools::{
+        circular_buffer::CircularBuffer,
+        g2::{g2_core::G2, G2Params, G2Result},
+    },
+    Click,
+};
+
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// Computes the symmetric g2 between every pair of `channels` in a single pass.
+///
+/// The result is keyed by `(channel_1, channel_2)` following the order in which the
+/// channels were given, e.g. `[0, 1, 2]` yields the pairs `(0, 1)`, `(0, 2)` and
+/// `(1, 2)`. Each histogram is identical to the one obtained by running
+/// [g2](../fn.g2.html) on that pair in symmetric mode.
+///
+/// Instead of one pair of click buffers per correlation a single buffer per channel
+/// is kept and every click is correlated against the buffers of all the other
+/// channels. This is much cheaper than N² separate passes over the file. See the
+/// symmetric g2 documentation for the finite buffer artifacts that also apply here.
+pub fn g2_matrix(
+    f: &File,
+    channels: &[i32],
+    correlation_window: f64,
+    resolution: f64,
+) -> Result<HashMap<(i32, i32), G2Result>, Error> {
+    for (i, ch) in channels.iter().enumerate() {
+        if channels[..i].contains(ch) {
+            return Err(Error::InvalidParams(format!(
+                "Channel {} is repeated on the g2 matrix channels",
+                ch
+            )));
+        }
+    }
+
+    let stream = f.records()?;
+    // Same histogram geometry and validation as the pairwise g2
+    let params = G2Params::new(0, 1, correlation_window, resolution);
+    let g2 = G2::init(&params, stream.time_resolution())?;
+    let n_bins = g2.n_bins();
+
+    let n_channels = channels.len();
+    let mut buffers: Vec<CircularBuffer> = (0..n_channels)
+        .map(|_| CircularBuffer::new(MAX_BUFFER_SIZE))
+        .collect();
+    // Row major matrix of histograms of which only the upper triangle is used
+    let mut histograms: Vec<Vec<u64>> = (0..n_channels * n_channels)
+        .map(|idx| {
+            if idx / n_channels < idx % n_channels {
+                vec![0; n_bins]
+            } else {
+                vec![]
+            }
+        })
+        .collect();
+
+    for rec in stream {
+        let (tof, channel) = (*rec.tof(), *rec.channel());
+        let this = match channels.iter().position(|&ch| ch == channel) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        for (other, buffer) in buffers.iter().enumerate() {
+            if other == this {
+                continue;
+            }
+            let hist = &mut histograms[this.min(other) * n_channels + this.max(other)];
+            for click in buffer.iter() {
+                // Negative delays when the click closing the pair is on channel_1
+                if !g2.add_pair(tof - click, this > other, hist) {
+                    break;
+                }
+            }
+        }
+        buffers[this].push(tof);
+    }
+
+    let mut t = vec![0.0; n_bins];
+    g2.fill_t(&mut t);
+
+    let mut results = HashMap::new();
+    for (idx, hist) in histograms.into_iter().enumerate() {
+        let (i, j) = (idx / n_channels, idx % n_channels);
+        if i < j {
+            results.insert(
+                (channels[i], channels[j]),
+                G2Result {
+                    t: t.clone(),
+                    hist,
+                    raw_deltas: None,
+                    centered: false,
+                },
+            );
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode};
+
+    #[test]
+    fn every_pair_matches_its_own_g2() {
+        let events: Vec<Event> = random_clicks(20_000, 2_000, 9)
+            .into_iter()
+            .enumerate()
+            .map(|(k, event)| match event {
+                Event::Photon(_, tof) => Event::Photon((k % 3) as i32, tof),
+                marker => marker,
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let matrix = g2_matrix(&f, &[0, 1, 2], 100e-9, 1e-9).unwrap();
+        assert_eq!(matrix.len(), 3);
+        for &(channel_1, channel_2) in &[(0, 1), (0, 2), (1, 2)] {
+            let params = G2Params::new(channel_1, channel_2, 100e-9, 1e-9);
+            let pair = g2(&f, &params, G2Mode::Symmetric).unwrap();
+            let from_matrix = &matrix[&(channel_1, channel_2)];
+            assert!(pair.hist.iter().sum::<u64>() > 0);
+            assert_eq!(from_matrix.hist, pair.hist);
+            assert_eq!(from_matrix.t, pair.t);
+        }
+    }
+}
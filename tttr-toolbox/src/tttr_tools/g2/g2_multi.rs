@@ -0,0 +1,137 @@
+use crate::{
+    errors::Error,
+    headers::File,
+    tttr_tools::{
+        circular_buffer::CircularBuffer,
+        g2::{g2_core::G2, G2Params, G2Result},
+    },
+    Click,
+};
+
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// Computes the symmetric g2 between `channels` for several correlation windows in a
+/// single pass over the file.
+///
+/// A result is returned for each of `windows`, in the same order, and it is identical
+/// to the one obtained by running [g2](../fn.g2.html) with that window and
+/// `resolution`. Past clicks are only stored once and each of them is correlated up
+/// to the largest window, so comparing window sizes doesn't need re-reading the file.
+/// If both channels are the same their autocorrelation is computed.
+pub fn g2_multi_window(
+    f: &File,
+    channels: (i32, i32),
+    windows: &[f64],
+    resolution: f64,
+) -> Result<Vec<G2Result>, Error> {
+    let stream = f.records()?;
+    let time_resolution = stream.time_resolution();
+
+    let mut correlators = Vec::with_capacity(windows.len());
+    for &correlation_window in windows {
+        let params = G2Params::new(channels.0, channels.1, correlation_window, resolution);
+        let g2 = G2::init(&params, time_resolution)?;
+        let hist = vec![0; g2.n_bins()];
+        correlators.push((g2, hist));
+    }
+
+    let (channel_1, channel_2) = channels;
+    let mut buff_1 = CircularBuffer::new(MAX_BUFFER_SIZE);
+    let mut buff_2 = CircularBuffer::new(MAX_BUFFER_SIZE);
+
+    // Substractions between u64 below are safe from over/underflows because `tof`
+    // is always the most recent click on the detector. Looking back stops once a
+    // pair falls outside of every window.
+    for rec in stream {
+        let (tof, channel) = (*rec.tof(), *rec.channel());
+
+        if channel_1 == channel_2 {
+            if channel == channel_1 {
+                for click in buff_1.iter() {
+                    let delta = tof - click;
+                    let mut in_window = false;
+                    for (g2, hist) in correlators.iter_mut() {
+                        if g2.add_pair(delta, true, hist) {
+                            g2.add_pair(delta, false, hist);
+                            in_window = true;
+                        }
+                    }
+                    if !in_window {
+                        break;
+                    }
+                }
+                buff_1.push(tof);
+            }
+        } else if channel == channel_1 {
+            buff_1.push(tof);
+            for click in buff_2.iter() {
+                let delta = tof - click;
+                let mut in_window = false;
+                for (g2, hist) in correlators.iter_mut() {
+                    in_window |= g2.add_pair(delta, false, hist);
+                }
+                if !in_window {
+                    break;
+                }
+            }
+        } else if channel == channel_2 {
+            buff_2.push(tof);
+            for click in buff_1.iter() {
+                let delta = tof - click;
+                let mut in_window = false;
+                for (g2, hist) in correlators.iter_mut() {
+                    in_window |= g2.add_pair(delta, true, hist);
+                }
+                if !in_window {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(correlators
+        .into_iter()
+        .map(|(g2, hist)| {
+            let mut t = vec![0.0; g2.n_bins()];
+            g2.fill_t(&mut t);
+            G2Result {
+                t,
+                hist,
+                raw_deltas: None,
+                centered: false,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode};
+
+    #[test]
+    fn every_window_matches_its_own_g2() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 2_000, 10)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        // Not sorted by size on purpose
+        let windows = [50e-9, 200e-9, 20e-9];
+
+        for &channels in &[(0, 1), (1, 0), (0, 0)] {
+            let results = g2_multi_window(&f, channels, &windows, 1e-9).unwrap();
+            assert_eq!(results.len(), windows.len());
+            for (result, &correlation_window) in results.iter().zip(windows.iter()) {
+                let params = G2Params::new(channels.0, channels.1, correlation_window, 1e-9);
+                let single = g2(&f, &params, G2Mode::Symmetric).unwrap();
+                assert!(single.hist.iter().sum::<u64>() > 0);
+                assert_eq!(result.hist, single.hist);
+                assert_eq!(result.t, single.t);
+            }
+        }
+    }
+}
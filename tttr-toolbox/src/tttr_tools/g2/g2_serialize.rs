@@ -0,0 +1,286 @@
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use crate::errors::Error;
+use crate::tttr_tools::g2::{ChannelDelays, G2Mode, G2Params, G2ResultFull};
+
+/// Plain text serialization of a [`G2ResultFull`].
+///
+/// Each field goes on its own line as its name followed by its space separated
+/// values, with `none` standing for missing optional values. Floats are written with
+/// as many digits as needed to read back the exact same value, so a result read with
+/// `read_from` is identical to the one written.
+impl G2ResultFull {
+    /// Write the result, its units and its parameters to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let p = &self.params;
+        writeln!(writer, "time_unit {}", self.time_unit)?;
+        writeln!(writer, "counts_unit {}", self.counts_unit)?;
+        match self.mode {
+            G2Mode::Symmetric => writeln!(writer, "mode symmetric")?,
+            G2Mode::Asymmetric => writeln!(writer, "mode asymmetric")?,
+            G2Mode::Adaptive {
+                fine_window,
+                coarse_factor,
+            } => writeln!(writer, "mode adaptive {:?} {}", fine_window, coarse_factor)?,
+        }
+        writeln!(writer, "channel_1 {}", p.channel_1)?;
+        writeln!(writer, "channel_2 {}", p.channel_2)?;
+        writeln!(writer, "correlation_window {:?}", p.correlation_window)?;
+        writeln!(writer, "resolution {:?}", p.resolution)?;
+        match &p.record_ranges {
+            Some(ranges) => writeln!(
+                writer,
+                "record_ranges {}",
+                join(
+                    ranges
+                        .iter()
+                        .map(|(start, stop)| format!("{}:{}", start, stop))
+                )
+            )?,
+            None => writeln!(writer, "record_ranges none")?,
+        }
+        writeln!(
+            writer,
+            "marker_channels {}",
+            optional(p.marker_channels, |x| x.to_string())
+        )?;
+        writeln!(
+            writer,
+            "raw_deltas_window {}",
+            optional(p.raw_deltas_window, |x| format!("{:?}", x))
+        )?;
+        writeln!(
+            writer,
+            "channel_delays {}",
+            optional(p.channel_delays, |x| match x {
+                ChannelDelays::Manual(delay_1, delay_2) => {
+                    format!("manual {:?} {:?}", delay_1, delay_2)
+                }
+                ChannelDelays::FromHeader => String::from("header"),
+            })
+        )?;
+        writeln!(
+            writer,
+            "buffer_sizes {}",
+            optional(p.buffer_sizes, |(size_1, size_2)| format!(
+                "{} {}",
+                size_1, size_2
+            ))
+        )?;
+        writeln!(writer, "centered {}", p.centered)?;
+        writeln!(
+            writer,
+            "burst_veto {}",
+            optional(p.burst_veto, |(max_clicks, window)| format!(
+                "{} {:?}",
+                max_clicks, window
+            ))
+        )?;
+        writeln!(writer, "last_start_only {}", p.last_start_only)?;
+        writeln!(
+            writer,
+            "t {}",
+            join(self.t.iter().map(|x| format!("{:?}", x)))
+        )?;
+        writeln!(writer, "hist {}", join(self.hist.iter()))?;
+        match &self.raw_deltas {
+            Some(deltas) => writeln!(writer, "raw_deltas {}", join(deltas.iter()))?,
+            None => writeln!(writer, "raw_deltas none")?,
+        }
+        Ok(())
+    }
+
+    /// Read back a result written by `write_to`. Fails if any field is missing, out
+    /// of order or can't be parsed.
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut lines = reader.lines();
+        let mut field = |name: &str| -> Result<String, Error> {
+            let line = lines.next().ok_or_else(|| {
+                Error::InvalidParams(format!("Missing field {} on the g2 result", name))
+            })??;
+            match line.split_once(' ') {
+                Some((key, value)) if key == name => Ok(value.to_string()),
+                _ if line == name => Ok(String::new()),
+                _ => Err(Error::InvalidParams(format!(
+                    "Expected field {} on the g2 result but got '{}'",
+                    name, line
+                ))),
+            }
+        };
+
+        let time_unit = field("time_unit")?;
+        let counts_unit = field("counts_unit")?;
+        let mode = field("mode")?;
+        let mode = match values(&mode).as_slice() {
+            ["symmetric"] => G2Mode::Symmetric,
+            ["asymmetric"] => G2Mode::Asymmetric,
+            ["adaptive", fine_window, coarse_factor] => G2Mode::Adaptive {
+                fine_window: parse(fine_window)?,
+                coarse_factor: parse(coarse_factor)?,
+            },
+            _ => return Err(malformed("mode", &mode)),
+        };
+        let channel_1 = parse(&field("channel_1")?)?;
+        let channel_2 = parse(&field("channel_2")?)?;
+        let correlation_window = parse(&field("correlation_window")?)?;
+        let resolution = parse(&field("resolution")?)?;
+        let record_ranges = field("record_ranges")?;
+        let record_ranges = match record_ranges.as_str() {
+            "none" => None,
+            ranges => Some(
+                values(ranges)
+                    .into_iter()
+                    .map(|range| match range.split_once(':') {
+                        Some((start, stop)) => Ok((parse(start)?, parse(stop)?)),
+                        None => Err(malformed("record_ranges", range)),
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?,
+            ),
+        };
+        let marker_channels = parse_optional(&field("marker_channels")?)?;
+        let raw_deltas_window = parse_optional(&field("raw_deltas_window")?)?;
+        let channel_delays = field("channel_delays")?;
+        let channel_delays = match values(&channel_delays).as_slice() {
+            ["none"] => None,
+            ["header"] => Some(ChannelDelays::FromHeader),
+            ["manual", delay_1, delay_2] => {
+                Some(ChannelDelays::Manual(parse(delay_1)?, parse(delay_2)?))
+            }
+            _ => return Err(malformed("channel_delays", &channel_delays)),
+        };
+        let buffer_sizes = field("buffer_sizes")?;
+        let buffer_sizes = match values(&buffer_sizes).as_slice() {
+            ["none"] => None,
+            [size_1, size_2] => Some((parse(size_1)?, parse(size_2)?)),
+            _ => return Err(malformed("buffer_sizes", &buffer_sizes)),
+        };
+        let centered = parse(&field("centered")?)?;
+        let burst_veto = field("burst_veto")?;
+        let burst_veto = match values(&burst_veto).as_slice() {
+            ["none"] => None,
+            [max_clicks, window] => Some((parse(max_clicks)?, parse(window)?)),
+            _ => return Err(malformed("burst_veto", &burst_veto)),
+        };
+        let last_start_only = parse(&field("last_start_only")?)?;
+        let t = parse_all(&field("t")?)?;
+        let hist = parse_all(&field("hist")?)?;
+        let raw_deltas = match field("raw_deltas")?.as_str() {
+            "none" => None,
+            deltas => Some(parse_all(deltas)?),
+        };
+
+        Ok(G2ResultFull {
+            t,
+            hist,
+            raw_deltas,
+            time_unit,
+            counts_unit,
+            params: G2Params {
+                channel_1,
+                channel_2,
+                correlation_window,
+                resolution,
+                record_ranges,
+                marker_channels,
+                raw_deltas_window,
+                channel_delays,
+                buffer_sizes,
+                centered,
+                burst_veto,
+                last_start_only,
+            },
+            mode,
+        })
+    }
+}
+
+fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+    values.map(|x| x.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn optional<T>(value: Option<T>, format: impl Fn(T) -> String) -> String {
+    value.map_or_else(|| String::from("none"), format)
+}
+
+fn values(field: &str) -> Vec<&str> {
+    field.split_whitespace().collect()
+}
+
+fn malformed(name: &str, value: &str) -> Error {
+    Error::InvalidParams(format!(
+        "Malformed field {} on the g2 result: '{}'",
+        name, value
+    ))
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidParams(format!("Can't parse '{}' on the g2 result", value)))
+}
+
+fn parse_optional<T: FromStr>(value: &str) -> Result<Option<T>, Error> {
+    match value {
+        "none" => Ok(None),
+        value => parse(value).map(Some),
+    }
+}
+
+fn parse_all<T: FromStr>(field: &str) -> Result<Vec<T>, Error> {
+    values(field).into_iter().map(parse).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::File;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, PHT2};
+    use crate::tttr_tools::g2::g2;
+
+    #[test]
+    fn params_and_histogram_round_trip() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(5_000, 6_250, 2)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let params = G2Params {
+            channel_1: 0,
+            channel_2: 1,
+            correlation_window: 100e-9,
+            resolution: 1e-9,
+            record_ranges: Some(vec![(0, 2_000), (3_000, 4_500)]),
+            marker_channels: Some(100),
+            raw_deltas_window: Some(0.3e-9),
+            channel_delays: Some(ChannelDelays::Manual(1.25e-9, -0.1e-9)),
+            buffer_sizes: Some((4096, 17)),
+            centered: false,
+            burst_veto: Some((3, 1e-7)),
+            last_start_only: true,
+        };
+        let mode = G2Mode::Adaptive {
+            fine_window: 10e-9,
+            coarse_factor: 4,
+        };
+        let full = g2(&f, &params, mode).unwrap().into_full(&params, mode);
+        assert!(full.hist.iter().sum::<u64>() > 0);
+
+        let mut bytes = vec![];
+        full.write_to(&mut bytes).unwrap();
+        let read = G2ResultFull::read_from(&bytes[..]).unwrap();
+
+        assert_eq!(read.params, params);
+        assert_eq!(read.mode, mode);
+        assert_eq!(read.t, full.t);
+        assert_eq!(read.hist, full.hist);
+        assert_eq!(read.raw_deltas, full.raw_deltas);
+        assert_eq!(read.time_unit, "s");
+        assert_eq!(read.counts_unit, "dimensionless");
+
+        bytes.truncate(bytes.len() / 2);
+        assert!(G2ResultFull::read_from(&bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    errors::Error,
+    headers::File,
+    tttr_tools::g2::{apply_channel_delays, g2_core::G2, G2Params, G2Result},
+    Click,
+};
+
+/// Symmetric g2 that hands out the partial histogram while it is being computed.
+///
+/// `callback` is invoked every `every` records with the number of records processed
+/// so far and a reference to the result accumulated up to that point, e.g. to update
+/// a live plot during a long analysis. The histogram is lent rather than copied so
+/// the callback should copy whatever it needs to keep. Once the file is exhausted the
+/// callback is invoked one last time, unless it just was, so the last snapshot is
+/// always the returned result.
+///
+/// The result is the same as that of `g2` in `G2Mode::Symmetric`, including the
+/// handling of `params.record_ranges` and channel delays.
+pub fn g2_with_snapshots<F>(
+    f: &File,
+    params: &G2Params,
+    every: usize,
+    mut callback: F,
+) -> Result<G2Result, Error>
+where
+    F: FnMut(usize, &G2Result),
+{
+    params.validate()?;
+    if every == 0 {
+        return Err(Error::InvalidParams(String::from(
+            "The g2 snapshots must be taken at least every one record",
+        )));
+    }
+    let time_resolution = f.records()?.time_resolution();
+
+    let g2 = G2::init(params, time_resolution)?;
+    let mut state = g2.new_state();
+    let mut result = G2Result {
+        t: vec![0.0; g2.n_bins()],
+        hist: vec![0; g2.n_bins()],
+        raw_deltas: params.raw_deltas_window.map(|_| vec![]),
+        centered: params.centered,
+    };
+    g2.fill_t(&mut result.t);
+    apply_channel_delays(f, params, &mut result)?;
+
+    let ranges: Vec<(Option<usize>, Option<usize>)> = match &params.record_ranges {
+        Some(record_ranges) => record_ranges
+            .iter()
+            .map(|&(start, stop)| (Some(start), Some(stop)))
+            .collect(),
+        None => vec![(None, None)],
+    };
+
+    let mut n_records = 0;
+    let mut last_snapshot = 0;
+    for (start, stop) in ranges {
+        // Ranges are independent of each other like on the other g2 modes
+        state.clear();
+        let stream = f
+            .records_range(start, stop)?
+            .with_marker_channels(params.marker_channels);
+        for rec in stream {
+            g2.push(&mut state, *rec.tof(), *rec.channel(), &mut result.hist);
+            n_records += 1;
+            if n_records % every == 0 {
+                if let Some(raw_deltas) = &mut result.raw_deltas {
+                    raw_deltas.append(&mut state.take_raw_deltas());
+                }
+                callback(n_records, &result);
+                last_snapshot = n_records;
+            }
+        }
+        if let Some(raw_deltas) = &mut result.raw_deltas {
+            raw_deltas.append(&mut state.take_raw_deltas());
+        }
+    }
+    if last_snapshot != n_records {
+        callback(n_records, &result);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, PHT2};
+    use crate::tttr_tools::g2::{g2, G2Mode};
+
+    #[test]
+    fn last_snapshot_is_the_result() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(5_000, 2_000, 12)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let n_records = f.records().unwrap().count();
+        let mut params = G2Params {
+            raw_deltas_window: Some(5e-9),
+            ..G2Params::new(0, 1, 100e-9, 1e-9)
+        };
+
+        for (record_ranges, n_read) in [
+            (None, n_records),
+            (Some(vec![(0, 2_000), (3_000, 4_500)]), 3_500),
+        ] {
+            params.record_ranges = record_ranges;
+            let expected = g2(&f, &params, G2Mode::Symmetric).unwrap();
+            // Snapshots in between and one that falls right on the last record
+            for &every in &[601, n_read] {
+                let mut snapshots: Vec<(usize, Vec<u64>, Option<Vec<i64>>)> = vec![];
+                let result = g2_with_snapshots(&f, &params, every, |n, partial| {
+                    snapshots.push((n, partial.hist.clone(), partial.raw_deltas.clone()))
+                })
+                .unwrap();
+
+                let (n, hist, raw_deltas) = snapshots.last().unwrap();
+                assert_eq!(*hist, result.hist);
+                assert_eq!(*raw_deltas, result.raw_deltas);
+                assert_eq!(result.hist, expected.hist);
+                assert_eq!(result.raw_deltas, expected.raw_deltas);
+                assert_eq!(*n, n_read);
+                // The partial histograms only grow
+                for pair in snapshots.windows(2) {
+                    assert!(pair[0].0 < pair[1].0);
+                    let counts = |hist: &[u64]| hist.iter().sum::<u64>();
+                    assert!(counts(&pair[0].1) <= counts(&pair[1].1));
+                }
+            }
+        }
+    }
+}
@@ -1,103 +1,10 @@
 use crate::{
     errors::Error,
     headers::{File, RecordType},
-    parsers::ptu,
-    tttr_tools::{
-        circular_buffer::CircularBuffer,
-        g2::{G2Params, G2Result},
-    },
-    Click, TTTRFile, TTTRStream,
+    parsers::{custom::CustomStream, ptu},
+    tttr_tools::g2::{g2_core::G2, G2Params, G2Result},
+    TTTRFile,
 };
-use std::fmt::Debug;
-
-const MAX_BUFFER_SIZE: usize = 4096;
-
-// ToDo
-// Streamer params and G2Params should probably be different here
-
-struct G2 {
-    central_bin: u64,
-    n_bins: u64,
-    resolution: u64,
-    correlation_window: u64,
-    real_resolution: f64,
-    channel_1: i32,
-    channel_2: i32,
-}
-
-impl G2 {
-    fn init(params: &G2Params, time_resolution: f64) -> Self {
-        let real_resolution = params.resolution.clone();
-        let n_bins = (params.correlation_window / params.resolution) as u64;
-        let correlation_window = params.correlation_window / time_resolution;
-
-        let resolution = (correlation_window / (n_bins as f64)) as u64;
-        let correlation_window = n_bins * resolution;
-        let n_bins = n_bins * 2;
-
-        let central_bin = n_bins / 2;
-
-        Self {
-            central_bin,
-            n_bins,
-            resolution,
-            correlation_window,
-            real_resolution,
-            channel_1: params.channel_1,
-            channel_2: params.channel_2,
-        }
-    }
-
-    fn compute<P: TTTRStream + Iterator>(
-        &self,
-        streamer: P,
-        out_hist: &mut [u64],
-        out_t: &mut [f64],
-    ) where
-        <P as Iterator>::Item: Debug + Click,
-    {
-        let mut buff_1 = CircularBuffer::new(MAX_BUFFER_SIZE);
-        let mut buff_2 = CircularBuffer::new(MAX_BUFFER_SIZE);
-
-        // Substractions between u64 below are safe from over/underflows due to
-        // algorithm invariants.
-        //   1. `rec.tof` is always the most recent click on the detector.
-        //   2. The `if` guard on `delta`.
-        for rec in streamer.into_iter() {
-            let (tof, channel) = (*rec.tof(), *rec.channel());
-
-            if channel == self.channel_1 {
-                buff_1.push(tof);
-
-                for click in buff_2.iter() {
-                    let delta = tof - click;
-                    if delta < self.correlation_window {
-                        let hist_idx = self.central_bin - delta / self.resolution - 1;
-                        out_hist[hist_idx as usize] += 1;
-                    } else {
-                        break;
-                    }
-                }
-            } else if channel == self.channel_2 {
-                buff_2.push(tof);
-
-                for click in buff_1.iter() {
-                    let delta = tof - click;
-                    if delta < self.correlation_window {
-                        let hist_idx = self.central_bin + delta / self.resolution;
-                        out_hist[hist_idx as usize] += 1;
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
-
-        for i in 0..self.n_bins {
-            out_t[i as usize] = ((i as f64) - (self.central_bin as f64)) * self.real_resolution
-        }
-    }
-}
 
 /// Computes the second order autocorrelation (g2) between two channels on a TCSPC module.
 ///
@@ -110,6 +17,9 @@ impl G2 {
 ///    - correlation_window: Length of the correlation window of interest in seconds,
 ///    - resolution: Resolution of the g2 histogram in seconds,
 ///
+/// If both channels are the same the autocorrelation of that channel is computed with
+/// a single click buffer, excluding the pairs of each click with itself.
+///
 /// ## Algorithm description
 ///
 /// The streaming g2 algorithm measures the time difference between a
@@ -141,14 +51,26 @@ impl G2 {
 /// Taking into consideration typical emitter lifetimes and collection optics efficiency
 /// this should be more than enough to capture any relevant dynamics. If this is
 /// not the case for you will need to modify the hard coded maximum buffer size
-/// defined on `src/tttr_tools/g2.rs`.
-pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
+/// defined on `src/tttr_tools/g2/g2_core.rs`.
+///
+/// `coarse_bins` are the fine window and coarse factor of adaptive resolution
+/// histograms, see `G2Mode::Adaptive`.
+pub(super) fn g2(
+    f: &File,
+    params: &G2Params,
+    coarse_bins: Option<(f64, u64)>,
+) -> Result<G2Result, Error> {
     match f {
         File::PTU(x) => match x.record_type().unwrap() {
             RecordType::PHT2 => {
-                let tt = G2::init(params, x.time_resolution()?);
-                let mut g2_histogram = vec![0; tt.n_bins as usize];
-                let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let tt = G2::init(params, x.time_resolution()?)?;
+                let tt = match coarse_bins {
+                    Some((fine_window, factor)) => tt.with_coarse_bins(fine_window, factor)?,
+                    None => tt,
+                };
+                let mut g2_histogram = vec![0; tt.n_bins()];
+                let mut t_histogram = vec![0.0; tt.n_bins()];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -156,22 +78,31 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::PHT2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::PHT2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: params.centered,
                 })
             }
             RecordType::HHT2_HH1 => {
-                let tt = G2::init(params, x.time_resolution()?);
-                let mut g2_histogram = vec![0; tt.n_bins as usize];
-                let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let tt = G2::init(params, x.time_resolution()?)?;
+                let tt = match coarse_bins {
+                    Some((fine_window, factor)) => tt.with_coarse_bins(fine_window, factor)?,
+                    None => tt,
+                };
+                let mut g2_histogram = vec![0; tt.n_bins()];
+                let mut t_histogram = vec![0.0; tt.n_bins()];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -179,22 +110,31 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT2_HH1Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT2_HH1Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: params.centered,
                 })
             }
             RecordType::HHT2_HH2 => {
-                let tt = G2::init(params, x.time_resolution()?);
-                let mut g2_histogram = vec![0; tt.n_bins as usize];
-                let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let tt = G2::init(params, x.time_resolution()?)?;
+                let tt = match coarse_bins {
+                    Some((fine_window, factor)) => tt.with_coarse_bins(fine_window, factor)?,
+                    None => tt,
+                };
+                let mut g2_histogram = vec![0; tt.n_bins()];
+                let mut t_histogram = vec![0.0; tt.n_bins()];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -202,22 +142,31 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT2_HH2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT2_HH2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: params.centered,
                 })
             }
             RecordType::HHT3_HH2 => {
-                let tt = G2::init(params, 1e-12);
-                let mut g2_histogram = vec![0; tt.n_bins as usize];
-                let mut t_histogram = vec![0.0; tt.n_bins as usize];
+                let tt = G2::init(params, 1e-12)?;
+                let tt = match coarse_bins {
+                    Some((fine_window, factor)) => tt.with_coarse_bins(fine_window, factor)?,
+                    None => tt,
+                };
+                let mut g2_histogram = vec![0; tt.n_bins()];
+                let mut t_histogram = vec![0.0; tt.n_bins()];
+                let mut raw_deltas = vec![];
 
                 if let Some(record_ranges) = &params.record_ranges {
                     for &(start_record, stop_record) in record_ranges {
@@ -225,19 +174,52 @@ pub(super) fn g2(f: &File, params: &G2Params) -> Result<G2Result, Error> {
                             x,
                             Some(start_record),
                             Some(stop_record),
-                        )?;
-                        tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                        )?
+                        .with_marker_channels(params.marker_channels);
+                        tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                     }
                 } else {
-                    let stream = ptu::streamers::HHT3_HH2Stream::new(x, None, None)?;
-                    tt.compute(stream, &mut g2_histogram, &mut t_histogram);
+                    let stream = ptu::streamers::HHT3_HH2Stream::new(x, None, None)?
+                        .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
                 };
                 Ok(G2Result {
                     hist: g2_histogram,
                     t: t_histogram,
+                    raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                    centered: params.centered,
                 })
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(x) => {
+            let tt = G2::init(params, x.time_resolution()?)?;
+            let tt = match coarse_bins {
+                Some((fine_window, factor)) => tt.with_coarse_bins(fine_window, factor)?,
+                None => tt,
+            };
+            let mut g2_histogram = vec![0; tt.n_bins()];
+            let mut t_histogram = vec![0.0; tt.n_bins()];
+            let mut raw_deltas = vec![];
+
+            if let Some(record_ranges) = &params.record_ranges {
+                for &(start_record, stop_record) in record_ranges {
+                    let stream =
+                        CustomStream::new(x.as_ref(), Some(start_record), Some(stop_record))?
+                            .with_marker_channels(params.marker_channels);
+                    tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
+                }
+            } else {
+                let stream = CustomStream::new(x.as_ref(), None, None)?
+                    .with_marker_channels(params.marker_channels);
+                tt.compute(stream, &mut g2_histogram, &mut t_histogram, &mut raw_deltas);
+            };
+            Ok(G2Result {
+                hist: g2_histogram,
+                t: t_histogram,
+                raw_deltas: params.raw_deltas_window.map(|_| raw_deltas),
+                centered: params.centered,
+            })
+        }
     }
 }
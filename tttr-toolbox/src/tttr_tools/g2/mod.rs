@@ -1,19 +1,151 @@
 use crate::errors::Error;
 use crate::headers::File;
+use crate::tttr_tools::merge::merge_ordered;
+use crate::TTTRRecord;
 
 pub mod g2_asymmetric;
+pub mod g2_core;
+pub mod g2_drift;
+pub mod g2_engine;
+pub mod g2_matrix;
+pub mod g2_multi;
+pub mod g2_serialize;
+pub mod g2_snapshots;
 pub mod g2_symmetric;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum G2Mode {
     Asymmetric,
     Symmetric,
+    /// Symmetric g2 with the requested resolution only for delays shorter than
+    /// `fine_window` seconds. Longer delays are binned `coarse_factor` times wider so
+    /// the `t` axis is not uniform.
+    Adaptive {
+        fine_window: f64,
+        coarse_factor: u64,
+    },
+}
+
+/// Electronic delays of the input channels to correct the g2 delay axis for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChannelDelays {
+    /// Delays in seconds of `channel_1` and `channel_2`
+    Manual(f64, f64),
+    /// Use the per input offsets stored on the file header
+    FromHeader,
+}
+
+/// A [`G2Result`] bundled with its units and the parameters it was computed with,
+/// so it can be stored and interpreted later without the original call. See
+/// `write_to` and `read_from` to serialize it.
+#[derive(Debug, Clone)]
+pub struct G2ResultFull {
+    pub t: Vec<f64>,
+    pub hist: Vec<u64>,
+    /// Signed delays in native units, only if `raw_deltas_window` was requested.
+    pub raw_deltas: Option<Vec<i64>>,
+    /// Unit of `t`, always seconds
+    pub time_unit: String,
+    /// Unit of `hist`, coincidence counts are dimensionless
+    pub counts_unit: String,
+    pub params: G2Params,
+    pub mode: G2Mode,
 }
 
 /// Result from the g2 algorithm
+#[derive(Debug, Clone)]
 pub struct G2Result {
     pub t: Vec<f64>,
     pub hist: Vec<u64>,
+    /// Signed delays in native units, only if `raw_deltas_window` was requested.
+    pub raw_deltas: Option<Vec<i64>>,
+    /// Whether `t` holds the center of each bin, with zero delay at the center of a
+    /// bin, instead of their left edge.
+    pub centered: bool,
+}
+
+impl G2Result {
+    /// Attach the units and the `params` and `mode` that produced this result.
+    pub fn into_full(self, params: &G2Params, mode: G2Mode) -> G2ResultFull {
+        G2ResultFull {
+            t: self.t,
+            hist: self.hist,
+            raw_deltas: self.raw_deltas,
+            time_unit: String::from("s"),
+            counts_unit: String::from("dimensionless"),
+            params: params.clone(),
+            mode,
+        }
+    }
+
+    /// Coincidences at zero delay. That is the bin centered closest to zero delay on
+    /// centered histograms, or otherwise the sum of the two bins on each side of the
+    /// bin edge closest to zero delay. Zero if the histogram is empty.
+    pub fn g2_zero(&self) -> u64 {
+        let closest =
+            match (0..self.t.len()).min_by(|&a, &b| self.t[a].abs().total_cmp(&self.t[b].abs())) {
+                Some(x) => x,
+                None => return 0,
+            };
+        if self.centered {
+            self.hist[closest]
+        } else {
+            // The bin before the first positive delays may be missing, e.g. on the
+            // asymmetric g2
+            closest.checked_sub(1).map_or(0, |x| self.hist[x]) + self.hist[closest]
+        }
+    }
+
+    /// Delay in seconds at the center of `bin`.
+    ///
+    /// `t` holds the center of each bin on centered histograms but their left edge
+    /// otherwise. Panics if `bin` is out of range.
+    pub fn tau_of(&self, bin: usize) -> f64 {
+        if self.centered {
+            return self.t[bin];
+        }
+        // The width of the last bin is taken from the previous one
+        let width = match (self.t.get(bin + 1), bin.checked_sub(1)) {
+            (Some(next), _) => next - self.t[bin],
+            (None, Some(previous)) => self.t[bin] - self.t[previous],
+            (None, None) => 0.0,
+        };
+        self.t[bin] + width / 2.0
+    }
+
+    /// Check that `other`, a symmetric g2 computed with `channel_1` and `channel_2`
+    /// swapped, is the mirror image of this one.
+    ///
+    /// Bins are compared against their mirrored counterpart and are considered equal
+    /// when they differ by less than three standard deviations of the counting noise.
+    /// Useful to catch channel ordering mistakes.
+    pub fn is_symmetric_swap(&self, other: &G2Result) -> bool {
+        if self.hist.len() != other.hist.len() {
+            return false;
+        }
+        self.hist
+            .iter()
+            .zip(other.hist.iter().rev())
+            .all(|(&a, &b)| {
+                let diff = (a as f64) - (b as f64);
+                diff.abs() <= 3.0 * ((a + b) as f64).sqrt()
+            })
+    }
+
+    /// Histogram with each bin averaged with its mirror image around zero delay.
+    ///
+    /// Bin `i` is averaged with bin `len - 1 - i` which improves the signal to noise
+    /// ratio of low statistics measurements. On centered histograms the middle bin,
+    /// holding zero delay, is its own mirror and is kept as is. This is only valid
+    /// for a symmetric g2 between interchangeable channels, e.g. the two arms of a
+    /// HBT setup, and the result keeps the `t` axis of the original histogram.
+    pub fn symmetrize(&self) -> Vec<f64> {
+        self.hist
+            .iter()
+            .zip(self.hist.iter().rev())
+            .map(|(&a, &b)| ((a + b) as f64) / 2.0)
+            .collect()
+    }
 }
 
 /// Parameters for the g2 algorithm
@@ -23,18 +155,723 @@ pub struct G2Result {
 ///    - channel_2: The number of the second input channel into the TCSPC
 ///    - correlation_window: Length of the correlation window of interest in seconds
 ///    - resolution: Resolution of the g2 histogram in seconds
-#[derive(Debug, Clone)]
+///    - record_ranges: Optional list of `(start, stop)` record ranges to process
+///    - marker_channels: Optional offset to map marker records onto the virtual
+///      channel `offset + marker_bits`, so markers can be correlated like photons
+///    - raw_deltas_window: Optional narrow window in seconds. Every delay within it
+///      is also returned unbinned, in native units of the file, for jitter analysis.
+///      It can't be wider than the correlation window.
+///    - channel_delays: Optional delays of each channel. The delay axis of the
+///      result is shifted so that the peaks land at their true position.
+///    - buffer_sizes: Optional number of past clicks kept for `channel_1` and
+///      `channel_2` respectively. Defaults to 4096 on each channel. Give the faster
+///      channel a larger buffer when the count rates are very different.
+///    - centered: Use an odd number of bins with zero delay at the center of the
+///      middle bin instead of on the edge between the two middle bins. Only
+///      supported by the symmetric g2.
+///    - burst_veto: Optional `(max_clicks, window)` pre-filter. Clicks are dropped when
+///      more than `max_clicks` clicks on the same channel fall within `window` seconds,
+///      which removes afterpulsing and noise bursts that add accidental coincidences.
+///      Only past clicks are considered, so the first clicks of a burst are kept.
+///    - last_start_only: Correlate each `channel_2` click only against the most recent
+///      `channel_1` click instead of against the whole buffer, i.e. a start-stop
+///      measurement gated on the last start. Delays where `channel_1` clicked last are
+///      not affected. For autocorrelations each click only sees the previous one.
+#[derive(Debug, Clone, PartialEq)]
 pub struct G2Params {
     pub channel_1: i32,
     pub channel_2: i32,
     pub correlation_window: f64,
     pub resolution: f64,
     pub record_ranges: Option<Vec<(usize, usize)>>,
+    pub marker_channels: Option<i32>,
+    pub raw_deltas_window: Option<f64>,
+    pub channel_delays: Option<ChannelDelays>,
+    pub buffer_sizes: Option<(usize, usize)>,
+    pub centered: bool,
+    pub burst_veto: Option<(usize, f64)>,
+    pub last_start_only: bool,
+}
+
+impl G2Params {
+    /// Correlation between `channel_1` and `channel_2` over the whole file with every
+    /// optional setting off. Use struct update syntax to change the rest, e.g.
+    /// `G2Params { centered: true, ..G2Params::new(0, 1, 100e-9, 1e-9) }`.
+    pub fn new(channel_1: i32, channel_2: i32, correlation_window: f64, resolution: f64) -> Self {
+        G2Params {
+            channel_1,
+            channel_2,
+            correlation_window,
+            resolution,
+            record_ranges: None,
+            marker_channels: None,
+            raw_deltas_window: None,
+            channel_delays: None,
+            buffer_sizes: None,
+            centered: false,
+            burst_veto: None,
+            last_start_only: false,
+        }
+    }
+
+    /// Number of bins of the histogram returned by the symmetric g2. The asymmetric
+    /// mode only keeps positive delays and returns half as many. Centered histograms
+    /// have an extra bin at zero delay.
+    pub fn n_bins(&self) -> usize {
+        2 * (self.correlation_window / self.resolution) as usize + self.centered as usize
+    }
+
+    /// Check that the resolution fits within the correlation window, that the raw
+    /// deltas window is not wider than it and that the click buffers can hold at
+    /// least one click.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.resolution.is_nan()
+            || self.correlation_window.is_nan()
+            || self.resolution <= 0.0
+            || self.resolution > self.correlation_window
+        {
+            return Err(Error::InvalidParams(format!(
+                "The g2 resolution must be positive and no larger than the correlation window but got {} and {}",
+                self.resolution, self.correlation_window
+            )));
+        }
+        if let Some(raw_deltas_window) = self.raw_deltas_window {
+            if raw_deltas_window > self.correlation_window {
+                return Err(Error::InvalidParams(String::from(
+                    "The raw deltas window can't be wider than the correlation window",
+                )));
+            }
+        }
+        if let Some((max_clicks, window)) = self.burst_veto {
+            if max_clicks == 0 || window.is_nan() || window <= 0.0 {
+                return Err(Error::InvalidParams(String::from(
+                    "The burst veto needs at least one click and a positive window",
+                )));
+            }
+        }
+        if let Some((size_1, size_2)) = self.buffer_sizes {
+            if size_1 == 0 || size_2 == 0 {
+                return Err(Error::InvalidParams(String::from(
+                    "The g2 buffer sizes must be positive",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bin width in seconds actually used when the requested resolution is rounded
+    /// to a whole number of ticks of a file with the given `time_resolution`.
+    pub fn effective_resolution(&self, time_resolution: f64) -> f64 {
+        let n_bins = (self.correlation_window / self.resolution) as u64;
+        let ticks = ((self.correlation_window / time_resolution) / (n_bins as f64)) as u64;
+        (ticks as f64) * time_resolution
+    }
 }
 
 pub fn g2(f: &File, params: &G2Params, mode: G2Mode) -> Result<G2Result, Error> {
-    match mode {
-        G2Mode::Symmetric => g2_symmetric::g2(f, params),
+    params.validate()?;
+    match (params.centered, mode) {
+        (true, G2Mode::Asymmetric) | (true, G2Mode::Adaptive { .. }) => {
+            return Err(Error::InvalidParams(String::from(
+                "Centered bins are only supported by the symmetric g2",
+            )));
+        }
+        (
+            _,
+            G2Mode::Adaptive {
+                fine_window,
+                coarse_factor,
+            },
+        ) if fine_window.is_nan() || fine_window < params.resolution || coarse_factor == 0 => {
+            return Err(Error::InvalidParams(format!(
+                "The fine window of an adaptive g2 must span at least one bin and the coarse factor must be positive but got {} and {}",
+                fine_window, coarse_factor
+            )));
+        }
+        _ => {}
+    }
+    let mut result = match mode {
+        G2Mode::Symmetric => g2_symmetric::g2(f, params, None),
         G2Mode::Asymmetric => g2_asymmetric::g2(f, params),
+        G2Mode::Adaptive {
+            fine_window,
+            coarse_factor,
+        } => g2_symmetric::g2(f, params, Some((fine_window, coarse_factor))),
+    }?;
+    apply_channel_delays(f, params, &mut result)?;
+    Ok(result)
+}
+
+/// Shift the delay axis of `result` by the channel delays requested in `params`.
+pub(crate) fn apply_channel_delays(
+    f: &File,
+    params: &G2Params,
+    result: &mut G2Result,
+) -> Result<(), Error> {
+    if let Some(channel_delays) = params.channel_delays {
+        let (delay_1, delay_2) = match channel_delays {
+            ChannelDelays::Manual(delay_1, delay_2) => (delay_1, delay_2),
+            ChannelDelays::FromHeader => {
+                let offsets = f.input_offsets()?;
+                (
+                    offsets.get(&params.channel_1).copied().unwrap_or(0.0),
+                    offsets.get(&params.channel_2).copied().unwrap_or(0.0),
+                )
+            }
+        };
+        // Delays are positive on the channel_2 side of the histogram
+        for t in result.t.iter_mut() {
+            *t -= delay_2 - delay_1;
+        }
+    }
+    Ok(())
+}
+
+/// Computes the symmetric g2 between two pre-separated lists of click times.
+///
+/// Both `ch1` and `ch2` must be sorted in ascending order. `window` and `resolution`
+/// are expressed in the same units as the timestamps and so is the `t` axis of the
+/// returned histogram. Fails if the resolution is zero or wider than the window.
+pub fn g2_from_slices(
+    ch1: &[u64],
+    ch2: &[u64],
+    window: u64,
+    resolution: u64,
+) -> Result<G2Result, Error> {
+    let params = G2Params::new(0, 1, window as f64, resolution as f64);
+    let tt = g2_core::G2::init(&params, 1.0)?;
+    let mut g2_histogram = vec![0; tt.n_bins()];
+    let mut t_histogram = vec![0.0; tt.n_bins()];
+    let mut raw_deltas = vec![];
+
+    // Merge both channels back into a single time ordered stream
+    let records = merge_ordered(
+        ch1.iter().map(|&tof| TTTRRecord::new(0, tof)),
+        ch2.iter().map(|&tof| TTTRRecord::new(1, tof)),
+    );
+    tt.compute(
+        records,
+        &mut g2_histogram,
+        &mut t_histogram,
+        &mut raw_deltas,
+    );
+
+    Ok(G2Result {
+        hist: g2_histogram,
+        t: t_histogram,
+        raw_deltas: None,
+        centered: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        encode_hht2_hh2, encode_pht2, random_clicks, write_ptu, Event, Tag, HHT2_HH2, PHT2,
+    };
+    use crate::tttr_tools::zero_finder::{zerofinder, ZeroFinderParams};
+    use crate::Click;
+
+    fn params(channel_1: i32, channel_2: i32) -> G2Params {
+        G2Params::new(channel_1, channel_2, 1e-6, 10e-9)
+    }
+
+    #[test]
+    fn photons_correlate_against_remapped_markers() {
+        // A marker every 4 us followed by a photon 200 ns later, 4 ps ticks
+        let events: Vec<Event> = (1..=500u64)
+            .flat_map(|k| {
+                let marker = k * 1_000_000;
+                vec![Event::Marker(1, marker), Event::Photon(0, marker + 50_000)]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let mut params = params(101, 0);
+        params.marker_channels = Some(100);
+        let result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+
+        let peak = (0..result.hist.len())
+            .max_by_key(|&i| result.hist[i])
+            .unwrap();
+        assert_eq!(result.hist[peak], 500);
+        assert!((result.tau_of(peak) - 200e-9).abs() <= 10e-9);
+        assert_eq!(result.hist.iter().sum::<u64>(), 500);
+    }
+
+    #[test]
+    fn g2_from_slices_matches_file() {
+        let events = random_clicks(10_000, 6_250, 3);
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(0, 1);
+        params.correlation_window = 100e-9;
+        params.resolution = 1e-9;
+        let from_file = g2(&f, &params, G2Mode::Symmetric).unwrap();
+
+        let times = |channel: i32| -> Vec<u64> {
+            f.records()
+                .unwrap()
+                .filter(|rec| *rec.channel() == channel)
+                .map(|rec| *rec.tof())
+                .collect()
+        };
+        // Same bins in ticks of 4 ps as the file g2
+        let resolution = (params.effective_resolution(4e-12) / 4e-12).round() as u64;
+        let window = (params.n_bins() / 2) as u64 * resolution;
+        let from_slices = g2_from_slices(&times(0), &times(1), window, resolution).unwrap();
+        assert_eq!(from_slices.hist, from_file.hist);
+    }
+
+    #[test]
+    fn header_offsets_shift_the_delay_axis() {
+        // Inputs 0 and 1 of a HydraHarp on channels 1 and 2
+        let events: Vec<Event> = random_clicks(1_000, 10_000, 4)
+            .into_iter()
+            .map(|event| match event {
+                Event::Photon(channel, tof) => Event::Photon(channel + 1, tof),
+                marker => marker,
+            })
+            .collect();
+        let tags = [
+            ("HW_InpChannelOffset[0]", Tag::Int8(100)),
+            ("HW_InpChannelOffset[1]", Tag::Int8(350)),
+        ];
+        let tmp = write_ptu(HHT2_HH2, 1e-12, &tags, &encode_hht2_hh2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let mut params = params(1, 2);
+        let plain = g2(&f, &params, G2Mode::Symmetric).unwrap();
+        params.channel_delays = Some(ChannelDelays::FromHeader);
+        let corrected = g2(&f, &params, G2Mode::Symmetric).unwrap();
+
+        assert_eq!(corrected.hist, plain.hist);
+        for (t, t_plain) in corrected.t.iter().zip(plain.t.iter()) {
+            assert!((t - (t_plain - 250e-12)).abs() < 1e-15);
+        }
+    }
+
+    #[test]
+    fn sub_tick_resolutions_are_invalid_params() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(100, 1_000, 5)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        // Ten bins over a window of two ticks
+        let mut params = params(0, 1);
+        params.correlation_window = 10e-12;
+        params.resolution = 1e-12;
+
+        for &mode in &[G2Mode::Symmetric, G2Mode::Asymmetric] {
+            assert!(matches!(
+                g2(&f, &params, mode),
+                Err(Error::InvalidParams(_))
+            ));
+        }
+        assert!(matches!(
+            g2_engine::G2Engine::new(&params, 4e-12),
+            Err(Error::InvalidParams(_))
+        ));
+        let zero_finder_params = ZeroFinderParams {
+            channel_1: 0,
+            channel_2: 1,
+            correlation_window: 10e-12,
+            resolution: 1e-12,
+        };
+        assert!(matches!(
+            zerofinder(&f, &zero_finder_params),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn zero_delay_follows_the_bin_layout() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 2_500, 7)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+
+        let mut centered_params = params(0, 1);
+        centered_params.centered = true;
+        let centered = g2(&f, &centered_params, G2Mode::Symmetric).unwrap();
+        let center = centered.hist.len() / 2;
+        assert!(centered.centered);
+        assert_eq!(centered.t[center], 0.0);
+        assert_eq!(centered.g2_zero(), centered.hist[center]);
+        assert_eq!(centered.tau_of(center), 0.0);
+
+        let edges = g2(&f, &params(0, 1), G2Mode::Symmetric).unwrap();
+        let center = edges.t.iter().position(|&t| t == 0.0).unwrap();
+        assert!(!edges.centered);
+        assert_eq!(edges.g2_zero(), edges.hist[center - 1] + edges.hist[center]);
+        assert!((edges.tau_of(center) - 5e-9).abs() < 1e-15);
+
+        // An odd number of bins that starts at zero delay
+        let mut odd_params = params(0, 1);
+        odd_params.correlation_window = 1.05e-6;
+        odd_params.resolution = 50e-9;
+        let odd = g2(&f, &odd_params, G2Mode::Asymmetric).unwrap();
+        assert_eq!(odd.hist.len() % 2, 1);
+        assert_eq!(odd.t[0], 0.0);
+        assert_eq!(odd.g2_zero(), odd.hist[0]);
+        assert!((odd.tau_of(0) - 25e-9).abs() < 1e-15);
+    }
+
+    #[test]
+    fn adaptive_fine_region_matches_uniform_bins() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 2_500, 9)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(0, 1);
+        params.resolution = 1e-9;
+        let uniform = g2(&f, &params, G2Mode::Symmetric).unwrap();
+        let adaptive = g2(
+            &f,
+            &params,
+            G2Mode::Adaptive {
+                fine_window: 100e-9,
+                coarse_factor: 7,
+            },
+        )
+        .unwrap();
+
+        let n_fine = 100;
+        let center_uniform = uniform.hist.len() / 2;
+        let center_adaptive = adaptive.hist.len() / 2;
+        assert!(adaptive.hist.len() < uniform.hist.len());
+        let fine = |result: &G2Result, center: usize| {
+            let bins = center - n_fine..center + n_fine;
+            (result.hist[bins.clone()].to_vec(), result.t[bins].to_vec())
+        };
+        assert_eq!(
+            fine(&adaptive, center_adaptive),
+            fine(&uniform, center_uniform)
+        );
+        // Coarse bins merge the rest of the window, cut short at its edges
+        assert_eq!(
+            adaptive.hist.iter().sum::<u64>(),
+            uniform.hist.iter().sum::<u64>()
+        );
+        assert!((adaptive.t[center_adaptive + n_fine] - 100e-9).abs() < 1e-15);
+        assert!((adaptive.t[center_adaptive + n_fine + 1] - 107e-9).abs() < 1e-15);
+
+        let invalid = G2Mode::Adaptive {
+            fine_window: f64::NAN,
+            coarse_factor: 7,
+        };
+        assert!(matches!(
+            g2(&f, &params, invalid),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn g2_from_slices_rejects_zero_resolution() {
+        assert!(g2_from_slices(&[1, 2], &[3], 100, 0).is_err());
+        assert!(g2_from_slices(&[1, 2], &[3], 100, 200).is_err());
+    }
+
+    #[test]
+    fn n_bins_matches_the_histogram_length() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(2_000, 6_250, 5)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        for &(correlation_window, resolution) in &[(1e-6, 10e-9), (1e-6, 30e-9), (250e-9, 4e-9)] {
+            for &centered in &[false, true] {
+                let mut params = params(0, 1);
+                params.correlation_window = correlation_window;
+                params.resolution = resolution;
+                params.centered = centered;
+                let symmetric = g2(&f, &params, G2Mode::Symmetric).unwrap();
+                assert_eq!(symmetric.hist.len(), params.n_bins());
+                assert_eq!(symmetric.t.len(), params.n_bins());
+                if !centered {
+                    let asymmetric = g2(&f, &params, G2Mode::Asymmetric).unwrap();
+                    assert_eq!(asymmetric.hist.len(), params.n_bins() / 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(2_000, 6_250, 8)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(0, 1);
+        params.raw_deltas_window = Some(100e-9);
+        let result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+        let (t, hist, raw_deltas) = (
+            result.t.clone(),
+            result.hist.clone(),
+            result.raw_deltas.clone(),
+        );
+
+        let mut copy = result.clone();
+        copy.t[0] = 1.0;
+        copy.hist.iter_mut().for_each(|x| *x += 1);
+        copy.raw_deltas.as_mut().unwrap().push(7);
+        assert_eq!(result.t, t);
+        assert_eq!(result.hist, hist);
+        assert_eq!(result.raw_deltas, raw_deltas);
+        assert_ne!(copy.hist, hist);
+    }
+
+    #[test]
+    fn swapped_channels_mirror_the_histogram() {
+        // Channel 1 lags channel 0 by 200 ns, so the peak sits off zero delay
+        let events: Vec<Event> = (1..=2_000u64)
+            .flat_map(|k| {
+                let tof = k * 1_000_000 + (k * 7919) % 100_000;
+                vec![Event::Photon(0, tof), Event::Photon(1, tof + 50_000)]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let forward = g2(&f, &params(0, 1), G2Mode::Symmetric).unwrap();
+        let swapped = g2(&f, &params(1, 0), G2Mode::Symmetric).unwrap();
+        assert!(forward.hist.iter().sum::<u64>() > 0);
+        assert!(forward.is_symmetric_swap(&swapped));
+        assert!(swapped.is_symmetric_swap(&forward));
+        assert!(!forward.is_symmetric_swap(&forward));
+
+        let mut coarser = params(1, 0);
+        coarser.resolution = 20e-9;
+        let coarser = g2(&f, &coarser, G2Mode::Symmetric).unwrap();
+        assert!(!forward.is_symmetric_swap(&coarser));
+    }
+
+    #[test]
+    fn raw_deltas_reproduce_the_central_bins() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 6_250, 4)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(0, 1);
+        params.raw_deltas_window = Some(100e-9);
+        let result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+
+        // 10 ns bins are 2500 ticks and the raw window spans 10 bins on each side.
+        // Like the g2, negative delays are binned as the mirror of the positive ones.
+        let (resolution, n_raw) = (2_500, 10);
+        let central = result.hist.len() / 2;
+        let mut rebinned = vec![0; 2 * n_raw];
+        for &delta in result.raw_deltas.as_ref().unwrap() {
+            let bin = if delta < 0 {
+                n_raw - 1 - (delta.unsigned_abs() / resolution) as usize
+            } else {
+                n_raw + (delta as u64 / resolution) as usize
+            };
+            rebinned[bin] += 1;
+        }
+        assert!(rebinned.iter().sum::<u64>() > 0);
+        assert_eq!(rebinned, result.hist[central - n_raw..central + n_raw]);
+    }
+
+    #[test]
+    fn autocorrelation_matches_a_two_channel_split() {
+        let tofs: Vec<u64> = random_clicks(10_000, 6_250, 5)
+            .iter()
+            .map(Event::tof)
+            .collect();
+        let single = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(
+                &tofs
+                    .iter()
+                    .map(|&tof| Event::Photon(0, tof))
+                    .collect::<Vec<_>>(),
+            ),
+        );
+        // Every click copied onto two virtual channels. Other than the copies
+        // correlating with each other at zero delay, the cross correlation sees
+        // each pair of clicks once at each sign of their delay.
+        let split = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(
+                &tofs
+                    .iter()
+                    .flat_map(|&tof| vec![Event::Photon(0, tof), Event::Photon(1, tof)])
+                    .collect::<Vec<_>>(),
+            ),
+        );
+        let mut params = params(0, 0);
+        params.correlation_window = 100e-9;
+        params.resolution = 1e-9;
+        let auto = g2(
+            &File::open(single.path()).unwrap(),
+            &params,
+            G2Mode::Symmetric,
+        )
+        .unwrap();
+        params.channel_2 = 1;
+        let mut cross = g2(
+            &File::open(split.path()).unwrap(),
+            &params,
+            G2Mode::Symmetric,
+        )
+        .unwrap();
+
+        let central = cross.hist.len() / 2;
+        assert!(cross.hist[central] >= tofs.len() as u64);
+        cross.hist[central] -= tofs.len() as u64;
+        assert!(auto.hist.iter().sum::<u64>() > 0);
+        assert_eq!(auto.t, cross.t);
+        assert_eq!(auto.hist, cross.hist);
+        assert!(auto.hist.iter().eq(auto.hist.iter().rev()));
+    }
+
+    #[test]
+    fn symmetrizing_a_symmetric_histogram_is_a_no_op() {
+        // The autocorrelation is symmetric by construction
+        let events: Vec<Event> = random_clicks(5_000, 6_250, 6)
+            .iter()
+            .map(|event| Event::Photon(0, event.tof()))
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let mut result = g2(&f, &params(0, 0), G2Mode::Symmetric).unwrap();
+        assert!(result.hist.iter().sum::<u64>() > 0);
+
+        let hist: Vec<f64> = result.hist.iter().map(|&x| x as f64).collect();
+        assert_eq!(result.symmetrize(), hist);
+
+        // Otherwise each side gets the mean of the pair
+        let central = result.hist.len() / 2;
+        result.hist[central + 3] += 4;
+        let symmetrized = result.symmetrize();
+        assert_eq!(symmetrized[central + 3], hist[central + 3] + 2.0);
+        assert_eq!(symmetrized[central - 4], hist[central - 4] + 2.0);
+        assert!(symmetrized.iter().eq(symmetrized.iter().rev()));
+    }
+
+    #[test]
+    fn centered_bins_hold_zero_delay_in_the_middle() {
+        // Pairs 10 us apart, far beyond the window. Channel 1 is shifted by the
+        // given number of 4 ps ticks, the 10 ns bins are 2500 ticks wide.
+        let shifts = [-2_500i64, -1_000, -500, 0, 0, 500, 1_000, 2_500, 2_500];
+        let events: Vec<Event> = shifts
+            .iter()
+            .enumerate()
+            .flat_map(|(k, &shift)| {
+                let tof = (k as i64 + 1) * 2_500_000;
+                let mut pair = vec![
+                    Event::Photon(0, tof as u64),
+                    Event::Photon(1, (tof + shift) as u64),
+                ];
+                pair.sort_by_key(Event::tof);
+                pair
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(0, 1);
+        params.centered = true;
+        let result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+
+        let n = result.hist.len();
+        let center = n / 2;
+        assert_eq!(n % 2, 1);
+        assert_eq!(result.t[center], 0.0);
+        for k in 1..=center {
+            assert_eq!(result.t[center + k], -result.t[center - k]);
+        }
+        assert!((result.t[center + 1] - 10e-9).abs() < 1e-15);
+        assert_eq!(result.hist[center], 6);
+        assert_eq!(result.hist[center - 1], 1);
+        assert_eq!(result.hist[center + 1], 2);
+        assert_eq!(result.hist.iter().sum::<u64>(), 9);
+    }
+
+    #[test]
+    fn last_start_only_ignores_older_starts() {
+        // Starts 300 ns and 200 ns before each stop and another one 95 ns after it,
+        // repeated every 10 us. 10 ns bins are 2500 ticks of 4 ps.
+        let events: Vec<Event> = (1..=500u64)
+            .flat_map(|k| {
+                let tof = k * 2_500_000;
+                vec![
+                    Event::Photon(0, tof),
+                    Event::Photon(0, tof + 25_000),
+                    Event::Photon(1, tof + 75_000),
+                    Event::Photon(0, tof + 98_750),
+                ]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let full = g2(&f, &params(0, 1), G2Mode::Symmetric).unwrap();
+        let mut gated_params = params(0, 1);
+        gated_params.last_start_only = true;
+        let gated = g2(&f, &gated_params, G2Mode::Symmetric).unwrap();
+
+        let central = full.hist.len() / 2;
+        assert_eq!(full.hist[central + 30], 500);
+        assert_eq!(full.hist[central + 20], 500);
+        assert_eq!(gated.hist[central + 30], 0);
+        assert_eq!(gated.hist[central + 20], 500);
+        assert_eq!(full.hist.iter().sum::<u64>(), 1_500);
+        assert_eq!(gated.hist.iter().sum::<u64>(), 1_000);
+        // Stops followed by a start are correlated the same way
+        assert_eq!(full.hist[central - 10], 500);
+        assert_eq!(full.hist[..central], gated.hist[..central]);
+    }
+
+    #[test]
+    fn symmetrizing_a_centered_histogram_keeps_the_middle_bin() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(5_000, 6_250, 6)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let params = G2Params {
+            centered: true,
+            ..params(0, 1)
+        };
+        let mut result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+        assert_eq!(result.hist.len() % 2, 1);
+        let central = result.hist.len() / 2;
+        result.hist[central] = 10;
+        result.hist[central + 3] = 4;
+        result.hist[central - 3] = 0;
+
+        let symmetrized = result.symmetrize();
+        assert_eq!(symmetrized[central], 10.0);
+        assert_eq!(symmetrized[central + 3], 2.0);
+        assert_eq!(symmetrized[central - 3], 2.0);
+        for (i, &x) in symmetrized.iter().enumerate() {
+            let mirror = result.hist.len() - 1 - i;
+            assert_eq!(x, ((result.hist[i] + result.hist[mirror]) as f64) / 2.0);
+        }
     }
 }
@@ -1,14 +1,19 @@
 use crate::{
     errors::Error,
     headers::{File, RecordType},
-    parsers::ptu,
+    parsers::{custom::CustomStream, ptu},
     tttr_tools::colored_circular_buffer::CCircularBuffer,
-    {Click, TTTRFile, TTTRStream},
+    {to_native_units, Click, TTTRFile, TTTRStream},
 };
 use std::fmt::Debug;
+#[cfg(feature = "mmap")]
+use std::path::Path;
 
 use ndarray::Array2;
 
+#[cfg(feature = "mmap")]
+use crate::tttr_tools::mapped_histogram::MappedHistogram;
+
 const MAX_BUFFER_SIZE: usize = 4096;
 
 struct G3<P: TTTRStream + Iterator> {
@@ -17,9 +22,51 @@ struct G3<P: TTTRStream + Iterator> {
 }
 
 /// Result from the g3 algorithm
+#[derive(Debug, Clone)]
 pub struct G3Result {
     pub t: Vec<f64>,
     pub hist: Array2<u64>,
+    /// Bin width in seconds actually used on both axes. The requested resolution is
+    /// rounded to a whole number of ticks of the file.
+    pub effective_resolution: f64,
+}
+
+impl G3Result {
+    /// Iterate over the histogram as `(tau1, tau2, count)` triples, with the delays
+    /// taken from the `t` axis. Every bin is visited, including empty ones.
+    pub fn iter_points(&self) -> impl Iterator<Item = (f64, f64, u64)> + '_ {
+        self.hist
+            .indexed_iter()
+            .map(move |((i, j), &count)| (self.t[i], self.t[j], count))
+    }
+
+    /// Same as `iter_points` but skipping the bins with zero counts.
+    pub fn iter_nonzero_points(&self) -> impl Iterator<Item = (f64, f64, u64)> + '_ {
+        self.iter_points().filter(|&(_, _, count)| count > 0)
+    }
+
+    /// Counts on the `(i, j)` bin, or `None` if it is out of bounds.
+    pub fn get(&self, i: usize, j: usize) -> Option<u64> {
+        self.hist.get([i, j]).copied()
+    }
+}
+
+/// Counts on the `(i, j)` bin. Panics if it is out of bounds.
+impl std::ops::Index<(usize, usize)> for G3Result {
+    type Output = u64;
+
+    fn index(&self, idx: (usize, usize)) -> &u64 {
+        &self.hist[[idx.0, idx.1]]
+    }
+}
+
+/// Result from the g3 algorithm with the histogram stored on a memory mapped file
+#[cfg(feature = "mmap")]
+pub struct G3MappedResult {
+    pub t: Vec<f64>,
+    pub hist: MappedHistogram,
+    /// Bin width in seconds actually used on both axes.
+    pub effective_resolution: f64,
 }
 
 /// Parameters for the g3 algorithm
@@ -30,6 +77,9 @@ pub struct G3Result {
 ///    - channel_3: The number of the third input channel into the TCSPC
 ///    - correlation_window: Length of the correlation window of interest in seconds
 ///    - resolution: Resolution of the g3 histogram in seconds
+///    - memory_limit: Optional maximum size in bytes of the histogram. `g3` refuses
+///      to allocate larger histograms instead of running out of memory. It does not
+///      apply to `g3_mapped`.
 #[derive(Debug, Copy, Clone)]
 pub struct G3Params {
     pub channel_1: i32,
@@ -39,24 +89,114 @@ pub struct G3Params {
     pub resolution: f64,
     pub start_record: Option<usize>,
     pub stop_record: Option<usize>,
+    pub memory_limit: Option<usize>,
+}
+
+impl G3Params {
+    /// Number of bins along each axis of the histogram.
+    pub fn n_bins(&self) -> usize {
+        2 * (self.correlation_window / self.resolution) as usize
+    }
+
+    /// Size in bytes of the histogram allocated by `g3` on a stream with the given
+    /// `time_resolution` in seconds, which grows with the square of the number of
+    /// bins. Bins can't be finer than a tick of the stream. Saturates at `usize::MAX`.
+    pub fn estimated_memory(&self, time_resolution: f64) -> usize {
+        let resolution = self.resolution.max(time_resolution);
+        let n_bins = 2 * (self.correlation_window / resolution) as usize;
+        n_bins
+            .saturating_mul(n_bins)
+            .saturating_mul(std::mem::size_of::<u64>())
+    }
+
+    /// Check that the resolution fits within the correlation window.
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.resolution > 0.0) || self.resolution > self.correlation_window {
+            return Err(Error::InvalidParams(format!(
+                "The g3 resolution must be positive and no larger than the correlation window but got {} and {}",
+                self.resolution, self.correlation_window
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Storage of the g3 counts.
+trait G3Bins {
+    fn bin_mut(&mut self, idx1: usize, idx2: usize) -> Option<&mut u64>;
+}
+
+impl G3Bins for Array2<u64> {
+    #[inline(always)]
+    fn bin_mut(&mut self, idx1: usize, idx2: usize) -> Option<&mut u64> {
+        self.get_mut([idx1, idx2])
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl G3Bins for MappedHistogram {
+    #[inline(always)]
+    fn bin_mut(&mut self, idx1: usize, idx2: usize) -> Option<&mut u64> {
+        self.get_mut(idx1, idx2)
+    }
+}
+
+/// Histogram index of a negative delay or `None` if it falls outside the histogram.
+#[inline(always)]
+fn negative_idx(central_bin: u64, tau: u64, resolution: u64) -> Option<usize> {
+    central_bin
+        .checked_sub(tau / resolution + 1)
+        .map(|idx| idx as usize)
+}
+
+/// Histogram index of a positive delay or `None` if it falls outside the histogram.
+#[inline(always)]
+fn positive_idx(central_bin: u64, tau: u64, resolution: u64, n_bins: u64) -> Option<usize> {
+    let idx = central_bin + tau / resolution;
+    if idx < n_bins {
+        Some(idx as usize)
+    } else {
+        None
+    }
+}
+
+/// Add a count to the `(idx1, idx2)` bin skipping indices out of range.
+#[inline(always)]
+fn increment_bin<H: G3Bins>(histogram: &mut H, idx1: Option<usize>, idx2: Option<usize>) {
+    if let (Some(idx1), Some(idx2)) = (idx1, idx2) {
+        if let Some(bin) = histogram.bin_mut(idx1, idx2) {
+            *bin += 1;
+        }
+    }
 }
 
 impl<P: TTTRStream + Iterator> G3<P> {
-    fn compute(self) -> G3Result
+    /// Accumulate the counts into `histogram` and return the delay axis and the
+    /// effective resolution.
+    fn compute<H: G3Bins>(self, histogram: &mut H) -> Result<(Vec<f64>, f64), Error>
     where
         <P as Iterator>::Item: Debug + Click,
     {
         let real_resolution = self.params.resolution.clone();
         let n_bins = (self.params.correlation_window / self.params.resolution) as u64;
-        let correlation_window =
-            self.params.correlation_window / (self.click_stream.time_resolution());
+        let correlation_window = to_native_units(
+            self.params.correlation_window,
+            self.click_stream.time_resolution(),
+        )? as f64;
 
         let resolution = (correlation_window / (n_bins as f64)) as u64;
+        if resolution == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The g3 resolution {} is finer than the time resolution of the file {}",
+                real_resolution,
+                self.click_stream.time_resolution()
+            )));
+        }
+        let effective_resolution = (resolution as f64) * self.click_stream.time_resolution();
         let correlation_window = n_bins * resolution;
         let n_bins = n_bins * 2;
 
         let central_bin = n_bins / 2;
-        let mut histogram = Array2::<u64>::zeros((n_bins as usize, n_bins as usize));
 
         let mut click_buffer = CCircularBuffer::new(MAX_BUFFER_SIZE);
 
@@ -128,9 +268,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta12;
                                 let tau2 = delta13;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin - tau1 / resolution - 1;
-                                    let idx2 = central_bin - tau2 / resolution - 1;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = negative_idx(central_bin, tau1, resolution);
+                                    let idx2 = negative_idx(central_bin, tau2, resolution);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -141,9 +281,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta13;
                                 let tau2 = delta12;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin - tau1 / resolution - 1;
-                                    let idx2 = central_bin - tau2 / resolution - 1;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = negative_idx(central_bin, tau1, resolution);
+                                    let idx2 = negative_idx(central_bin, tau2, resolution);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -166,9 +306,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta12;
                                 let tau2 = delta23;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin + tau1 / resolution;
-                                    let idx2 = central_bin - tau2 / resolution - 1;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = positive_idx(central_bin, tau1, resolution, n_bins);
+                                    let idx2 = negative_idx(central_bin, tau2, resolution);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -179,9 +319,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta13;
                                 let tau2 = delta23;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin + tau1 / resolution;
-                                    let idx2 = central_bin + tau2 / resolution;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = positive_idx(central_bin, tau1, resolution, n_bins);
+                                    let idx2 = positive_idx(central_bin, tau2, resolution, n_bins);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -194,9 +334,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta23;
                                 let tau2 = delta12;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin - tau1 / resolution - 1;
-                                    let idx2 = central_bin + tau2 / resolution;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = negative_idx(central_bin, tau1, resolution);
+                                    let idx2 = positive_idx(central_bin, tau2, resolution, n_bins);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -207,9 +347,9 @@ impl<P: TTTRStream + Iterator> G3<P> {
                                 let tau1 = delta23;
                                 let tau2 = delta13;
                                 if tau1 < correlation_window && tau2 < correlation_window {
-                                    let idx1 = central_bin + tau1 / resolution;
-                                    let idx2 = central_bin + tau2 / resolution;
-                                    histogram[[idx1 as usize, idx2 as usize]] += 1;
+                                    let idx1 = positive_idx(central_bin, tau1, resolution, n_bins);
+                                    let idx2 = positive_idx(central_bin, tau2, resolution, n_bins);
+                                    increment_bin(histogram, idx1, idx2);
                                 } else {
                                     break;
                                 }
@@ -228,10 +368,7 @@ impl<P: TTTRStream + Iterator> G3<P> {
         let t = (0..n_bins)
             .map(|i| ((i as f64) - (central_bin as f64)) * real_resolution)
             .collect::<Vec<f64>>();
-        G3Result {
-            t: t,
-            hist: histogram,
-        }
+        Ok((t, effective_resolution))
     }
 }
 
@@ -269,6 +406,55 @@ impl<P: TTTRStream + Iterator> G3<P> {
 /// consideration apply. See the [second order autocorrelation documentation](tttr_tools/g2/fn.g2.html).
 ///
 pub fn g3(f: &File, params: &G3Params) -> Result<G3Result, Error> {
+    params.validate()?;
+    if let Some(memory_limit) = params.memory_limit {
+        let memory = params.estimated_memory(f.records()?.time_resolution());
+        if memory > memory_limit {
+            return Err(Error::InvalidParams(format!(
+                "The g3 histogram needs {} bytes which is above the limit of {} bytes",
+                memory, memory_limit
+            )));
+        }
+    }
+    let n_bins = params.n_bins();
+    let mut hist = Array2::<u64>::zeros((n_bins, n_bins));
+    let (t, effective_resolution) = g3_into(f, params, &mut hist)?;
+    Ok(G3Result {
+        t,
+        hist,
+        effective_resolution,
+    })
+}
+
+/// Same as [g3](fn.g3.html) but accumulating the histogram on a memory mapped file
+/// at `path`.
+///
+/// Computing a g3 at fine resolution over a wide window can need a histogram larger
+/// than the available RAM. Here the counts are written straight into the mapped file
+/// and the OS pages them in and out of disk as needed. This is slower than the in
+/// memory g3, especially when the histogram does not fit in RAM, but makes it possible
+/// to compute otherwise impossible histograms. The file is overwritten if it exists
+/// and it is left on disk once the result is dropped. See `MappedHistogram` for its
+/// layout.
+#[cfg(feature = "mmap")]
+pub fn g3_mapped(f: &File, params: &G3Params, path: &Path) -> Result<G3MappedResult, Error> {
+    params.validate()?;
+    let n_bins = params.n_bins();
+    let mut hist = MappedHistogram::create(path, (n_bins, n_bins))?;
+    let (t, effective_resolution) = g3_into(f, params, &mut hist)?;
+    hist.flush()?;
+    Ok(G3MappedResult {
+        t,
+        hist,
+        effective_resolution,
+    })
+}
+
+fn g3_into<H: G3Bins>(
+    f: &File,
+    params: &G3Params,
+    histogram: &mut H,
+) -> Result<(Vec<f64>, f64), Error> {
     let start_record = params.start_record;
     let stop_record = params.stop_record;
     match f {
@@ -279,7 +465,7 @@ pub fn g3(f: &File, params: &G3Params) -> Result<G3Result, Error> {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(histogram)
             }
             RecordType::HHT2_HH1 => {
                 let stream = ptu::streamers::HHT2_HH1Stream::new(x, start_record, stop_record)?;
@@ -287,7 +473,7 @@ pub fn g3(f: &File, params: &G3Params) -> Result<G3Result, Error> {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(histogram)
             }
             RecordType::HHT2_HH2 => {
                 let stream = ptu::streamers::HHT2_HH2Stream::new(x, start_record, stop_record)?;
@@ -295,7 +481,7 @@ pub fn g3(f: &File, params: &G3Params) -> Result<G3Result, Error> {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(histogram)
             }
             RecordType::HHT3_HH2 => {
                 let stream = ptu::streamers::HHT3_HH2Stream::new(x, start_record, stop_record)?;
@@ -303,9 +489,210 @@ pub fn g3(f: &File, params: &G3Params) -> Result<G3Result, Error> {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(histogram)
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(x) => {
+            let stream = CustomStream::new(x.as_ref(), start_record, stop_record)?;
+            let tt = G3 {
+                click_stream: stream,
+                params: *params,
+            };
+            tt.compute(histogram)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, TestFile, PHT2};
+
+    /// Photons cycling through channels 0, 1 and 2 about 4 ns apart.
+    fn three_channel_file() -> TestFile {
+        let events: Vec<Event> = random_clicks(20_000, 1_000, 3)
+            .into_iter()
+            .enumerate()
+            .map(|(k, event)| match event {
+                Event::Photon(_, tof) => Event::Photon((k % 3) as i32, tof),
+                marker => marker,
+            })
+            .collect();
+        write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events))
+    }
+
+    fn params() -> G3Params {
+        G3Params {
+            channel_1: 0,
+            channel_2: 1,
+            channel_3: 2,
+            correlation_window: 10e-9,
+            resolution: 1e-9,
+            start_record: None,
+            stop_record: None,
+            memory_limit: None,
+        }
+    }
+
+    #[test]
+    fn every_bin_is_a_point() {
+        let tmp = three_channel_file();
+        let result = g3(&File::open(tmp.path()).unwrap(), &params()).unwrap();
+        let n_bins = params().n_bins();
+        assert_eq!(result.t.len(), n_bins);
+        assert_eq!(result.iter_points().count(), n_bins * n_bins);
+
+        let total: u64 = result.iter_points().map(|(_, _, count)| count).sum();
+        assert!(total > 0);
+        assert_eq!(total, result.hist.sum());
+        assert_eq!(
+            result.iter_nonzero_points().count(),
+            result.hist.iter().filter(|&&x| x > 0).count()
+        );
+        // Rows follow tau1 and columns tau2
+        let (tau1, tau2, count) = result.iter_points().nth(n_bins + 2).unwrap();
+        assert_eq!(
+            (tau1, tau2, count),
+            (result.t[1], result.t[2], result[(1, 2)])
+        );
+    }
+
+    #[test]
+    fn sub_tick_resolution_is_invalid_params() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(100, 1_000, 5)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let params = G3Params {
+            channel_1: 0,
+            channel_2: 1,
+            channel_3: 2,
+            correlation_window: 10e-12,
+            resolution: 1e-12,
+            start_record: None,
+            stop_record: None,
+            memory_limit: None,
+        };
+        assert!(matches!(g3(&f, &params), Err(Error::InvalidParams(_))));
+    }
+
+    #[test]
+    fn absurd_resolutions_exceed_the_memory_limit() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(100, 1_000, 5)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = G3Params {
+            channel_1: 0,
+            channel_2: 1,
+            channel_3: 2,
+            correlation_window: 1e-6,
+            resolution: 1e-15,
+            start_record: None,
+            stop_record: None,
+            memory_limit: Some(1 << 30),
+        };
+        // Bins are a tick wide at most, i.e. 500000 bins per axis
+        assert_eq!(params.estimated_memory(4e-12), 500_000 * 500_000 * 8);
+        assert!(matches!(g3(&f, &params), Err(Error::InvalidParams(_))));
+
+        params.resolution = 10e-9;
+        assert_eq!(params.estimated_memory(4e-12), 200 * 200 * 8);
+        assert!(g3(&f, &params).is_ok());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_g3_matches_in_memory_g3() {
+        let events: Vec<Event> = random_clicks(20_000, 1_000, 3)
+            .into_iter()
+            .enumerate()
+            .map(|(k, event)| match event {
+                Event::Photon(_, tof) => Event::Photon((k % 3) as i32, tof),
+                marker => marker,
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let params = G3Params {
+            channel_1: 0,
+            channel_2: 1,
+            channel_3: 2,
+            correlation_window: 10e-9,
+            resolution: 1e-9,
+            start_record: None,
+            stop_record: None,
+            memory_limit: None,
+        };
+        let hist_path = tmp.path().with_extension("bin");
+
+        let in_memory = g3(&f, &params).unwrap();
+        let mapped = g3_mapped(&f, &params, &hist_path).unwrap();
+        assert!(in_memory.hist.sum() > 0);
+        assert_eq!(mapped.hist.view(), in_memory.hist);
+        assert_eq!(mapped.t, in_memory.t);
+        assert_eq!(mapped.effective_resolution, in_memory.effective_resolution);
+
+        drop(mapped);
+        let _ = std::fs::remove_file(hist_path);
+    }
+
+    #[test]
+    fn delays_on_the_window_edges_map_to_the_outer_bins() {
+        // 20 bins of 100 ticks with zero delay between bins 9 and 10
+        let (central_bin, resolution, n_bins) = (10, 100, 20);
+        assert_eq!(negative_idx(central_bin, 0, resolution), Some(9));
+        assert_eq!(negative_idx(central_bin, 99, resolution), Some(9));
+        assert_eq!(negative_idx(central_bin, 100, resolution), Some(8));
+        assert_eq!(negative_idx(central_bin, 999, resolution), Some(0));
+        assert_eq!(negative_idx(central_bin, 1_000, resolution), None);
+
+        assert_eq!(positive_idx(central_bin, 0, resolution, n_bins), Some(10));
+        assert_eq!(positive_idx(central_bin, 100, resolution, n_bins), Some(11));
+        assert_eq!(positive_idx(central_bin, 999, resolution, n_bins), Some(19));
+        assert_eq!(positive_idx(central_bin, 1_000, resolution, n_bins), None);
+    }
+
+    #[test]
+    fn non_divisible_resolution_is_reported() {
+        // 3 bins of 3 ns don't fit the 10 ns window, which is 2500 ticks of 4 ps
+        let tmp = three_channel_file();
+        let mut params = params();
+        params.resolution = 3e-9;
+        let result = g3(&File::open(tmp.path()).unwrap(), &params).unwrap();
+        assert_eq!(result.t.len(), 6);
+        assert!((result.effective_resolution - 833.0 * 4e-12).abs() < 1e-18);
+    }
+
+    #[test]
+    fn index_a_known_bin() {
+        // Channel 1 clicks 2 ns and channel 2 clicks 5 ns after channel 0, every 1 us
+        let events: Vec<Event> = (1..=100u64)
+            .flat_map(|k| {
+                let tof = k * 250_000;
+                vec![
+                    Event::Photon(0, tof),
+                    Event::Photon(1, tof + 500),
+                    Event::Photon(2, tof + 1_250),
+                ]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let result = g3(&File::open(tmp.path()).unwrap(), &params()).unwrap();
+
+        // 20 bins of 1 ns with zero delay between bins 9 and 10
+        assert_eq!(result.hist.sum(), 100);
+        assert_eq!(result[(12, 15)], 100);
+        assert_eq!(result.get(12, 15), Some(100));
+        assert_eq!(result.get(15, 12), Some(0));
+        assert_eq!(result.get(20, 0), None);
+        assert_eq!(result.get(0, 20), None);
     }
 }
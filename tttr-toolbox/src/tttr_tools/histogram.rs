@@ -0,0 +1,248 @@
+use crate::errors::Error;
+use crate::tttr_tools::{g2::G2Result, lifetime::LifetimeResult, zero_finder::ZeroFinderResult};
+
+/// Common analysis for the results made of a single histogram and its time axis.
+pub trait Histogram1D {
+    /// Bin delays in seconds.
+    fn t(&self) -> &[f64];
+    /// Counts on each bin.
+    fn hist(&self) -> &[u64];
+
+    /// Poisson standard deviation of the counts on each bin, `sqrt(n)`.
+    ///
+    /// Empty bins are given an error of one count instead of zero, `sqrt(max(1, n))`,
+    /// so that they don't get an infinite weight when fitting.
+    fn errors(&self) -> Vec<f64> {
+        self.hist()
+            .iter()
+            .map(|&count| (count.max(1) as f64).sqrt())
+            .collect()
+    }
+
+    /// Histogram as a rate density in counts per second per second of delay.
+    ///
+    /// Each bin is divided by `acquisition_time * bin_width`, with `acquisition_time`
+    /// in seconds, so that histograms from different experiments can be compared. The
+    /// bins are assumed to be evenly spaced. Use `File::acquisition_time` to read it
+    /// from the header when available.
+    fn as_rate(&self, acquisition_time: f64) -> Vec<f64> {
+        let t = self.t();
+        let bin_width = if t.len() > 1 { t[1] - t[0] } else { f64::NAN };
+        self.hist()
+            .iter()
+            .map(|&count| (count as f64) / (acquisition_time * bin_width))
+            .collect()
+    }
+
+    /// Full width at half maximum of the highest peak of the histogram in seconds.
+    ///
+    /// The half maximum crossings are found by linear interpolation between the bins
+    /// on each side of the crossing. If several bins share the maximum count the first
+    /// one is used as the peak. Flat or empty histograms, and peaks that don't fall back
+    /// below half maximum before the edges of the histogram, return an error.
+    fn fwhm(&self) -> Result<f64, Error> {
+        let (t, hist) = (self.t(), self.hist());
+
+        let mut peak_idx = 0;
+        for (i, &count) in hist.iter().enumerate() {
+            if count > hist[peak_idx] {
+                peak_idx = i;
+            }
+        }
+        let max_count = hist.get(peak_idx).copied().unwrap_or(0);
+        if max_count == 0 || hist.iter().all(|&x| x == max_count) {
+            return Err(Error::InvalidHistogram(String::from(
+                "Can't compute the FWHM of a flat histogram",
+            )));
+        }
+        let half_max = (max_count as f64) / 2.0;
+
+        // Linear interpolation of the crossing between bins `lo` and `hi`
+        let crossing = |lo: usize, hi: usize| {
+            let (y_lo, y_hi) = (hist[lo] as f64, hist[hi] as f64);
+            t[lo] + (half_max - y_lo) / (y_hi - y_lo) * (t[hi] - t[lo])
+        };
+
+        let left = (0..peak_idx)
+            .rev()
+            .find(|&i| (hist[i] as f64) < half_max)
+            .map(|i| crossing(i, i + 1));
+        let right = (peak_idx + 1..hist.len())
+            .find(|&i| (hist[i] as f64) < half_max)
+            .map(|i| crossing(i - 1, i));
+
+        match (left, right) {
+            (Some(left), Some(right)) => Ok(right - left),
+            _ => Err(Error::InvalidHistogram(String::from(
+                "The peak does not drop below half maximum within the histogram",
+            ))),
+        }
+    }
+
+    /// Peak normalized histogram averaged onto log spaced bins for display.
+    ///
+    /// The positive delays are grouped in bins `points_per_decade` per decade wide,
+    /// starting at the first positive delay, and the counts of the linear bins that
+    /// fall in each of them are averaged. Log bins narrower than the linear spacing
+    /// hold no linear bins and are skipped. Each point is placed at the mean delay of
+    /// the bins it averages. The zero delay bin can't be log binned and, if present,
+    /// is returned unchanged as the first point. Negative delays are dropped,
+    /// symmetrize the g2 first if both sides are of interest.
+    ///
+    /// Returns the delays in seconds and the counts divided by the maximum count of
+    /// the histogram. Panics if `points_per_decade` is zero.
+    fn resample_log(&self, points_per_decade: usize) -> (Vec<f64>, Vec<f64>) {
+        assert!(points_per_decade > 0, "points_per_decade must be positive");
+        let (t, hist) = (self.t(), self.hist());
+        let peak = hist.iter().copied().max().unwrap_or(0).max(1) as f64;
+
+        let mut t_log = vec![];
+        let mut hist_log = vec![];
+        if let Some(zero_idx) = t.iter().position(|&x| x == 0.0) {
+            t_log.push(0.0);
+            hist_log.push((hist[zero_idx] as f64) / peak);
+        }
+
+        let t_min = match t.iter().copied().find(|&x| x > 0.0) {
+            Some(x) => x,
+            None => return (t_log, hist_log),
+        };
+        let log_bin = |x: f64| ((x / t_min).log10() * (points_per_decade as f64)).floor() as i64;
+
+        // Bins are accumulated until a delay falls on the next log bin
+        let mut current = None;
+        let (mut t_sum, mut count_sum, mut n) = (0.0, 0.0, 0);
+        for (&x, &count) in t.iter().zip(hist.iter()).filter(|(&x, _)| x > 0.0) {
+            let bin = log_bin(x);
+            if current != Some(bin) && n > 0 {
+                t_log.push(t_sum / (n as f64));
+                hist_log.push(count_sum / (n as f64) / peak);
+                t_sum = 0.0;
+                count_sum = 0.0;
+                n = 0;
+            }
+            current = Some(bin);
+            t_sum += x;
+            count_sum += count as f64;
+            n += 1;
+        }
+        if n > 0 {
+            t_log.push(t_sum / (n as f64));
+            hist_log.push(count_sum / (n as f64) / peak);
+        }
+
+        (t_log, hist_log)
+    }
+}
+
+impl Histogram1D for G2Result {
+    fn t(&self) -> &[f64] {
+        &self.t
+    }
+    fn hist(&self) -> &[u64] {
+        &self.hist
+    }
+}
+
+impl Histogram1D for LifetimeResult {
+    fn t(&self) -> &[f64] {
+        &self.t
+    }
+    fn hist(&self) -> &[u64] {
+        &self.hist
+    }
+}
+
+impl Histogram1D for ZeroFinderResult {
+    fn t(&self) -> &[f64] {
+        &self.t
+    }
+    fn hist(&self) -> &[u64] {
+        &self.hist
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn g2_result(t: Vec<f64>, hist: Vec<u64>) -> G2Result {
+        G2Result {
+            t,
+            hist,
+            raw_deltas: None,
+            centered: true,
+        }
+    }
+
+    #[test]
+    fn fwhm_of_a_gaussian() {
+        let sigma = 1e-9;
+        let t: Vec<f64> = (-100..=100).map(|i| i as f64 * 0.1e-9).collect();
+        let hist = t
+            .iter()
+            .map(|x| (10_000.0 * (-x * x / (2.0 * sigma * sigma)).exp()).round() as u64)
+            .collect();
+        let expected = 2.0 * (2.0 * 2f64.ln()).sqrt() * sigma;
+        let fwhm = g2_result(t, hist).fwhm().unwrap();
+        assert!((fwhm - expected).abs() < 0.01 * expected);
+
+        let flat = g2_result(vec![0.0, 1.0, 2.0], vec![5, 5, 5]);
+        assert!(matches!(flat.fwhm(), Err(Error::InvalidHistogram(_))));
+        let edge = g2_result(vec![0.0, 1.0, 2.0], vec![10, 8, 1]);
+        assert!(matches!(edge.fwhm(), Err(Error::InvalidHistogram(_))));
+    }
+
+    #[test]
+    fn flat_histogram_as_rate() {
+        // 100 counts on every 1 ns bin over 2 s
+        let t: Vec<f64> = (0..50).map(|i| i as f64 * 1e-9).collect();
+        let rate = g2_result(t, vec![100; 50]).as_rate(2.0);
+        assert_eq!(rate.len(), 50);
+        for x in rate {
+            assert!((x - 5e10).abs() < 1e-6 * 5e10);
+        }
+    }
+
+    #[test]
+    fn errors_are_the_square_root_of_the_counts() {
+        let hist = vec![0, 1, 4, 9, 17, 1_000_000];
+        let t = (0..hist.len()).map(|i| i as f64).collect();
+        let result = g2_result(t, hist.clone());
+        let errors = result.errors();
+        assert_eq!(errors.len(), hist.len());
+        for (&count, &error) in hist.iter().zip(errors.iter()).skip(1) {
+            assert_eq!(error, (count as f64).sqrt());
+        }
+        // Empty bins get one count of error
+        assert_eq!(errors[0], 1.0);
+    }
+
+    #[test]
+    fn resample_log_of_a_power_law() {
+        // Counts proportional to the delay on 1 ns bins, so the averages are exact
+        let t: Vec<f64> = (-50..=10_000).map(|i| i as f64 * 1e-9).collect();
+        let hist = (-50..=10_000i64)
+            .map(|i| if i == 0 { 7 } else { 100 * i.unsigned_abs() })
+            .collect();
+        let (t_log, hist_log) = g2_result(t, hist).resample_log(5);
+
+        // Zero delay first, then 5 points per decade over 4 decades
+        assert_eq!(t_log.len(), 1 + 4 * 5 + 1);
+        assert_eq!(hist_log.len(), t_log.len());
+        assert_eq!((t_log[0], hist_log[0]), (0.0, 7.0 / 1e6));
+        assert_eq!(t_log[1], 1e-9);
+        assert_eq!(*hist_log.last().unwrap(), 1.0);
+        for (&x, &y) in t_log.iter().zip(hist_log.iter()).skip(1) {
+            assert!((y / x - 1e5).abs() < 1e-9 * 1e5);
+        }
+        // Away from the first decades there are enough linear bins for the points
+        // to be evenly spaced on a log axis. The last log bin only holds the last
+        // linear bin.
+        let step = 10f64.powf(1.0 / 5.0);
+        let full_bins = &t_log[..t_log.len() - 1];
+        for pair in full_bins.windows(2).filter(|pair| pair[0] > 100e-9) {
+            assert!((pair[1] / pair[0] - step).abs() < 0.02 * step);
+        }
+    }
+}
@@ -2,7 +2,9 @@ use crate::errors::Error;
 use crate::headers::{File, RecordType};
 use crate::parsers::ptu;
 //use crate::tttr_tools::circular_buffer::CircularBuffer;
-use crate::{Click, TTTRFile, TTTRStream};
+use crate::tttr_tools::histogram::Histogram1D;
+use crate::tttr_tools::merge::merge_ordered;
+use crate::{Click, RecordKind, TTTRFile, TTTRRecord, TTTRStream};
 use std::fmt::Debug;
 
 struct Lifetime<P: TTTRStream + Iterator> {
@@ -12,73 +14,305 @@ struct Lifetime<P: TTTRStream + Iterator> {
 }
 
 /// Result from the lifetime algorithm
+///
+/// Besides the decay histogram it includes the total number of clicks on it and their
+/// mean arrival time after the sync in seconds, a fit free lifetime estimate once the
+/// IRF offset is subtracted. The mean is `None` if the histogram is empty.
+#[derive(Debug, Clone)]
 pub struct LifetimeResult {
     pub t: Vec<f64>,
     pub hist: Vec<u64>,
+    pub total_counts: u64,
+    pub mean_arrival_time: Option<f64>,
+}
+
+/// Number of Richardson-Lucy iterations run by `LifetimeResult::deconvolve`
+const DECONVOLUTION_ITERATIONS: usize = 50;
+
+impl LifetimeResult {
+    /// Remove the blurring of the instrument response function (IRF) from the decay
+    /// with the Richardson-Lucy algorithm.
+    ///
+    /// `irf` can be e.g. a `ZeroFinderResult` or the lifetime of a reference sample
+    /// with a negligible lifetime, and must share the time grid of the decay. Its
+    /// position on the grid is kept, so the deconvolved decay starts at the time the
+    /// excitation reaches the sample. Since the histogram covers a whole sync period
+    /// the convolution is circular. A fixed number of iterations is run, which
+    /// sharpens the decay while limiting the amplification of the counting noise.
+    pub fn deconvolve(&self, irf: &dyn Histogram1D) -> Result<Vec<f64>, Error> {
+        let n = self.hist.len();
+        let bin_width = if n > 1 { self.t[1] - self.t[0] } else { 0.0 };
+        let same_grid = irf.t().len() == n
+            && irf
+                .t()
+                .iter()
+                .zip(self.t.iter())
+                .all(|(a, b)| (a - b).abs() <= 1e-6 * bin_width.abs());
+        if !same_grid {
+            return Err(Error::InvalidHistogram(String::from(
+                "The IRF and the decay must share the same time grid",
+            )));
+        }
+        let irf_total = irf.hist().iter().sum::<u64>();
+        if irf_total == 0 {
+            return Err(Error::InvalidHistogram(String::from("The IRF is empty")));
+        }
+        let irf = irf
+            .hist()
+            .iter()
+            .map(|&x| (x as f64) / (irf_total as f64))
+            .collect::<Vec<_>>();
+        let decay = self.hist.iter().map(|&x| x as f64).collect::<Vec<_>>();
+
+        // Circular convolution of `x` with the IRF
+        let convolve = |x: &[f64]| {
+            (0..n)
+                .map(|i| (0..n).map(|j| x[j] * irf[(n + i - j) % n]).sum::<f64>())
+                .collect::<Vec<f64>>()
+        };
+
+        // Start from a flat estimate with the total counts of the decay
+        let mut estimate = vec![decay.iter().sum::<f64>() / (n as f64); n];
+        for _ in 0..DECONVOLUTION_ITERATIONS {
+            let blurred = convolve(&estimate);
+            let ratio = decay
+                .iter()
+                .zip(blurred.iter())
+                .map(|(&d, &b)| if b > 0.0 { d / b } else { 0.0 })
+                .collect::<Vec<_>>();
+            for (j, x) in estimate.iter_mut().enumerate() {
+                // Correlation of the ratio with the IRF
+                *x *= (0..n).map(|i| ratio[i] * irf[(n + i - j) % n]).sum::<f64>();
+            }
+        }
+        Ok(estimate)
+    }
 }
 
 /// Parameters for the lifetime algorithm
 ///
 /// # Parameters
-///    - channel_sync: The number of the sync channel TCSPC
+///    - channel_sync: The numbers of the sync channels into the TCSPC. A click on any
+///      of them resets the sync, e.g. when the sync alternates between two inputs.
 ///    - channel_source: The number of the channel your source is connected into the TCSPC
 ///    - correlation_window: Length of the correlation window of interest in seconds. If
 ///      it is longer than the sync pulse period you will get a tail of zero counts.
 ///    - resolution: Resolution of the lifetime histogram in seconds
-#[derive(Debug, Copy, Clone)]
+///    - sync_offset: Optional phase offset in seconds added to the sync before folding
+///      the clicks. It wraps around the sync period and can be used to center the
+///      decay on the histogram.
+///    - log_bins: Optional number of bins per decade for a log-time histogram. The
+///      first bin spans from zero to `resolution` and the following ones grow
+///      geometrically up to the sync period. `t` holds the left edge of each bin and
+///      the counts are not normalized by the bin widths.
+///    - sync_jitter: Optional tolerance in seconds for source clicks time tagged
+///      slightly before the sync they belong to because of timing jitter. They are
+///      given a zero delay instead of being folded against the previous sync, which
+///      is what happens to clicks further ahead of the sync.
+#[derive(Debug, Clone)]
 pub struct LifetimeParams {
-    pub channel_sync: i32,
+    pub channel_sync: Vec<i32>,
     pub channel_source: i32,
     pub resolution: f64,
     pub start_record: Option<usize>,
     pub stop_record: Option<usize>,
+    pub sync_offset: Option<f64>,
+    pub log_bins: Option<usize>,
+    pub sync_jitter: Option<f64>,
 }
 
-impl<P: TTTRStream + Iterator> Lifetime<P> {
-    fn compute(self) -> LifetimeResult
-    where
-        <P as Iterator>::Item: Debug + Click,
-    {
-        let real_resolution = self.params.resolution.clone();
-        let correlation_window = (self.sync_period as f64) * 1e-12;
-
-        let n_bins = (correlation_window / self.params.resolution) as u64;
-        let resolution = self.sync_period / n_bins;
+impl LifetimeParams {
+    /// Check that there is at least one sync channel and that the source channel is
+    /// not one of them.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.channel_sync.is_empty() {
+            return Err(Error::InvalidParams(String::from(
+                "At least one sync channel is required",
+            )));
+        }
+        if self.channel_sync.contains(&self.channel_source) {
+            return Err(Error::InvalidParams(format!(
+                "Sync and source channels must differ but both are {}",
+                self.channel_source
+            )));
+        }
+        if self.log_bins == Some(0) {
+            return Err(Error::InvalidParams(String::from(
+                "A log-time histogram needs at least one bin per decade",
+            )));
+        }
+        Ok(())
+    }
+}
 
-        let mut histogram = vec![0; n_bins as usize];
-        let mut tof_sync = 0;
+/// Per click update of the lifetime histogram.
+pub(super) struct LifetimeHistogram {
+    n_bins: u64,
+    resolution: u64,
+    real_resolution: f64,
+    sync_period: u64,
+    channel_sync: Vec<i32>,
+    channel_source: i32,
+    tof_sync: u64,
+    previous_sync: u64,
+    sync_jitter: u64,
+    sync_offset: u64,
+    /// Bins per decade and width of the first bin in native units of log-time
+    /// histograms.
+    log_bins: Option<(f64, f64)>,
+    histogram: Vec<u64>,
+    total_counts: u64,
+    arrival_time_sum: u128,
+}
 
-        for rec in self.click_stream.into_iter() {
-            let (tof, channel) = (*rec.tof(), *rec.channel());
+impl LifetimeHistogram {
+    /// `sync_period` is in picoseconds like the times of flight of T3 records.
+    pub(super) fn new(params: &LifetimeParams, sync_period: u64) -> Result<Self, Error> {
+        let real_resolution = params.resolution;
+        let correlation_window = (sync_period as f64) * 1e-12;
+        if params.resolution.is_nan()
+            || params.resolution <= 0.0
+            || params.resolution > correlation_window
+        {
+            return Err(Error::InvalidParams(format!(
+                "The lifetime resolution must be positive and no larger than the sync period but got {} and {}",
+                params.resolution, correlation_window
+            )));
+        }
 
-            if channel == self.params.channel_source {
-                let delta = tof - tof_sync;
-                let hist_idx = ((delta % self.sync_period) / resolution) as usize;
-                if hist_idx < (n_bins as usize) {
-                    histogram[hist_idx] += 1;
-                };
-            } else if channel == self.params.channel_sync {
-                tof_sync = tof;
+        let log_bins = params
+            .log_bins
+            .map(|per_decade| (per_decade as f64, (params.resolution * 1e12).max(1.0)));
+        let n_bins = match log_bins {
+            // One linear bin below the first edge and as many decades as needed to
+            // reach the sync period
+            Some((per_decade, first_edge)) => {
+                1 + (per_decade * ((sync_period as f64) / first_edge).log10())
+                    .ceil()
+                    .max(0.0) as u64
             }
+            // Rounding may leave a resolution equal to the period just below one bin
+            None => ((correlation_window / params.resolution) as u64).max(1),
+        };
+        let resolution = sync_period / n_bins;
+        if log_bins.is_none() && resolution == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The lifetime resolution must be at least one picosecond but got {}",
+                params.resolution
+            )));
         }
+        let sync_offset = params.sync_offset.map_or(0, |offset| {
+            ((offset * 1e12).round() as i64).rem_euclid(sync_period as i64) as u64
+        });
+
+        Ok(Self {
+            n_bins,
+            resolution,
+            real_resolution,
+            sync_period,
+            channel_sync: params.channel_sync.clone(),
+            channel_source: params.channel_source,
+            tof_sync: 0,
+            previous_sync: 0,
+            sync_jitter: params
+                .sync_jitter
+                .map_or(0, |jitter| (jitter * 1e12).round().max(0.0) as u64),
+            sync_offset,
+            log_bins,
+            histogram: vec![0; n_bins as usize],
+            total_counts: 0,
+            arrival_time_sum: 0,
+        })
+    }
+
+    #[inline(always)]
+    pub(super) fn push(&mut self, tof: u64, channel: i32) {
+        if channel == self.channel_source {
+            self.push_microtime(tof);
+        } else if self.channel_sync.contains(&channel) {
+            self.previous_sync = self.tof_sync;
+            self.tof_sync = tof;
+        }
+    }
+
+    /// Add a click regardless of its channel.
+    #[inline(always)]
+    pub(super) fn push_microtime(&mut self, tof: u64) {
+        // Clicks tagged before the latest sync would underflow
+        let delta = if tof >= self.tof_sync {
+            tof - self.tof_sync
+        } else if self.tof_sync - tof <= self.sync_jitter {
+            0
+        } else {
+            tof.saturating_sub(self.previous_sync)
+        };
+        let arrival_time = (delta + self.sync_offset) % self.sync_period;
+        let hist_idx = match self.log_bins {
+            Some((per_decade, first_edge)) => {
+                let arrival_time = arrival_time as f64;
+                if arrival_time < first_edge {
+                    0
+                } else {
+                    1 + (per_decade * (arrival_time / first_edge).log10()) as usize
+                }
+            }
+            None => (arrival_time / self.resolution) as usize,
+        };
+        if hist_idx < (self.n_bins as usize) {
+            self.histogram[hist_idx] += 1;
+            self.total_counts += 1;
+            self.arrival_time_sum += arrival_time as u128;
+        };
+    }
 
-        let t = (0..n_bins)
-            .map(|i| (i as f64) * real_resolution)
-            .collect::<Vec<f64>>();
+    pub(super) fn finish(self) -> LifetimeResult {
+        let t = match self.log_bins {
+            Some((per_decade, first_edge)) => (0..self.n_bins)
+                .map(|i| match i {
+                    0 => 0.0,
+                    _ => first_edge * 10f64.powf(((i - 1) as f64) / per_decade) * 1e-12,
+                })
+                .collect::<Vec<f64>>(),
+            None => (0..self.n_bins)
+                .map(|i| (i as f64) * self.real_resolution)
+                .collect::<Vec<f64>>(),
+        };
+        // Arrival times are accumulated in picoseconds
+        let mean_arrival_time = if self.total_counts > 0 {
+            Some((self.arrival_time_sum as f64) / (self.total_counts as f64) * 1e-12)
+        } else {
+            None
+        };
         LifetimeResult {
-            t: t,
-            hist: histogram,
+            t,
+            hist: self.histogram,
+            total_counts: self.total_counts,
+            mean_arrival_time,
         }
     }
 }
 
+impl<P: TTTRStream + Iterator> Lifetime<P> {
+    fn compute(self) -> Result<LifetimeResult, Error>
+    where
+        <P as Iterator>::Item: Debug + Click,
+    {
+        let mut histogram = LifetimeHistogram::new(&self.params, self.sync_period)?;
+
+        for rec in self.click_stream {
+            histogram.push(*rec.tof(), *rec.channel());
+        }
+        Ok(histogram.finish())
+    }
+}
+
 /// Lifetime algorithm for photon emitters.
 ///
 /// ## Parameters
 ///
 /// The parameters to the algorithm are passed via a `LifetimeParams` struct that contains
 /// the following:
-///    - channel_sync: The number of the sync channel into the TCSPC,
+///    - channel_sync: The numbers of the sync channels into the TCSPC,
 ///    - channel_source: The number of the source input channel into the TCSPC,
 ///    - correlation_window: Length of the correlation window of interest in seconds,
 ///    - resolution: Resolution of the lifetime histogram in seconds,
@@ -96,6 +330,7 @@ impl<P: TTTRStream + Iterator> Lifetime<P> {
 /// with the  excitation source. Typically pulsed lasers used for lifetime measurements include
 /// an RF output for this purpose.
 pub fn lifetime(f: &File, params: &LifetimeParams) -> Result<LifetimeResult, Error> {
+    params.validate()?;
     let start_record = params.start_record;
     let stop_record = params.stop_record;
     match f {
@@ -114,12 +349,385 @@ pub fn lifetime(f: &File, params: &LifetimeParams) -> Result<LifetimeResult, Err
                 let sync_period = stream.sync_period;
                 let tt = Lifetime {
                     click_stream: stream,
-                    params: *params,
+                    params: params.clone(),
                     sync_period,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(_) => Err(Error::NotImplemented(String::from(
+            "The lifetime algorithm is only supported in T3 mode",
+        ))),
+    }
+}
+
+/// Lifetime against sync times that were not recorded on the file.
+///
+/// Useful when the sync comes from a separate log, e.g. the readout of a function
+/// generator, instead of from an input of the TCSPC. The clicks on `channel_source`
+/// are merged in time order with `sync_times` and folded like in `lifetime`.
+///
+/// `sync_times` must be sorted in ascending order and expressed in native units of
+/// the file, on the same clock as the records. The sync period is taken as the
+/// average spacing of the sync times, so at least two of them are needed.
+pub fn lifetime_external_sync(
+    f: &File,
+    channel_source: i32,
+    sync_times: &[u64],
+    resolution: f64,
+) -> Result<LifetimeResult, Error> {
+    if sync_times.len() < 2 || sync_times.windows(2).any(|x| x[0] > x[1]) {
+        return Err(Error::InvalidParams(String::from(
+            "At least two sync times sorted in ascending order are required",
+        )));
+    }
+    // Photons never land on negative channels
+    const EXTERNAL_SYNC: i32 = i32::MIN;
+    let params = LifetimeParams {
+        channel_sync: vec![EXTERNAL_SYNC],
+        channel_source,
+        resolution,
+        start_record: None,
+        stop_record: None,
+        sync_offset: None,
+        log_bins: None,
+        sync_jitter: None,
+    };
+    params.validate()?;
+
+    let stream = f.records()?;
+    // The lifetime histogram works in picoseconds like T3 records
+    let ps_per_tick = stream.time_resolution() * 1e12;
+    let to_ps = |tof: u64| ((tof as f64) * ps_per_tick).round() as u64;
+
+    let first = to_ps(sync_times[0]);
+    let last = to_ps(sync_times[sync_times.len() - 1]);
+    let sync_period = (last - first) / (sync_times.len() as u64 - 1);
+    if sync_period == 0 {
+        return Err(Error::InvalidParams(String::from(
+            "The sync times must span more than one picosecond",
+        )));
+    }
+
+    let mut histogram = LifetimeHistogram::new(&params, sync_period)?;
+    // Overflows and markers are dropped since their time of flight may not be ordered
+    let photons = stream.filter(
+        |rec| matches!(rec.kind(), RecordKind::Photon { channel } if channel == channel_source),
+    );
+    let syncs = sync_times
+        .iter()
+        .map(|&tof| TTTRRecord::new(EXTERNAL_SYNC, tof));
+    for rec in merge_ordered(syncs, photons) {
+        histogram.push(to_ps(*rec.tof()), *rec.channel());
+    }
+    Ok(histogram.finish())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_utils::{encode_hht3, write_ptu, T3Event, Tag, HHT3_HH2};
+
+    pub(crate) fn params(channel_sync: Vec<i32>, channel_source: i32) -> LifetimeParams {
+        LifetimeParams {
+            channel_sync,
+            channel_source,
+            resolution: 100e-12,
+            start_record: None,
+            stop_record: None,
+            sync_offset: None,
+            log_bins: None,
+            sync_jitter: None,
+        }
+    }
+
+    #[test]
+    fn source_colliding_with_a_sync_channel_is_rejected() {
+        assert!(params(vec![0, 3], 1).validate().is_ok());
+        assert!(matches!(
+            params(vec![0, 3], 3).validate(),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn sync_offset_shifts_the_peak() {
+        // Every photon arrives 2 ns after its sync, 12.5 ns sync period
+        let events: Vec<T3Event> = (0..1_000u64)
+            .map(|k| T3Event::Photon {
+                channel: 1,
+                nsync: 3 * k,
+                dtime: 2_000,
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(1e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let peak = |sync_offset: Option<f64>| {
+            let mut params = params(vec![0], 1);
+            params.sync_offset = sync_offset;
+            let result = lifetime(&f, &params).unwrap();
+            assert_eq!(result.total_counts, 1_000);
+            result.hist.iter().position(|&x| x == 1_000).unwrap()
+        };
+
+        assert_eq!(peak(None), 20);
+        assert_eq!(peak(Some(1e-9)), 30);
+        // Negative offsets wrap around the sync period
+        assert_eq!(peak(Some(-3e-9)), 115);
+    }
+
+    #[test]
+    fn mean_arrival_time_of_a_single_exponential() {
+        // Quantiles of a 5 ns exponential decay delayed by a 2 ns IRF offset, on a
+        // 100 ns sync period with 4 ps dtime bins
+        let (tau, offset, n) = (1_250.0, 500.0, 20_000u64);
+        let events: Vec<T3Event> = (0..n)
+            .map(|k| {
+                let quantile = (k as f64 + 0.5) / (n as f64);
+                T3Event::Photon {
+                    channel: 1,
+                    nsync: k,
+                    dtime: (offset - tau * (1.0 - quantile).ln()).round() as u64,
+                }
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let tmp = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&events));
+        let result = lifetime(&File::open(tmp.path()).unwrap(), &params(vec![0], 1)).unwrap();
+
+        assert_eq!(result.total_counts, n);
+        assert_eq!(result.hist.iter().sum::<u64>(), n);
+        let mean = result.mean_arrival_time.unwrap();
+        assert!((mean - 7e-9).abs() < 0.01 * 5e-9);
+    }
+
+    #[test]
+    fn alternating_sync_channels_give_a_single_decay() {
+        // The sync alternates between channels 2 and 3 with a jitter of a few ns
+        // around the 100 ns period. Each photon arrives 2 ns after its sync.
+        let n = 1_000u64;
+        let events: Vec<T3Event> = (0..n)
+            .flat_map(|k| {
+                let sync = 250 * (k % 5);
+                vec![
+                    T3Event::Photon {
+                        channel: 2 + (k % 2) as i32,
+                        nsync: k,
+                        dtime: sync,
+                    },
+                    T3Event::Photon {
+                        channel: 1,
+                        nsync: k,
+                        dtime: sync + 500,
+                    },
+                ]
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let tmp = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let result = lifetime(&f, &params(vec![2, 3], 1)).unwrap();
+        assert_eq!(result.total_counts, n);
+        assert_eq!(result.hist[20], n);
+
+        // Half of the photons are folded against the wrong sync with a single one
+        let result = lifetime(&f, &params(vec![2], 1)).unwrap();
+        assert_eq!(result.total_counts, n);
+        assert_eq!(result.hist[20], n / 2);
+    }
+
+    #[test]
+    fn log_bins_separate_a_bi_exponential_decay() {
+        // Quantiles of 100 ps and 10 ns decays with the same amplitude, alternating
+        // between them, on a 125 ns sync period with 4 ps dtime bins
+        let n = 10_000u64;
+        let events: Vec<T3Event> = (0..2 * n)
+            .map(|k| {
+                let tau = if k % 2 == 0 { 25.0 } else { 2_500.0 };
+                let quantile = ((k / 2) as f64 + 0.5) / (n as f64);
+                T3Event::Photon {
+                    channel: 1,
+                    nsync: k,
+                    dtime: (-tau * (1.0 - quantile).ln()).round() as u64,
+                }
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let tmp = write_ptu(HHT3_HH2, 125e-9, &tags, &encode_hht3(&events));
+        let mut params = params(vec![0], 1);
+        params.resolution = 10e-12;
+        params.log_bins = Some(10);
+        let result = lifetime(&File::open(tmp.path()).unwrap(), &params).unwrap();
+        assert_eq!(result.total_counts, 2 * n);
+
+        // On a log axis each component peaks at its lifetime, with a dip between
+        // them. The first bin holds every delay below the first edge instead.
+        let peak = |from: f64, to: f64| {
+            (0..result.t.len())
+                .filter(|&i| result.t[i] >= from && result.t[i] < to)
+                .max_by_key(|&i| result.hist[i])
+                .unwrap()
+        };
+        let (fast, slow) = (peak(10e-12, 1e-9), peak(1e-9, 125e-9));
+        let close = |t: f64, tau: f64| (t / tau).log10().abs() <= 0.2;
+        assert!(close(result.t[fast], 100e-12));
+        assert!(close(result.t[slow], 10e-9));
+        let dip = (fast..slow).min_by_key(|&i| result.hist[i]).unwrap();
+        assert!(2 * result.hist[dip] < result.hist[fast]);
+        assert!(2 * result.hist[dip] < result.hist[slow]);
+    }
+
+    #[test]
+    fn external_sync_reproduces_the_recorded_sync() {
+        // The sync is recorded on channel 2 every 100 ns and each photon arrives
+        // between 0 and 8 ns after it
+        let n = 2_000u64;
+        let events: Vec<T3Event> = (0..n)
+            .flat_map(|k| {
+                vec![
+                    T3Event::Photon {
+                        channel: 2,
+                        nsync: k,
+                        dtime: 250,
+                    },
+                    T3Event::Photon {
+                        channel: 1,
+                        nsync: k,
+                        dtime: 251 + (k * 37) % 2_000,
+                    },
+                ]
+            })
+            .collect();
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let tmp = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let internal = lifetime(&f, &params(vec![2], 1)).unwrap();
+        // The same syncs as if they came from a separate log
+        let sync_times: Vec<u64> = f
+            .records()
+            .unwrap()
+            .filter(|rec| *rec.channel() == 2)
+            .map(|rec| *rec.tof())
+            .collect();
+        assert_eq!(sync_times.len() as u64, n);
+        let external = lifetime_external_sync(&f, 1, &sync_times, 100e-12).unwrap();
+
+        assert_eq!(internal.total_counts, n);
+        assert_eq!(external.total_counts, internal.total_counts);
+        assert_eq!(external.t, internal.t);
+        assert_eq!(external.hist, internal.hist);
+        assert_eq!(external.mean_arrival_time, internal.mean_arrival_time);
+    }
+
+    #[test]
+    fn sync_jitter_keeps_early_photons_on_their_sync() {
+        // A 100 ns sync on channel 2. Every other photon is tagged 40 ps before the
+        // sync it belongs to, the rest arrive 8 ns after theirs. A 1 ns sync offset
+        // keeps the early photons off the edges of the histogram.
+        let n = 1_000u64;
+        let mut events = vec![T3Event::Photon {
+            channel: 2,
+            nsync: 0,
+            dtime: 300,
+        }];
+        for k in 1..=n {
+            events.push(T3Event::Photon {
+                channel: 2,
+                nsync: k,
+                dtime: 300,
+            });
+            events.push(T3Event::Photon {
+                channel: 1,
+                nsync: k,
+                dtime: if k % 2 == 0 { 290 } else { 2_300 },
+            });
+        }
+        let tags = [("MeasDesc_Resolution", Tag::Float8(4e-12))];
+        let tmp = write_ptu(HHT3_HH2, 100e-9, &tags, &encode_hht3(&events));
+        let f = File::open(tmp.path()).unwrap();
+        let result = |sync_jitter: Option<f64>| {
+            let mut params = params(vec![2], 1);
+            params.sync_offset = Some(1e-9);
+            params.sync_jitter = sync_jitter;
+            lifetime(&f, &params).unwrap()
+        };
+
+        // Without a tolerance the early photons fold against the previous sync
+        for sync_jitter in &[None, Some(20e-12)] {
+            let result = result(*sync_jitter);
+            assert_eq!(result.total_counts, n);
+            assert_eq!(result.hist[90], n / 2);
+            assert_eq!(result.hist[9], n / 2);
+        }
+        let result = result(Some(50e-12));
+        assert_eq!(result.total_counts, n);
+        assert_eq!(result.hist[90], n / 2);
+        assert_eq!(result.hist[10], n / 2);
+    }
+
+    #[test]
+    fn deconvolution_recovers_the_lifetime() {
+        // A 1 ns decay starting at zero blurred by a Gaussian IRF 2 ns after the sync,
+        // on 100 ps bins
+        let n = 200;
+        let tau = 10.0;
+        let histogram = |hist: Vec<u64>| LifetimeResult {
+            t: (0..n).map(|i| (i as f64) * 100e-12).collect(),
+            total_counts: hist.iter().sum(),
+            hist,
+            mean_arrival_time: None,
+        };
+        let irf: Vec<f64> = (0..n)
+            .map(|i| (-((i as f64 - 20.0) / 3.0).powi(2) / 2.0).exp())
+            .collect();
+        let irf_total: f64 = irf.iter().sum();
+        let measured = histogram(
+            (0..n)
+                .map(|i| {
+                    let counts: f64 = (0..=i)
+                        .map(|j| 1e5 * (-(j as f64) / tau).exp() * irf[i - j] / irf_total)
+                        .sum();
+                    counts.round() as u64
+                })
+                .collect(),
+        );
+        let irf = histogram(irf.iter().map(|x| (1e4 * x).round() as u64).collect());
+
+        // Log-linear fit of the 15 bins after the maximum
+        let fit_tau = |decay: &[f64]| {
+            let peak = (0..n)
+                .max_by(|&a, &b| decay[a].partial_cmp(&decay[b]).unwrap())
+                .unwrap();
+            let points: Vec<(f64, f64)> = (peak..peak + 15)
+                .map(|i| (i as f64, decay[i].ln()))
+                .collect();
+            let mean_x = points.iter().map(|p| p.0).sum::<f64>() / 15.0;
+            let mean_y = points.iter().map(|p| p.1).sum::<f64>() / 15.0;
+            let slope = points
+                .iter()
+                .map(|p| (p.0 - mean_x) * (p.1 - mean_y))
+                .sum::<f64>()
+                / points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+            -1.0 / slope
+        };
+        let raw: Vec<f64> = measured.hist.iter().map(|&x| x as f64).collect();
+        let deconvolved = measured.deconvolve(&irf).unwrap();
+        let raw_error = (fit_tau(&raw) - tau).abs();
+        let deconvolved_error = (fit_tau(&deconvolved) - tau).abs();
+        assert!(deconvolved_error < raw_error);
+        // The counts are kept
+        let total: f64 = deconvolved.iter().sum();
+        assert!((total - measured.total_counts as f64).abs() < 1e-6 * total);
+
+        let mut shifted = irf.clone();
+        shifted.t.iter_mut().for_each(|t| *t += 50e-12);
+        assert!(matches!(
+            measured.deconvolve(&shifted),
+            Err(Error::InvalidHistogram(_))
+        ));
     }
 }
@@ -0,0 +1,95 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use memmap2::MmapMut;
+use ndarray::{ArrayView2, Ix2};
+
+use crate::errors::Error;
+
+/// Two dimensional histogram of `u64` counts stored on a memory mapped file.
+///
+/// The file holds the raw counts in row major order and native byte order without
+/// any header, so it can be reopened with e.g. `numpy.memmap(path, dtype=np.uint64,
+/// shape=shape)`. Histograms larger than the available RAM are paged in and out of
+/// disk by the OS.
+pub struct MappedHistogram {
+    mmap: MmapMut,
+    shape: (usize, usize),
+}
+
+impl MappedHistogram {
+    /// Create a zeroed histogram of the given `shape` on the file at `path`. An
+    /// existing file is overwritten. Fails without touching the file if the size in
+    /// bytes of the histogram overflows.
+    pub fn create(path: &Path, shape: (usize, usize)) -> Result<Self, Error> {
+        let len = shape
+            .0
+            .checked_mul(shape.1)
+            .and_then(|x| x.checked_mul(std::mem::size_of::<u64>()))
+            .ok_or_else(|| {
+                Error::InvalidParams(format!(
+                    "A histogram of shape {:?} is too large to be mapped",
+                    shape
+                ))
+            })?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        // Safety: the file was just created by us and nobody else should modify it
+        // while it is mapped.
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self { mmap, shape })
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        self.shape
+    }
+
+    /// View of the counts as an ndarray.
+    pub fn view(&self) -> ArrayView2<'_, u64> {
+        // Safety: the mapping is page aligned and holds exactly `shape` u64s
+        unsafe { ArrayView2::from_shape_ptr(Ix2(self.shape.0, self.shape.1), self.ptr()) }
+    }
+
+    /// Mutable reference to the `(idx1, idx2)` bin or `None` if out of range.
+    #[inline(always)]
+    pub(crate) fn get_mut(&mut self, idx1: usize, idx2: usize) -> Option<&mut u64> {
+        if idx1 < self.shape.0 && idx2 < self.shape.1 {
+            let ptr = self.mmap.as_mut_ptr() as *mut u64;
+            // Safety: the index was bounds checked against `shape` above
+            Some(unsafe { &mut *ptr.add(idx1 * self.shape.1 + idx2) })
+        } else {
+            None
+        }
+    }
+
+    /// Write the counts still held in memory back to the file.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.mmap.flush()?;
+        Ok(())
+    }
+
+    fn ptr(&self) -> *const u64 {
+        self.mmap.as_ptr() as *const u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_histogram_is_rejected() {
+        let path = std::env::temp_dir().join(format!(
+            "tttr-toolbox-test-{}-oversized.bin",
+            std::process::id()
+        ));
+        let result = MappedHistogram::create(&path, (usize::MAX / 4, 4));
+        assert!(matches!(result, Err(Error::InvalidParams(_))));
+        assert!(!path.exists());
+    }
+}
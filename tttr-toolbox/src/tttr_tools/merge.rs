@@ -0,0 +1,88 @@
+use std::iter::Peekable;
+
+use crate::{Click, TTTRRecord};
+
+/// Iterator returned by [`merge_ordered`].
+pub struct MergeOrdered<A: Iterator<Item = TTTRRecord>, B: Iterator<Item = TTTRRecord>> {
+    a: Peekable<A>,
+    b: Peekable<B>,
+}
+
+impl<A, B> Iterator for MergeOrdered<A, B>
+where
+    A: Iterator<Item = TTTRRecord>,
+    B: Iterator<Item = TTTRRecord>,
+{
+    type Item = TTTRRecord;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let take_a = match (self.a.peek(), self.b.peek()) {
+            (Some(rec_a), Some(rec_b)) => rec_a.tof() <= rec_b.tof(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if take_a {
+            self.a.next()
+        } else {
+            self.b.next()
+        }
+    }
+}
+
+/// Merge two time ordered record streams into a single time ordered stream.
+///
+/// Both inputs must already be sorted by time of flight and share the same time
+/// resolution. On ties records from `a` come first. This makes it possible to
+/// correlate channels that were recorded into separate files.
+pub fn merge_ordered<A, B>(a: A, b: B) -> MergeOrdered<A::IntoIter, B::IntoIter>
+where
+    A: IntoIterator<Item = TTTRRecord>,
+    B: IntoIterator<Item = TTTRRecord>,
+{
+    MergeOrdered {
+        a: a.into_iter().peekable(),
+        b: b.into_iter().peekable(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photon(channel: i32, tof: u64) -> TTTRRecord {
+        TTTRRecord {
+            channel,
+            tof,
+            markers: 0,
+            overflow: false,
+        }
+    }
+
+    #[test]
+    fn interleaves_by_time_with_ties_from_a_first() {
+        let a = vec![photon(0, 1), photon(0, 4), photon(0, 4), photon(0, 9)];
+        let b = vec![photon(1, 0), photon(1, 4), photon(1, 5), photon(1, 12)];
+
+        let merged: Vec<_> = merge_ordered(a, b)
+            .map(|rec| (rec.channel, rec.tof))
+            .collect();
+
+        assert_eq!(
+            merged,
+            vec![
+                (1, 0),
+                (0, 1),
+                (0, 4),
+                (0, 4),
+                (1, 4),
+                (1, 5),
+                (0, 9),
+                (1, 12)
+            ]
+        );
+
+        let only_b = merge_ordered(vec![], vec![photon(1, 3), photon(1, 7)]);
+        assert_eq!(only_b.map(|rec| rec.tof).collect::<Vec<_>>(), vec![3, 7]);
+    }
+}
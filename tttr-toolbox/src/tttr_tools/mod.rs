@@ -1,8 +1,26 @@
+pub mod analysis;
+pub mod car;
 pub(self) mod circular_buffer;
+#[cfg(feature = "ndarray")]
 pub(self) mod colored_circular_buffer;
+pub mod combined;
+pub mod dump;
+pub mod extract;
 pub mod g2;
+#[cfg(feature = "ndarray")]
 pub mod g3;
+pub mod histogram;
 pub mod lifetime;
+#[cfg(feature = "mmap")]
+pub mod mapped_histogram;
+pub mod merge;
+pub mod quick_stats;
+pub mod segments;
+pub mod self_delay;
+#[cfg(feature = "ndarray")]
 pub mod synced_g3;
+pub mod timeline;
 pub mod timetrace;
 pub mod zero_finder;
+
+pub use timeline::timeline;
@@ -0,0 +1,278 @@
+use crate::{Click, RecordKind, TTTRRecord};
+
+/// Iterator over the records between two consecutive markers.
+pub struct Segment<'a, I: Iterator<Item = TTTRRecord>> {
+    records: &'a mut I,
+    marker_mask: u8,
+    finished: bool,
+    exhausted: bool,
+}
+
+impl<'a, I: Iterator<Item = TTTRRecord>> Iterator for Segment<'a, I> {
+    type Item = TTTRRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.records.next() {
+            Some(rec) if (*rec.markers() & self.marker_mask) != 0 => {
+                self.finished = true;
+                None
+            }
+            Some(rec) => Some(rec),
+            None => {
+                self.finished = true;
+                self.exhausted = true;
+                None
+            }
+        }
+    }
+}
+
+/// Split a record stream into the segments delimited by markers.
+///
+/// Every marker record whose marker bits overlap with `marker_mask` closes the
+/// current segment and opens a new one. The marker records themselves are not
+/// part of any segment. `callback` is invoked once per segment with the index of
+/// the segment and an iterator over its records, which makes it possible to e.g.
+/// compute a g2 per frame. Records left unconsumed by the callback are skipped.
+///
+/// Returns the number of segments found. A stream with `n` markers yields `n + 1`
+/// segments.
+pub fn split_at_markers<I, F>(mut records: I, marker_mask: u8, mut callback: F) -> usize
+where
+    I: Iterator<Item = TTTRRecord>,
+    F: FnMut(usize, &mut Segment<I>),
+{
+    let mut n_segments = 0;
+    loop {
+        let mut segment = Segment {
+            records: &mut records,
+            marker_mask,
+            finished: false,
+            exhausted: false,
+        };
+        callback(n_segments, &mut segment);
+        // drain whatever the callback did not consume
+        while segment.next().is_some() {}
+        n_segments += 1;
+
+        if segment.exhausted {
+            break;
+        }
+    }
+    n_segments
+}
+
+/// Marker bits a laser scanning microscope uses to delimit the frames and lines of
+/// a FLIM image.
+///
+/// Each field holds the marker bits that signal the event. A field set to zero is
+/// never matched.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameMarkers {
+    pub line_start: u8,
+    pub line_stop: u8,
+    pub frame: u8,
+}
+
+/// Iterator over the photons of a single scanned line.
+pub struct Line<'a, I: Iterator<Item = TTTRRecord>> {
+    records: &'a mut I,
+    markers: FrameMarkers,
+    finished: bool,
+    exhausted: bool,
+    /// Marker bits of the record that closed the line.
+    closing_markers: u8,
+}
+
+impl<'a, I: Iterator<Item = TTTRRecord>> Iterator for Line<'a, I> {
+    type Item = TTTRRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let closing_mask = self.markers.line_start | self.markers.line_stop | self.markers.frame;
+        for rec in self.records.by_ref() {
+            if (*rec.markers() & closing_mask) != 0 {
+                self.finished = true;
+                self.closing_markers = *rec.markers();
+                return None;
+            }
+            if let RecordKind::Photon { .. } = rec.kind() {
+                return Some(rec);
+            }
+        }
+        self.finished = true;
+        self.exhausted = true;
+        None
+    }
+}
+
+/// Split a record stream into the frames and lines of a FLIM image.
+///
+/// A frame marker opens a new frame and a line start marker opens a new line on the
+/// current frame. Lines are closed by a line stop marker, by the start of the next
+/// line or frame, or by the end of the stream. `callback` is invoked once per line
+/// with the index of the frame, the index of the line within the frame and an
+/// iterator over the photons of the line, which is the plumbing needed to
+/// reconstruct the image pixel by pixel. Records left unconsumed by the callback are
+/// skipped.
+///
+/// Records before the first frame marker belong to an incomplete frame and are
+/// ignored, and so are the photons between the end of a line and the start of the
+/// next one, e.g. during the flyback of the scanner.
+///
+/// Returns the number of frames found.
+pub fn split_frames<I, F>(mut records: I, markers: FrameMarkers, mut callback: F) -> usize
+where
+    I: Iterator<Item = TTTRRecord>,
+    F: FnMut(usize, usize, &mut Line<I>),
+{
+    let mut n_frames = 0;
+    let mut n_lines = 0;
+    // Marker bits of a record that closed a line and must open the next one
+    let mut pending_markers = None;
+    loop {
+        let marker_bits = match pending_markers.take() {
+            Some(bits) => bits,
+            None => match records.next() {
+                Some(rec) => *rec.markers(),
+                None => break,
+            },
+        };
+
+        if (marker_bits & markers.frame) != 0 {
+            n_frames += 1;
+            n_lines = 0;
+        }
+        if n_frames > 0 && (marker_bits & markers.line_start) != 0 {
+            let mut line = Line {
+                records: &mut records,
+                markers,
+                finished: false,
+                exhausted: false,
+                closing_markers: 0,
+            };
+            callback(n_frames - 1, n_lines, &mut line);
+            // drain whatever the callback did not consume
+            while line.next().is_some() {}
+            n_lines += 1;
+
+            if line.exhausted {
+                break;
+            }
+            pending_markers = Some(line.closing_markers);
+        }
+    }
+    n_frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photon(tof: u64) -> TTTRRecord {
+        TTTRRecord {
+            channel: 0,
+            tof,
+            markers: 0,
+            overflow: false,
+        }
+    }
+
+    fn marker(bits: u8, tof: u64) -> TTTRRecord {
+        TTTRRecord {
+            channel: -2,
+            tof,
+            markers: bits,
+            overflow: false,
+        }
+    }
+
+    #[test]
+    fn periodic_markers_delimit_segments() {
+        // Ten periods of five photons closed by a marker, plus an unrelated marker
+        // on each period, followed by a last open segment of three photons
+        let mut records = vec![];
+        for period in 0..10 {
+            let start = 100 * period;
+            records.extend((0..5).map(|k| photon(start + k)));
+            records.push(marker(0b10, start + 50));
+            records.push(marker(0b01, start + 99));
+        }
+        records.extend((0..3).map(|k| photon(1_000 + k)));
+
+        let mut lengths = vec![];
+        let n_segments = split_at_markers(records.into_iter(), 0b01, |idx, segment| {
+            assert_eq!(idx, lengths.len());
+            lengths.push(segment.count());
+        });
+        assert_eq!(n_segments, 11);
+        let mut expected = vec![6; 10];
+        expected.push(3);
+        assert_eq!(lengths, expected);
+
+        // Markers right at the start and end of the stream open empty segments
+        let records = vec![marker(0b01, 0), photon(1), marker(0b01, 2)];
+        let mut lengths = vec![];
+        let n_segments = split_at_markers(records.into_iter(), 0b01, |_, segment| {
+            lengths.push(segment.count());
+        });
+        assert_eq!(n_segments, 3);
+        assert_eq!(lengths, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn frames_and_lines_are_split() {
+        let markers = FrameMarkers {
+            line_start: 0b001,
+            line_stop: 0b010,
+            frame: 0b100,
+        };
+        let records = vec![
+            // Incomplete frame
+            photon(1),
+            marker(0b001, 2),
+            photon(3),
+            // First frame with two lines and a flyback photon between them
+            marker(0b100, 10),
+            marker(0b001, 11),
+            photon(12),
+            photon(13),
+            marker(0b010, 14),
+            photon(15),
+            marker(0b001, 16),
+            photon(17),
+            marker(0b010, 18),
+            // Second frame whose only line is closed by the next frame
+            marker(0b100, 20),
+            marker(0b001, 21),
+            photon(22),
+            photon(23),
+            photon(24),
+            // Third frame, with a line start on the same record, cut by the end of
+            // the stream
+            marker(0b101, 30),
+            photon(31),
+        ];
+
+        let mut lines = vec![];
+        let n_frames = split_frames(records.into_iter(), markers, |frame, line, photons| {
+            let tofs: Vec<u64> = photons.map(|rec| *rec.tof()).collect();
+            lines.push((frame, line, tofs));
+        });
+        assert_eq!(n_frames, 3);
+        assert_eq!(
+            lines,
+            vec![
+                (0, 0, vec![12, 13]),
+                (0, 1, vec![17]),
+                (1, 0, vec![22, 23, 24]),
+                (2, 0, vec![31]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    errors::Error, headers::File, to_native_units, tttr_tools::circular_buffer::CircularBuffer,
+    Click,
+};
+
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// Result from the self delay scan
+#[derive(Debug, Clone)]
+pub struct SelfDelayResult {
+    /// Delays in seconds, in the order they were requested
+    pub delays: Vec<f64>,
+    /// Coincidences found at each of the delays
+    pub counts: Vec<u64>,
+}
+
+/// Parameters for the self delay scan
+///
+/// # Parameters
+///    - channel: The number of the input channel into the TCSPC
+///    - delays: Non-negative delays in seconds of the copy of `channel` it is
+///      correlated against
+///    - coincidence_window: Full width in seconds of the window around each delay in
+///      which a pair of clicks counts as a coincidence
+#[derive(Debug, Clone)]
+pub struct SelfDelayParams {
+    pub channel: i32,
+    pub delays: Vec<f64>,
+    pub coincidence_window: f64,
+    pub start_record: Option<usize>,
+    pub stop_record: Option<usize>,
+}
+
+impl SelfDelayParams {
+    /// Check that there is at least one delay and that the coincidence window is
+    /// positive.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.delays.is_empty() {
+            return Err(Error::InvalidParams(String::from(
+                "The self delay scan needs at least one delay",
+            )));
+        }
+        if self.coincidence_window.is_nan() || self.coincidence_window <= 0.0 {
+            return Err(Error::InvalidParams(format!(
+                "The coincidence window must be positive but got {}",
+                self.coincidence_window
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Counts the coincidences between a channel and a copy of itself delayed by each
+/// of the requested delays.
+///
+/// Correlations of a detector with itself, e.g. afterpulsing or the periodicity of
+/// a pulsed source, show up as an excess of coincidences at the affected delays.
+/// A pair of clicks `delta` apart counts towards every delay that is closer than
+/// half a `coincidence_window` to `delta`. Each click is never paired with itself.
+///
+/// ## Algorithm description
+/// The past clicks on the channel are kept on a circular buffer of 4096 clicks and
+/// each new click is compared against it up to the longest delay. The buffer must
+/// span the longest delay, see the g2 documentation for the finite buffer artifacts.
+pub fn self_delay_scan(f: &File, params: &SelfDelayParams) -> Result<SelfDelayResult, Error> {
+    params.validate()?;
+    let stream = f.records_range(params.start_record, params.stop_record)?;
+    let time_resolution = stream.time_resolution();
+    let half_window = to_native_units(params.coincidence_window / 2.0, time_resolution)?;
+    let delays = params
+        .delays
+        .iter()
+        .map(|&delay| to_native_units(delay, time_resolution))
+        .collect::<Result<Vec<_>, _>>()?;
+    let max_delta = delays.iter().max().copied().unwrap_or(0) + half_window;
+
+    let mut buff = CircularBuffer::new(MAX_BUFFER_SIZE);
+    let mut counts = vec![0; delays.len()];
+
+    // Substractions between u64 below are safe from over/underflows because `tof`
+    // is always the most recent click on the channel.
+    for rec in stream {
+        let (tof, channel) = (*rec.tof(), *rec.channel());
+        if channel != params.channel {
+            continue;
+        }
+
+        for click in buff.iter() {
+            let delta = tof - click;
+            if delta >= max_delta {
+                break;
+            }
+            for (count, &delay) in counts.iter_mut().zip(delays.iter()) {
+                if delta.abs_diff(delay) < half_window {
+                    *count += 1;
+                }
+            }
+        }
+        buff.push(tof);
+    }
+
+    Ok(SelfDelayResult {
+        delays: params.delays.clone(),
+        counts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, write_ptu, Event, PHT2};
+
+    #[test]
+    fn periodic_source() {
+        // A click every 12.5 ns with 4 ps ticks
+        let events: Vec<Event> = (1..=1_000).map(|k| Event::Photon(0, k * 3_125)).collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let params = SelfDelayParams {
+            channel: 0,
+            delays: vec![0.0, 12.5e-9, 18.75e-9, 25e-9, 37.6e-9],
+            coincidence_window: 1e-9,
+            start_record: None,
+            stop_record: None,
+        };
+        let result = self_delay_scan(&File::open(tmp.path()).unwrap(), &params).unwrap();
+
+        assert_eq!(result.delays, params.delays);
+        // Whole periods pair every click with the one that many periods later, and
+        // the window tolerates the 0.1 ns offset of the last delay
+        assert_eq!(result.counts, vec![0, 999, 0, 998, 997]);
+
+        let invalid = SelfDelayParams {
+            coincidence_window: f64::NAN,
+            ..params
+        };
+        assert!(matches!(invalid.validate(), Err(Error::InvalidParams(_))));
+    }
+}
@@ -16,15 +16,20 @@ struct G3Sync<P: TTTRStream + Iterator> {
 }
 
 /// Result from the g3 synced algorithm
+#[derive(Debug, Clone)]
 pub struct G3SyncResult {
     pub t: Vec<f64>,
     pub hist: Array2<u64>,
+    /// Bin width in seconds actually used on both axes. The requested resolution is
+    /// rounded to a whole number of ticks of the file.
+    pub effective_resolution: f64,
 }
 
 /// Parameters for the synced g3 algorithm
 ///
 /// # Parameters
-///    - channel_sync: The number of the first input channel into the TCSPC
+///    - channel_sync: The number of the first input channel into the TCSPC. It must
+///      be different from both data channels.
 ///    - channel_1: The number of the second input channel into the TCSPC
 ///    - channel_2: The number of the third input channel into the TCSPC
 ///    - resolution: Resolution of the g3 histogram in seconds
@@ -38,8 +43,28 @@ pub struct G3SyncParams {
     pub stop_record: Option<usize>,
 }
 
+impl G3SyncParams {
+    /// Check that neither of the data channels is the sync channel and that the
+    /// resolution is positive.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.channel_sync == self.channel_1 || self.channel_sync == self.channel_2 {
+            return Err(Error::InvalidParams(format!(
+                "Sync channel {} can't also be used as a data channel",
+                self.channel_sync
+            )));
+        }
+        if !(self.resolution > 0.0) {
+            return Err(Error::InvalidParams(format!(
+                "The synced g3 resolution must be positive but got {}",
+                self.resolution
+            )));
+        }
+        Ok(())
+    }
+}
+
 impl<P: TTTRStream + Iterator> G3Sync<P> {
-    fn compute(self) -> G3SyncResult
+    fn compute(self) -> Result<G3SyncResult, Error>
     where
         <P as Iterator>::Item: Debug + Click,
     {
@@ -47,7 +72,14 @@ impl<P: TTTRStream + Iterator> G3Sync<P> {
         let correlation_window = (self.sync_period as f64) * 1e-12;
 
         let n_bins = (correlation_window / self.params.resolution) as u64;
+        if n_bins == 0 || self.sync_period / n_bins == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The synced g3 resolution must be between one picosecond and the sync period {} but got {}",
+                correlation_window, self.params.resolution
+            )));
+        }
         let resolution = self.sync_period / n_bins as u64;
+        let effective_resolution = (resolution as f64) * 1e-12;
 
         let mut histogram = Array2::<u64>::zeros((n_bins as usize, n_bins as usize));
 
@@ -123,10 +155,11 @@ impl<P: TTTRStream + Iterator> G3Sync<P> {
         let t = (0..n_bins)
             .map(|i| (i as f64) * real_resolution)
             .collect::<Vec<f64>>();
-        G3SyncResult {
+        Ok(G3SyncResult {
             t: t,
             hist: histogram,
-        }
+            effective_resolution,
+        })
     }
 }
 
@@ -135,18 +168,27 @@ impl<P: TTTRStream + Iterator> G3Sync<P> {
 ///
 /// ## Parameters
 ///
-/// The parameters to the algorithm are passed via a `G3Params` struct that contains
+/// The parameters to the algorithm are passed via a `G3SyncParams` struct that contains
 /// the following:
 ///    - channel_sync: The number of the first input channel into the TCSPC,
 ///    - channel_1: The number of the second input channel into the TCSPC,
 ///    - channel_2: The number of the third input channel into the TCSPC,
-///    - correlation_window: Length of the correlation window of interest in seconds,
 ///    - resolution: Resolution of the g3 histogram in seconds,
 ///
+/// The correlation window is fixed to the sync period read from the file.
+///
+/// ## Sync channel restrictions
+/// The sync is always taken to be the oldest click of each triplet, i.e. both photons
+/// are timed against the sync pulse that precedes them. Therefore the sync channel
+/// must be a dedicated input and can't double as one of the data channels. Nor is it
+/// possible to use two independent syncs, one per data channel. Parameters that
+/// break this are rejected with an `InvalidParams` error.
+///
 /// ## Return
 /// A square matrix with the (0, 0) index being the (t1=0, t2=0) delays grow down and
 /// to the right. First index is tau1 and second index is tau2.
 pub fn g3_sync(f: &File, params: &G3SyncParams) -> Result<G3SyncResult, Error> {
+    params.validate()?;
     let start_record = params.start_record;
     let stop_record = params.stop_record;
     match f {
@@ -168,9 +210,96 @@ pub fn g3_sync(f: &File, params: &G3SyncParams) -> Result<G3SyncResult, Error> {
                     params: *params,
                     sync_period,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(_) => Err(Error::NotImplemented(String::from(
+            "The synced g3 algorithm is only supported in T3 mode",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_hht3, write_ptu, T3Event, Tag, HHT3_HH2};
+
+    fn params(channel_sync: i32, channel_1: i32, channel_2: i32) -> G3SyncParams {
+        G3SyncParams {
+            channel_sync,
+            channel_1,
+            channel_2,
+            resolution: 100e-12,
+            start_record: None,
+            stop_record: None,
+        }
+    }
+
+    #[test]
+    fn sync_colliding_with_channel_1_is_rejected() {
+        assert!(params(0, 1, 2).validate().is_ok());
+        assert!(matches!(
+            params(1, 1, 2).validate(),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn sync_colliding_with_channel_2_is_rejected() {
+        assert!(matches!(
+            params(2, 1, 2).validate(),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    /// T3 file with a sync click followed by a photon on each data channel on every
+    /// sync period.
+    fn triplets(channel_sync: i32, channel_1: i32, channel_2: i32) -> Vec<T3Event> {
+        (0..2_000u64)
+            .flat_map(|k| {
+                let photon = |channel, dtime| T3Event::Photon {
+                    channel,
+                    nsync: k,
+                    dtime,
+                };
+                vec![
+                    photon(channel_sync, 0),
+                    photon(channel_1, 20 + k % 30),
+                    photon(channel_2, 60 + k % 40),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_on_the_last_channel_gives_the_same_histogram() {
+        let g3 = |events: &[T3Event], params: &G3SyncParams| {
+            let tags = [("MeasDesc_Resolution", Tag::Float8(100e-12))];
+            let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(events));
+            g3_sync(&File::open(tmp.path()).unwrap(), params).unwrap()
+        };
+        let mut first = params(1, 2, 3);
+        first.resolution = 1e-9;
+        let mut last = params(3, 1, 2);
+        last.resolution = 1e-9;
+
+        let reference = g3(&triplets(1, 2, 3), &first);
+        assert!(reference.hist.sum() > 0);
+        let swapped = g3(&triplets(3, 1, 2), &last);
+        assert_eq!(swapped.hist, reference.hist);
+        assert_eq!(swapped.t, reference.t);
+    }
+
+    #[test]
+    fn non_divisible_resolution_is_reported() {
+        // 4 bins of 3 ns don't fill the 12.5 ns sync period
+        let tags = [("MeasDesc_Resolution", Tag::Float8(100e-12))];
+        let tmp = write_ptu(HHT3_HH2, 12.5e-9, &tags, &encode_hht3(&triplets(1, 2, 3)));
+        let mut params = params(1, 2, 3);
+        params.resolution = 3e-9;
+        let result = g3_sync(&File::open(tmp.path()).unwrap(), &params).unwrap();
+        assert_eq!(result.hist.dim(), (4, 4));
+        assert!((result.effective_resolution - 3.125e-9).abs() < 1e-18);
     }
 }
@@ -0,0 +1,47 @@
+use crate::{errors::Error, headers::File, Click, RecordKind};
+
+/// Time in seconds and bits of every marker record of the file, in file order.
+///
+/// Photons and overflows are skipped so the result is a compact log of the external
+/// events, e.g. to line up the measurement with the instruments that sent them.
+pub fn timeline(f: &File) -> Result<Vec<(f64, u8)>, Error> {
+    let stream = f.records()?;
+    let time_resolution = stream.time_resolution();
+
+    Ok(stream
+        .filter_map(|rec| match rec.kind() {
+            RecordKind::Marker { bits } => Some(((*rec.tof() as f64) * time_resolution, bits)),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encode_pht2, write_ptu, Event, PHT2};
+
+    #[test]
+    fn periodic_markers_are_evenly_spaced() {
+        // A marker every 100 us over many overflows of the time tag, with photons in
+        // between them
+        let period = 25_000_000u64;
+        let events: Vec<Event> = (1..=200u64)
+            .flat_map(|k| {
+                vec![
+                    Event::Marker(1 << (k % 3), k * period),
+                    Event::Photon((k % 2) as i32, k * period + 12_345),
+                ]
+            })
+            .collect();
+        let tmp = write_ptu(PHT2, 4e-12, &[], &encode_pht2(&events));
+        let f = File::open(tmp.path()).unwrap();
+
+        let markers = timeline(&f).unwrap();
+        assert_eq!(markers.len(), 200);
+        for (k, &(t, bits)) in (1..=200u64).zip(markers.iter()) {
+            assert!((t - (k as f64) * 100e-6).abs() < 1e-12);
+            assert_eq!(bits, 1 << (k % 3));
+        }
+    }
+}
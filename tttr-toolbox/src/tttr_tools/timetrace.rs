@@ -1,7 +1,8 @@
 use crate::errors::Error;
 use crate::headers::{File, RecordType};
+use crate::parsers::custom::CustomStream;
 use crate::parsers::ptu;
-use crate::{Click, TTTRFile, TTTRStream};
+use crate::{to_native_units, Click, TTTRFile, TTTRStream};
 use std::fmt::Debug;
 
 struct TimeTrace<P: TTTRStream + Iterator> {
@@ -11,12 +12,21 @@ struct TimeTrace<P: TTTRStream + Iterator> {
 
 /// Results for the timetrace algorithm
 ///
-/// It stores both the intensity trace and the record number of the last click for
-/// each bin in the intensity trace. This makes is possible to implement algorithms
-/// like photon post-selection.
+/// It stores both the intensity trace and, for each bin in the intensity trace, the
+/// record number of the first record past its end. The records of bin `i` are then
+/// those between `recnum_trace[i - 1]` and `recnum_trace[i]`. This makes is possible
+/// to implement algorithms like photon post-selection.
+///
+/// The Mandel Q parameter of the counts per bin, `variance / mean - 1`, is only
+/// computed when requested with `TimeTraceParams::mandel_q`. It is `None` otherwise
+/// or if the trace has no counts. Likewise `smoothed` is only computed when a
+/// smoothing time constant is given.
+#[derive(Debug, Clone)]
 pub struct TimeTraceResult {
     pub intensity: Vec<u64>,
     pub recnum_trace: Vec<u64>,
+    pub mandel_q: Option<f64>,
+    pub smoothed: Option<Vec<f64>>,
 }
 
 /// Parameters for the timetrace algorithm
@@ -25,42 +35,233 @@ pub struct TimeTraceResult {
 ///   1. resolution: The resolution in seconds of the intensity time trace.
 ///   2. channel: Optional channel we want to monitor. If None is passed then all
 ///      all channels are summed together.
+///   3. marker_channels: Optional offset to map marker records onto the virtual
+///      channel `offset + marker_bits`. Markers are never included in the sum
+///      over all channels.
+///   4. overflow_channel: Optional channel to report overflow records on as clicks.
+///      Monitoring it with `channel` gives a timetrace of the overflows, gaps on it
+///      reveal clock glitches and dead zones in the count rate. Overflows are never
+///      included in the sum over all channels.
+///   5. fixed_length: Emit exactly `ceil(duration / resolution)` bins, including the
+///      last partial bin and trailing empty ones, so that traces of files with the
+///      same duration can be aligned. The duration is read from the header and falls
+///      back to the time of the last record, usually an overflow.
+///   6. mandel_q: Compute the Mandel Q parameter of the counts per bin in the same
+///      pass. Negative values signal sub-Poissonian light.
+///   7. smoothing: Optional time constant in seconds of an exponential moving average
+///      of the counts per bin, for less noisy live displays. It is computed in the
+///      same pass and returned next to the raw trace. A zero time constant leaves the
+///      trace untouched.
+///   8. start_record: Optional index of the first record to process. The overflows
+///      before it are unknown so the trace starts at the time of that record.
+///      `recnum_trace` still counts records from the start of the file.
+///   9. stop_record: Optional index of the record to stop at. When a range is
+///      selected fixed length traces span the selected records instead of the
+///      acquisition time on the header.
 #[derive(Debug, Copy, Clone)]
 pub struct TimeTraceParams {
     pub resolution: f64,
     pub channel: Option<i32>,
+    pub marker_channels: Option<i32>,
+    pub overflow_channel: Option<i32>,
+    pub fixed_length: bool,
+    pub mandel_q: bool,
+    pub smoothing: Option<f64>,
+    pub start_record: Option<usize>,
+    pub stop_record: Option<usize>,
 }
 
-impl<P: TTTRStream + Iterator> TimeTrace<P> {
-    fn compute(self) -> TimeTraceResult
-    where
-        <P as Iterator>::Item: Debug + Click,
-    {
-        let blips_per_bin = (self.params.resolution / self.click_stream.time_resolution()) as u64;
-        let mut trace: Vec<u64> = vec![];
-        let mut recnum_trace: Vec<u64> = vec![];
+/// Running mean and variance of the counts per bin using Welford's algorithm.
+#[derive(Default)]
+struct CountStats {
+    n: u64,
+    mean: f64,
+    m2: f64,
+}
 
-        let mut counter = 0;
-        let mut end_of_bin = blips_per_bin;
+impl CountStats {
+    #[inline(always)]
+    fn push(&mut self, counts: u64) {
+        let x = counts as f64;
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / (self.n as f64);
+        self.m2 += delta * (x - self.mean);
+    }
 
-        for (idx, rec) in self.click_stream.into_iter().enumerate() {
-            if let Some(ch) = self.params.channel {
-                counter += if *rec.channel() == ch { 1 } else { 0 }
+    fn mandel_q(&self) -> Option<f64> {
+        if self.mean > 0.0 {
+            let variance = self.m2 / (self.n as f64);
+            Some(variance / self.mean - 1.0)
+        } else {
+            None
+        }
+    }
+}
+
+/// Per click update of the intensity timetrace.
+pub(super) struct TimeTraceHistogram {
+    blips_per_bin: u64,
+    channel: Option<i32>,
+    overflow_channel: Option<i32>,
+    counter: u64,
+    end_of_bin: u64,
+    trace: Vec<u64>,
+    recnum_trace: Vec<u64>,
+    fixed_length: bool,
+    duration: Option<u64>,
+    /// Time of flight at which the first bin starts. `None` until the first record
+    /// when it is unknown.
+    origin: Option<u64>,
+    last_tof: u64,
+    /// Number of the record following the last one pushed.
+    next_idx: u64,
+    stats: Option<CountStats>,
+    /// Weight of the newest bin on the moving average and the smoothed trace.
+    smoothing: Option<(f64, Vec<f64>)>,
+}
+
+impl TimeTraceHistogram {
+    /// `acquisition_time` in seconds is only used for fixed length traces.
+    pub(super) fn new(
+        params: &TimeTraceParams,
+        time_resolution: f64,
+        acquisition_time: Option<f64>,
+    ) -> Result<Self, Error> {
+        let blips_per_bin = to_native_units(params.resolution, time_resolution)?;
+        if blips_per_bin == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The timetrace resolution {} is finer than the time resolution of the file {}",
+                params.resolution, time_resolution
+            )));
+        }
+        if let Some(tau) = params.smoothing {
+            if tau.is_nan() || tau < 0.0 {
+                return Err(Error::InvalidParams(format!(
+                    "The smoothing time constant must be non-negative but got {}",
+                    tau
+                )));
+            }
+        }
+        Ok(Self {
+            blips_per_bin,
+            channel: params.channel,
+            overflow_channel: params.overflow_channel,
+            counter: 0,
+            end_of_bin: blips_per_bin,
+            trace: vec![],
+            recnum_trace: vec![],
+            fixed_length: params.fixed_length,
+            // Rounded to the closest tick rather than truncated
+            duration: acquisition_time
+                .map(|x| to_native_units(x + time_resolution / 2.0, time_resolution))
+                .transpose()?,
+            origin: if params.start_record.is_some() {
+                None
             } else {
-                counter += if *rec.channel() >= 0 { 1 } else { 0 };
-            };
+                Some(0)
+            },
+            last_tof: 0,
+            next_idx: 0,
+            stats: if params.mandel_q {
+                Some(CountStats::default())
+            } else {
+                None
+            },
+            smoothing: params
+                .smoothing
+                .map(|tau| (1.0 - (-params.resolution / tau).exp(), vec![])),
+        })
+    }
 
-            if *rec.tof() > end_of_bin {
-                trace.push(counter);
-                recnum_trace.push(idx as u64);
-                counter = 0;
-                end_of_bin += blips_per_bin;
-            };
+    /// `idx` is the position of the record within the stream.
+    #[inline(always)]
+    pub(super) fn push(&mut self, idx: usize, tof: u64, channel: i32, markers: u8) {
+        if self.origin.is_none() {
+            self.origin = Some(tof);
+            self.end_of_bin = tof + self.blips_per_bin;
+        }
+
+        // Gaps longer than a bin leave empty bins behind
+        while tof > self.end_of_bin {
+            self.close_bin(self.counter);
+            self.trace.push(self.counter);
+            self.recnum_trace.push(idx as u64);
+            self.counter = 0;
+            self.end_of_bin += self.blips_per_bin;
+        }
+
+        if let Some(ch) = self.channel {
+            self.counter += if channel == ch { 1 } else { 0 }
+        } else {
+            let is_photon = channel >= 0 && markers == 0 && Some(channel) != self.overflow_channel;
+            self.counter += if is_photon { 1 } else { 0 };
+        };
+        self.last_tof = tof;
+        self.next_idx = idx as u64 + 1;
+    }
+
+    /// Feed the `counts` of a finished bin to the statistics and the moving average.
+    #[inline(always)]
+    fn close_bin(&mut self, counts: u64) {
+        if let Some(stats) = &mut self.stats {
+            stats.push(counts);
+        }
+        if let Some((alpha, smoothed)) = &mut self.smoothing {
+            let x = counts as f64;
+            // The average starts at the first bin instead of at zero
+            let previous = smoothed.last().copied().unwrap_or(x);
+            smoothed.push(previous + *alpha * (x - previous));
+        }
+    }
+
+    pub(super) fn finish(mut self) -> TimeTraceResult {
+        if self.fixed_length {
+            let origin = self.origin.unwrap_or(0);
+            let duration = self.duration.unwrap_or(self.last_tof - origin);
+            let n_bins = duration.div_ceil(self.blips_per_bin) as usize;
+            // Close the partial bin left open by the last record
+            if self.trace.len() < n_bins {
+                self.close_bin(self.counter);
+                self.trace.push(self.counter);
+                self.recnum_trace.push(self.next_idx);
+            }
+            for _ in self.trace.len()..n_bins {
+                self.close_bin(0);
+            }
+            self.trace.resize(n_bins, 0);
+            self.recnum_trace.resize(n_bins, self.next_idx);
         }
         TimeTraceResult {
-            intensity: trace,
-            recnum_trace,
+            intensity: self.trace,
+            recnum_trace: self.recnum_trace,
+            mandel_q: self.stats.and_then(|x| x.mandel_q()),
+            smoothed: self.smoothing.map(|(_, smoothed)| smoothed),
+        }
+    }
+}
+
+impl<P: TTTRStream + Iterator> TimeTrace<P> {
+    fn compute(self, acquisition_time: Option<f64>) -> Result<TimeTraceResult, Error>
+    where
+        <P as Iterator>::Item: Debug + Click,
+    {
+        let mut histogram = TimeTraceHistogram::new(
+            &self.params,
+            self.click_stream.time_resolution(),
+            acquisition_time,
+        )?;
+
+        let first_record = self.params.start_record.unwrap_or(0);
+        for (idx, rec) in self.click_stream.into_iter().enumerate() {
+            histogram.push(
+                first_record + idx,
+                *rec.tof(),
+                *rec.channel(),
+                *rec.markers(),
+            );
         }
+        Ok(histogram.finish())
     }
 }
 
@@ -80,43 +281,288 @@ impl<P: TTTRStream + Iterator> TimeTrace<P> {
 /// of clicks per interval and therefore the relative error for the number of counts
 /// grows as we make intervals finer.
 pub fn timetrace(f: &File, params: &TimeTraceParams) -> Result<TimeTraceResult, Error> {
-    let start_record = None;
-    let stop_record = None;
+    let start_record = params.start_record;
+    let stop_record = params.stop_record;
+    let acquisition_time = if start_record.is_none() && stop_record.is_none() {
+        f.acquisition_time()
+    } else {
+        None
+    };
     match f {
         File::PTU(x) => match x.record_type().unwrap() {
             RecordType::PHT2 => {
-                let stream = ptu::streamers::PHT2Stream::new(x, start_record, stop_record)?;
+                let stream = ptu::streamers::PHT2Stream::new(x, start_record, stop_record)?
+                    .with_marker_channels(params.marker_channels)
+                    .with_overflow_channel(params.overflow_channel);
                 let tt = TimeTrace {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(acquisition_time)
             }
             RecordType::HHT2_HH1 => {
-                let stream = ptu::streamers::HHT2_HH1Stream::new(x, start_record, stop_record)?;
+                let stream = ptu::streamers::HHT2_HH1Stream::new(x, start_record, stop_record)?
+                    .with_marker_channels(params.marker_channels)
+                    .with_overflow_channel(params.overflow_channel);
                 let tt = TimeTrace {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(acquisition_time)
             }
             RecordType::HHT2_HH2 => {
-                let stream = ptu::streamers::HHT2_HH2Stream::new(x, start_record, stop_record)?;
+                let stream = ptu::streamers::HHT2_HH2Stream::new(x, start_record, stop_record)?
+                    .with_marker_channels(params.marker_channels)
+                    .with_overflow_channel(params.overflow_channel);
                 let tt = TimeTrace {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(acquisition_time)
             }
             RecordType::HHT3_HH2 => {
-                let stream = ptu::streamers::HHT3_HH2Stream::new(x, start_record, stop_record)?;
+                let stream = ptu::streamers::HHT3_HH2Stream::new(x, start_record, stop_record)?
+                    .with_marker_channels(params.marker_channels)
+                    .with_overflow_channel(params.overflow_channel);
                 let tt = TimeTrace {
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute(acquisition_time)
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(x) => {
+            let stream = CustomStream::new(x.as_ref(), start_record, stop_record)?
+                .with_marker_channels(params.marker_channels)
+                .with_overflow_channel(params.overflow_channel);
+            let tt = TimeTrace {
+                click_stream: stream,
+                params: *params,
+            };
+            tt.compute(acquisition_time)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::headers::RecordStream;
+    use crate::test_utils::{encode_pht2, random_clicks, write_ptu, Event, Tag, PHT2};
+    use crate::{RecordDecoder, TTTRRecord};
+    use std::io::Write;
+
+    pub(crate) fn params(fixed_length: bool) -> TimeTraceParams {
+        TimeTraceParams {
+            resolution: 1e-9,
+            channel: None,
+            marker_channels: None,
+            overflow_channel: None,
+            fixed_length,
+            mandel_q: false,
+            smoothing: None,
+            start_record: None,
+            stop_record: None,
+        }
+    }
+
+    #[test]
+    fn gaps_leave_empty_bins() {
+        let tofs = [500, 700, 5500, 5600, 9000];
+        let events: Vec<Event> = tofs.iter().map(|&t| Event::Photon(0, t)).collect();
+        let tmp = write_ptu(PHT2, 1e-12, &[], &encode_pht2(&events));
+        let tt = timetrace(&File::open(tmp.path()).unwrap(), &params(false)).unwrap();
+
+        assert_eq!(tt.intensity, vec![2, 0, 0, 0, 0, 2, 0, 0]);
+        assert_eq!(tt.recnum_trace, vec![2, 2, 2, 2, 2, 4, 4, 4]);
+    }
+
+    #[test]
+    fn equal_durations_give_equal_lengths() {
+        // 1 ms acquisitions with different count rates and last photons
+        let tags = [("MeasDesc_AcquisitionTime", Tag::Int8(1))];
+        let lengths: Vec<usize> = [(2_000, 400_000), (500, 1_000_000)]
+            .iter()
+            .map(|&(n, mean_gap)| {
+                let events: Vec<Event> = random_clicks(n, mean_gap, 11)
+                    .into_iter()
+                    .take_while(|event| matches!(*event, Event::Photon(_, t) if t < 1_000_000_000))
+                    .collect();
+                let tmp = write_ptu(PHT2, 1e-12, &tags, &encode_pht2(&events));
+                let params = TimeTraceParams {
+                    resolution: 10e-6,
+                    ..params(true)
+                };
+                let tt = timetrace(&File::open(tmp.path()).unwrap(), &params).unwrap();
+                assert_eq!(tt.intensity.iter().sum::<u64>(), events.len() as u64);
+                tt.intensity.len()
+            })
+            .collect();
+
+        assert_eq!(lengths, vec![100, 100]);
+    }
+
+    #[test]
+    fn zero_time_constant_keeps_the_raw_trace() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(5_000, 2_500, 1)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+        let mut params = params(false);
+        params.resolution = 100e-9;
+        params.smoothing = Some(0.0);
+        let result = timetrace(&f, &params).unwrap();
+
+        let raw: Vec<f64> = result.intensity.iter().map(|&x| x as f64).collect();
+        assert!(raw.len() > 10);
+        assert_eq!(result.smoothed, Some(raw));
+
+        params.smoothing = Some(f64::NAN);
+        assert!(matches!(
+            timetrace(&f, &params),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    /// In memory file with the channel on the top four bits of each record and the
+    /// time of flight in picoseconds on the rest.
+    struct Clicks(Vec<u32>);
+
+    struct Decoder;
+
+    impl RecordDecoder for Decoder {
+        fn decode(&mut self, raw: u32) -> TTTRRecord {
+            TTTRRecord::new((raw >> 28) as i32, (raw & 0x0FFF_FFFF) as u64)
+        }
+    }
+
+    impl TTTRFile for Clicks {
+        fn time_resolution(&self) -> Result<f64, Error> {
+            Ok(1e-12)
+        }
+        fn record_type(&self) -> Result<RecordType, Error> {
+            Ok(RecordType::NotImplemented)
+        }
+        fn record_size_bytes(&self) -> usize {
+            4
+        }
+        fn raw_records(
+            &self,
+            start_record: Option<usize>,
+            stop_record: Option<usize>,
+        ) -> Result<Box<dyn Iterator<Item = u32>>, Error> {
+            let records =
+                self.0[start_record.unwrap_or(0)..stop_record.unwrap_or(self.0.len())].to_vec();
+            Ok(Box::new(records.into_iter()))
+        }
+        fn decoder(&self) -> Result<Box<dyn RecordDecoder>, Error> {
+            Ok(Box::new(Decoder))
+        }
+    }
+
+    #[test]
+    fn custom_decoder_matches_the_ptu_file() {
+        let tofs = [500, 700, 5500, 5600, 9000];
+        let custom = File::Custom(Box::new(Clicks(
+            tofs.iter().map(|&t| (1 << 28) | t as u32).collect(),
+        )));
+        let events: Vec<Event> = tofs.iter().map(|&t| Event::Photon(1, t)).collect();
+        let tmp = write_ptu(PHT2, 1e-12, &[], &encode_pht2(&events));
+        let ptu = File::open(tmp.path()).unwrap();
+
+        let mut params = params(false);
+        params.channel = Some(1);
+        let tt = timetrace(&custom, &params).unwrap();
+        let expected = timetrace(&ptu, &params).unwrap();
+        assert_eq!(tt.intensity, vec![2, 0, 0, 0, 0, 2, 0, 0]);
+        assert_eq!(tt.intensity, expected.intensity);
+        assert_eq!(tt.recnum_trace, expected.recnum_trace);
+    }
+
+    #[test]
+    fn appended_records_continue_the_trace() {
+        let records = encode_pht2(&random_clicks(4_000, 6_250, 8));
+        let half = records.len() / 2;
+        let params = TimeTraceParams {
+            resolution: 1e-6,
+            ..params(false)
+        };
+        let complete = write_ptu(PHT2, 4e-12, &[], &records);
+        let expected = timetrace(&File::open(complete.path()).unwrap(), &params).unwrap();
+
+        // Only the first half of the records have been written so far
+        let growing = write_ptu(PHT2, 4e-12, &[], &records[..half]);
+        let f = File::open(growing.path()).unwrap();
+        let mut stream = f.records().unwrap();
+        let mut histogram =
+            TimeTraceHistogram::new(&params, stream.time_resolution(), None).unwrap();
+        let mut idx = 0;
+        let mut push_available = |stream: &mut RecordStream| {
+            for rec in stream {
+                histogram.push(idx, rec.tof, rec.channel, rec.markers);
+                idx += 1;
+            }
+        };
+        push_available(&mut stream);
+        assert!(!stream.poll_new().unwrap());
+
+        let appended: Vec<u8> = records[half..]
+            .iter()
+            .flat_map(|record| record.to_ne_bytes().to_vec())
+            .collect();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(growing.path())
+            .unwrap()
+            .write_all(&appended)
+            .unwrap();
+        assert!(stream.poll_new().unwrap());
+        push_available(&mut stream);
+        assert!(!stream.poll_new().unwrap());
+        assert_eq!(idx, records.len());
+
+        let continued = histogram.finish();
+        assert!(expected.intensity.iter().sum::<u64>() > 0);
+        assert_eq!(continued.intensity, expected.intensity);
+        assert_eq!(continued.recnum_trace, expected.recnum_trace);
+    }
+
+    #[test]
+    fn mandel_q_matches_the_intensity_trace() {
+        let tmp = write_ptu(
+            PHT2,
+            4e-12,
+            &[],
+            &encode_pht2(&random_clicks(20_000, 6_250, 11)),
+        );
+        let f = File::open(tmp.path()).unwrap();
+
+        for &fixed_length in &[false, true] {
+            let params = TimeTraceParams {
+                resolution: 200e-9,
+                mandel_q: true,
+                ..params(fixed_length)
+            };
+            let tt = timetrace(&f, &params).unwrap();
+
+            // Two pass population variance of the returned trace
+            let n = tt.intensity.len() as f64;
+            let mean = tt.intensity.iter().sum::<u64>() as f64 / n;
+            let variance = tt
+                .intensity
+                .iter()
+                .map(|&x| (x as f64 - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            let expected = variance / mean - 1.0;
+            let mandel_q = tt.mandel_q.unwrap();
+            assert!((mandel_q - expected).abs() < 1e-9 * expected.abs().max(1.0));
+        }
+
+        assert!(timetrace(&f, &params(false)).unwrap().mandel_q.is_none());
     }
 }
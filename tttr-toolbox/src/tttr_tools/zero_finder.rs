@@ -1,7 +1,8 @@
 use crate::errors::Error;
 use crate::headers::{File, RecordType};
+use crate::parsers::custom::CustomStream;
 use crate::parsers::ptu;
-use crate::{Click, TTTRFile, TTTRStream};
+use crate::{to_native_units, Click, TTTRFile, TTTRStream};
 use std::fmt::Debug;
 
 struct ZeroFinder<P: TTTRStream + Iterator> {
@@ -10,6 +11,7 @@ struct ZeroFinder<P: TTTRStream + Iterator> {
 }
 
 /// Result from the zero finder algorithm
+#[derive(Debug, Clone)]
 pub struct ZeroFinderResult {
     pub t: Vec<f64>,
     pub hist: Vec<u64>,
@@ -31,16 +33,25 @@ pub struct ZeroFinderParams {
 }
 
 impl<P: TTTRStream + Iterator> ZeroFinder<P> {
-    fn compute(self) -> ZeroFinderResult
+    fn compute(self) -> Result<ZeroFinderResult, Error>
     where
         <P as Iterator>::Item: Debug + Click,
     {
         let real_resolution = self.params.resolution.clone();
         let n_bins = (self.params.correlation_window / real_resolution) as u64;
-        let correlation_window =
-            self.params.correlation_window / self.click_stream.time_resolution();
+        let correlation_window = to_native_units(
+            self.params.correlation_window,
+            self.click_stream.time_resolution(),
+        )? as f64;
 
         let resolution = (correlation_window / (n_bins as f64)) as u64;
+        if resolution == 0 {
+            return Err(Error::InvalidParams(format!(
+                "The zero finder resolution {} is finer than the time resolution of the file {}",
+                real_resolution,
+                self.click_stream.time_resolution()
+            )));
+        }
         let correlation_window = n_bins * resolution;
         let n_bins = n_bins * 2;
 
@@ -78,10 +89,10 @@ impl<P: TTTRStream + Iterator> ZeroFinder<P> {
         let t = (0..n_bins)
             .map(|i| ((i as f64) - (central_bin as f64)) * real_resolution)
             .collect::<Vec<f64>>();
-        ZeroFinderResult {
+        Ok(ZeroFinderResult {
             t: t,
             hist: histogram,
-        }
+        })
     }
 }
 
@@ -123,7 +134,7 @@ pub fn zerofinder(f: &File, params: &ZeroFinderParams) -> Result<ZeroFinderResul
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::HHT2_HH1 => {
                 let stream = ptu::streamers::HHT2_HH1Stream::new(x, start_record, stop_record)?;
@@ -131,7 +142,7 @@ pub fn zerofinder(f: &File, params: &ZeroFinderParams) -> Result<ZeroFinderResul
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::HHT2_HH2 => {
                 let stream = ptu::streamers::HHT2_HH2Stream::new(x, start_record, stop_record)?;
@@ -139,7 +150,7 @@ pub fn zerofinder(f: &File, params: &ZeroFinderParams) -> Result<ZeroFinderResul
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::HHT3_HH2 => {
                 let stream = ptu::streamers::HHT3_HH2Stream::new(x, start_record, stop_record)?;
@@ -147,9 +158,17 @@ pub fn zerofinder(f: &File, params: &ZeroFinderParams) -> Result<ZeroFinderResul
                     click_stream: stream,
                     params: *params,
                 };
-                Ok(tt.compute())
+                tt.compute()
             }
             RecordType::NotImplemented => panic! {"Record type not implemented"},
         },
+        File::Custom(x) => {
+            let stream = CustomStream::new(x.as_ref(), start_record, stop_record)?;
+            let tt = ZeroFinder {
+                click_stream: stream,
+                params: *params,
+            };
+            tt.compute()
+        }
     }
 }
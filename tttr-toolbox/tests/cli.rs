@@ -0,0 +1,120 @@
+//! Failures of the command line tool must explain what went wrong.
+#![cfg(feature = "ndarray")]
+
+mod common;
+
+use std::process::{Command, Output};
+
+use common::{temp_path, write_pht2};
+
+fn run_tttr(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tttr"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn unwritable_output_names_the_path_and_subcommand() {
+    let input = temp_path("cli-input.ptu");
+    write_pht2(&input, 100);
+    let output = temp_path("missing-directory").join("intensity.npz");
+    let output_arg = output.to_str().unwrap();
+
+    let run = run_tttr(&[
+        "intensity",
+        "-i",
+        input.to_str().unwrap(),
+        "-o",
+        output_arg,
+        "-r",
+        "1e-6",
+    ]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(!run.status.success());
+    let stderr = String::from_utf8(run.stderr).unwrap();
+    assert!(stderr.contains(&format!(
+        "Could not create output file {} for the intensity subcommand",
+        output_arg
+    )));
+}
+
+#[test]
+fn header_prints_a_single_tag() {
+    let input = temp_path("cli-header.ptu");
+    write_pht2(&input, 100);
+    let input_arg = input.to_str().unwrap();
+
+    let resolution = run_tttr(&[
+        "header",
+        "-i",
+        input_arg,
+        "--tag",
+        "MeasDesc_GlobalResolution",
+    ]);
+    let records = run_tttr(&[
+        "header",
+        "-i",
+        input_arg,
+        "--tag",
+        "TTResult_NumberOfRecords",
+    ]);
+    let missing = run_tttr(&["header", "-i", input_arg, "--tag", "Not_A_Tag"]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(resolution.status.success());
+    assert_eq!(
+        String::from_utf8(resolution.stdout).unwrap(),
+        format!("{}\n", common::RESOLUTION)
+    );
+    assert!(records.status.success());
+    assert_eq!(String::from_utf8(records.stdout).unwrap(), "100\n");
+    assert!(!missing.status.success());
+    assert!(missing.stdout.is_empty());
+    let stderr = String::from_utf8(missing.stderr).unwrap();
+    assert!(stderr.contains("Tag Not_A_Tag is not on the header"));
+}
+
+#[test]
+fn unparseable_arguments_are_named() {
+    let input = temp_path("cli-arguments.ptu");
+    write_pht2(&input, 1_000);
+    let output = temp_path("cli-arguments.npz");
+    let g2_args = |channel_1: &'static str, window: &'static str| {
+        let run = run_tttr(&[
+            "g2",
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "-1",
+            channel_1,
+            "-2",
+            "1",
+            "-w",
+            window,
+            "-r",
+            "1e-9",
+        ]);
+        (run.status.success(), String::from_utf8(run.stderr).unwrap())
+    };
+
+    let (success, stderr) = g2_args("zero", "50e-9");
+    assert!(!success);
+    assert!(stderr.contains("Invalid channel 'zero' for argument <channel1>"));
+    assert!(!stderr.contains("panicked"));
+
+    let (success, stderr) = g2_args("0", "50ns");
+    assert!(!success);
+    assert!(stderr.contains("Invalid value '50ns' for argument <correlation_window>"));
+    assert!(!stderr.contains("panicked"));
+
+    // Hexadecimal channels and scientific notation are accepted
+    let (success, stderr) = g2_args("0x0", "50e-9");
+    assert!(success, "{}", stderr);
+    assert!(output.exists());
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+}
@@ -0,0 +1,52 @@
+//! Synthetic PTU files shared by the integration tests.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+/// Time resolution in seconds of the files written by `write_pht2`.
+pub const RESOLUTION: f64 = 4e-12;
+
+fn tag(name: &str, tag_type: u32, value: [u8; 8]) -> Vec<u8> {
+    let mut bytes = vec![0; 32];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    bytes.extend_from_slice(&(-1i32).to_le_bytes());
+    bytes.extend_from_slice(&tag_type.to_le_bytes());
+    bytes.extend_from_slice(&value);
+    bytes
+}
+
+/// PicoHarp T2 file with clicks alternating between channels 0 and 1 every
+/// `1000 + k % 7` ticks, well before the first overflow. Returns the time of
+/// flight of every record.
+pub fn write_pht2(path: &Path, n: u32) -> Vec<u64> {
+    let tofs: Vec<u64> = (1..=n as u64).map(|k| k * 1000 + k % 7).collect();
+    let mut bytes = b"PQTTTR\0\0".to_vec();
+    bytes.extend_from_slice(b"1.0.00\0\0");
+    bytes.extend(tag(
+        "TTResultFormat_TTTRRecType",
+        0x10000008,
+        0x00010203i64.to_le_bytes(),
+    ));
+    bytes.extend(tag(
+        "TTResult_NumberOfRecords",
+        0x10000008,
+        (n as i64).to_le_bytes(),
+    ));
+    bytes.extend(tag(
+        "MeasDesc_GlobalResolution",
+        0x20000008,
+        RESOLUTION.to_le_bytes(),
+    ));
+    bytes.extend(tag("Header_End", 0xFFFF0008, [0; 8]));
+    for (k, tof) in tofs.iter().enumerate() {
+        let record = (((k % 2) as u32) << 28) | *tof as u32;
+        bytes.extend_from_slice(&record.to_le_bytes());
+    }
+    std::fs::write(path, bytes).unwrap();
+    tofs
+}
+
+/// Path on the temporary directory unique to the test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("tttr-toolbox-it-{}-{}", std::process::id(), name))
+}
@@ -0,0 +1,42 @@
+//! With every optional feature disabled, e.g. `cargo test --no-default-features`,
+//! the crate must still build without pyo3 and read PTU files.
+#![cfg(not(any(
+    feature = "python",
+    feature = "ndarray",
+    feature = "matlab",
+    feature = "mmap"
+)))]
+
+mod common;
+
+use tttr_toolbox::{
+    headers::{in_seconds, File, RecordType},
+    parsers::ptu::PTUTag,
+    TTTRFile,
+};
+
+#[test]
+fn headers_and_records_without_features() {
+    let path = common::temp_path("feature-free.ptu");
+    let tofs = common::write_pht2(&path, 1_000);
+
+    let f = File::open(path.clone()).unwrap();
+    match &f {
+        File::PTU(x) => {
+            assert!(matches!(x.record_type(), Ok(RecordType::PHT2)));
+            match x.header.get("MeasDesc_GlobalResolution") {
+                Some(PTUTag::Float8(resolution)) => assert_eq!(*resolution, common::RESOLUTION),
+                tag => panic!("Unexpected resolution tag {:?}", tag),
+            }
+        }
+        File::Custom(_) => unreachable!(),
+    }
+    let clicks: Vec<(i32, f64)> = in_seconds(f.records().unwrap()).collect();
+    assert_eq!(clicks.len(), tofs.len());
+    for (k, (&(channel, seconds), &tof)) in clicks.iter().zip(tofs.iter()).enumerate() {
+        assert_eq!(channel, (k % 2) as i32);
+        assert!((seconds - tof as f64 * common::RESOLUTION).abs() < 1e-15);
+    }
+
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,126 @@
+//! The `--t-start`/`--t-stop` arguments of the command line tool must give the same
+//! output as the library run on the equivalent record range.
+#![cfg(feature = "matlab")]
+
+mod common;
+
+use std::process::Command;
+
+use ndarray::arr1;
+use tttr_toolbox::{
+    headers::File,
+    output::matlab::MatWriter,
+    tttr_tools::{
+        g2::{g2, G2Mode, G2Params},
+        timetrace::{timetrace, TimeTraceParams},
+    },
+};
+
+use common::{temp_path, write_pht2, RESOLUTION};
+
+const T_START: f64 = 10e-6;
+const T_STOP: f64 = 30e-6;
+
+fn run_tttr(args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_tttr"))
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn time_range_matches_record_range() {
+    let input = temp_path("input.ptu");
+    let g2_output = temp_path("g2.mat");
+    let intensity_output = temp_path("intensity.mat");
+    let tofs = write_pht2(&input, 20_000);
+
+    // First record at or after the start and first record at or after the stop
+    let start = tofs
+        .iter()
+        .position(|&t| t as f64 * RESOLUTION >= T_START)
+        .unwrap();
+    let stop = tofs
+        .iter()
+        .position(|&t| t as f64 * RESOLUTION >= T_STOP)
+        .unwrap();
+    let (t_start, t_stop) = (T_START.to_string(), T_STOP.to_string());
+    let input_arg = input.to_str().unwrap();
+
+    run_tttr(&[
+        "--format",
+        "mat",
+        "g2",
+        "-i",
+        input_arg,
+        "-o",
+        g2_output.to_str().unwrap(),
+        "-1",
+        "0",
+        "-2",
+        "1",
+        "-w",
+        "20e-9",
+        "-r",
+        "100e-12",
+        "--t-start",
+        &t_start,
+        "--t-stop",
+        &t_stop,
+    ]);
+    run_tttr(&[
+        "--format",
+        "mat",
+        "intensity",
+        "-i",
+        input_arg,
+        "-o",
+        intensity_output.to_str().unwrap(),
+        "-r",
+        "1e-6",
+        "--t-start",
+        &t_start,
+        "--t-stop",
+        &t_stop,
+    ]);
+
+    let f = File::open(input.clone()).unwrap();
+    let params = G2Params {
+        record_ranges: Some(vec![(start, stop)]),
+        ..G2Params::new(0, 1, 20e-9, 100e-12)
+    };
+    let g2_histogram = g2(&f, &params, G2Mode::Symmetric).unwrap();
+    assert!(g2_histogram.hist.iter().sum::<u64>() > 0);
+    let mut mat = MatWriter::new(vec![]).unwrap();
+    mat.add_array("histogram", &arr1(&g2_histogram.hist))
+        .unwrap();
+    mat.add_array("t", &arr1(&g2_histogram.t)).unwrap();
+    assert_eq!(std::fs::read(&g2_output).unwrap(), mat.finish().unwrap());
+
+    let params = TimeTraceParams {
+        resolution: 1e-6,
+        channel: None,
+        marker_channels: None,
+        overflow_channel: None,
+        fixed_length: false,
+        mandel_q: false,
+        smoothing: None,
+        start_record: Some(start),
+        stop_record: Some(stop),
+    };
+    let tt = timetrace(&f, &params).unwrap();
+    assert!(!tt.intensity.is_empty());
+    let mut mat = MatWriter::new(vec![]).unwrap();
+    mat.add_array("intensity", &arr1(&tt.intensity)).unwrap();
+    mat.add_array("recnum_trace", &arr1(&tt.recnum_trace))
+        .unwrap();
+    assert_eq!(
+        std::fs::read(&intensity_output).unwrap(),
+        mat.finish().unwrap()
+    );
+
+    for path in &[input, g2_output, intensity_output] {
+        let _ = std::fs::remove_file(path);
+    }
+}
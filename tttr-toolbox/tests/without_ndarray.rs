@@ -0,0 +1,46 @@
+//! Without the `ndarray` feature, e.g. `cargo test --no-default-features`, the g3
+//! tools are left out but the `Vec` based tools must still build and work.
+#![cfg(not(feature = "ndarray"))]
+
+mod common;
+
+use tttr_toolbox::headers::File;
+use tttr_toolbox::tttr_tools::g2::{g2, G2Mode, G2Params};
+use tttr_toolbox::tttr_tools::lifetime::lifetime_external_sync;
+use tttr_toolbox::tttr_tools::timetrace::{timetrace, TimeTraceParams};
+
+#[test]
+fn one_dimensional_tools_without_ndarray() {
+    let path = common::temp_path("without-ndarray.ptu");
+    let n = 1_000;
+    common::write_pht2(&path, n);
+    let f = File::open(path.clone()).unwrap();
+
+    // Consecutive clicks alternate channels about 4 ns apart
+    let params = G2Params::new(0, 1, 10e-9, 1e-9);
+    let result = g2(&f, &params, G2Mode::Symmetric).unwrap();
+    assert_eq!(result.t.len(), result.hist.len());
+    assert_eq!(result.hist.iter().sum::<u64>(), n as u64 - 1);
+
+    let params = TimeTraceParams {
+        resolution: 1e-6,
+        channel: None,
+        marker_channels: None,
+        overflow_channel: None,
+        fixed_length: true,
+        mandel_q: false,
+        smoothing: None,
+        start_record: None,
+        stop_record: None,
+    };
+    let trace = timetrace(&f, &params).unwrap();
+    assert_eq!(trace.intensity.iter().sum::<u64>(), n as u64);
+
+    // Clicks on channel 0 come 1000 to 1006 ticks after a sync every 2000 ticks
+    let sync_times: Vec<u64> = (0..=n as u64 / 2).map(|k| 2_000 * k).collect();
+    let decay = lifetime_external_sync(&f, 0, &sync_times, 100e-12).unwrap();
+    assert_eq!(decay.total_counts, n as u64 / 2);
+    assert_eq!(decay.hist[40], n as u64 / 2);
+
+    let _ = std::fs::remove_file(path);
+}